@@ -57,9 +57,9 @@ fn write_b_bench(b: &mut Bencher, tx_size: TxSize, qindex: usize) {
     aom_dsp_rtcd();
   }
   let config =
-    EncoderConfig { quantizer: qindex, speed: 10, ..Default::default() };
+    EncoderConfig { quantizer: qindex, ..EncoderConfig::with_speed_preset(10) };
   let mut fi = FrameInvariants::new(1024, 1024, config);
-  let mut w = ec::Writer::new();
+  let mut w = ec::WriterEncoder::new();
   let fc = CDFContext::new(fi.config.quantizer as u8);
   let bc = BlockContext::new(fi.sb_width * 16, fi.sb_height * 16);
   let mut fs = FrameState::new(&fi);
@@ -88,6 +88,12 @@ fn write_b_bench(b: &mut Bencher, tx_size: TxSize, qindex: usize) {
               p,
               &bo,
               mode,
+              0,
+              IntraEdgeFilter::Disabled,
+              CFLParams::default(),
+              None,
+              PaletteParams::default(),
+              None,
               tx_size,
               tx_type,
               tx_size.block_size(),
@@ -101,6 +107,72 @@ fn write_b_bench(b: &mut Bencher, tx_size: TxSize, qindex: usize) {
   });
 }
 
+struct EncodeFrame {
+  speed: usize,
+  prune_partitions: bool
+}
+
+impl TDynBenchFn for EncodeFrame {
+  fn run(&self, b: &mut Bencher) {
+    encode_frame_bench(b, self.speed, self.prune_partitions);
+  }
+}
+
+// Bottom-up (speed 0) and top-down (speed 1) partition searches, with and
+// without pruning
+pub fn partition_search() -> Vec<TestDescAndFn> {
+  use std::borrow::Cow;
+  let mut benches = ::std::vec::Vec::new();
+  for &speed in &[0, 1] {
+    for &prune_partitions in &[false, true] {
+      let e = EncodeFrame { speed, prune_partitions };
+      let n = format!(
+        "encode_frame_bench(speed: {}, prune_partitions: {})",
+        speed, prune_partitions
+      );
+      benches.push(TestDescAndFn {
+        desc: TestDesc { name: Cow::from(n), ignore: false },
+        testfn: TestFn::DynBenchFn(Box::new(e))
+      });
+    }
+  }
+  benches
+}
+
+fn encode_frame_bench(b: &mut Bencher, speed: usize, prune_partitions: bool) {
+  use rand::{ChaChaRng, Rng, SeedableRng};
+  unsafe {
+    av1_rtcd();
+    aom_dsp_rtcd();
+  }
+  let (w, h) = (128, 128);
+  let mut config = EncoderConfig::with_speed_preset(speed);
+  config.speed_settings.prune_partitions = prune_partitions;
+  let mut seq = Sequence::new(w, h, config);
+  let mut fi = FrameInvariants::new(w, h, config);
+  fi.intra_only = true;
+  fi.refresh_frame_flags = ALL_REF_FRAMES_MASK;
+
+  // Gradients on the left half of the frame, noise on the right half
+  let mut ra = ChaChaRng::from_seed([0; 32]);
+  let mut input = FrameState::new(&fi).input;
+  for plane in input.planes.iter_mut() {
+    let stride = plane.cfg.stride;
+    let width = plane.cfg.width;
+    for (y, row) in plane.data.chunks_mut(stride).enumerate() {
+      for (x, pixel) in row.iter_mut().enumerate() {
+        *pixel = if x < width / 2 { (x + y) as u16 } else { ra.gen::<u8>() as u16 };
+      }
+    }
+  }
+
+  b.iter(|| {
+    let mut fs = FrameState::new(&fi);
+    fs.input = input.clone();
+    encode_frame(&mut seq, &mut fi, &mut fs)
+  });
+}
+
 benchmark_group!(
   intra_prediction,
   predict::intra_dc_4x4,
@@ -116,4 +188,4 @@ benchmark_group!(
 benchmark_main!(comparative::intra_prediction);
 
 #[cfg(not(feature = "comparative_bench"))]
-benchmark_main!(write_b, intra_prediction);
+benchmark_main!(write_b, partition_search, intra_prediction);
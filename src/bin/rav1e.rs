@@ -25,7 +25,7 @@ fn main() {
   };
 
   let mut fi = FrameInvariants::new(width, height, config);
-  let mut sequence = Sequence::new(width, height);
+  let mut sequence = Sequence::new(width, height, config);
   write_ivf_header(
     &mut io.output,
     width,
@@ -43,7 +43,6 @@ fn main() {
       if fi.frame_type == FrameType::KEY { ALL_REF_FRAMES_MASK } else { 1 };
     fi.intra_only = fi.frame_type == FrameType::KEY
       || fi.frame_type == FrameType::INTRA_ONLY;
-    fi.use_prev_frame_mvs = !(fi.intra_only || fi.error_resilient);
 
     if !process_frame(
       &mut sequence,
@@ -28,7 +28,7 @@ fn main() {
     None => None
   };
   let mut fi = FrameInvariants::new(width, height, &config);
-  let mut sequence = Sequence::new(width, height);
+  let mut sequence = Sequence::new(width, height, config);
   write_ivf_header(
     &mut io.output,
     fi.padded_w,
@@ -197,7 +197,7 @@ pub fn cdef_frame(fi: &FrameInvariants, rec: &mut Frame, bc: &mut BlockContext)
     let cdef_damping = fi.cdef_damping as i32;
 
     // Each filter block is 64x64, except right and/or bottom for non-multiple-of-64 sizes.
-    // FIXME: 128x128 SB support will break this, we need FilterBlockOffset etc.
+    // This does not depend on the superblock size.
     let fb_height = (fi.padded_h + 63) / 64;
     let fb_width = (fi.padded_w + 63) / 64;
 
@@ -252,12 +252,12 @@ pub fn cdef_frame(fi: &FrameInvariants, rec: &mut Frame, bc: &mut BlockContext)
     // Perform actual CDEF, using the padded copy as source, and the input rec vector as destination.
     for fby in 0..fb_height {
         for fbx in 0..fb_width {
-            let sbo = SuperBlockOffset { x: fbx, y: fby };
+            let fbo = FilterBlockOffset { x: fbx, y: fby };
 
             // Each direction block is 8x8 in y, potentially smaller if subsampled in chroma
             for by in 0..8 {
                 for bx in 0..8 {
-                    let block_offset = sbo.block_offset(bx, by);
+                    let block_offset = fbo.block_offset(bx, by);
                     if block_offset.x < bc.cols && block_offset.y < bc.rows {
                         let skip = bc.at(&block_offset).skip;
                         if !skip {
@@ -278,7 +278,7 @@ pub fn cdef_frame(fi: &FrameInvariants, rec: &mut Frame, bc: &mut BlockContext)
                             }
                             for p in 0..3 {
                                 let mut rec_plane = &mut rec.planes[p];
-                                let rec_po = sbo.plane_offset(&rec_plane.cfg);
+                                let rec_po = fbo.plane_offset(&rec_plane.cfg);
                                 let mut cdef_plane = &mut cdef_frame.planes[p];
                                 let xdec = cdef_plane.cfg.xdec;
                                 let ydec = cdef_plane.cfg.ydec;
@@ -294,7 +294,7 @@ pub fn cdef_frame(fi: &FrameInvariants, rec: &mut Frame, bc: &mut BlockContext)
                                     let rec_stride = rec_plane.cfg.stride;
                                     let mut rec_slice = &mut rec_plane.mut_slice(&rec_po);
                                     let cdef_stride = cdef_plane.cfg.stride;
-                                    let cdef_po = sbo.plane_offset(&cdef_plane.cfg);
+                                    let cdef_po = fbo.plane_offset(&cdef_plane.cfg);
                                     let cdef_slice = &cdef_plane.mut_slice(&cdef_po);
 
                                     let mut local_pri_strength;
@@ -0,0 +1,318 @@
+// Copyright (c) 2017-2018, The rav1e contributors. All rights reserved
+//
+// This source code is subject to the terms of the BSD 2 Clause License and
+// the Alliance for Open Media Patent License 1.0. If the BSD 2 Clause License
+// was not distributed with this source code in the LICENSE file, you can
+// obtain it at www.aomedia.org/license/software. If the Alliance for Open
+// Media Patent License 1.0 was not distributed with this source code in the
+// PATENTS file, you can obtain it at www.aomedia.org/license/patent.
+
+#![allow(non_camel_case_types)]
+#![cfg_attr(feature = "cargo-clippy", allow(cast_lossless))]
+
+use partition::*;
+use plane::*;
+use std::cmp;
+use BlockSize::*;
+
+use self::WedgeDirection::*;
+
+pub const MAX_WEDGE_TYPES: usize = 16;
+
+const MASK_MASTER_SIZE: usize = 64;
+const MAX_SB_SIZE: usize = 128;
+
+// Edges of the 64x64 master masks, from which the wedges are cut
+const WEDGE_MASTER_OBLIQUE_ODD: [i32; MASK_MASTER_SIZE] = [
+  0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0,
+  0, 1, 2, 6, 18, 37, 53, 60, 63, 64, 64, 64, 64, 64, 64, 64, 64, 64, 64, 64,
+  64, 64, 64, 64, 64, 64, 64, 64, 64, 64, 64, 64, 64, 64, 64, 64, 64, 64, 64
+];
+const WEDGE_MASTER_OBLIQUE_EVEN: [i32; MASK_MASTER_SIZE] = [
+  0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0,
+  0, 1, 4, 11, 27, 46, 58, 62, 63, 64, 64, 64, 64, 64, 64, 64, 64, 64, 64, 64,
+  64, 64, 64, 64, 64, 64, 64, 64, 64, 64, 64, 64, 64, 64, 64, 64, 64, 64, 64
+];
+const WEDGE_MASTER_VERTICAL: [i32; MASK_MASTER_SIZE] = [
+  0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0,
+  0, 0, 2, 7, 21, 43, 57, 62, 64, 64, 64, 64, 64, 64, 64, 64, 64, 64, 64, 64,
+  64, 64, 64, 64, 64, 64, 64, 64, 64, 64, 64, 64, 64, 64, 64, 64, 64, 64, 64
+];
+
+// Weights of the intra prediction away from the edges of the block, in units
+// of 1/128 of the block size
+const II_WEIGHTS_1D: [u8; MAX_SB_SIZE] = [
+  60, 58, 56, 54, 52, 50, 48, 47, 45, 44, 42, 41, 39, 38, 37, 35, 34, 33, 32,
+  31, 30, 29, 28, 27, 26, 25, 24, 23, 22, 22, 21, 20, 19, 19, 18, 18, 17, 16,
+  16, 15, 15, 14, 14, 13, 13, 12, 12, 12, 11, 11, 10, 10, 10, 9, 9, 9, 8,
+  8, 8, 8, 7, 7, 7, 7, 6, 6, 6, 6, 6, 5, 5, 5, 5, 5, 4, 4,
+  4, 4, 4, 4, 4, 4, 3, 3, 3, 3, 3, 3, 3, 3, 3, 2, 2, 2, 2,
+  2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 1, 1, 1, 1, 1, 1,
+  1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1
+];
+
+#[derive(Copy, Clone)]
+enum WedgeDirection {
+  WEDGE_HORIZONTAL,
+  WEDGE_VERTICAL,
+  WEDGE_OBLIQUE27,
+  WEDGE_OBLIQUE63,
+  WEDGE_OBLIQUE117,
+  WEDGE_OBLIQUE153
+}
+
+// Direction and center, in eighths of the width and height, of the wedges of
+// square, tall and wide blocks
+const WEDGE_CODEBOOK: [[(WedgeDirection, usize, usize); MAX_WEDGE_TYPES]; 3] = [
+  [
+    (WEDGE_OBLIQUE27, 4, 4), (WEDGE_OBLIQUE63, 4, 4),
+    (WEDGE_OBLIQUE117, 4, 4), (WEDGE_OBLIQUE153, 4, 4),
+    (WEDGE_HORIZONTAL, 4, 2), (WEDGE_HORIZONTAL, 4, 6),
+    (WEDGE_VERTICAL, 2, 4), (WEDGE_VERTICAL, 6, 4),
+    (WEDGE_OBLIQUE27, 4, 2), (WEDGE_OBLIQUE27, 4, 6),
+    (WEDGE_OBLIQUE153, 4, 2), (WEDGE_OBLIQUE153, 4, 6),
+    (WEDGE_OBLIQUE63, 2, 4), (WEDGE_OBLIQUE63, 6, 4),
+    (WEDGE_OBLIQUE117, 2, 4), (WEDGE_OBLIQUE117, 6, 4)
+  ],
+  [
+    (WEDGE_OBLIQUE27, 4, 4), (WEDGE_OBLIQUE63, 4, 4),
+    (WEDGE_OBLIQUE117, 4, 4), (WEDGE_OBLIQUE153, 4, 4),
+    (WEDGE_HORIZONTAL, 4, 2), (WEDGE_HORIZONTAL, 4, 4),
+    (WEDGE_HORIZONTAL, 4, 6), (WEDGE_VERTICAL, 4, 4),
+    (WEDGE_OBLIQUE27, 4, 2), (WEDGE_OBLIQUE27, 4, 6),
+    (WEDGE_OBLIQUE153, 4, 2), (WEDGE_OBLIQUE153, 4, 6),
+    (WEDGE_OBLIQUE63, 2, 4), (WEDGE_OBLIQUE63, 6, 4),
+    (WEDGE_OBLIQUE117, 2, 4), (WEDGE_OBLIQUE117, 6, 4)
+  ],
+  [
+    (WEDGE_OBLIQUE27, 4, 4), (WEDGE_OBLIQUE63, 4, 4),
+    (WEDGE_OBLIQUE117, 4, 4), (WEDGE_OBLIQUE153, 4, 4),
+    (WEDGE_VERTICAL, 2, 4), (WEDGE_VERTICAL, 4, 4),
+    (WEDGE_VERTICAL, 6, 4), (WEDGE_HORIZONTAL, 4, 4),
+    (WEDGE_OBLIQUE27, 4, 2), (WEDGE_OBLIQUE27, 4, 6),
+    (WEDGE_OBLIQUE153, 4, 2), (WEDGE_OBLIQUE153, 4, 6),
+    (WEDGE_OBLIQUE63, 2, 4), (WEDGE_OBLIQUE63, 6, 4),
+    (WEDGE_OBLIQUE117, 2, 4), (WEDGE_OBLIQUE117, 6, 4)
+  ]
+];
+
+/// Intra prediction blended with the inter prediction of a block, over the
+/// whole block or on one side of a wedge
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct InterIntraParams {
+  pub mode: InterIntraMode,
+  pub wedge_index: Option<usize>
+}
+
+/// Mask weighting the two predictions of a compound block
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum CompoundMask {
+  Wedge { index: usize, sign: bool },
+  DiffWeighted { inverse: bool }
+}
+
+impl CompoundMask {
+  pub fn compound_type(self) -> CompoundType {
+    match self {
+      CompoundMask::Wedge { .. } => CompoundType::COMPOUND_WEDGE,
+      CompoundMask::DiffWeighted { .. } => CompoundType::COMPOUND_DIFFWTD
+    }
+  }
+}
+
+/// Number of bits of the wedge index of blocks of size `bsize`, which have
+/// no wedges if 0
+pub fn wedge_bits(bsize: BlockSize) -> usize {
+  match bsize {
+    BLOCK_8X8 | BLOCK_8X16 | BLOCK_16X8 | BLOCK_16X16 | BLOCK_16X32
+    | BLOCK_32X16 | BLOCK_32X32 | BLOCK_8X32 | BLOCK_32X8 => 4,
+    _ => 0
+  }
+}
+
+/// Whether the inter prediction of blocks of size `bsize` can be blended
+/// with an intra prediction
+pub fn interintra_allowed(bsize: BlockSize) -> bool {
+  bsize >= BLOCK_8X8 && bsize <= BLOCK_32X32
+}
+
+// Weight at (x, y) of the master mask in the direction `dir`. The oblique
+// masks are all transposed or mirrored from the 63 degree one, whose pairs of
+// rows are shifted right by one pixel.
+fn master_weight(dir: WedgeDirection, x: usize, y: usize) -> i32 {
+  let last = MASK_MASTER_SIZE - 1;
+  let oblique63 = |x: usize, y: usize| {
+    let shift = (MASK_MASTER_SIZE / 4 - (y + 1) / 2) as isize;
+    let i = cmp::max(0, cmp::min(last as isize, x as isize - shift)) as usize;
+    if y & 1 == 0 {
+      WEDGE_MASTER_OBLIQUE_EVEN[i]
+    } else {
+      WEDGE_MASTER_OBLIQUE_ODD[i]
+    }
+  };
+
+  match dir {
+    WEDGE_HORIZONTAL => WEDGE_MASTER_VERTICAL[y],
+    WEDGE_VERTICAL => WEDGE_MASTER_VERTICAL[x],
+    WEDGE_OBLIQUE27 => oblique63(y, x),
+    WEDGE_OBLIQUE63 => oblique63(x, y),
+    WEDGE_OBLIQUE117 => 64 - oblique63(last - x, y),
+    WEDGE_OBLIQUE153 => 64 - oblique63(last - y, x)
+  }
+}
+
+// Weight at (x, y) of the wedge `index` of blocks of size `bsize`, cut from
+// the master mask around the center of the wedge
+fn wedge_weight(bsize: BlockSize, index: usize, x: usize, y: usize) -> i32 {
+  let (w, h) = (bsize.width(), bsize.height());
+  let shape = if w == h { 0 } else if h > w { 1 } else { 2 };
+  let (dir, x_offset, y_offset) = WEDGE_CODEBOOK[shape][index];
+  let woff = (x_offset * w) >> 3;
+  let hoff = (y_offset * h) >> 3;
+  master_weight(
+    dir,
+    MASK_MASTER_SIZE / 2 - woff + x,
+    MASK_MASTER_SIZE / 2 - hoff + y
+  )
+}
+
+// Whether the wedge `index` of blocks of size `bsize` is complemented for a
+// sign of 0, so that its top and left edges mostly weight the first
+// prediction
+fn wedge_sign_flip(bsize: BlockSize, index: usize) -> bool {
+  let (w, h) = (bsize.width(), bsize.height());
+  let sum: i32 = (0..w).map(|x| wedge_weight(bsize, index, x, 0)).sum::<i32>()
+    + (1..h).map(|y| wedge_weight(bsize, index, 0, y)).sum::<i32>();
+  let n = (w + h - 1) as i32;
+  (sum + n / 2) / n < 32
+}
+
+/// Weights of the first prediction, out of 64, over the luma of a block of
+/// size `bsize` with the wedge `index`, in raster order. `sign` selects the
+/// complementary mask.
+pub fn wedge_mask(bsize: BlockSize, index: usize, sign: bool) -> Vec<u8> {
+  let (w, h) = (bsize.width(), bsize.height());
+  let flip = sign != wedge_sign_flip(bsize, index);
+  let mut mask = Vec::with_capacity(w * h);
+  for y in 0..h {
+    for x in 0..w {
+      let m = wedge_weight(bsize, index, x, y);
+      mask.push(if flip { 64 - m } else { m } as u8);
+    }
+  }
+  mask
+}
+
+/// Weights of the intra prediction, out of 64, over a `w`x`h` block of a
+/// plane predicted with the inter-intra `mode`, in raster order. They
+/// decrease away from the edges the intra prediction is made from.
+pub fn interintra_mask(mode: InterIntraMode, w: usize, h: usize) -> Vec<u8> {
+  let scale = MAX_SB_SIZE / cmp::max(w, h);
+  let mut mask = Vec::with_capacity(w * h);
+  for y in 0..h {
+    for x in 0..w {
+      mask.push(match mode {
+        InterIntraMode::II_DC_PRED => 32,
+        InterIntraMode::II_V_PRED => II_WEIGHTS_1D[y * scale],
+        InterIntraMode::II_H_PRED => II_WEIGHTS_1D[x * scale],
+        InterIntraMode::II_SMOOTH_PRED =>
+          II_WEIGHTS_1D[cmp::min(x, y) * scale],
+      });
+    }
+  }
+  mask
+}
+
+/// Weights of the first prediction, out of 64, from the difference of the
+/// luma predictions `p0` and `p1` of a compound block, which have `shift`
+/// more bits than pixels. The first prediction is favored where they differ
+/// unless `inverse`.
+pub fn diff_weighted_mask(
+  p0: &[i32], p1: &[i32], inverse: bool, shift: usize
+) -> Vec<u8> {
+  p0.iter()
+    .zip(p1)
+    .map(|(&a, &b)| {
+      let diff = ((a - b).abs() + (1 << shift >> 1)) >> shift;
+      let m = cmp::min(64, 38 + diff / 16);
+      (if inverse { 64 - m } else { m }) as u8
+    }).collect()
+}
+
+/// Weight at (x, y) of a plane subsampled by `xdec` and `ydec`, from the
+/// luma `mask` of width `stride`
+pub fn subsampled_weight(
+  mask: &[u8], stride: usize, x: usize, y: usize, xdec: usize, ydec: usize
+) -> i32 {
+  let m = |x: usize, y: usize| mask[y * stride + x] as i32;
+  match (xdec, ydec) {
+    (0, 0) => m(x, y),
+    (1, 0) => (m(2 * x, y) + m(2 * x + 1, y) + 1) >> 1,
+    (0, 1) => (m(x, 2 * y) + m(x, 2 * y + 1) + 1) >> 1,
+    _ =>
+      (m(2 * x, 2 * y) + m(2 * x + 1, 2 * y) + m(2 * x, 2 * y + 1)
+        + m(2 * x + 1, 2 * y + 1) + 2) >> 2,
+  }
+}
+
+/// Blends the inter prediction `inter` of the `w`x`h` block at `po` into
+/// the intra prediction in `dst`, weighting the intra prediction by `mask`
+/// of width `stride`, subsampled by `xdec` and `ydec`
+pub fn blend_interintra(
+  dst: &mut Plane, po: &PlaneOffset, w: usize, h: usize, inter: &[u16],
+  mask: &[u8], stride: usize, xdec: usize, ydec: usize
+) {
+  let dst_stride = dst.cfg.stride;
+  for y in 0..h {
+    for x in 0..w {
+      let m = subsampled_weight(mask, stride, x, y, xdec, ydec);
+      let i = (po.y + y) * dst_stride + po.x + x;
+      let blend = m * dst.data[i] as i32 + (64 - m) * inter[y * w + x] as i32;
+      dst.data[i] = ((blend + 32) >> 6) as u16;
+    }
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+
+  const WEDGE_SIZES: [BlockSize; 9] = [
+    BLOCK_8X8, BLOCK_8X16, BLOCK_16X8, BLOCK_16X16, BLOCK_16X32,
+    BLOCK_32X16, BLOCK_32X32, BLOCK_8X32, BLOCK_32X8
+  ];
+
+  #[test]
+  fn wedge_signs() {
+    for &bsize in &WEDGE_SIZES {
+      let (w, h) = (bsize.width(), bsize.height());
+      for index in 0..MAX_WEDGE_TYPES {
+        let mask = wedge_mask(bsize, index, false);
+        let complement = wedge_mask(bsize, index, true);
+        assert!(mask.iter().zip(&complement).all(|(&a, &b)| a + b == 64));
+        assert!(mask.iter().any(|&m| m != mask[0]));
+
+        let edge: u32 = mask[..w].iter().map(|&m| m as u32).sum::<u32>()
+          + (1..h).map(|y| mask[y * w] as u32).sum::<u32>();
+        assert!(edge >= 32 * (w + h - 1) as u32 - (w + h - 1) as u32 / 2);
+      }
+    }
+  }
+
+  #[test]
+  fn interintra_weights() {
+    let mask = interintra_mask(InterIntraMode::II_V_PRED, 8, 32);
+    assert_eq!(&mask[..8], &[60; 8]);
+    assert_eq!(mask[8], II_WEIGHTS_1D[4]);
+    let mask = interintra_mask(InterIntraMode::II_SMOOTH_PRED, 8, 8);
+    assert_eq!(mask[7 * 8 + 2], II_WEIGHTS_1D[2 * 16]);
+  }
+
+  #[test]
+  fn diff_weights() {
+    let p0 = [0, 16 << 4, 1000 << 4];
+    let p1 = [0, 0, 0];
+    assert_eq!(diff_weighted_mask(&p0, &p1, false, 4), vec![38, 39, 64]);
+    assert_eq!(diff_weighted_mask(&p0, &p1, true, 4), vec![26, 25, 0]);
+  }
+}
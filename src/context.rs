@@ -16,7 +16,10 @@
 #![cfg_attr(feature = "cargo-clippy", allow(needless_range_loop))]
 #![cfg_attr(feature = "cargo-clippy", allow(collapsible_if))]
 
+use compound::*;
 use ec::Writer;
+#[cfg(test)]
+use ec::WriterEncoder;
 use partition::BlockSize::*;
 use partition::PredictionMode::*;
 use partition::TxType::*;
@@ -41,11 +44,11 @@ const MAX_MIB_SIZE_LOG2: usize = (MAX_SB_SIZE_LOG2 - MI_SIZE_LOG2);
 pub const MAX_MIB_SIZE: usize = (1 << MAX_MIB_SIZE_LOG2);
 pub const MAX_MIB_MASK: usize = (MAX_MIB_SIZE - 1);
 
-const MAX_SB_SIZE_LOG2: usize = 6;
+const MAX_SB_SIZE_LOG2: usize = 7;
 const MAX_SB_SIZE: usize = (1 << MAX_SB_SIZE_LOG2);
 const MAX_SB_SQUARE: usize = (MAX_SB_SIZE * MAX_SB_SIZE);
 
-pub const MAX_TX_SIZE: usize = 32;
+pub const MAX_TX_SIZE: usize = 64;
 const MAX_TX_SQUARE: usize = MAX_TX_SIZE * MAX_TX_SIZE;
 
 pub const INTRA_MODES: usize = 13;
@@ -53,12 +56,68 @@ const UV_INTRA_MODES: usize = 14;
 const NEWMV_MODE_CONTEXTS: usize = 7;
 const GLOBALMV_MODE_CONTEXTS: usize = 2;
 const REFMV_MODE_CONTEXTS: usize = 9;
+const DRL_MODE_CONTEXTS: usize = 3;
+const INTER_MODE_CONTEXTS: usize = 8;
+const INTER_COMPOUND_MODES: usize = 8;
+const COMP_NEWMV_CTXS: usize = 5;
 
 const BLOCK_SIZE_GROUPS: usize = 4;
 const MAX_ANGLE_DELTA: usize = 3;
 const DIRECTIONAL_MODES: usize = 8;
 const KF_MODE_CONTEXTS: usize = 5;
 
+const FILTER_INTRA_MODES: usize = 5;
+
+const MOTION_MODES: usize = 3;
+const INTERINTRA_MODES: usize = 4;
+const COMP_GROUP_IDX_CONTEXTS: usize = 6;
+
+const CFL_JOINT_SIGNS: usize = 8;
+const CFL_ALPHA_CONTEXTS: usize = 6;
+const CFL_ALPHABET_SIZE: usize = 16;
+
+const PALETTE_BSIZE_CTXS: usize = 7;
+const PALETTE_Y_MODE_CONTEXTS: usize = 3;
+const PALETTE_UV_MODE_CONTEXTS: usize = 2;
+const PALETTE_SIZES: usize = PALETTE_MAX_SIZE - PALETTE_MIN_SIZE + 1;
+const PALETTE_COLOR_INDEX_CONTEXTS: usize = 5;
+const PALETTE_NUM_NEIGHBORS: usize = 3;
+
+const MV_JOINTS: usize = 4;
+const MV_CLASSES: usize = 11;
+const CLASS0_BITS: usize = 1;
+const CLASS0_SIZE: usize = 1 << CLASS0_BITS;
+const MV_FP_SIZE: usize = 4;
+const MV_OFFSET_BITS: usize = MV_CLASSES + CLASS0_BITS - 2;
+const MV_CONTEXTS: usize = 2;
+pub const MV_INTRABC_CONTEXT: usize = 1;
+
+const MV_JOINT_ZERO: usize = 0; // Both components are zero
+const MV_JOINT_HNZVZ: usize = 1; // Only the column is non-zero
+const MV_JOINT_HZVNZ: usize = 2; // Only the row is non-zero
+const MV_JOINT_HNZVNZ: usize = 3; // Both components are non-zero
+
+const MAX_REF_MV_STACK_SIZE: usize = 8;
+const MAX_MV_REF_CANDIDATES: usize = 2;
+const MVREF_ROW_COLS: isize = 3;
+const LEAST_SQUARES_SAMPLES_MAX: usize = 8;
+const REF_CAT_LEVEL: u32 = 640;
+// Motion vectors of the blocks around are clamped to 16 pixels past the
+// frame edges, in 1/8 pixels
+const MV_BORDER: isize = 16 << 3;
+
+// Intra block copy only references superblocks this many pixels, or 64x64
+// units, behind the current one in coding order
+pub const INTRABC_DELAY_PIXELS: usize = 256;
+pub const INTRABC_DELAY_SB64: usize = 4;
+
+#[derive(Copy, Clone, PartialEq, PartialOrd)]
+pub enum MvSubpelPrecision {
+  MV_SUBPEL_NONE,
+  MV_SUBPEL_LOW_PRECISION,
+  MV_SUBPEL_HIGH_PRECISION
+}
+
 const EXT_PARTITION_TYPES: usize = 10;
 const TX_SIZES: usize = 4;
 const TX_SETS: usize = 9;
@@ -71,6 +130,15 @@ const REFMV_OFFSET: usize = 4;
 const NEWMV_CTX_MASK: usize = ((1 << GLOBALMV_OFFSET) - 1);
 const GLOBALMV_CTX_MASK: usize = ((1 << (REFMV_OFFSET - GLOBALMV_OFFSET)) - 1);
 
+// Context of the compound modes from the REFMV context, halved, and the NEWMV
+// context
+static compound_mode_ctx_map: [[usize; COMP_NEWMV_CTXS]; 3] =
+  [[0, 1, 1, 1, 1], [1, 2, 3, 4, 4], [4, 4, 5, 6, 7]];
+
+const MAX_TX_CATS: usize = TxSize::TX_SIZES - 1;
+const TX_SIZE_CONTEXTS: usize = 3;
+const TXFM_PARTITION_CONTEXTS: usize = (TxSize::TX_SIZES - 1) * 6 - 3;
+
 // Number of transform types in each set type
 static num_tx_set: [usize; TX_SETS] =
   [1, 2, 5, 7, 7, 10, 12, 16, 16];
@@ -454,6 +522,11 @@ enum TailToken {
 const PLANE_TYPES: usize = 2;
 const REF_TYPES: usize = 2;
 const SKIP_CONTEXTS: usize = 3;
+const SKIP_MODE_CONTEXTS: usize = 3;
+const COMP_INTER_CONTEXTS: usize = 5;
+const COMP_REF_TYPE_CONTEXTS: usize = 5;
+const UNI_COMP_REF_CONTEXTS: usize = 3;
+const UNIDIR_COMP_REFS: usize = 4;
 const INTRA_INTER_CONTEXTS: usize = 4;
 
 // Level Map
@@ -764,7 +837,7 @@ pub fn get_tx_set(
 ) -> TxSet {
   let tx_size_sqr_up = tx_size.sqr_up();
   let tx_size_sqr = tx_size.sqr();
-  if tx_size_sqr > TxSize::TX_32X32 {
+  if tx_size_sqr_up > TxSize::TX_32X32 {
     TxSet::TX_SET_DCTONLY
   } else if tx_size_sqr_up == TxSize::TX_32X32 {
     if is_inter {
@@ -856,17 +929,58 @@ extern "C" {
   static default_newmv_cdf: [[u16; 2 + 1]; NEWMV_MODE_CONTEXTS];
   static default_zeromv_cdf: [[u16; 2 + 1]; GLOBALMV_MODE_CONTEXTS];
   static default_refmv_cdf: [[u16; 2 + 1]; REFMV_MODE_CONTEXTS];
+  static default_drl_cdf: [[u16; 2 + 1]; DRL_MODE_CONTEXTS];
+  static default_inter_compound_mode_cdf:
+    [[u16; INTER_COMPOUND_MODES + 1]; INTER_MODE_CONTEXTS];
   static default_intra_ext_tx_cdf:
     [[[[u16; TX_TYPES + 1]; INTRA_MODES]; TX_SIZES]; TX_SETS_INTRA];
   static default_inter_ext_tx_cdf:
     [[[u16; TX_TYPES + 1]; TX_SIZES]; TX_SETS_INTER];
   static default_skip_cdfs: [[u16; 3]; SKIP_CONTEXTS];
+  static default_skip_mode_cdfs: [[u16; 3]; SKIP_MODE_CONTEXTS];
   static default_intra_inter_cdf: [[u16; 3]; INTRA_INTER_CONTEXTS];
+  static default_comp_inter_cdf: [[u16; 3]; COMP_INTER_CONTEXTS];
   static default_angle_delta_cdf:
     [[u16; 2 * MAX_ANGLE_DELTA + 1 + 1]; DIRECTIONAL_MODES];
   static default_filter_intra_cdfs: [[u16; 3]; BlockSize::BLOCK_SIZES_ALL];
+  static default_filter_intra_mode_cdf: [u16; FILTER_INTRA_MODES + 1];
+  static default_cfl_sign_cdf: [u16; CFL_JOINT_SIGNS + 1];
+  static default_cfl_alpha_cdf:
+    [[u16; CFL_ALPHABET_SIZE + 1]; CFL_ALPHA_CONTEXTS];
+  static default_palette_y_mode_cdf:
+    [[[u16; 3]; PALETTE_Y_MODE_CONTEXTS]; PALETTE_BSIZE_CTXS];
+  static default_palette_uv_mode_cdf: [[u16; 3]; PALETTE_UV_MODE_CONTEXTS];
+  static default_palette_y_size_cdf:
+    [[u16; PALETTE_SIZES + 1]; PALETTE_BSIZE_CTXS];
+  static default_palette_uv_size_cdf:
+    [[u16; PALETTE_SIZES + 1]; PALETTE_BSIZE_CTXS];
+  static default_palette_y_color_index_cdf:
+    [[[u16; PALETTE_MAX_SIZE + 1]; PALETTE_COLOR_INDEX_CONTEXTS]; PALETTE_SIZES];
+  static default_palette_uv_color_index_cdf:
+    [[[u16; PALETTE_MAX_SIZE + 1]; PALETTE_COLOR_INDEX_CONTEXTS]; PALETTE_SIZES];
+  static default_intrabc_cdf: [u16; 3];
+  static default_motion_mode_cdf:
+    [[u16; MOTION_MODES + 1]; BlockSize::BLOCK_SIZES_ALL];
+  static default_obmc_cdf: [[u16; 3]; BlockSize::BLOCK_SIZES_ALL];
+  static default_interintra_cdf: [[u16; 3]; BLOCK_SIZE_GROUPS];
+  static default_interintra_mode_cdf:
+    [[u16; INTERINTRA_MODES + 1]; BLOCK_SIZE_GROUPS];
+  static default_wedge_interintra_cdf: [[u16; 3]; BlockSize::BLOCK_SIZES_ALL];
+  static default_wedge_idx_cdf:
+    [[u16; MAX_WEDGE_TYPES + 1]; BlockSize::BLOCK_SIZES_ALL];
+  static default_compound_type_cdf: [[u16; 3]; BlockSize::BLOCK_SIZES_ALL];
+  static default_comp_group_idx_cdfs: [[u16; 3]; COMP_GROUP_IDX_CONTEXTS];
+  static default_nmv_context: NMVContext;
+  static default_tx_size_cdf:
+    [[[u16; MAX_TX_DEPTH + 1 + 1]; TX_SIZE_CONTEXTS]; MAX_TX_CATS];
+  static default_txfm_partition_cdf: [[u16; 3]; TXFM_PARTITION_CONTEXTS];
 
   static default_single_ref_cdf: [[[u16; 2 + 1]; SINGLE_REFS - 1]; REF_CONTEXTS];
+  static default_comp_ref_type_cdf: [[u16; 3]; COMP_REF_TYPE_CONTEXTS];
+  static default_uni_comp_ref_cdf:
+    [[[u16; 3]; UNIDIR_COMP_REFS - 1]; UNI_COMP_REF_CONTEXTS];
+  static default_comp_ref_cdf: [[[u16; 3]; FWD_REFS - 1]; REF_CONTEXTS];
+  static default_comp_bwdref_cdf: [[[u16; 3]; BWD_REFS - 1]; REF_CONTEXTS];
   static av1_scan_orders: [[SCAN_ORDER; TX_TYPES]; TxSize::TX_SIZES_ALL];
 
   // lv_map
@@ -902,6 +1016,57 @@ pub struct SCAN_ORDER {
   pub neighbors: &'static [u16; ((64 * 64) + 1) * 2]
 }
 
+// Motion vector component CDFs, laid out as libaom's nmv_component
+#[repr(C)]
+#[derive(Copy, Clone)]
+pub struct NMVComponent {
+  classes_cdf: [u16; MV_CLASSES + 1],
+  class0_fp_cdf: [[u16; MV_FP_SIZE + 1]; CLASS0_SIZE],
+  fp_cdf: [u16; MV_FP_SIZE + 1],
+  sign_cdf: [u16; 2 + 1],
+  class0_hp_cdf: [u16; 2 + 1],
+  hp_cdf: [u16; 2 + 1],
+  class0_cdf: [u16; CLASS0_SIZE + 1],
+  bits_cdf: [[u16; 2 + 1]; MV_OFFSET_BITS]
+}
+
+// Motion vector CDFs, laid out as libaom's nmv_context
+#[repr(C)]
+#[derive(Copy, Clone)]
+pub struct NMVContext {
+  joints_cdf: [u16; MV_JOINTS + 1],
+  comps: [NMVComponent; 2]
+}
+
+/// Reset the adaptation counter of a CDF. The counter follows the last
+/// probability, which is always stored as 0, so this also works on CDFs
+/// with fewer symbols than their array holds.
+fn reset_cdf_counter(cdf: &mut [u16]) {
+  let nsymbs = cdf.iter().position(|&v| v == 0).unwrap() + 1;
+  cdf[nsymbs] = 0;
+}
+
+macro_rules! reset_cdf_counters {
+  ($cdf:expr, 1) => {
+    reset_cdf_counter(&mut $cdf[..])
+  };
+  ($cdfs:expr, 2) => {
+    for cdf in $cdfs.iter_mut() {
+      reset_cdf_counters!(cdf, 1);
+    }
+  };
+  ($cdfs:expr, 3) => {
+    for cdfs in $cdfs.iter_mut() {
+      reset_cdf_counters!(cdfs, 2);
+    }
+  };
+  ($cdfs:expr, 4) => {
+    for cdfs in $cdfs.iter_mut() {
+      reset_cdf_counters!(cdfs, 3);
+    }
+  };
+}
+
 #[derive(Clone)]
 pub struct CDFContext {
   partition_cdf: [[u16; EXT_PARTITION_TYPES + 1]; PARTITION_CONTEXTS],
@@ -911,14 +1076,45 @@ pub struct CDFContext {
   newmv_cdf: [[u16; 2 + 1]; NEWMV_MODE_CONTEXTS],
   zeromv_cdf: [[u16; 2 + 1]; GLOBALMV_MODE_CONTEXTS],
   refmv_cdf: [[u16; 2 + 1]; REFMV_MODE_CONTEXTS],
+  drl_cdf: [[u16; 2 + 1]; DRL_MODE_CONTEXTS],
+  compound_mode_cdf: [[u16; INTER_COMPOUND_MODES + 1]; INTER_MODE_CONTEXTS],
   intra_tx_cdf:
     [[[[u16; TX_TYPES + 1]; INTRA_MODES]; TX_SIZES]; TX_SETS_INTRA],
   inter_tx_cdf: [[[u16; TX_TYPES + 1]; TX_SIZES]; TX_SETS_INTER],
   skip_cdfs: [[u16; 3]; SKIP_CONTEXTS],
+  skip_mode_cdfs: [[u16; 3]; SKIP_MODE_CONTEXTS],
   intra_inter_cdfs: [[u16; 3]; INTRA_INTER_CONTEXTS],
+  comp_inter_cdfs: [[u16; 3]; COMP_INTER_CONTEXTS],
   angle_delta_cdf: [[u16; 2 * MAX_ANGLE_DELTA + 1 + 1]; DIRECTIONAL_MODES],
   filter_intra_cdfs: [[u16; 3]; BlockSize::BLOCK_SIZES_ALL],
+  filter_intra_mode_cdf: [u16; FILTER_INTRA_MODES + 1],
+  cfl_sign_cdf: [u16; CFL_JOINT_SIGNS + 1],
+  cfl_alpha_cdf: [[u16; CFL_ALPHABET_SIZE + 1]; CFL_ALPHA_CONTEXTS],
+  palette_y_mode_cdfs: [[[u16; 3]; PALETTE_Y_MODE_CONTEXTS]; PALETTE_BSIZE_CTXS],
+  palette_uv_mode_cdfs: [[u16; 3]; PALETTE_UV_MODE_CONTEXTS],
+  palette_y_size_cdfs: [[u16; PALETTE_SIZES + 1]; PALETTE_BSIZE_CTXS],
+  palette_uv_size_cdfs: [[u16; PALETTE_SIZES + 1]; PALETTE_BSIZE_CTXS],
+  palette_y_color_cdfs:
+    [[[u16; PALETTE_MAX_SIZE + 1]; PALETTE_COLOR_INDEX_CONTEXTS]; PALETTE_SIZES],
+  palette_uv_color_cdfs:
+    [[[u16; PALETTE_MAX_SIZE + 1]; PALETTE_COLOR_INDEX_CONTEXTS]; PALETTE_SIZES],
+  intrabc_cdf: [u16; 3],
+  motion_mode_cdf: [[u16; MOTION_MODES + 1]; BlockSize::BLOCK_SIZES_ALL],
+  obmc_cdf: [[u16; 3]; BlockSize::BLOCK_SIZES_ALL],
+  interintra_cdf: [[u16; 3]; BLOCK_SIZE_GROUPS],
+  interintra_mode_cdf: [[u16; INTERINTRA_MODES + 1]; BLOCK_SIZE_GROUPS],
+  wedge_interintra_cdf: [[u16; 3]; BlockSize::BLOCK_SIZES_ALL],
+  wedge_idx_cdf: [[u16; MAX_WEDGE_TYPES + 1]; BlockSize::BLOCK_SIZES_ALL],
+  compound_type_cdf: [[u16; 3]; BlockSize::BLOCK_SIZES_ALL],
+  comp_group_idx_cdfs: [[u16; 3]; COMP_GROUP_IDX_CONTEXTS],
+  nmv_contexts: [NMVContext; MV_CONTEXTS],
+  tx_size_cdf: [[[u16; MAX_TX_DEPTH + 1 + 1]; TX_SIZE_CONTEXTS]; MAX_TX_CATS],
+  txfm_partition_cdf: [[u16; 3]; TXFM_PARTITION_CONTEXTS],
   single_ref_cdfs: [[[u16; 2 + 1]; SINGLE_REFS - 1]; REF_CONTEXTS],
+  comp_ref_type_cdfs: [[u16; 3]; COMP_REF_TYPE_CONTEXTS],
+  uni_comp_ref_cdfs: [[[u16; 3]; UNIDIR_COMP_REFS - 1]; UNI_COMP_REF_CONTEXTS],
+  comp_ref_cdfs: [[[u16; 3]; FWD_REFS - 1]; REF_CONTEXTS],
+  comp_bwdref_cdfs: [[[u16; 3]; BWD_REFS - 1]; REF_CONTEXTS],
 
   // lv_map
   txb_skip_cdf: [[[u16; 3]; TXB_SKIP_CONTEXTS]; TxSize::TX_SIZES],
@@ -958,13 +1154,42 @@ impl CDFContext {
       newmv_cdf: default_newmv_cdf,
       zeromv_cdf: default_zeromv_cdf,
       refmv_cdf: default_refmv_cdf,
+      drl_cdf: default_drl_cdf,
+      compound_mode_cdf: default_inter_compound_mode_cdf,
       intra_tx_cdf: default_intra_ext_tx_cdf,
       inter_tx_cdf: default_inter_ext_tx_cdf,
       skip_cdfs: default_skip_cdfs,
+      skip_mode_cdfs: default_skip_mode_cdfs,
       intra_inter_cdfs: default_intra_inter_cdf,
+      comp_inter_cdfs: default_comp_inter_cdf,
       angle_delta_cdf: default_angle_delta_cdf,
       filter_intra_cdfs: default_filter_intra_cdfs,
+      filter_intra_mode_cdf: default_filter_intra_mode_cdf,
+      cfl_sign_cdf: default_cfl_sign_cdf,
+      cfl_alpha_cdf: default_cfl_alpha_cdf,
+      palette_y_mode_cdfs: default_palette_y_mode_cdf,
+      palette_uv_mode_cdfs: default_palette_uv_mode_cdf,
+      palette_y_size_cdfs: default_palette_y_size_cdf,
+      palette_uv_size_cdfs: default_palette_uv_size_cdf,
+      palette_y_color_cdfs: default_palette_y_color_index_cdf,
+      palette_uv_color_cdfs: default_palette_uv_color_index_cdf,
+      intrabc_cdf: default_intrabc_cdf,
+      motion_mode_cdf: default_motion_mode_cdf,
+      obmc_cdf: default_obmc_cdf,
+      interintra_cdf: default_interintra_cdf,
+      interintra_mode_cdf: default_interintra_mode_cdf,
+      wedge_interintra_cdf: default_wedge_interintra_cdf,
+      wedge_idx_cdf: default_wedge_idx_cdf,
+      compound_type_cdf: default_compound_type_cdf,
+      comp_group_idx_cdfs: default_comp_group_idx_cdfs,
+      nmv_contexts: [default_nmv_context; MV_CONTEXTS],
+      tx_size_cdf: default_tx_size_cdf,
+      txfm_partition_cdf: default_txfm_partition_cdf,
       single_ref_cdfs: default_single_ref_cdf,
+      comp_ref_type_cdfs: default_comp_ref_type_cdf,
+      uni_comp_ref_cdfs: default_uni_comp_ref_cdf,
+      comp_ref_cdfs: default_comp_ref_cdf,
+      comp_bwdref_cdfs: default_comp_bwdref_cdf,
 
       // lv_map
       txb_skip_cdf: av1_default_txb_skip_cdfs[qctx],
@@ -985,6 +1210,82 @@ impl CDFContext {
     }
   }
 
+  /// Reset the adaptation counters of all the CDFs, as libaom does when
+  /// it saves them for the frames predicted from the current one
+  pub fn reset_counts(&mut self) {
+    reset_cdf_counters!(self.partition_cdf, 2);
+    reset_cdf_counters!(self.kf_y_cdf, 3);
+    reset_cdf_counters!(self.y_mode_cdf, 2);
+    reset_cdf_counters!(self.uv_mode_cdf, 3);
+    reset_cdf_counters!(self.newmv_cdf, 2);
+    reset_cdf_counters!(self.zeromv_cdf, 2);
+    reset_cdf_counters!(self.refmv_cdf, 2);
+    reset_cdf_counters!(self.drl_cdf, 2);
+    reset_cdf_counters!(self.compound_mode_cdf, 2);
+    reset_cdf_counters!(self.intra_tx_cdf, 4);
+    reset_cdf_counters!(self.inter_tx_cdf, 3);
+    reset_cdf_counters!(self.skip_cdfs, 2);
+    reset_cdf_counters!(self.skip_mode_cdfs, 2);
+    reset_cdf_counters!(self.intra_inter_cdfs, 2);
+    reset_cdf_counters!(self.comp_inter_cdfs, 2);
+    reset_cdf_counters!(self.angle_delta_cdf, 2);
+    reset_cdf_counters!(self.filter_intra_cdfs, 2);
+    reset_cdf_counters!(self.filter_intra_mode_cdf, 1);
+    reset_cdf_counters!(self.cfl_sign_cdf, 1);
+    reset_cdf_counters!(self.cfl_alpha_cdf, 2);
+    reset_cdf_counters!(self.palette_y_mode_cdfs, 3);
+    reset_cdf_counters!(self.palette_uv_mode_cdfs, 2);
+    reset_cdf_counters!(self.palette_y_size_cdfs, 2);
+    reset_cdf_counters!(self.palette_uv_size_cdfs, 2);
+    reset_cdf_counters!(self.palette_y_color_cdfs, 3);
+    reset_cdf_counters!(self.palette_uv_color_cdfs, 3);
+    reset_cdf_counters!(self.intrabc_cdf, 1);
+    reset_cdf_counters!(self.motion_mode_cdf, 2);
+    reset_cdf_counters!(self.obmc_cdf, 2);
+    reset_cdf_counters!(self.interintra_cdf, 2);
+    reset_cdf_counters!(self.interintra_mode_cdf, 2);
+    reset_cdf_counters!(self.wedge_interintra_cdf, 2);
+    reset_cdf_counters!(self.wedge_idx_cdf, 2);
+    reset_cdf_counters!(self.compound_type_cdf, 2);
+    reset_cdf_counters!(self.comp_group_idx_cdfs, 2);
+    for nmv in self.nmv_contexts.iter_mut() {
+      reset_cdf_counters!(nmv.joints_cdf, 1);
+      for comp in nmv.comps.iter_mut() {
+        reset_cdf_counters!(comp.classes_cdf, 1);
+        reset_cdf_counters!(comp.class0_fp_cdf, 2);
+        reset_cdf_counters!(comp.fp_cdf, 1);
+        reset_cdf_counters!(comp.sign_cdf, 1);
+        reset_cdf_counters!(comp.class0_hp_cdf, 1);
+        reset_cdf_counters!(comp.hp_cdf, 1);
+        reset_cdf_counters!(comp.class0_cdf, 1);
+        reset_cdf_counters!(comp.bits_cdf, 2);
+      }
+    }
+    reset_cdf_counters!(self.tx_size_cdf, 3);
+    reset_cdf_counters!(self.txfm_partition_cdf, 2);
+    reset_cdf_counters!(self.single_ref_cdfs, 3);
+    reset_cdf_counters!(self.comp_ref_type_cdfs, 2);
+    reset_cdf_counters!(self.uni_comp_ref_cdfs, 3);
+    reset_cdf_counters!(self.comp_ref_cdfs, 3);
+    reset_cdf_counters!(self.comp_bwdref_cdfs, 3);
+
+    reset_cdf_counters!(self.txb_skip_cdf, 3);
+    reset_cdf_counters!(self.dc_sign_cdf, 3);
+    reset_cdf_counters!(self.eob_extra_cdf, 4);
+
+    reset_cdf_counters!(self.eob_flag_cdf16, 3);
+    reset_cdf_counters!(self.eob_flag_cdf32, 3);
+    reset_cdf_counters!(self.eob_flag_cdf64, 3);
+    reset_cdf_counters!(self.eob_flag_cdf128, 3);
+    reset_cdf_counters!(self.eob_flag_cdf256, 3);
+    reset_cdf_counters!(self.eob_flag_cdf512, 3);
+    reset_cdf_counters!(self.eob_flag_cdf1024, 3);
+
+    reset_cdf_counters!(self.coeff_base_eob_cdf, 4);
+    reset_cdf_counters!(self.coeff_base_cdf, 4);
+    reset_cdf_counters!(self.coeff_br_cdf, 4);
+  }
+
   pub fn build_map(&self) -> Vec<(&'static str, usize, usize)> {
     use std::mem::size_of_val;
 
@@ -1009,10 +1310,18 @@ impl CDFContext {
       inter_tx_cdf_start + size_of_val(&self.inter_tx_cdf);
     let skip_cdfs_start = self.skip_cdfs.first().unwrap().as_ptr() as usize;
     let skip_cdfs_end = skip_cdfs_start + size_of_val(&self.skip_cdfs);
+    let skip_mode_cdfs_start =
+      self.skip_mode_cdfs.first().unwrap().as_ptr() as usize;
+    let skip_mode_cdfs_end =
+      skip_mode_cdfs_start + size_of_val(&self.skip_mode_cdfs);
     let intra_inter_cdfs_start =
       self.intra_inter_cdfs.first().unwrap().as_ptr() as usize;
     let intra_inter_cdfs_end =
       intra_inter_cdfs_start + size_of_val(&self.intra_inter_cdfs);
+    let comp_inter_cdfs_start =
+      self.comp_inter_cdfs.first().unwrap().as_ptr() as usize;
+    let comp_inter_cdfs_end =
+      comp_inter_cdfs_start + size_of_val(&self.comp_inter_cdfs);
     let angle_delta_cdf_start =
       self.angle_delta_cdf.first().unwrap().as_ptr() as usize;
     let angle_delta_cdf_end =
@@ -1021,6 +1330,114 @@ impl CDFContext {
       self.filter_intra_cdfs.first().unwrap().as_ptr() as usize;
     let filter_intra_cdfs_end =
       filter_intra_cdfs_start + size_of_val(&self.filter_intra_cdfs);
+    let filter_intra_mode_cdf_start =
+      self.filter_intra_mode_cdf.as_ptr() as usize;
+    let filter_intra_mode_cdf_end =
+      filter_intra_mode_cdf_start + size_of_val(&self.filter_intra_mode_cdf);
+    let cfl_sign_cdf_start = self.cfl_sign_cdf.as_ptr() as usize;
+    let cfl_sign_cdf_end = cfl_sign_cdf_start + size_of_val(&self.cfl_sign_cdf);
+    let cfl_alpha_cdf_start =
+      self.cfl_alpha_cdf.first().unwrap().as_ptr() as usize;
+    let cfl_alpha_cdf_end =
+      cfl_alpha_cdf_start + size_of_val(&self.cfl_alpha_cdf);
+    let palette_y_mode_cdfs_start =
+      self.palette_y_mode_cdfs.first().unwrap().as_ptr() as usize;
+    let palette_y_mode_cdfs_end =
+      palette_y_mode_cdfs_start + size_of_val(&self.palette_y_mode_cdfs);
+    let palette_uv_mode_cdfs_start =
+      self.palette_uv_mode_cdfs.first().unwrap().as_ptr() as usize;
+    let palette_uv_mode_cdfs_end =
+      palette_uv_mode_cdfs_start + size_of_val(&self.palette_uv_mode_cdfs);
+    let palette_y_size_cdfs_start =
+      self.palette_y_size_cdfs.first().unwrap().as_ptr() as usize;
+    let palette_y_size_cdfs_end =
+      palette_y_size_cdfs_start + size_of_val(&self.palette_y_size_cdfs);
+    let palette_uv_size_cdfs_start =
+      self.palette_uv_size_cdfs.first().unwrap().as_ptr() as usize;
+    let palette_uv_size_cdfs_end =
+      palette_uv_size_cdfs_start + size_of_val(&self.palette_uv_size_cdfs);
+    let palette_y_color_cdfs_start =
+      self.palette_y_color_cdfs.first().unwrap().as_ptr() as usize;
+    let palette_y_color_cdfs_end =
+      palette_y_color_cdfs_start + size_of_val(&self.palette_y_color_cdfs);
+    let palette_uv_color_cdfs_start =
+      self.palette_uv_color_cdfs.first().unwrap().as_ptr() as usize;
+    let palette_uv_color_cdfs_end =
+      palette_uv_color_cdfs_start + size_of_val(&self.palette_uv_color_cdfs);
+    let intrabc_cdf_start = self.intrabc_cdf.as_ptr() as usize;
+    let intrabc_cdf_end = intrabc_cdf_start + size_of_val(&self.intrabc_cdf);
+    let motion_mode_cdf_start =
+      self.motion_mode_cdf.first().unwrap().as_ptr() as usize;
+    let motion_mode_cdf_end =
+      motion_mode_cdf_start + size_of_val(&self.motion_mode_cdf);
+    let obmc_cdf_start = self.obmc_cdf.first().unwrap().as_ptr() as usize;
+    let obmc_cdf_end = obmc_cdf_start + size_of_val(&self.obmc_cdf);
+    let interintra_cdf_start =
+      self.interintra_cdf.first().unwrap().as_ptr() as usize;
+    let interintra_cdf_end =
+      interintra_cdf_start + size_of_val(&self.interintra_cdf);
+    let interintra_mode_cdf_start =
+      self.interintra_mode_cdf.first().unwrap().as_ptr() as usize;
+    let interintra_mode_cdf_end =
+      interintra_mode_cdf_start + size_of_val(&self.interintra_mode_cdf);
+    let wedge_interintra_cdf_start =
+      self.wedge_interintra_cdf.first().unwrap().as_ptr() as usize;
+    let wedge_interintra_cdf_end =
+      wedge_interintra_cdf_start + size_of_val(&self.wedge_interintra_cdf);
+    let wedge_idx_cdf_start =
+      self.wedge_idx_cdf.first().unwrap().as_ptr() as usize;
+    let wedge_idx_cdf_end =
+      wedge_idx_cdf_start + size_of_val(&self.wedge_idx_cdf);
+    let compound_type_cdf_start =
+      self.compound_type_cdf.first().unwrap().as_ptr() as usize;
+    let compound_type_cdf_end =
+      compound_type_cdf_start + size_of_val(&self.compound_type_cdf);
+    let comp_group_idx_cdfs_start =
+      self.comp_group_idx_cdfs.first().unwrap().as_ptr() as usize;
+    let comp_group_idx_cdfs_end =
+      comp_group_idx_cdfs_start + size_of_val(&self.comp_group_idx_cdfs);
+    let newmv_cdf_start = self.newmv_cdf.first().unwrap().as_ptr() as usize;
+    let newmv_cdf_end = newmv_cdf_start + size_of_val(&self.newmv_cdf);
+    let zeromv_cdf_start = self.zeromv_cdf.first().unwrap().as_ptr() as usize;
+    let zeromv_cdf_end = zeromv_cdf_start + size_of_val(&self.zeromv_cdf);
+    let refmv_cdf_start = self.refmv_cdf.first().unwrap().as_ptr() as usize;
+    let refmv_cdf_end = refmv_cdf_start + size_of_val(&self.refmv_cdf);
+    let drl_cdf_start = self.drl_cdf.first().unwrap().as_ptr() as usize;
+    let drl_cdf_end = drl_cdf_start + size_of_val(&self.drl_cdf);
+    let compound_mode_cdf_start =
+      self.compound_mode_cdf.first().unwrap().as_ptr() as usize;
+    let compound_mode_cdf_end =
+      compound_mode_cdf_start + size_of_val(&self.compound_mode_cdf);
+    let single_ref_cdfs_start =
+      self.single_ref_cdfs.first().unwrap().as_ptr() as usize;
+    let single_ref_cdfs_end =
+      single_ref_cdfs_start + size_of_val(&self.single_ref_cdfs);
+    let comp_ref_type_cdfs_start =
+      self.comp_ref_type_cdfs.first().unwrap().as_ptr() as usize;
+    let comp_ref_type_cdfs_end =
+      comp_ref_type_cdfs_start + size_of_val(&self.comp_ref_type_cdfs);
+    let uni_comp_ref_cdfs_start =
+      self.uni_comp_ref_cdfs.first().unwrap().as_ptr() as usize;
+    let uni_comp_ref_cdfs_end =
+      uni_comp_ref_cdfs_start + size_of_val(&self.uni_comp_ref_cdfs);
+    let comp_ref_cdfs_start =
+      self.comp_ref_cdfs.first().unwrap().as_ptr() as usize;
+    let comp_ref_cdfs_end =
+      comp_ref_cdfs_start + size_of_val(&self.comp_ref_cdfs);
+    let comp_bwdref_cdfs_start =
+      self.comp_bwdref_cdfs.first().unwrap().as_ptr() as usize;
+    let comp_bwdref_cdfs_end =
+      comp_bwdref_cdfs_start + size_of_val(&self.comp_bwdref_cdfs);
+    let nmv_contexts_start = self.nmv_contexts.as_ptr() as usize;
+    let nmv_contexts_end =
+      nmv_contexts_start + size_of_val(&self.nmv_contexts);
+    let tx_size_cdf_start =
+      self.tx_size_cdf.first().unwrap().as_ptr() as usize;
+    let tx_size_cdf_end = tx_size_cdf_start + size_of_val(&self.tx_size_cdf);
+    let txfm_partition_cdf_start =
+      self.txfm_partition_cdf.first().unwrap().as_ptr() as usize;
+    let txfm_partition_cdf_end =
+      txfm_partition_cdf_start + size_of_val(&self.txfm_partition_cdf);
     let txb_skip_cdf_start =
       self.txb_skip_cdf.first().unwrap().as_ptr() as usize;
     let txb_skip_cdf_end =
@@ -1081,9 +1498,42 @@ impl CDFContext {
       ("intra_tx_cdf", intra_tx_cdf_start, intra_tx_cdf_end),
       ("inter_tx_cdf", inter_tx_cdf_start, inter_tx_cdf_end),
       ("skip_cdfs", skip_cdfs_start, skip_cdfs_end),
+      ("skip_mode_cdfs", skip_mode_cdfs_start, skip_mode_cdfs_end),
       ("intra_inter_cdfs", intra_inter_cdfs_start, intra_inter_cdfs_end),
+      ("comp_inter_cdfs", comp_inter_cdfs_start, comp_inter_cdfs_end),
       ("angle_delta_cdf", angle_delta_cdf_start, angle_delta_cdf_end),
       ("filter_intra_cdfs", filter_intra_cdfs_start, filter_intra_cdfs_end),
+      ("filter_intra_mode_cdf", filter_intra_mode_cdf_start, filter_intra_mode_cdf_end),
+      ("cfl_sign_cdf", cfl_sign_cdf_start, cfl_sign_cdf_end),
+      ("cfl_alpha_cdf", cfl_alpha_cdf_start, cfl_alpha_cdf_end),
+      ("palette_y_mode_cdfs", palette_y_mode_cdfs_start, palette_y_mode_cdfs_end),
+      ("palette_uv_mode_cdfs", palette_uv_mode_cdfs_start, palette_uv_mode_cdfs_end),
+      ("palette_y_size_cdfs", palette_y_size_cdfs_start, palette_y_size_cdfs_end),
+      ("palette_uv_size_cdfs", palette_uv_size_cdfs_start, palette_uv_size_cdfs_end),
+      ("palette_y_color_cdfs", palette_y_color_cdfs_start, palette_y_color_cdfs_end),
+      ("palette_uv_color_cdfs", palette_uv_color_cdfs_start, palette_uv_color_cdfs_end),
+      ("intrabc_cdf", intrabc_cdf_start, intrabc_cdf_end),
+      ("motion_mode_cdf", motion_mode_cdf_start, motion_mode_cdf_end),
+      ("obmc_cdf", obmc_cdf_start, obmc_cdf_end),
+      ("interintra_cdf", interintra_cdf_start, interintra_cdf_end),
+      ("interintra_mode_cdf", interintra_mode_cdf_start, interintra_mode_cdf_end),
+      ("wedge_interintra_cdf", wedge_interintra_cdf_start, wedge_interintra_cdf_end),
+      ("wedge_idx_cdf", wedge_idx_cdf_start, wedge_idx_cdf_end),
+      ("compound_type_cdf", compound_type_cdf_start, compound_type_cdf_end),
+      ("comp_group_idx_cdfs", comp_group_idx_cdfs_start, comp_group_idx_cdfs_end),
+      ("newmv_cdf", newmv_cdf_start, newmv_cdf_end),
+      ("zeromv_cdf", zeromv_cdf_start, zeromv_cdf_end),
+      ("refmv_cdf", refmv_cdf_start, refmv_cdf_end),
+      ("drl_cdf", drl_cdf_start, drl_cdf_end),
+      ("compound_mode_cdf", compound_mode_cdf_start, compound_mode_cdf_end),
+      ("single_ref_cdfs", single_ref_cdfs_start, single_ref_cdfs_end),
+      ("comp_ref_type_cdfs", comp_ref_type_cdfs_start, comp_ref_type_cdfs_end),
+      ("uni_comp_ref_cdfs", uni_comp_ref_cdfs_start, uni_comp_ref_cdfs_end),
+      ("comp_ref_cdfs", comp_ref_cdfs_start, comp_ref_cdfs_end),
+      ("comp_bwdref_cdfs", comp_bwdref_cdfs_start, comp_bwdref_cdfs_end),
+      ("nmv_contexts", nmv_contexts_start, nmv_contexts_end),
+      ("tx_size_cdf", tx_size_cdf_start, tx_size_cdf_end),
+      ("txfm_partition_cdf", txfm_partition_cdf_start, txfm_partition_cdf_end),
       ("txb_skip_cdf", txb_skip_cdf_start, txb_skip_cdf_end),
       ("dc_sign_cdf", dc_sign_cdf_start, dc_sign_cdf_end),
       ("eob_extra_cdf", eob_extra_cdf_start, eob_extra_cdf_end),
@@ -1101,6 +1551,38 @@ impl CDFContext {
   }
 }
 
+impl CDFContext {
+  // All the CDFs, which are only made of u16 values
+  fn as_slice(&self) -> &[u16] {
+    unsafe {
+      slice::from_raw_parts(
+        self as *const CDFContext as *const u16,
+        mem::size_of::<CDFContext>() / mem::size_of::<u16>()
+      )
+    }
+  }
+}
+
+impl PartialEq for CDFContext {
+  fn eq(&self, other: &CDFContext) -> bool {
+    self.as_slice() == other.as_slice()
+  }
+}
+
+// Lists the CDFs of each field, flattened
+impl fmt::Debug for CDFContext {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    let start = self as *const CDFContext as usize;
+    let cdfs = self.as_slice();
+    let mut s = f.debug_struct("CDFContext");
+    for (name, field_start, field_end) in self.build_map() {
+      let range = (field_start - start) / 2..(field_end - start) / 2;
+      s.field(name, &&cdfs[range]);
+    }
+    s.finish()
+  }
+}
+
 #[cfg(test)]
 mod test {
   #[test]
@@ -1114,15 +1596,166 @@ mod test {
     let f = &cdf.partition_cdf[2];
     cdf_map.lookup(f.as_ptr() as usize);
   }
+
+  #[test]
+  fn cdf_map_covers_all_cdfs() {
+    use super::*;
+
+    let cdf = CDFContext::new(8);
+    let mapped: usize =
+      cdf.build_map().iter().map(|&(_, start, end)| end - start).sum();
+    assert_eq!(mapped, mem::size_of::<CDFContext>());
+  }
+
+  #[test]
+  fn reset_counts() {
+    use super::*;
+
+    let mut cdf = CDFContext::new(8);
+    let mut w = WriterEncoder::new();
+    for s in 0..4 {
+      w.symbol(s, &mut cdf.partition_cdf[0][..PARTITION_TYPES + 1]);
+      w.symbol(s & 1, &mut cdf.skip_cdfs[0]);
+    }
+    let adapted = cdf.clone();
+    assert_eq!(cdf.partition_cdf[0][PARTITION_TYPES], 4);
+    assert_eq!(cdf.skip_cdfs[0][2], 4);
+
+    cdf.reset_counts();
+    assert_eq!(cdf.partition_cdf[0][PARTITION_TYPES], 0);
+    assert_eq!(cdf.skip_cdfs[0][2], 0);
+    assert_eq!(
+      cdf.partition_cdf[0][..PARTITION_TYPES],
+      adapted.partition_cdf[0][..PARTITION_TYPES]
+    );
+    assert_eq!(cdf.skip_cdfs[0][..2], adapted.skip_cdfs[0][..2]);
+  }
+
+  #[test]
+  fn tx_tree() {
+    use super::*;
+
+    let bo = BlockOffset { x: 8, y: 8 };
+    let offsets = |tx_tree: &TxTree| -> Vec<(usize, usize, TxSize)> {
+      tx_tree.leaves.iter().map(|&(ref bo, size)| (bo.x, bo.y, size)).collect()
+    };
+
+    // The transforms of each node are coded one after the other
+    let uniform = TxTree::uniform(&bo, BLOCK_32X32, TxSize::TX_8X8);
+    assert_eq!(uniform.leaves.len(), 16);
+    assert_eq!(offsets(&uniform)[..5], [
+      (8, 8, TxSize::TX_8X8), (10, 8, TxSize::TX_8X8), (8, 10, TxSize::TX_8X8),
+      (10, 10, TxSize::TX_8X8), (12, 8, TxSize::TX_8X8)
+    ]);
+    assert_eq!(uniform.uniform_size(), Some(TxSize::TX_8X8));
+
+    let mut tx_tree = TxTree::uniform(&bo, BLOCK_32X32, TxSize::TX_32X32);
+    tx_tree.split(&bo);
+    tx_tree.split(&BlockOffset { x: 12, y: 8 });
+    assert_eq!(offsets(&tx_tree), [
+      (8, 8, TxSize::TX_16X16), (12, 8, TxSize::TX_8X8),
+      (14, 8, TxSize::TX_8X8), (12, 10, TxSize::TX_8X8),
+      (14, 10, TxSize::TX_8X8), (8, 12, TxSize::TX_16X16),
+      (12, 12, TxSize::TX_16X16)
+    ]);
+    assert_eq!(tx_tree.leaf_size(&BlockOffset { x: 12, y: 8 }), TxSize::TX_8X8);
+    assert_eq!(tx_tree.max_size(), TxSize::TX_16X16);
+    assert_eq!(tx_tree.uniform_size(), None);
+
+    tx_tree.merge(&BlockOffset { x: 12, y: 8 }, TxSize::TX_16X16);
+    assert_eq!(tx_tree.uniform_size(), Some(TxSize::TX_16X16));
+    tx_tree.merge(&bo, TxSize::TX_32X32);
+    assert_eq!(offsets(&tx_tree), [(8, 8, TxSize::TX_32X32)]);
+  }
+
+  #[test]
+  fn inter_mode_context() {
+    use super::*;
+
+    let mut bc = BlockContext::new(16, 16);
+    let bo = BlockOffset { x: 4, y: 4 };
+    let above = BlockOffset { x: 4, y: 2 };
+    let bsize = BlockSize::BLOCK_8X8;
+    let gm_mv = MotionVector::default();
+
+    let (_, ctx) = bc.find_mv_stack(&bo, bsize, LAST_FRAME, gm_mv);
+    assert_eq!(ctx, 0);
+
+    bc.set_ref_frames(&above, bsize, [LAST_FRAME, INTRA_FRAME]);
+    bc.set_mode(&above, bsize, PredictionMode::NEWMV);
+    let (_, ctx) = bc.find_mv_stack(&bo, bsize, LAST_FRAME, gm_mv);
+    assert_eq!(ctx, 2 | (3 << REFMV_OFFSET));
+
+    bc.set_mode(&above, bsize, PredictionMode::NEARESTMV);
+    let (_, ctx) = bc.find_mv_stack(&bo, bsize, LAST_FRAME, gm_mv);
+    assert_eq!(ctx, 3 | (3 << REFMV_OFFSET));
+
+    // Another reference is not a match
+    let (_, ctx) = bc.find_mv_stack(&bo, bsize, GOLDEN_FRAME, gm_mv);
+    assert_eq!(ctx, 0);
+
+    // Motion projected below-right of the block but not at its top-left
+    let mut temporal_mvs = vec![None; (16 >> 1) * (16 >> 1)];
+    temporal_mvs[(7 >> 1) * (16 >> 1) + (7 >> 1)] = Some(gm_mv);
+    bc.temporal_mvs = vec![temporal_mvs; ALTREF_FRAME - LAST_FRAME + 1];
+    let (_, ctx) = bc.find_mv_stack(&bo, bsize, LAST_FRAME, gm_mv);
+    assert_eq!(ctx, 3 | (1 << GLOBALMV_OFFSET) | (3 << REFMV_OFFSET));
+
+    // Motion projected at the top-left, close to the global motion
+    bc.temporal_mvs[0][(5 >> 1) * (16 >> 1) + (5 >> 1)] = Some(gm_mv);
+    let (_, ctx) = bc.find_mv_stack(&bo, bsize, LAST_FRAME, gm_mv);
+    assert_eq!(ctx, 3 | (3 << REFMV_OFFSET));
+
+    // and far from it
+    let far_mv = MotionVector { row: 16, col: 0 };
+    bc.temporal_mvs[0][(5 >> 1) * (16 >> 1) + (5 >> 1)] = Some(far_mv);
+    let (_, ctx) = bc.find_mv_stack(&bo, bsize, LAST_FRAME, gm_mv);
+    assert_eq!(ctx, 3 | (1 << GLOBALMV_OFFSET) | (3 << REFMV_OFFSET));
+  }
+
+  #[test]
+  fn comp_ref_type_context() {
+    use super::*;
+
+    let mut bc = BlockContext::new(16, 16);
+    let bo = BlockOffset { x: 4, y: 4 };
+    let above = BlockOffset { x: 4, y: 2 };
+    let left = BlockOffset { x: 2, y: 4 };
+    let bsize = BlockSize::BLOCK_8X8;
+
+    assert_eq!(bc.comp_ref_type_context(&BlockOffset { x: 0, y: 0 }), 2);
+    assert_eq!(bc.comp_ref_type_context(&bo), 2);
+
+    // A compound block next to an intra one
+    bc.set_mode(&above, bsize, PredictionMode::NEAREST_NEARESTMV);
+    bc.set_ref_frames(&above, bsize, [LAST_FRAME, ALTREF_FRAME]);
+    assert_eq!(bc.comp_ref_type_context(&bo), 1);
+    bc.set_ref_frames(&above, bsize, [LAST_FRAME, GOLDEN_FRAME]);
+    assert_eq!(bc.comp_ref_type_context(&bo), 3);
+
+    // and next to a single reference block on the same side
+    bc.set_mode(&left, bsize, PredictionMode::NEARESTMV);
+    bc.set_ref_frames(&left, bsize, [LAST_FRAME, INTRA_FRAME]);
+    assert_eq!(bc.comp_ref_type_context(&bo), 4);
+
+    // Single reference blocks on both sides
+    bc.set_mode(&above, bsize, PredictionMode::NEARESTMV);
+    bc.set_ref_frames(&above, bsize, [ALTREF_FRAME, INTRA_FRAME]);
+    assert_eq!(bc.comp_ref_type_context(&bo), 1);
+  }
 }
 
 const SUPERBLOCK_TO_PLANE_SHIFT: usize = MAX_SB_SIZE_LOG2;
 const SUPERBLOCK_TO_BLOCK_SHIFT: usize = MAX_MIB_SIZE_LOG2;
+const FILTER_BLOCK_TO_PLANE_SHIFT: usize = 6;
+const FILTER_BLOCK_TO_BLOCK_SHIFT: usize = FILTER_BLOCK_TO_PLANE_SHIFT - MI_SIZE_LOG2;
 const BLOCK_TO_PLANE_SHIFT: usize = MI_SIZE_LOG2;
 pub const LOCAL_BLOCK_MASK: usize = (1 << SUPERBLOCK_TO_BLOCK_SHIFT) - 1;
 
 /// Absolute offset in superblocks inside a plane, where a superblock is defined
-/// to be an N*N square where N = (1 << SUPERBLOCK_TO_PLANE_SHIFT).
+/// to be an N*N square where N = (1 << SUPERBLOCK_TO_PLANE_SHIFT), i.e. the
+/// largest superblock size. Frames coded with 64x64 superblocks fit four of
+/// them in each of these.
 #[derive(Clone)]
 pub struct SuperBlockOffset {
   pub x: usize,
@@ -1147,6 +1780,34 @@ impl SuperBlockOffset {
   }
 }
 
+/// Absolute offset in filter blocks inside a plane, where a filter block is
+/// defined to be an N*N square where N = (1 << FILTER_BLOCK_TO_PLANE_SHIFT).
+/// CDEF parameters are signaled per filter block, whatever the superblock
+/// size.
+#[derive(Clone)]
+pub struct FilterBlockOffset {
+  pub x: usize,
+  pub y: usize
+}
+
+impl FilterBlockOffset {
+  /// Offset of a block inside the current filter block.
+  pub fn block_offset(&self, block_x: usize, block_y: usize) -> BlockOffset {
+    BlockOffset {
+      x: (self.x << FILTER_BLOCK_TO_BLOCK_SHIFT) + block_x,
+      y: (self.y << FILTER_BLOCK_TO_BLOCK_SHIFT) + block_y
+    }
+  }
+
+  /// Offset of the top-left pixel of this filter block.
+  pub fn plane_offset(&self, plane: &PlaneConfig) -> PlaneOffset {
+    PlaneOffset {
+      x: self.x << (FILTER_BLOCK_TO_PLANE_SHIFT - plane.xdec),
+      y: self.y << (FILTER_BLOCK_TO_PLANE_SHIFT - plane.ydec)
+    }
+  }
+}
+
 /// Absolute offset in blocks inside a plane, where a block is defined
 /// to be an N*N square where N = (1 << BLOCK_TO_PLANE_SHIFT).
 #[derive(Clone)]
@@ -1180,31 +1841,184 @@ impl BlockOffset {
   }
 }
 
+/// Transform blocks of a block, in coding order. Every largest transform of
+/// the block is either coded whole or split, and each of the resulting nodes
+/// of its transform partitioning tree in turn, depth first. Only inter blocks
+/// can split their transforms unevenly.
+#[derive(Clone)]
+pub struct TxTree {
+  pub leaves: Vec<(BlockOffset, TxSize)>
+}
+
+impl TxTree {
+  /// Transforms of the block of size `bsize` at `bo` splitting every largest
+  /// transform down to `tx_size`.
+  pub fn uniform(
+    bo: &BlockOffset, bsize: BlockSize, tx_size: TxSize
+  ) -> TxTree {
+    let max_tx_size = bsize.largest_tx_size();
+    let mut tx_tree = TxTree { leaves: Vec::new() };
+    for by in 0..bsize.height_mi() / max_tx_size.height_mi() {
+      for bx in 0..bsize.width_mi() / max_tx_size.width_mi() {
+        let max_tx_bo = BlockOffset {
+          x: bo.x + bx * max_tx_size.width_mi(),
+          y: bo.y + by * max_tx_size.height_mi()
+        };
+        tx_tree.leaves.push((max_tx_bo, max_tx_size));
+      }
+    }
+    let mut i = 0;
+    while i < tx_tree.leaves.len() {
+      if tx_tree.leaves[i].1 == tx_size {
+        i += 1;
+      } else {
+        let leaf_bo = tx_tree.leaves[i].0.clone();
+        tx_tree.split(&leaf_bo);
+      }
+    }
+    tx_tree
+  }
+
+  fn leaf_index(&self, bo: &BlockOffset) -> usize {
+    self
+      .leaves
+      .iter()
+      .position(|&(ref leaf_bo, _)| leaf_bo.x == bo.x && leaf_bo.y == bo.y)
+      .unwrap()
+  }
+
+  /// Size of the transform at the top-left of the node at `bo`, which is
+  /// the size of the node unless it is split.
+  pub fn leaf_size(&self, bo: &BlockOffset) -> TxSize {
+    self.leaves[self.leaf_index(bo)].1
+  }
+
+  /// Size of the largest transforms, which are split the least.
+  pub fn max_size(&self) -> TxSize {
+    self
+      .leaves
+      .iter()
+      .map(|&(_, tx_size)| tx_size)
+      .max_by_key(|tx_size| tx_size.width() * tx_size.height())
+      .unwrap()
+  }
+
+  /// Size of the transforms, when they all have the same.
+  pub fn uniform_size(&self) -> Option<TxSize> {
+    let tx_size = self.leaves[0].1;
+    if self.leaves.iter().all(|&(_, size)| size == tx_size) {
+      Some(tx_size)
+    } else {
+      None
+    }
+  }
+
+  /// Split the transform at `bo` into those of the next depth.
+  pub fn split(&mut self, bo: &BlockOffset) {
+    let i = self.leaf_index(bo);
+    let tx_size = self.leaves[i].1;
+    let sub_tx_size = tx_size.split();
+    let mut sub_leaves = Vec::new();
+    for by in 0..tx_size.height_mi() / sub_tx_size.height_mi() {
+      for bx in 0..tx_size.width_mi() / sub_tx_size.width_mi() {
+        let sub_bo = BlockOffset {
+          x: bo.x + bx * sub_tx_size.width_mi(),
+          y: bo.y + by * sub_tx_size.height_mi()
+        };
+        sub_leaves.push((sub_bo, sub_tx_size));
+      }
+    }
+    self.leaves.splice(i..i + 1, sub_leaves);
+  }
+
+  /// Code the node of size `tx_size` at `bo` with a single transform again.
+  pub fn merge(&mut self, bo: &BlockOffset, tx_size: TxSize) {
+    let i = self.leaf_index(bo);
+    let n = self.leaves[i..]
+      .iter()
+      .take_while(|&&(ref leaf_bo, _)| {
+        leaf_bo.x < bo.x + tx_size.width_mi()
+          && leaf_bo.y < bo.y + tx_size.height_mi()
+          && leaf_bo.x >= bo.x
+          && leaf_bo.y >= bo.y
+      }).count();
+    self.leaves.splice(i..i + n, Some((bo.clone(), tx_size)));
+  }
+}
+
 #[derive(Copy, Clone)]
 pub struct Block {
   pub mode: PredictionMode,
+  pub uv_mode: PredictionMode,
+  pub filter_intra_mode: Option<FilterIntraMode>,
+  pub palette: PaletteParams,
+  pub use_intrabc: bool,
   pub bsize: BlockSize,
   pub partition: PartitionType,
   pub skip: bool,
+  pub skip_mode: bool,
   pub ref_frames: [usize; 2],
+  pub mvs: [MotionVector; 2],
+  pub motion_mode: MotionMode,
+  pub interintra: Option<InterIntraParams>,
+  pub compound_mask: Option<CompoundMask>,
   pub neighbors_ref_counts: [usize; TOTAL_REFS_PER_FRAME],
   pub cdef_index: u8
 }
 
+/// Inter neighbour above or left of a block, whose prediction with its own
+/// motion is blended over the edge of the block from `offset` along `len`
+/// 4x4 units
+#[derive(Copy, Clone, Debug)]
+pub struct OverlapCandidate {
+  pub above: bool,
+  pub offset: usize,
+  pub len: usize,
+  pub ref_frame: usize,
+  pub mv: MotionVector
+}
+
+/// Motion vector candidate, with the motion vector for the second reference
+/// of compound blocks
+#[derive(Copy, Clone)]
+pub struct CandidateMV {
+  pub this_mv: MotionVector,
+  pub comp_mv: MotionVector,
+  pub weight: u32
+}
+
+// Whether the blocks scanned for motion vector candidates had the references
+// sought, and how many of those had coded motion vectors
+#[derive(Default)]
+struct RefMatches {
+  found: bool,
+  new_mvs: usize
+}
+
 impl Block {
   pub fn default() -> Block {
     Block {
       mode: PredictionMode::DC_PRED,
+      uv_mode: PredictionMode::DC_PRED,
+      filter_intra_mode: None,
+      palette: PaletteParams::default(),
+      use_intrabc: false,
       bsize: BlockSize::BLOCK_64X64,
       partition: PartitionType::PARTITION_NONE,
       skip: false,
+      skip_mode: false,
       ref_frames: [INTRA_FRAME; 2],
+      mvs: [MotionVector::default(); 2],
+      motion_mode: MotionMode::SIMPLE_TRANSLATION,
+      interintra: None,
+      compound_mask: None,
       neighbors_ref_counts: [0; TOTAL_REFS_PER_FRAME],
       cdef_index: 0
     }
   }
+  // Intra block copy predicts from the current frame, like an inter block
   pub fn is_inter(&self) -> bool {
-    self.mode >= PredictionMode::NEARESTMV
+    self.mode >= PredictionMode::NEARESTMV || self.use_intrabc
   }
   pub fn has_second_ref(&self) -> bool {
     self.ref_frames[1] > INTRA_FRAME
@@ -1225,9 +2039,23 @@ pub struct BlockContext {
   left_partition_context: [u8; MAX_MIB_SIZE],
   above_coeff_context: [Vec<u8>; PLANES],
   left_coeff_context: [[u8; MAX_MIB_SIZE]; PLANES],
-  blocks: Vec<Vec<Block>>
+  above_tx_context: Vec<u8>,
+  left_tx_context: [u8; MAX_MIB_SIZE],
+  // Whether the 4x4 units of the current superblock, and of the row above and
+  // the column left of it, are decoded, per plane
+  block_decoded: [Vec<bool>; PLANES],
+  sb_size_mi: usize,
+  blocks: Vec<Vec<Block>>,
+  // Motion vectors of the 8x8 blocks projected from the reference frames, for
+  // each reference, if the frame uses them
+  pub temporal_mvs: Vec<Vec<Option<MotionVector>>>,
+  // Whether each reference follows the current frame, its motion vectors
+  // pointing the other way
+  pub ref_frame_sign_bias: [bool; TOTAL_REFS_PER_FRAME]
 }
 
+const BLOCK_DECODED_STRIDE: usize = MAX_MIB_SIZE + 2;
+
 impl BlockContext {
   pub fn new(cols: usize, rows: usize) -> BlockContext {
     // Align power of two
@@ -1245,7 +2073,17 @@ impl BlockContext {
         vec![0; cols << (MI_SIZE_LOG2 - TxSize::smallest_width_log2())]
       ],
       left_coeff_context: [[0; MAX_MIB_SIZE]; PLANES],
-      blocks: vec![vec![Block::default(); cols]; rows]
+      above_tx_context: vec![TxSize::TX_64X64.width() as u8; cols],
+      left_tx_context: [TxSize::TX_64X64.height() as u8; MAX_MIB_SIZE],
+      block_decoded: [
+        vec![false; BLOCK_DECODED_STRIDE * BLOCK_DECODED_STRIDE],
+        vec![false; BLOCK_DECODED_STRIDE * BLOCK_DECODED_STRIDE],
+        vec![false; BLOCK_DECODED_STRIDE * BLOCK_DECODED_STRIDE]
+      ],
+      sb_size_mi: BlockSize::BLOCK_64X64.width_mi(),
+      blocks: vec![vec![Block::default(); cols]; rows],
+      temporal_mvs: Vec::new(),
+      ref_frame_sign_bias: [false; TOTAL_REFS_PER_FRAME]
     }
   }
 
@@ -1258,18 +2096,39 @@ impl BlockContext {
       left_partition_context: self.left_partition_context,
       above_coeff_context: self.above_coeff_context.clone(),
       left_coeff_context: self.left_coeff_context,
-      blocks: vec![vec![Block::default(); 0]; 0]
+      above_tx_context: self.above_tx_context.clone(),
+      left_tx_context: self.left_tx_context,
+      block_decoded: self.block_decoded.clone(),
+      sb_size_mi: self.sb_size_mi,
+      blocks: vec![vec![Block::default(); 0]; 0],
+      temporal_mvs: Vec::new(),
+      ref_frame_sign_bias: self.ref_frame_sign_bias
     }
   }
 
+  // Copies the contexts of `checkpoint`, into the buffers already allocated
   pub fn rollback(&mut self, checkpoint: &BlockContext) {
     self.cols = checkpoint.cols;
     self.rows = checkpoint.rows;
     self.cdef_coded = checkpoint.cdef_coded;
-    self.above_partition_context = checkpoint.above_partition_context.clone();
+    self
+      .above_partition_context
+      .clone_from(&checkpoint.above_partition_context);
     self.left_partition_context = checkpoint.left_partition_context;
-    self.above_coeff_context = checkpoint.above_coeff_context.clone();
+    for (ctx, saved) in
+      self.above_coeff_context.iter_mut().zip(&checkpoint.above_coeff_context)
+    {
+      ctx.clone_from(saved);
+    }
     self.left_coeff_context = checkpoint.left_coeff_context;
+    self.above_tx_context.clone_from(&checkpoint.above_tx_context);
+    self.left_tx_context = checkpoint.left_tx_context;
+    for (decoded, saved) in
+      self.block_decoded.iter_mut().zip(&checkpoint.block_decoded)
+    {
+      decoded.clone_from(saved);
+    }
+    self.sb_size_mi = checkpoint.sb_size_mi;
   }
 
   pub fn at(&mut self, bo: &BlockOffset) -> &mut Block {
@@ -1305,6 +2164,31 @@ impl BlockContext {
     }
   }
 
+  /// Information of the blocks covered by `bsize` at `bo`, row by row,
+  /// which checkpoints leave out
+  pub fn blocks_at(
+    &self, bo: &BlockOffset, bsize: BlockSize, blocks: &mut Vec<Block>
+  ) {
+    let x_end = cmp::min(bo.x + bsize.width_mi(), self.cols);
+    let y_end = cmp::min(bo.y + bsize.height_mi(), self.rows);
+    blocks.clear();
+    for row in &self.blocks[bo.y..y_end] {
+      blocks.extend_from_slice(&row[bo.x..x_end]);
+    }
+  }
+
+  pub fn set_blocks(
+    &mut self, bo: &BlockOffset, bsize: BlockSize, blocks: &[Block]
+  ) {
+    let x_end = cmp::min(bo.x + bsize.width_mi(), self.cols);
+    let y_end = cmp::min(bo.y + bsize.height_mi(), self.rows);
+    for (row, saved) in
+      self.blocks[bo.y..y_end].iter_mut().zip(blocks.chunks(x_end - bo.x))
+    {
+      row[bo.x..x_end].copy_from_slice(saved);
+    }
+  }
+
   pub fn set_dc_sign(&mut self, cul_level: &mut u32, dc_val: i32) {
     if dc_val < 0 {
       *cul_level |= 1 << COEFF_CONTEXT_BITS;
@@ -1339,7 +2223,12 @@ impl BlockContext {
       *c = 0;
     }
   }
-  //TODO(anyone): Add reset_left_tx_context() here then call it in reset_left_contexts()
+
+  fn reset_left_tx_context(&mut self) {
+    for c in &mut self.left_tx_context {
+      *c = TxSize::TX_64X64.height() as u8;
+    }
+  }
 
   pub fn reset_skip_context(
     &mut self, bo: &BlockOffset, bsize: BlockSize, xdec: usize, ydec: usize
@@ -1387,8 +2276,7 @@ impl BlockContext {
       BlockContext::reset_left_coeff_context(self, p);
     }
     BlockContext::reset_left_partition_context(self);
-
-    //TODO(anyone): Call reset_left_tx_context() here.
+    BlockContext::reset_left_tx_context(self);
   }
 
   pub fn set_mode(
@@ -1401,117 +2289,1388 @@ impl BlockContext {
     self.blocks[bo.y][bo.x].mode
   }
 
-  fn partition_plane_context(
-    &self, bo: &BlockOffset, bsize: BlockSize
-  ) -> usize {
-    // TODO: this should be way simpler without sub8x8
-    let above_ctx = self.above_partition_context[bo.x];
-    let left_ctx = self.left_partition_context[bo.y_in_sb()];
-    let bsl = bsize.width_log2() - BLOCK_8X8.width_log2();
-    let above = (above_ctx >> bsl) & 1;
-    let left = (left_ctx >> bsl) & 1;
+  pub fn set_uv_mode(
+    &mut self, bo: &BlockOffset, bsize: BlockSize, mode: PredictionMode
+  ) {
+    self.for_each(bo, bsize, |block| block.uv_mode = mode);
+  }
 
-    assert!(bsize.is_sqr());
+  pub fn set_filter_intra_mode(
+    &mut self, bo: &BlockOffset, bsize: BlockSize,
+    mode: Option<FilterIntraMode>
+  ) {
+    self.for_each(bo, bsize, |block| block.filter_intra_mode = mode);
+  }
 
-    (left * 2 + above) as usize + bsl as usize * PARTITION_PLOFFSET
+  pub fn set_palette(
+    &mut self, bo: &BlockOffset, bsize: BlockSize, palette: PaletteParams
+  ) {
+    self.for_each(bo, bsize, |block| block.palette = palette);
   }
 
-  pub fn update_partition_context(
-    &mut self, bo: &BlockOffset, subsize: BlockSize, bsize: BlockSize
+  pub fn set_intrabc(
+    &mut self, bo: &BlockOffset, bsize: BlockSize,
+    intrabc: Option<MotionVector>
   ) {
-    #[allow(dead_code)]
-    let bw = bsize.width_mi();
-    let bh = bsize.height_mi();
+    self.for_each(bo, bsize, |block| {
+      block.use_intrabc = intrabc.is_some();
+      block.mvs[0] = intrabc.unwrap_or_default();
+    });
+  }
 
-    let above_ctx =
-      &mut self.above_partition_context[bo.x..bo.x + bw as usize];
-    let left_ctx = &mut self.left_partition_context
-      [bo.y_in_sb()..bo.y_in_sb() + bh as usize];
+  pub fn set_motion_vectors(
+    &mut self, bo: &BlockOffset, bsize: BlockSize, mvs: [MotionVector; 2]
+  ) {
+    self.for_each(bo, bsize, |block| block.mvs = mvs);
+  }
 
-    // update the partition context at the end notes. set partition bits
-    // of block sizes larger than the current one to be one, and partition
-    // bits of smaller block sizes to be zero.
-    for i in 0..bw {
-      above_ctx[i as usize] = partition_context_lookup[subsize as usize][0];
-    }
+  pub fn set_motion_mode(
+    &mut self, bo: &BlockOffset, bsize: BlockSize, mode: MotionMode
+  ) {
+    self.for_each(bo, bsize, |block| block.motion_mode = mode);
+  }
 
-    for i in 0..bh {
-      left_ctx[i as usize] = partition_context_lookup[subsize as usize][1];
-    }
+  pub fn set_interintra(
+    &mut self, bo: &BlockOffset, bsize: BlockSize,
+    interintra: Option<InterIntraParams>
+  ) {
+    self.for_each(bo, bsize, |block| block.interintra = interintra);
   }
 
-  fn skip_context(&mut self, bo: &BlockOffset) -> usize {
-    let above_skip = if bo.y > 0 {
-      self.above_of(bo).skip as usize
-    } else {
-      0
-    };
-    let left_skip = if bo.x > 0 {
-      self.left_of(bo).skip as usize
-    } else {
-      0
-    };
-    above_skip + left_skip
+  pub fn set_compound_mask(
+    &mut self, bo: &BlockOffset, bsize: BlockSize, mask: Option<CompoundMask>
+  ) {
+    self.for_each(bo, bsize, |block| block.compound_mask = mask);
   }
 
-  pub fn set_skip(&mut self, bo: &BlockOffset, bsize: BlockSize, skip: bool) {
-    self.for_each(bo, bsize, |block| block.skip = skip);
+  // Context of comp_group_idx from the blocks above and left, which are
+  // either compound or count if they predict from ALTREF_FRAME only
+  pub fn comp_group_idx_context(&mut self, bo: &BlockOffset) -> usize {
+    let ctx: usize = [self.above_of(bo), self.left_of(bo)]
+      .iter()
+      .map(|block| {
+        if block.has_second_ref() {
+          block.compound_mask.is_some() as usize
+        } else if block.ref_frames[0] == ALTREF_FRAME {
+          3
+        } else {
+          0
+        }
+      }).sum();
+    cmp::min(5, ctx)
   }
 
-  pub fn set_ref_frame(&mut self, bo: &BlockOffset, bsize: BlockSize, r: usize) {
-    let bw = bsize.width_mi();
-    let bh = bsize.height_mi();
+  // Sorted union of the luma (plane 0) or U (plane 1) palette colors of the
+  // blocks left and above the block at `bo`, the block above being ignored
+  // across 64-row boundaries. Returns the number of colors in `cache`.
+  pub fn palette_cache(
+    &mut self, bo: &BlockOffset, plane: usize, cache: &mut [u16]
+  ) -> usize {
+    let above = if bo.y % (64 >> MI_SIZE_LOG2) != 0 {
+      self.above_of(bo).palette
+    } else {
+      PaletteParams::default()
+    };
+    let left = self.left_of(bo).palette;
+    let above_colors = &above.colors[plane][..above.size[plane]];
+    let left_colors = &left.colors[plane][..left.size[plane]];
 
-    for y in 0..bh {
-      for x in 0..bw {
-        self.blocks[bo.y + y as usize][bo.x + x as usize].ref_frames[0] = r;
+    let mut n = 0;
+    {
+      let mut push = |v: u16| {
+        if n == 0 || cache[n - 1] != v {
+          cache[n] = v;
+          n += 1;
+        }
+      };
+
+      let mut a = 0;
+      let mut l = 0;
+      while a < above_colors.len() && l < left_colors.len() {
+        if left_colors[l] < above_colors[a] {
+          push(left_colors[l]);
+          l += 1;
+        } else {
+          push(above_colors[a]);
+          if left_colors[l] == above_colors[a] {
+            l += 1;
+          }
+          a += 1;
+        }
+      }
+      for &v in above_colors[a..].iter().chain(&left_colors[l..]) {
+        push(v);
       }
     }
+    n
   }
 
-  pub fn set_cdef(&mut self, bo: &BlockOffset, bsize: BlockSize, cdef_index: u8) {
-    self.for_each(bo, bsize, |block| block.cdef_index = cdef_index);
-  }
-
-  // The mode info data structure has a one element border above and to the
-  // left of the entries corresponding to real macroblocks.
-  // The prediction flags in these dummy entries are initialized to 0.
-  // 0 - inter/inter, inter/--, --/inter, --/--
-  // 1 - intra/inter, inter/intra
-  // 2 - intra/--, --/intra
-  // 3 - intra/intra
-  pub fn intra_inter_context(&mut self, bo: &BlockOffset) -> usize {
-    let has_above = bo.y > 0;
-    let has_left = bo.x > 0;
-
-    match (has_above, has_left) {
-      (true, true) => {
-        let above_intra = !self.above_of(bo).is_inter();
-        let left_intra = !self.left_of(bo).is_inter();
-        if above_intra && left_intra {
-          3
-        } else {
-          (above_intra || left_intra) as usize
+  // Adds `weight` to the candidate with the motion vectors `this_mv` and
+  // `comp_mv`, or a new candidate if there is room
+  fn add_candidate(
+    this_mv: MotionVector, comp_mv: MotionVector, weight: u32,
+    mv_stack: &mut Vec<CandidateMV>
+  ) {
+    match mv_stack
+      .iter()
+      .position(|c| c.this_mv == this_mv && c.comp_mv == comp_mv)
+    {
+      Some(idx) => mv_stack[idx].weight += weight,
+      None => {
+        if mv_stack.len() < MAX_REF_MV_STACK_SIZE {
+          mv_stack.push(CandidateMV { this_mv, comp_mv, weight });
         }
       }
-      (true, _) | (_, true) =>
-        2 * if has_above {
-          !self.above_of(bo).is_inter() as usize
-        } else {
-          !self.left_of(bo).is_inter() as usize
-        },
-      (_, _) => 0
     }
   }
 
-  pub fn get_txb_ctx(
-    &mut self, plane_bsize: BlockSize, tx_size: TxSize, plane: usize,
-    bo: &BlockOffset, xdec: usize, ydec: usize
-  ) -> TXB_CTX {
-    let mut txb_ctx = TXB_CTX {
-      txb_skip_ctx: 0,
-      dc_sign_ctx: 0
-    };
+  // Adds the motion vectors from `ref_frames` of the block at (`x`, `y`) to
+  // the candidates, or the weight of the candidates they are already in.
+  // Compound candidates need both references. Blocks using global motion
+  // count as having the global motion vectors `gm_mvs` of the current block.
+  // The references found are counted in `matches`.
+  fn add_ref_mv_candidate(
+    &self, x: usize, y: usize, ref_frames: [usize; 2],
+    gm_mvs: [MotionVector; 2], weight: u32, mv_stack: &mut Vec<CandidateMV>,
+    matches: &mut RefMatches
+  ) {
+    let block = &self.blocks[y][x];
+    if !block.is_inter() {
+      return;
+    }
+    let global = block.mode == GLOBALMV || block.mode == GLOBAL_GLOBALMV;
+
+    if ref_frames[1] > INTRA_FRAME {
+      if block.ref_frames == ref_frames {
+        matches.found = true;
+        matches.new_mvs += block.mode.has_newmv() as usize;
+        let mvs = if global { gm_mvs } else { block.mvs };
+        BlockContext::add_candidate(mvs[0], mvs[1], weight, mv_stack);
+      }
+      return;
+    }
+
+    for i in 0..1 + block.has_second_ref() as usize {
+      if block.ref_frames[i] != ref_frames[0] {
+        continue;
+      }
+      matches.found = true;
+      matches.new_mvs += block.mode.has_newmv() as usize;
+      let this_mv = if global { gm_mvs[0] } else { block.mvs[i] };
+      BlockContext::add_candidate(
+        this_mv,
+        MotionVector::default(),
+        weight,
+        mv_stack
+      );
+    }
+  }
+
+  // Scans the row `row_offset` above the block at `bo` for candidates, and
+  // updates the number of rows covered by the blocks found
+  fn scan_row_mbmi(
+    &self, bo: &BlockOffset, bsize: BlockSize, row_offset: isize,
+    max_row_offset: isize, processed_rows: &mut isize, ref_frames: [usize; 2],
+    gm_mvs: [MotionVector; 2], mv_stack: &mut Vec<CandidateMV>,
+    matches: &mut RefMatches
+  ) {
+    let bw4 = bsize.width_mi();
+    let end4 = cmp::min(cmp::min(bw4, self.cols - bo.x), 16);
+    let use_step_16 = bw4 >= 16;
+    // Rows further away are scanned in 8x8 units
+    let col_offset = if row_offset.abs() > 1 && !(bo.x & 1 == 1 && bw4 < 2) {
+      1
+    } else {
+      0
+    };
+    let y = (bo.y as isize + row_offset) as usize;
+
+    let mut i = 0;
+    while i < end4 {
+      let x = bo.x + col_offset + i;
+      let cand_bsize = self.blocks[y][x].bsize;
+      let mut len = cmp::min(bw4, cand_bsize.width_mi());
+      if use_step_16 {
+        len = cmp::max(len, 4);
+      } else if row_offset.abs() > 1 {
+        len = cmp::max(len, 2);
+      }
+
+      let mut weight = 2;
+      if bw4 >= 2 && bw4 <= cand_bsize.width_mi() {
+        let inc = cmp::min(
+          -max_row_offset + row_offset + 1,
+          cand_bsize.height_mi() as isize
+        );
+        weight = cmp::max(weight, inc);
+        *processed_rows = inc - row_offset - 1;
+      }
+
+      self.add_ref_mv_candidate(
+        x,
+        y,
+        ref_frames,
+        gm_mvs,
+        (len as isize * weight) as u32,
+        mv_stack,
+        matches
+      );
+      i += len;
+    }
+  }
+
+  // Scans the column `col_offset` left of the block at `bo` for candidates,
+  // and updates the number of columns covered by the blocks found
+  fn scan_col_mbmi(
+    &self, bo: &BlockOffset, bsize: BlockSize, col_offset: isize,
+    max_col_offset: isize, processed_cols: &mut isize, ref_frames: [usize; 2],
+    gm_mvs: [MotionVector; 2], mv_stack: &mut Vec<CandidateMV>,
+    matches: &mut RefMatches
+  ) {
+    let bh4 = bsize.height_mi();
+    let end4 = cmp::min(cmp::min(bh4, self.rows - bo.y), 16);
+    let use_step_16 = bh4 >= 16;
+    // Columns further away are scanned in 8x8 units
+    let row_offset = if col_offset.abs() > 1 && !(bo.y & 1 == 1 && bh4 < 2) {
+      1
+    } else {
+      0
+    };
+    let x = (bo.x as isize + col_offset) as usize;
+
+    let mut i = 0;
+    while i < end4 {
+      let y = bo.y + row_offset + i;
+      let cand_bsize = self.blocks[y][x].bsize;
+      let mut len = cmp::min(bh4, cand_bsize.height_mi());
+      if use_step_16 {
+        len = cmp::max(len, 4);
+      } else if col_offset.abs() > 1 {
+        len = cmp::max(len, 2);
+      }
+
+      let mut weight = 2;
+      if bh4 >= 2 && bh4 <= cand_bsize.height_mi() {
+        let inc = cmp::min(
+          -max_col_offset + col_offset + 1,
+          cand_bsize.width_mi() as isize
+        );
+        weight = cmp::max(weight, inc);
+        *processed_cols = inc - col_offset - 1;
+      }
+
+      self.add_ref_mv_candidate(
+        x,
+        y,
+        ref_frames,
+        gm_mvs,
+        (len as isize * weight) as u32,
+        mv_stack,
+        matches
+      );
+      i += len;
+    }
+  }
+
+  // Adds the candidates of the block at (`row_offset`, `col_offset`) from the
+  // block at `bo`, if inside the frame
+  fn scan_blk_mbmi(
+    &self, bo: &BlockOffset, row_offset: isize, col_offset: isize,
+    ref_frames: [usize; 2], gm_mvs: [MotionVector; 2],
+    mv_stack: &mut Vec<CandidateMV>, matches: &mut RefMatches
+  ) {
+    let x = bo.x as isize + col_offset;
+    let y = bo.y as isize + row_offset;
+    if x >= 0 && y >= 0 && (x as usize) < self.cols && (y as usize) < self.rows
+    {
+      self.add_ref_mv_candidate(
+        x as usize, y as usize, ref_frames, gm_mvs, 4, mv_stack, matches
+      );
+    }
+  }
+
+  // Adds the projected motion vectors for `ref_frames` of the 8x8 block
+  // containing the 4x4 block at (`row_offset`, `col_offset`) from the block at
+  // `bo`, if inside the frame, and returns them if there were any
+  fn add_tpl_ref_mv(
+    &self, bo: &BlockOffset, row_offset: isize, col_offset: isize,
+    ref_frames: [usize; 2], mv_stack: &mut Vec<CandidateMV>
+  ) -> Option<(MotionVector, MotionVector)> {
+    let x = (bo.x as isize + col_offset) | 1;
+    let y = (bo.y as isize + row_offset) | 1;
+    if x < 0 || y < 0 || x as usize >= self.cols || y as usize >= self.rows {
+      return None;
+    }
+    let idx = (y as usize >> 1) * (self.cols >> 1) + (x as usize >> 1);
+    let projected =
+      |ref_frame: usize| self.temporal_mvs[ref_frame - LAST_FRAME][idx];
+
+    // The motion of both references is projected from the same motion vector
+    let comp_mv = if ref_frames[1] > INTRA_FRAME {
+      projected(ref_frames[1])
+    } else {
+      Some(MotionVector::default())
+    };
+    if let (Some(this_mv), Some(comp_mv)) = (projected(ref_frames[0]), comp_mv)
+    {
+      BlockContext::add_candidate(this_mv, comp_mv, 2, mv_stack);
+      Some((this_mv, comp_mv))
+    } else {
+      None
+    }
+  }
+
+  // Adds the projected motion vectors of the 8x8 blocks covering the block at
+  // `bo`, in 16x16 steps for blocks of 64 pixels. Blocks of 8x8 up to 32x32
+  // also add those below and right of them, within the 64x64 block.
+  // Returns the GLOBALMV context, which only looks at the top-left sample: 0
+  // when the motion projected there is within 2 pixels of the global motion
+  // vectors `gm_mvs`, 1 when it is farther or no motion is projected there.
+  fn scan_temporal(
+    &self, bo: &BlockOffset, bsize: BlockSize, ref_frames: [usize; 2],
+    gm_mvs: [MotionVector; 2], mv_stack: &mut Vec<CandidateMV>
+  ) -> usize {
+    let bw4 = bsize.width_mi() as isize;
+    let bh4 = bsize.height_mi() as isize;
+    let step_w4 = if bw4 >= 16 { 4 } else { 2 };
+    let step_h4 = if bh4 >= 16 { 4 } else { 2 };
+    let far = |mv: MotionVector, gm_mv: MotionVector| {
+      (mv.row - gm_mv.row).abs() >= 16 || (mv.col - gm_mv.col).abs() >= 16
+    };
+
+    let mut globalmv_ctx = 1;
+    let mut row_offset = 0;
+    while row_offset < cmp::min(bh4, 16) {
+      let mut col_offset = 0;
+      while col_offset < cmp::min(bw4, 16) {
+        let mvs =
+          self.add_tpl_ref_mv(bo, row_offset, col_offset, ref_frames, mv_stack);
+        if let Some((this_mv, comp_mv)) = mvs {
+          if row_offset == 0
+            && col_offset == 0
+            && !far(this_mv, gm_mvs[0])
+            && !far(comp_mv, gm_mvs[1])
+          {
+            globalmv_ctx = 0;
+          }
+        }
+        col_offset += step_w4;
+      }
+      row_offset += step_h4;
+    }
+
+    if bw4 >= 2 && bw4 < 16 && bh4 >= 2 && bh4 < 16 {
+      for &(row_offset, col_offset) in &[(bh4, -2), (bh4, bw4), (bh4 - 2, bw4)]
+      {
+        let y = (bo.y & 15) as isize + row_offset;
+        let x = (bo.x & 15) as isize + col_offset;
+        if y >= 0 && y < 16 && x >= 0 && x < 16 {
+          self.add_tpl_ref_mv(
+            bo, row_offset, col_offset, ref_frames, mv_stack
+          );
+        }
+      }
+    }
+
+    globalmv_ctx
+  }
+
+  // Blocks adjacent to the block at `bo`, along the row above then the column
+  // left of it
+  fn adjacent_blocks(&self, bo: &BlockOffset, bsize: BlockSize) -> Vec<&Block> {
+    let w4 = cmp::min(cmp::min(bsize.width_mi(), 16), self.cols - bo.x);
+    let h4 = cmp::min(cmp::min(bsize.height_mi(), 16), self.rows - bo.y);
+    let num4x4 = cmp::min(w4, h4);
+    let mut blocks = Vec::new();
+
+    for &above in &[true, false] {
+      if (above && bo.y == 0) || (!above && bo.x == 0) {
+        continue;
+      }
+      let mut idx = 0;
+      while idx < num4x4 {
+        let block = if above {
+          &self.blocks[bo.y - 1][bo.x + idx]
+        } else {
+          &self.blocks[bo.y + idx][bo.x - 1]
+        };
+        blocks.push(block);
+        idx += if above {
+          block.bsize.width_mi()
+        } else {
+          block.bsize.height_mi()
+        };
+      }
+    }
+    blocks
+  }
+
+  // Motion vector `mv` of a block predicted from `src_ref`, pointing the way
+  // of the motion vectors from `ref_frame`
+  fn oriented_mv(
+    &self, mv: MotionVector, src_ref: usize, ref_frame: usize
+  ) -> MotionVector {
+    if self.ref_frame_sign_bias[src_ref] != self.ref_frame_sign_bias[ref_frame]
+    {
+      MotionVector { row: -mv.row, col: -mv.col }
+    } else {
+      mv
+    }
+  }
+
+  // Adds the motion vectors from any reference of the blocks adjacent to the
+  // block at `bo` for `ref_frame`, until there are enough candidates
+  fn scan_extra(
+    &self, bo: &BlockOffset, bsize: BlockSize, ref_frame: usize,
+    mv_stack: &mut Vec<CandidateMV>
+  ) {
+    for block in self.adjacent_blocks(bo, bsize) {
+      if mv_stack.len() >= MAX_MV_REF_CANDIDATES {
+        break;
+      }
+      for i in 0..1 + block.has_second_ref() as usize {
+        if block.ref_frames[i] == INTRA_FRAME {
+          continue;
+        }
+        let this_mv =
+          self.oriented_mv(block.mvs[i], block.ref_frames[i], ref_frame);
+        if !mv_stack.iter().any(|c| c.this_mv == this_mv) {
+          mv_stack.push(CandidateMV {
+            this_mv,
+            comp_mv: MotionVector::default(),
+            weight: 2
+          });
+        }
+      }
+    }
+  }
+
+  // Completes the compound candidates with pairs of motion vectors from the
+  // blocks adjacent to the block at `bo`: for each of `ref_frames`, those of
+  // the same reference first, then those of others, then `gm_mvs`
+  fn scan_extra_compound(
+    &self, bo: &BlockOffset, bsize: BlockSize, ref_frames: [usize; 2],
+    gm_mvs: [MotionVector; 2], mv_stack: &mut Vec<CandidateMV>
+  ) {
+    let mut ref_id: [Vec<MotionVector>; 2] = [Vec::new(), Vec::new()];
+    let mut ref_diff: [Vec<MotionVector>; 2] = [Vec::new(), Vec::new()];
+
+    for block in self.adjacent_blocks(bo, bsize) {
+      for i in 0..2 {
+        let cand_ref = block.ref_frames[i];
+        for j in 0..2 {
+          if cand_ref == ref_frames[j] && ref_id[j].len() < 2 {
+            ref_id[j].push(block.mvs[i]);
+          } else if cand_ref > INTRA_FRAME && ref_diff[j].len() < 2 {
+            ref_diff[j].push(self.oriented_mv(
+              block.mvs[i],
+              cand_ref,
+              ref_frames[j]
+            ));
+          }
+        }
+      }
+    }
+
+    let comp_list = |j: usize| {
+      let mut mvs: Vec<MotionVector> =
+        ref_id[j].iter().chain(&ref_diff[j]).cloned().collect();
+      mvs.resize(MAX_MV_REF_CANDIDATES, gm_mvs[j]);
+      mvs
+    };
+    let pairs: Vec<(MotionVector, MotionVector)> =
+      comp_list(0).into_iter().zip(comp_list(1)).collect();
+
+    if mv_stack.is_empty() {
+      for &(this_mv, comp_mv) in &pairs {
+        mv_stack.push(CandidateMV { this_mv, comp_mv, weight: 2 });
+      }
+    } else {
+      // The first pair is skipped if it repeats the only candidate
+      let repeated = pairs[0] == (mv_stack[0].this_mv, mv_stack[0].comp_mv);
+      let (this_mv, comp_mv) = pairs[repeated as usize];
+      mv_stack.push(CandidateMV { this_mv, comp_mv, weight: 2 });
+    }
+  }
+
+  // Whether the luma above-right of the block at `bo` is decoded, blocks
+  // larger than 64x64 never using it
+  fn has_top_right(&self, bo: &BlockOffset, bsize: BlockSize) -> bool {
+    let x = (bo.x & (self.sb_size_mi - 1)) as isize;
+    let y = (bo.y & (self.sb_size_mi - 1)) as isize;
+    cmp::max(bsize.width_mi(), bsize.height_mi()) <= 16
+      && self.block_decoded[0][BlockContext::block_decoded_index(
+        x + bsize.width_mi() as isize,
+        y - 1
+      )]
+  }
+
+  // Motion vectors from the single reference `ref_frame` of the blocks around
+  // the block at `bo`, and projected from the reference frames, the closest
+  // first, by decreasing weight. `gm_mv` is the global motion vector of the
+  // block for `ref_frame`. Also returns the context of the inter mode, from
+  // how many of the blocks around had the reference.
+  pub fn find_mv_stack(
+    &self, bo: &BlockOffset, bsize: BlockSize, ref_frame: usize,
+    gm_mv: MotionVector
+  ) -> (Vec<CandidateMV>, usize) {
+    self.scan_mv_stack(
+      bo,
+      bsize,
+      [ref_frame, INTRA_FRAME],
+      [gm_mv, MotionVector::default()]
+    )
+  }
+
+  // Pairs of motion vectors from the compound references `ref_frames` of the
+  // block at `bo`, found like those of single references, with the global
+  // motion vectors `gm_mvs` of the block, and the context of the compound
+  // mode. There are always at least two.
+  pub fn find_compound_mv_stack(
+    &self, bo: &BlockOffset, bsize: BlockSize, ref_frames: [usize; 2],
+    gm_mvs: [MotionVector; 2]
+  ) -> (Vec<CandidateMV>, usize) {
+    self.scan_mv_stack(bo, bsize, ref_frames, gm_mvs)
+  }
+
+  fn scan_mv_stack(
+    &self, bo: &BlockOffset, bsize: BlockSize, ref_frames: [usize; 2],
+    gm_mvs: [MotionVector; 2]
+  ) -> (Vec<CandidateMV>, usize) {
+    let bw4 = bsize.width_mi() as isize;
+    let bh4 = bsize.height_mi() as isize;
+    let row_adj = (bh4 < 2 && bo.y & 1 == 1) as isize;
+    let col_adj = (bw4 < 2 && bo.x & 1 == 1) as isize;
+
+    let mut max_row_offset = 0;
+    if bo.y > 0 {
+      max_row_offset =
+        if bh4 < 2 { -(2 << 1) } else { -(MVREF_ROW_COLS << 1) } + row_adj;
+      max_row_offset = cmp::max(max_row_offset, -(bo.y as isize));
+    }
+    let mut max_col_offset = 0;
+    if bo.x > 0 {
+      max_col_offset =
+        if bw4 < 2 { -(2 << 1) } else { -(MVREF_ROW_COLS << 1) } + col_adj;
+      max_col_offset = cmp::max(max_col_offset, -(bo.x as isize));
+    }
+
+    let mut processed_rows = 0;
+    let mut processed_cols = 0;
+    let mut mv_stack = Vec::with_capacity(MAX_REF_MV_STACK_SIZE);
+    let mut matches = RefMatches::default();
+    let mut found_above = false;
+    let mut found_left = false;
+
+    // Nearest candidates, from the row above, the column left and the block
+    // above-right
+    if max_row_offset.abs() >= 1 {
+      self.scan_row_mbmi(
+        bo, bsize, -1, max_row_offset, &mut processed_rows, ref_frames, gm_mvs,
+        &mut mv_stack, &mut matches
+      );
+    }
+    found_above |= mem::replace(&mut matches.found, false);
+    if max_col_offset.abs() >= 1 {
+      self.scan_col_mbmi(
+        bo, bsize, -1, max_col_offset, &mut processed_cols, ref_frames, gm_mvs,
+        &mut mv_stack, &mut matches
+      );
+    }
+    found_left |= mem::replace(&mut matches.found, false);
+    if self.has_top_right(bo, bsize) {
+      self.scan_blk_mbmi(
+        bo, -1, bw4, ref_frames, gm_mvs, &mut mv_stack, &mut matches
+      );
+    }
+    found_above |= mem::replace(&mut matches.found, false);
+
+    let nearest_count = mv_stack.len();
+    let close_matches = found_above as usize + found_left as usize;
+    let new_mvs = matches.new_mvs;
+    for c in mv_stack.iter_mut() {
+      c.weight += REF_CAT_LEVEL;
+    }
+
+    // Temporal candidates, from the motion of the reference frames
+    let mut globalmv_ctx = 0;
+    if ref_frames[0] > INTRA_FRAME && !self.temporal_mvs.is_empty() {
+      globalmv_ctx =
+        self.scan_temporal(bo, bsize, ref_frames, gm_mvs, &mut mv_stack);
+    }
+
+    // Outer candidates, from the block above-left and further rows and
+    // columns
+    self.scan_blk_mbmi(
+      bo, -1, -1, ref_frames, gm_mvs, &mut mv_stack, &mut matches
+    );
+    found_above |= mem::replace(&mut matches.found, false);
+
+    for idx in 2..MVREF_ROW_COLS + 1 {
+      let row_offset = -(idx << 1) + 1 + row_adj;
+      let col_offset = -(idx << 1) + 1 + col_adj;
+
+      if row_offset.abs() <= max_row_offset.abs()
+        && row_offset.abs() > processed_rows
+      {
+        self.scan_row_mbmi(
+          bo, bsize, row_offset, max_row_offset, &mut processed_rows,
+          ref_frames, gm_mvs, &mut mv_stack, &mut matches
+        );
+      }
+      found_above |= mem::replace(&mut matches.found, false);
+      if col_offset.abs() <= max_col_offset.abs()
+        && col_offset.abs() > processed_cols
+      {
+        self.scan_col_mbmi(
+          bo, bsize, col_offset, max_col_offset, &mut processed_cols,
+          ref_frames, gm_mvs, &mut mv_stack, &mut matches
+        );
+      }
+      found_left |= mem::replace(&mut matches.found, false);
+    }
+
+    // The NEWMV and REFMV contexts grow with the nearest blocks having the
+    // reference, NEWMV being less likely when they had coded motion vectors
+    let total_matches = found_above as usize + found_left as usize;
+    let (newmv_ctx, refmv_ctx) = match close_matches {
+      0 => (cmp::min(total_matches, 1), total_matches),
+      1 => (3 - cmp::min(new_mvs, 1), 2 + total_matches),
+      _ => (5 - cmp::min(new_mvs, 1), 5)
+    };
+    let mode_context = newmv_ctx
+      | (globalmv_ctx << GLOBALMV_OFFSET)
+      | (refmv_ctx << REFMV_OFFSET);
+
+    // Stable sorts, equal weights keeping their scan order
+    mv_stack[..nearest_count].sort_by(|a, b| b.weight.cmp(&a.weight));
+    mv_stack[nearest_count..].sort_by(|a, b| b.weight.cmp(&a.weight));
+
+    if mv_stack.len() < MAX_MV_REF_CANDIDATES {
+      if ref_frames[1] > INTRA_FRAME {
+        self.scan_extra_compound(bo, bsize, ref_frames, gm_mvs, &mut mv_stack);
+      } else {
+        self.scan_extra(bo, bsize, ref_frames[0], &mut mv_stack);
+      }
+    }
+
+    // Clamp to MV_BORDER past the frame edges, in addition to the block size
+    let mi_size8 = (MI_SIZE * 8) as isize;
+    let (x, y) = (bo.x as isize, bo.y as isize);
+    let (cols, rows) = (self.cols as isize, self.rows as isize);
+    let min_col = -x * mi_size8 - bw4 * mi_size8 - MV_BORDER;
+    let max_col = (cols - bw4 - x) * mi_size8 + bw4 * mi_size8 + MV_BORDER;
+    let min_row = -y * mi_size8 - bh4 * mi_size8 - MV_BORDER;
+    let max_row = (rows - bh4 - y) * mi_size8 + bh4 * mi_size8 + MV_BORDER;
+
+    let clamp_mv = |mv: MotionVector| MotionVector {
+      row: cmp::min(cmp::max(mv.row as isize, min_row), max_row) as i16,
+      col: cmp::min(cmp::max(mv.col as isize, min_col), max_col) as i16
+    };
+    for c in mv_stack.iter_mut() {
+      c.this_mv = clamp_mv(c.this_mv);
+      if ref_frames[1] > INTRA_FRAME {
+        c.comp_mv = clamp_mv(c.comp_mv);
+      }
+    }
+    (mv_stack, mode_context)
+  }
+
+  // Reference of the displacement vector of the intra block copy of the block
+  // at `bo`: the nearest non-zero candidate, or else a vector to the
+  // superblock row above, or to the left past the coding delay
+  pub fn ref_dv(&self, bo: &BlockOffset, bsize: BlockSize) -> MotionVector {
+    let ref_dv = self
+      .find_mv_stack(bo, bsize, INTRA_FRAME, MotionVector::default())
+      .0
+      .into_iter()
+      .take(MAX_MV_REF_CANDIDATES)
+      .map(|c| c.this_mv)
+      .find(|mv| !mv.is_zero());
+
+    match ref_dv {
+      Some(mv) => {
+        MotionVector { row: (mv.row >> 3) * 8, col: (mv.col >> 3) * 8 }
+      }
+      None => {
+        if bo.y < self.sb_size_mi {
+          MotionVector {
+            row: 0,
+            col: -((((self.sb_size_mi << MI_SIZE_LOG2) + INTRABC_DELAY_PIXELS)
+              * 8) as i16)
+          }
+        } else {
+          MotionVector {
+            row: -(((self.sb_size_mi << MI_SIZE_LOG2) * 8) as i16),
+            col: 0
+          }
+        }
+      }
+    }
+  }
+
+  // Whether the block above or left of the block at `bo` is predicted with a
+  // smooth mode in plane `p`, which strengthens its intra edge filter
+  pub fn has_smooth_neighbor(
+    &self, bo: &BlockOffset, p: usize, xdec: usize, ydec: usize
+  ) -> bool {
+    let is_smooth = |block: &Block| {
+      let mode = if p == 0 {
+        block.mode
+      } else if block.is_inter() {
+        return false;
+      } else {
+        block.uv_mode
+      };
+      mode == PredictionMode::SMOOTH_PRED
+        || mode == PredictionMode::SMOOTH_V_PRED
+        || mode == PredictionMode::SMOOTH_H_PRED
+    };
+
+    // Sub-8x8 blocks coding chroma look around the whole 8x8 area
+    let (xdec, ydec) = if p == 0 { (0, 0) } else { (xdec, ydec) };
+    let x = bo.x & !xdec;
+    let y = bo.y & !ydec;
+
+    (y > 0 && is_smooth(&self.blocks[y - 1][x + xdec]))
+      || (x > 0 && is_smooth(&self.blocks[y + ydec][x - 1]))
+  }
+
+  // Whether any of the blocks above or left of the block at `bo` is an inter
+  // block, looking at every other 4x4 unit along the edges
+  pub fn has_overlappable_candidates(
+    &self, bo: &BlockOffset, bsize: BlockSize
+  ) -> bool {
+    if bo.y > 0 {
+      let mut x = bo.x;
+      while x < cmp::min(self.cols, bo.x + bsize.width_mi()) {
+        let x5 = cmp::min(x | 1, self.cols - 1);
+        if self.blocks[bo.y - 1][x5].ref_frames[0] > INTRA_FRAME {
+          return true;
+        }
+        x += 2;
+      }
+    }
+    if bo.x > 0 {
+      let mut y = bo.y;
+      while y < cmp::min(self.rows, bo.y + bsize.height_mi()) {
+        let y5 = cmp::min(y | 1, self.rows - 1);
+        if self.blocks[y5][bo.x - 1].ref_frames[0] > INTRA_FRAME {
+          return true;
+        }
+        y += 2;
+      }
+    }
+    false
+  }
+
+  // Inter neighbours above then left of the block at `bo`, whose predictions
+  // overlap the block, up to 4 along each edge. Neighbours of width or height
+  // 4 are taken by pairs.
+  pub fn overlap_candidates(
+    &self, bo: &BlockOffset, bsize: BlockSize
+  ) -> Vec<OverlapCandidate> {
+    let mut candidates = Vec::new();
+
+    for &above in &[true, false] {
+      let (start, len4, len_log2, end) = if above {
+        if bo.y == 0 {
+          continue;
+        }
+        (bo.x, bsize.width_mi(), bsize.width_log2(), self.cols)
+      } else {
+        if bo.x == 0 {
+          continue;
+        }
+        (bo.y, bsize.height_mi(), bsize.height_log2(), self.rows)
+      };
+      let limit = cmp::min(4, len_log2 - MI_SIZE_LOG2);
+
+      let mut count = 0;
+      let mut i = start;
+      while count < limit && i < cmp::min(end, start + len4) {
+        let c = cmp::min(i | 1, end - 1);
+        let block = if above {
+          &self.blocks[bo.y - 1][c]
+        } else {
+          &self.blocks[c][bo.x - 1]
+        };
+        let step = clamp(
+          if above { block.bsize.width_mi() } else { block.bsize.height_mi() }
+            as i32,
+          2,
+          16
+        ) as usize;
+        if block.ref_frames[0] > INTRA_FRAME {
+          count += 1;
+          candidates.push(OverlapCandidate {
+            above,
+            offset: i - start,
+            len: cmp::min(len4, step),
+            ref_frame: block.ref_frames[0],
+            mv: block.mvs[0]
+          });
+        }
+        i += step;
+      }
+    }
+
+    candidates
+  }
+
+  // Centers of the blocks around the block at `bo` with the single reference
+  // `ref_frame`, and their projections by the motion of the blocks, as
+  // (row, col, projected row, projected col) in 1/8 pixels. Blocks whose
+  // motion is far from `mv` are left out, unless all of them are, in which
+  // case only the first one is kept.
+  pub fn find_warp_samples(
+    &self, bo: &BlockOffset, bsize: BlockSize, ref_frame: usize,
+    mv: MotionVector
+  ) -> Vec<[i32; 4]> {
+    let w4 = bsize.width_mi();
+    let h4 = bsize.height_mi();
+    let threshold =
+      clamp(cmp::max(bsize.width(), bsize.height()) as i32, 16, 112);
+
+    let mut samples = Vec::new();
+    let mut first_sample = None;
+    {
+      let mut scanned = 0;
+      let mut add_sample = |row_offset: isize, col_offset: isize| {
+        let y = bo.y as isize + row_offset;
+        let x = bo.x as isize + col_offset;
+        if scanned >= LEAST_SQUARES_SAMPLES_MAX
+          || x < 0
+          || y < 0
+          || x >= self.cols as isize
+          || y >= self.rows as isize
+        {
+          return;
+        }
+        let cand = &self.blocks[y as usize][x as usize];
+        // Inter-intra neighbours have a second, intra reference
+        if cand.ref_frames[0] != ref_frame
+          || cand.has_second_ref()
+          || cand.interintra.is_some()
+        {
+          return;
+        }
+
+        let cand_w4 = cand.bsize.width_mi();
+        let cand_h4 = cand.bsize.height_mi();
+        let cand_row = y as usize & !(cand_h4 - 1);
+        let cand_col = x as usize & !(cand_w4 - 1);
+        let mid_y = (cand_row * MI_SIZE + cand_h4 * MI_SIZE / 2) as i32 - 1;
+        let mid_x = (cand_col * MI_SIZE + cand_w4 * MI_SIZE / 2) as i32 - 1;
+        let cand_mv = cand.mvs[0];
+        let sample = [
+          mid_y * 8,
+          mid_x * 8,
+          mid_y * 8 + cand_mv.row as i32,
+          mid_x * 8 + cand_mv.col as i32
+        ];
+        let diff = (cand_mv.row as i32 - mv.row as i32).abs()
+          + (cand_mv.col as i32 - mv.col as i32).abs();
+
+        scanned += 1;
+        if diff <= threshold {
+          samples.push(sample);
+        } else if scanned == 1 {
+          first_sample = Some(sample);
+        }
+      };
+
+      let mut do_top_left = true;
+      let mut do_top_right = true;
+
+      if bo.y > 0 {
+        let src_w4 = self.blocks[bo.y - 1][bo.x].bsize.width_mi();
+        if w4 <= src_w4 {
+          // A single block above, which may extend past either corner
+          let col_offset = bo.x & (src_w4 - 1);
+          if col_offset > 0 {
+            do_top_left = false;
+          }
+          if src_w4 - col_offset > w4 {
+            do_top_right = false;
+          }
+          add_sample(-1, 0);
+        } else {
+          let mut i = 0;
+          while i < cmp::min(w4, self.cols - bo.x) {
+            add_sample(-1, i as isize);
+            i += self.blocks[bo.y - 1][bo.x + i].bsize.width_mi();
+          }
+        }
+      }
+
+      if bo.x > 0 {
+        let src_h4 = self.blocks[bo.y][bo.x - 1].bsize.height_mi();
+        if h4 <= src_h4 {
+          if bo.y & (src_h4 - 1) > 0 {
+            do_top_left = false;
+          }
+          add_sample(0, -1);
+        } else {
+          let mut i = 0;
+          while i < cmp::min(h4, self.rows - bo.y) {
+            add_sample(i as isize, -1);
+            i += self.blocks[bo.y + i][bo.x - 1].bsize.height_mi();
+          }
+        }
+      }
+
+      if do_top_left {
+        add_sample(-1, -1);
+      }
+      if do_top_right && self.has_top_right(bo, bsize) {
+        add_sample(-1, w4 as isize);
+      }
+    }
+
+    if samples.is_empty() {
+      samples.extend(first_sample);
+    }
+    samples
+  }
+
+  fn block_decoded_index(x: isize, y: isize) -> usize {
+    ((y + 1) * BLOCK_DECODED_STRIDE as isize + x + 1) as usize
+  }
+
+  // Forgets the decoded 4x4 units when starting the superblock at `bo`, but
+  // for the ones of its above and left neighbours inside the frame
+  pub fn reset_block_decoded(
+    &mut self, bo: &BlockOffset, sb_size: BlockSize, xdec: usize, ydec: usize
+  ) {
+    self.sb_size_mi = sb_size.width_mi();
+
+    for p in 0..PLANES {
+      let (xdec, ydec) = if p == 0 { (0, 0) } else { (xdec, ydec) };
+      let sb_w = (self.sb_size_mi >> xdec) as isize;
+      let sb_h = (self.sb_size_mi >> ydec) as isize;
+      let frame_w = ((self.cols - bo.x) >> xdec) as isize;
+      let frame_h = ((self.rows - bo.y) >> ydec) as isize;
+
+      for y in -1..sb_h + 1 {
+        for x in -1..sb_w + 1 {
+          self.block_decoded[p][BlockContext::block_decoded_index(x, y)] =
+            (y < 0 && x < frame_w) || (x < 0 && y < frame_h);
+        }
+      }
+      // The superblock below-left comes later
+      self.block_decoded[p][BlockContext::block_decoded_index(-1, sb_h)] = false;
+    }
+  }
+
+  // Position of the 4x4 unit at `po` in plane `p` inside the current superblock
+  fn block_decoded_position(
+    &self, po: &PlaneOffset, xdec: usize, ydec: usize
+  ) -> (isize, isize) {
+    let x = (po.x >> MI_SIZE_LOG2) & ((self.sb_size_mi >> xdec) - 1);
+    let y = (po.y >> MI_SIZE_LOG2) & ((self.sb_size_mi >> ydec) - 1);
+    (x as isize, y as isize)
+  }
+
+  pub fn set_tx_decoded(
+    &mut self, p: usize, po: &PlaneOffset, tx_size: TxSize, xdec: usize,
+    ydec: usize
+  ) {
+    let (x, y) = self.block_decoded_position(po, xdec, ydec);
+    for ty in 0..tx_size.height_mi() as isize {
+      for tx in 0..tx_size.width_mi() as isize {
+        self.block_decoded[p][BlockContext::block_decoded_index(x + tx, y + ty)] =
+          true;
+      }
+    }
+  }
+
+  // Whether the neighbours above-right and below-left of the transform block
+  // at `po` in plane `p` are decoded
+  pub fn tx_edges_decoded(
+    &self, p: usize, po: &PlaneOffset, tx_size: TxSize, xdec: usize,
+    ydec: usize
+  ) -> (bool, bool) {
+    let (x, y) = self.block_decoded_position(po, xdec, ydec);
+    let w = tx_size.width_mi() as isize;
+    let h = tx_size.height_mi() as isize;
+    (
+      self.block_decoded[p][BlockContext::block_decoded_index(x + w, y - 1)],
+      self.block_decoded[p][BlockContext::block_decoded_index(x - 1, y + h)]
+    )
+  }
+
+  fn partition_plane_context(
+    &self, bo: &BlockOffset, bsize: BlockSize
+  ) -> usize {
+    // TODO: this should be way simpler without sub8x8
+    let above_ctx = self.above_partition_context[bo.x];
+    let left_ctx = self.left_partition_context[bo.y_in_sb()];
+    let bsl = bsize.width_log2() - BLOCK_8X8.width_log2();
+    let above = (above_ctx >> bsl) & 1;
+    let left = (left_ctx >> bsl) & 1;
+
+    assert!(bsize.is_sqr());
+
+    (left * 2 + above) as usize + bsl as usize * PARTITION_PLOFFSET
+  }
+
+  pub fn update_partition_context(
+    &mut self, bo: &BlockOffset, subsize: BlockSize, bsize: BlockSize
+  ) {
+    #[allow(dead_code)]
+    let bw = bsize.width_mi();
+    let bh = bsize.height_mi();
+
+    let above_ctx =
+      &mut self.above_partition_context[bo.x..bo.x + bw as usize];
+    let left_ctx = &mut self.left_partition_context
+      [bo.y_in_sb()..bo.y_in_sb() + bh as usize];
+
+    // update the partition context at the end notes. set partition bits
+    // of block sizes larger than the current one to be one, and partition
+    // bits of smaller block sizes to be zero.
+    for i in 0..bw {
+      above_ctx[i as usize] = partition_context_lookup[subsize as usize][0];
+    }
+
+    for i in 0..bh {
+      left_ctx[i as usize] = partition_context_lookup[subsize as usize][1];
+    }
+  }
+
+  /// Updates the partition context once all the blocks of `partition` are
+  /// coded. Blocks split in quadrants are updated by their sub-blocks.
+  pub fn update_ext_partition_context(
+    &mut self, bo: &BlockOffset, subsize: BlockSize, bsize: BlockSize,
+    partition: PartitionType
+  ) {
+    if bsize < BLOCK_8X8 {
+      return;
+    }
+
+    let hbs = bsize.width_mi() / 2;
+    let split_size = get_subsize(bsize, PartitionType::PARTITION_SPLIT);
+    let right = BlockOffset { x: bo.x + hbs, y: bo.y };
+    let bottom = BlockOffset { x: bo.x, y: bo.y + hbs };
+
+    match partition {
+      PartitionType::PARTITION_SPLIT => {
+        if bsize == BLOCK_8X8 {
+          self.update_partition_context(bo, subsize, bsize);
+        }
+      }
+      PartitionType::PARTITION_HORZ_A => {
+        self.update_partition_context(bo, split_size, subsize);
+        self.update_partition_context(&bottom, subsize, subsize);
+      }
+      PartitionType::PARTITION_HORZ_B => {
+        self.update_partition_context(bo, subsize, subsize);
+        self.update_partition_context(&bottom, split_size, subsize);
+      }
+      PartitionType::PARTITION_VERT_A => {
+        self.update_partition_context(bo, split_size, subsize);
+        self.update_partition_context(&right, subsize, subsize);
+      }
+      PartitionType::PARTITION_VERT_B => {
+        self.update_partition_context(bo, subsize, subsize);
+        self.update_partition_context(&right, split_size, subsize);
+      }
+      _ => self.update_partition_context(bo, subsize, bsize)
+    }
+  }
+
+  fn skip_context(&mut self, bo: &BlockOffset) -> usize {
+    let above_skip = if bo.y > 0 {
+      self.above_of(bo).skip as usize
+    } else {
+      0
+    };
+    let left_skip = if bo.x > 0 {
+      self.left_of(bo).skip as usize
+    } else {
+      0
+    };
+    above_skip + left_skip
+  }
+
+  fn skip_mode_context(&mut self, bo: &BlockOffset) -> usize {
+    let above_skip_mode = if bo.y > 0 {
+      self.above_of(bo).skip_mode as usize
+    } else {
+      0
+    };
+    let left_skip_mode = if bo.x > 0 {
+      self.left_of(bo).skip_mode as usize
+    } else {
+      0
+    };
+    above_skip_mode + left_skip_mode
+  }
+
+  // Context of the choice between single and compound references, from the
+  // references of the neighbours
+  fn comp_mode_context(&mut self, bo: &BlockOffset) -> usize {
+    let is_backward = |ref_frame: usize| ref_frame >= BWDREF_FRAME;
+    let above = if bo.y > 0 { Some(self.above_of(bo)) } else { None };
+    let left = if bo.x > 0 { Some(self.left_of(bo)) } else { None };
+
+    match (above, left) {
+      (Some(above), Some(left)) => {
+        match (above.has_second_ref(), left.has_second_ref()) {
+          (false, false) => {
+            (is_backward(above.ref_frames[0]) ^ is_backward(left.ref_frames[0]))
+              as usize
+          }
+          (false, true) => {
+            2 + (is_backward(above.ref_frames[0]) || !above.is_inter()) as usize
+          }
+          (true, false) => {
+            2 + (is_backward(left.ref_frames[0]) || !left.is_inter()) as usize
+          }
+          (true, true) => 4
+        }
+      }
+      (Some(edge), None) | (None, Some(edge)) => {
+        if edge.has_second_ref() {
+          3
+        } else {
+          is_backward(edge.ref_frames[0]) as usize
+        }
+      }
+      (None, None) => 1
+    }
+  }
+
+  // Context of the choice between compound references on the same side and
+  // on both sides, from the references of the neighbours
+  fn comp_ref_type_context(&mut self, bo: &BlockOffset) -> usize {
+    let is_backward = |ref_frame: usize| ref_frame >= BWDREF_FRAME;
+    let is_uni = |block: &Block| {
+      block.has_second_ref()
+        && is_backward(block.ref_frames[0]) == is_backward(block.ref_frames[1])
+    };
+    let above = if bo.y > 0 { Some(self.above_of(bo)) } else { None };
+    let left = if bo.x > 0 { Some(self.left_of(bo)) } else { None };
+
+    match (above, left) {
+      (Some(above), Some(left)) => {
+        match (above.is_inter(), left.is_inter()) {
+          (false, false) => 2,
+          (true, false) | (false, true) => {
+            let inter = if above.is_inter() { above } else { left };
+            if inter.has_second_ref() {
+              1 + 2 * is_uni(&inter) as usize
+            } else {
+              2
+            }
+          }
+          (true, true) => {
+            let same_side = is_backward(above.ref_frames[0])
+              == is_backward(left.ref_frames[0]);
+            match (above.has_second_ref(), left.has_second_ref()) {
+              (false, false) => 1 + 2 * same_side as usize,
+              (false, true) | (true, false) => {
+                let comp = if above.has_second_ref() { above } else { left };
+                if is_uni(&comp) {
+                  3 + same_side as usize
+                } else {
+                  1
+                }
+              }
+              (true, true) => match (is_uni(&above), is_uni(&left)) {
+                (false, false) => 0,
+                (true, true) => {
+                  3 + ((above.ref_frames[0] == BWDREF_FRAME)
+                    == (left.ref_frames[0] == BWDREF_FRAME))
+                    as usize
+                }
+                _ => 2
+              }
+            }
+          }
+        }
+      }
+      (Some(edge), None) | (None, Some(edge)) => {
+        if edge.has_second_ref() {
+          4 * is_uni(&edge) as usize
+        } else {
+          2
+        }
+      }
+      (None, None) => 2
+    }
+  }
+
+  pub fn set_block_size(&mut self, bo: &BlockOffset, bsize: BlockSize) {
+    self.for_each(bo, bsize, |block| block.bsize = bsize);
+  }
+
+  pub fn set_skip(&mut self, bo: &BlockOffset, bsize: BlockSize, skip: bool) {
+    self.for_each(bo, bsize, |block| block.skip = skip);
+  }
+
+  pub fn set_skip_mode(
+    &mut self, bo: &BlockOffset, bsize: BlockSize, skip_mode: bool
+  ) {
+    self.for_each(bo, bsize, |block| block.skip_mode = skip_mode);
+  }
+
+  // The second reference is INTRA_FRAME for blocks with a single reference
+  pub fn set_ref_frames(
+    &mut self, bo: &BlockOffset, bsize: BlockSize, ref_frames: [usize; 2]
+  ) {
+    self.for_each(bo, bsize, |block| block.ref_frames = ref_frames);
+  }
+
+  pub fn set_cdef(&mut self, bo: &BlockOffset, bsize: BlockSize, cdef_index: u8) {
+    self.for_each(bo, bsize, |block| block.cdef_index = cdef_index);
+  }
+
+  // Context for the transform depth of a block whose transform size is
+  // signaled once for the whole block
+  fn tx_size_context(&mut self, bo: &BlockOffset, bsize: BlockSize) -> usize {
+    let max_tx_size = bsize.largest_tx_size();
+    let max_tx_wide = max_tx_size.width() as u8;
+    let max_tx_high = max_tx_size.height() as u8;
+    let has_above = bo.y > 0;
+    let has_left = bo.x > 0;
+
+    let mut above = (self.above_tx_context[bo.x] >= max_tx_wide) as usize;
+    let mut left = (self.left_tx_context[bo.y_in_sb()] >= max_tx_high) as usize;
+
+    if has_above {
+      let above_blk = self.above_of(bo);
+      if above_blk.is_inter() {
+        above = (above_blk.bsize.width() >= max_tx_wide as usize) as usize;
+      }
+    }
+
+    if has_left {
+      let left_blk = self.left_of(bo);
+      if left_blk.is_inter() {
+        left = (left_blk.bsize.height() >= max_tx_high as usize) as usize;
+      }
+    }
+
+    match (has_above, has_left) {
+      (true, true) => above + left,
+      (true, false) => above,
+      (false, true) => left,
+      (false, false) => 0
+    }
+  }
+
+  // Context for the split flag of a node of the transform partitioning tree,
+  // `bo` being the position of the node
+  fn txfm_partition_context(
+    &self, bo: &BlockOffset, bsize: BlockSize, tx_size: TxSize
+  ) -> usize {
+    let above = (self.above_tx_context[bo.x] < tx_size.width() as u8) as usize;
+    let left =
+      (self.left_tx_context[bo.y_in_sb()] < tx_size.height() as u8) as usize;
+
+    // dummy return, not used by others.
+    if tx_size <= TxSize::TX_4X4 {
+      return 0;
+    }
+
+    // Index of the square transform size matching the largest dimension
+    let max_tx_size = cmp::min(
+      cmp::max(bsize.width_log2(), bsize.height_log2())
+        - TxSize::smallest_width_log2(),
+      TxSize::TX_64X64 as usize
+    );
+    let tx_size_sqr_up = tx_size.sqr_up() as usize;
+
+    let category = (tx_size_sqr_up != max_tx_size && max_tx_size > TxSize::TX_8X8 as usize) as usize
+      + (TxSize::TX_SIZES - 1 - max_tx_size) * 2;
+    assert!(category < TXFM_PARTITION_CONTEXTS / 3);
+
+    category * 3 + above + left
+  }
+
+  // Fill the transform size contexts covered by a node of the transform
+  // partitioning tree of size `node_size` coded with transforms of `tx_size`
+  fn update_txfm_partition_context(
+    &mut self, bo: &BlockOffset, tx_size: TxSize, node_size: TxSize
+  ) {
+    let bo_y = bo.y_in_sb();
+    for x in 0..node_size.width_mi() {
+      if bo.x + x < self.cols {
+        self.above_tx_context[bo.x + x] = tx_size.width() as u8;
+      }
+    }
+    for y in 0..node_size.height_mi() {
+      if bo_y + y < MAX_MIB_SIZE {
+        self.left_tx_context[bo_y + y] = tx_size.height() as u8;
+      }
+    }
+  }
+
+  /// Set the transform size contexts for a block coded with a single
+  /// transform size, as done for intra and skipped inter blocks.
+  pub fn update_tx_size_context(
+    &mut self, bo: &BlockOffset, bsize: BlockSize, tx_size: TxSize,
+    skip_inter: bool
+  ) {
+    let (tx_w, tx_h) = if skip_inter {
+      (bsize.width() as u8, bsize.height() as u8)
+    } else {
+      (tx_size.width() as u8, tx_size.height() as u8)
+    };
+    let bo_y = bo.y_in_sb();
+    for x in 0..bsize.width_mi() {
+      if bo.x + x < self.cols {
+        self.above_tx_context[bo.x + x] = tx_w;
+      }
+    }
+    for y in 0..bsize.height_mi() {
+      if bo_y + y < MAX_MIB_SIZE {
+        self.left_tx_context[bo_y + y] = tx_h;
+      }
+    }
+  }
+
+  // The mode info data structure has a one element border above and to the
+  // left of the entries corresponding to real macroblocks.
+  // The prediction flags in these dummy entries are initialized to 0.
+  // 0 - inter/inter, inter/--, --/inter, --/--
+  // 1 - intra/inter, inter/intra
+  // 2 - intra/--, --/intra
+  // 3 - intra/intra
+  pub fn intra_inter_context(&mut self, bo: &BlockOffset) -> usize {
+    let has_above = bo.y > 0;
+    let has_left = bo.x > 0;
+
+    match (has_above, has_left) {
+      (true, true) => {
+        let above_intra = !self.above_of(bo).is_inter();
+        let left_intra = !self.left_of(bo).is_inter();
+        if above_intra && left_intra {
+          3
+        } else {
+          (above_intra || left_intra) as usize
+        }
+      }
+      (true, _) | (_, true) =>
+        2 * if has_above {
+          !self.above_of(bo).is_inter() as usize
+        } else {
+          !self.left_of(bo).is_inter() as usize
+        },
+      (_, _) => 0
+    }
+  }
+
+  pub fn get_txb_ctx(
+    &mut self, plane_bsize: BlockSize, tx_size: TxSize, plane: usize,
+    bo: &BlockOffset, xdec: usize, ydec: usize
+  ) -> TXB_CTX {
+    let mut txb_ctx = TXB_CTX {
+      txb_skip_ctx: 0,
+      dc_sign_ctx: 0
+    };
     const MAX_TX_SIZE_UNIT: usize = 16;
     const signs: [i8; 3] = [0, -1, 1];
     const dc_sign_contexts: [usize; 4 * MAX_TX_SIZE_UNIT + 1] = [
@@ -1628,6 +3787,73 @@ impl FieldMap {
   }
 }
 
+// Smallest number of bits able to code `x` values
+fn ceil_log2(x: u32) -> u32 {
+  if x < 2 {
+    0
+  } else {
+    32 - (x - 1).leading_zeros()
+  }
+}
+
+/// Context of the color index at row `r` and column `c` of a color index map
+/// of `n` colors, from the colors of its left, top-left and top neighbors.
+/// Also returns the order in which the colors are coded there, the most
+/// frequent among the neighbors first.
+pub fn palette_color_context(
+  map: &[u8], stride: usize, r: usize, c: usize, n: usize
+) -> (usize, [u8; PALETTE_MAX_SIZE]) {
+  const PALETTE_COLOR_HASH_MULTIPLIERS: [usize; PALETTE_NUM_NEIGHBORS] =
+    [1, 2, 2];
+  // Only hashes of 2 and 5 to 8 can occur
+  const PALETTE_COLOR_CONTEXT: [usize; 9] = [0, 0, 0, 0, 0, 4, 3, 2, 1];
+
+  let mut scores = [0usize; PALETTE_MAX_SIZE];
+  let mut order = [0u8; PALETTE_MAX_SIZE];
+  for (i, o) in order.iter_mut().enumerate() {
+    *o = i as u8;
+  }
+
+  if c > 0 {
+    scores[map[r * stride + c - 1] as usize] += 2;
+  }
+  if r > 0 && c > 0 {
+    scores[map[(r - 1) * stride + c - 1] as usize] += 1;
+  }
+  if r > 0 {
+    scores[map[(r - 1) * stride + c] as usize] += 2;
+  }
+
+  // Move the colors of the highest scores to the front, keeping the order
+  // of the others
+  for i in 0..PALETTE_NUM_NEIGHBORS {
+    let mut max_idx = i;
+    for j in i + 1..n {
+      if scores[j] > scores[max_idx] {
+        max_idx = j;
+      }
+    }
+    if max_idx != i {
+      let max_score = scores[max_idx];
+      let max_order = order[max_idx];
+      for k in (i + 1..max_idx + 1).rev() {
+        scores[k] = scores[k - 1];
+        order[k] = order[k - 1];
+      }
+      scores[i] = max_score;
+      order[i] = max_order;
+    }
+  }
+
+  let hash: usize = scores
+    .iter()
+    .zip(PALETTE_COLOR_HASH_MULTIPLIERS.iter())
+    .map(|(s, m)| s * m)
+    .sum();
+
+  (PALETTE_COLOR_CONTEXT[hash], order)
+}
+
 macro_rules! symbol {
   ($self:ident, $w:ident, $s:expr, $cdf:expr) => {
     $w.symbol($s, $cdf);
@@ -1647,7 +3873,7 @@ pub struct ContextWriterCheckpoint {
 
 pub struct ContextWriter {
   pub bc: BlockContext,
-  fc: CDFContext,
+  pub fc: CDFContext,
   #[cfg(debug)]
   fc_map: Option<FieldMap> // For debugging purposes
 }
@@ -1681,7 +3907,7 @@ impl ContextWriter {
   }
 
   fn partition_gather_horz_alike(
-    out: &mut [u16; 2], cdf_in: &[u16], _bsize: BlockSize
+    out: &mut [u16; 2], cdf_in: &[u16], bsize: BlockSize
   ) {
     out[0] = 32768;
     out[0] -= ContextWriter::cdf_element_prob(
@@ -1704,16 +3930,18 @@ impl ContextWriter {
       cdf_in,
       PartitionType::PARTITION_VERT_A as usize
     );
-    out[0] -= ContextWriter::cdf_element_prob(
-      cdf_in,
-      PartitionType::PARTITION_HORZ_4 as usize
-    );
+    if bsize != BlockSize::BLOCK_128X128 {
+      out[0] -= ContextWriter::cdf_element_prob(
+        cdf_in,
+        PartitionType::PARTITION_HORZ_4 as usize
+      );
+    }
     out[0] = 32768 - out[0];
     out[1] = 0;
   }
 
   fn partition_gather_vert_alike(
-    out: &mut [u16; 2], cdf_in: &[u16], _bsize: BlockSize
+    out: &mut [u16; 2], cdf_in: &[u16], bsize: BlockSize
   ) {
     out[0] = 32768;
     out[0] -= ContextWriter::cdf_element_prob(
@@ -1736,10 +3964,12 @@ impl ContextWriter {
       cdf_in,
       PartitionType::PARTITION_VERT_B as usize
     );
-    out[0] -= ContextWriter::cdf_element_prob(
-      cdf_in,
-      PartitionType::PARTITION_VERT_4 as usize
-    );
+    if bsize != BlockSize::BLOCK_128X128 {
+      out[0] -= ContextWriter::cdf_element_prob(
+        cdf_in,
+        PartitionType::PARTITION_VERT_4 as usize
+      );
+    }
     out[0] = 32768 - out[0];
     out[1] = 0;
   }
@@ -1755,6 +3985,9 @@ impl ContextWriter {
     assert!(ctx < PARTITION_CONTEXTS);
     let partition_cdf = if bsize <= BlockSize::BLOCK_8X8 {
       &mut self.fc.partition_cdf[ctx][..PARTITION_TYPES+1]
+    } else if bsize == BlockSize::BLOCK_128X128 {
+      // 128x128 blocks cannot use 4:1 partitions
+      &mut self.fc.partition_cdf[ctx][..EXT_PARTITION_TYPES-2+1]
     } else {
       &mut self.fc.partition_cdf[ctx]
     };
@@ -1822,9 +4055,367 @@ impl ContextWriter {
         [mode as usize - PredictionMode::V_PRED as usize]
     );
   }
+  pub fn write_cfl_alphas(&mut self, w: &mut Writer, cfl: CFLParams) {
+    symbol!(self, w, cfl.joint_sign(), &mut self.fc.cfl_sign_cdf);
+    for uv in 0..2 {
+      if cfl.alpha[uv] != 0 {
+        symbol!(
+          self,
+          w,
+          cfl.index(uv),
+          &mut self.fc.cfl_alpha_cdf[cfl.context(uv)]
+        );
+      }
+    }
+  }
   pub fn write_use_filter_intra(&mut self, w: &mut Writer, enable: bool, block_size: BlockSize) {
     symbol!(self, w, enable as u32, &mut self.fc.filter_intra_cdfs[block_size as usize]);
   }
+  pub fn write_filter_intra_mode(&mut self, w: &mut Writer, mode: FilterIntraMode) {
+    symbol!(self, w, mode as u32, &mut self.fc.filter_intra_mode_cdf);
+  }
+  pub fn write_use_obmc(&mut self, w: &mut Writer, enable: bool, block_size: BlockSize) {
+    symbol!(self, w, enable as u32, &mut self.fc.obmc_cdf[block_size as usize]);
+  }
+  pub fn write_motion_mode(&mut self, w: &mut Writer, mode: MotionMode, block_size: BlockSize) {
+    symbol!(self, w, mode as u32, &mut self.fc.motion_mode_cdf[block_size as usize]);
+  }
+  pub fn write_interintra(
+    &mut self, w: &mut Writer, interintra: Option<InterIntraParams>,
+    bsize: BlockSize
+  ) {
+    let ctx = size_group_lookup[bsize as usize] as usize;
+    symbol!(
+      self,
+      w,
+      interintra.is_some() as u32,
+      &mut self.fc.interintra_cdf[ctx]
+    );
+    if let Some(ii) = interintra {
+      symbol!(self, w, ii.mode as u32, &mut self.fc.interintra_mode_cdf[ctx]);
+      symbol!(
+        self,
+        w,
+        ii.wedge_index.is_some() as u32,
+        &mut self.fc.wedge_interintra_cdf[bsize as usize]
+      );
+      if let Some(index) = ii.wedge_index {
+        symbol!(
+          self,
+          w,
+          index as u32,
+          &mut self.fc.wedge_idx_cdf[bsize as usize]
+        );
+      }
+    }
+  }
+  // Signals the masked blending of the predictions of a compound block, or
+  // their average
+  pub fn write_compound_type(
+    &mut self, w: &mut Writer, bo: &BlockOffset, mask: Option<CompoundMask>,
+    bsize: BlockSize
+  ) {
+    let ctx = self.bc.comp_group_idx_context(bo);
+    symbol!(
+      self,
+      w,
+      mask.is_some() as u32,
+      &mut self.fc.comp_group_idx_cdfs[ctx]
+    );
+    if let Some(mask) = mask {
+      // Blocks without wedges can only be difference-weighted
+      if wedge_bits(bsize) > 0 {
+        symbol!(
+          self,
+          w,
+          mask.compound_type() as u32,
+          &mut self.fc.compound_type_cdf[bsize as usize]
+        );
+      } else {
+        assert!(mask.compound_type() == CompoundType::COMPOUND_DIFFWTD);
+      }
+      match mask {
+        CompoundMask::Wedge { index, sign } => {
+          symbol!(
+            self,
+            w,
+            index as u32,
+            &mut self.fc.wedge_idx_cdf[bsize as usize]
+          );
+          w.bit(sign as u16);
+        }
+        CompoundMask::DiffWeighted { inverse } => w.bit(inverse as u16)
+      }
+    }
+  }
+
+  pub fn write_palette_mode_info(
+    &mut self, w: &mut Writer, bo: &BlockOffset, bsize: BlockSize,
+    luma_mode: PredictionMode, chroma_mode: PredictionMode, has_chroma: bool,
+    palette: &PaletteParams
+  ) {
+    let bsize_ctx = bsize.width_log2() + bsize.height_log2() - 6;
+
+    if luma_mode == PredictionMode::DC_PRED {
+      let n = palette.size[0];
+      let ctx = (self.bc.above_of(bo).palette.size[0] > 0) as usize
+        + (self.bc.left_of(bo).palette.size[0] > 0) as usize;
+      symbol!(
+        self,
+        w,
+        (n > 0) as u32,
+        &mut self.fc.palette_y_mode_cdfs[bsize_ctx][ctx]
+      );
+      if n > 0 {
+        symbol!(
+          self,
+          w,
+          (n - PALETTE_MIN_SIZE) as u32,
+          &mut self.fc.palette_y_size_cdfs[bsize_ctx]
+        );
+        self.write_palette_colors(w, bo, 0, &palette.colors[0][..n]);
+      }
+    } else {
+      assert!(palette.size[0] == 0);
+    }
+
+    if has_chroma && chroma_mode == PredictionMode::DC_PRED {
+      let n = palette.size[1];
+      let ctx = (palette.size[0] > 0) as usize;
+      symbol!(self, w, (n > 0) as u32, &mut self.fc.palette_uv_mode_cdfs[ctx]);
+      if n > 0 {
+        symbol!(
+          self,
+          w,
+          (n - PALETTE_MIN_SIZE) as u32,
+          &mut self.fc.palette_uv_size_cdfs[bsize_ctx]
+        );
+        self.write_palette_colors(w, bo, 1, &palette.colors[1][..n]);
+        self.write_palette_colors_v(w, &palette.colors[2][..n]);
+      }
+    } else {
+      assert!(palette.size[1] == 0);
+    }
+  }
+
+  // Luma (plane 0) or U (plane 1) palette colors: those found in the palette
+  // cache are flagged, and the others follow in increasing order, delta
+  // coded. Luma colors are distinct, so their deltas are at least 1.
+  fn write_palette_colors(
+    &mut self, w: &mut Writer, bo: &BlockOffset, plane: usize, colors: &[u16]
+  ) {
+    let bit_depth = 8;
+    let min_delta = if plane == 0 { 1 } else { 0 };
+    let n = colors.len();
+
+    let mut cache = [0u16; 2 * PALETTE_MAX_SIZE];
+    let cache_n = self.bc.palette_cache(bo, plane, &mut cache);
+
+    let mut cached = [false; PALETTE_MAX_SIZE];
+    let mut n_cached = 0;
+    for &c in &cache[..cache_n] {
+      if n_cached == n {
+        break;
+      }
+      let found = (0..n).find(|&i| !cached[i] && colors[i] == c);
+      w.bit(found.is_some() as u16);
+      if let Some(i) = found {
+        cached[i] = true;
+        n_cached += 1;
+      }
+    }
+
+    let rest: Vec<u32> =
+      (0..n).filter(|&i| !cached[i]).map(|i| colors[i] as u32).collect();
+
+    if let Some(&first) = rest.first() {
+      w.literal(bit_depth, first);
+
+      if rest.len() > 1 {
+        let min_bits = bit_depth as u32 - 3;
+        let max_delta = rest.windows(2).map(|c| c[1] - c[0]).max().unwrap();
+        let mut bits = cmp::max(ceil_log2(max_delta + 1 - min_delta), min_bits);
+        w.literal(2, bits - min_bits);
+
+        let mut range = (1 << bit_depth) - first - min_delta;
+        for c in rest.windows(2) {
+          let delta = c[1] - c[0];
+          w.literal(bits as u8, delta - min_delta);
+          range -= delta;
+          bits = cmp::min(bits, ceil_log2(range));
+        }
+      }
+    }
+  }
+
+  // V palette colors, either raw or as deltas wrapping around the range of
+  // values, whichever is shorter
+  fn write_palette_colors_v(&mut self, w: &mut Writer, colors: &[u16]) {
+    let bit_depth = 8;
+    let max_val = 1 << bit_depth;
+    let min_bits = bit_depth as u32 - 4;
+    let n = colors.len() as u32;
+
+    let deltas: Vec<i32> = colors.windows(2).map(|c| {
+      let d = c[1] as i32 - c[0] as i32;
+      if d.abs() <= max_val - d.abs() {
+        d
+      } else if d > 0 {
+        d - max_val
+      } else {
+        d + max_val
+      }
+    }).collect();
+
+    let max_delta = deltas.iter().map(|d| d.abs() as u32).max().unwrap();
+    let zero_count = deltas.iter().filter(|&&d| d == 0).count() as u32;
+    let bits = cmp::max(ceil_log2(max_delta + 1), min_bits);
+
+    let rate_delta = 2 + bit_depth as u32 + (bits + 1) * (n - 1) - zero_count;
+    let rate_raw = bit_depth as u32 * n;
+    let delta_encode = rate_delta < rate_raw && bits - min_bits < 4;
+
+    w.bit(delta_encode as u16);
+    if delta_encode {
+      w.literal(2, bits - min_bits);
+      w.literal(bit_depth, colors[0] as u32);
+      for &d in &deltas {
+        w.literal(bits as u8, d.abs() as u32);
+        if d != 0 {
+          w.bit((d < 0) as u16);
+        }
+      }
+    } else {
+      for &c in colors {
+        w.literal(bit_depth, c as u32);
+      }
+    }
+  }
+
+  // Color index map of the luma (plane 0) or chroma (plane 1) palette, whose
+  // pixels are coded along anti-diagonals, from the top-right to the
+  // bottom-left of each. Only the part inside the frame is coded.
+  pub fn write_palette_color_map(
+    &mut self, w: &mut Writer, plane: usize, map: &[u8], stride: usize,
+    onscreen_w: usize, onscreen_h: usize, n: usize
+  ) {
+    w.ns(n as u32, map[0] as u32);
+
+    for i in 1..onscreen_h + onscreen_w - 1 {
+      let j_min = (i + 1).saturating_sub(onscreen_h);
+      let j_max = cmp::min(i, onscreen_w - 1);
+      for j in (j_min..j_max + 1).rev() {
+        let (r, c) = (i - j, j);
+        let (ctx, order) = palette_color_context(map, stride, r, c, n);
+        let color = map[r * stride + c];
+        let idx = order.iter().position(|&o| o == color).unwrap();
+
+        let cdf = if plane == 0 {
+          &mut self.fc.palette_y_color_cdfs[n - PALETTE_MIN_SIZE][ctx]
+        } else {
+          &mut self.fc.palette_uv_color_cdfs[n - PALETTE_MIN_SIZE][ctx]
+        };
+        symbol!(self, w, idx as u32, &mut cdf[..n + 1]);
+      }
+    }
+  }
+
+  pub fn write_tx_size_intra(
+    &mut self, w: &mut Writer, bo: &BlockOffset, bsize: BlockSize,
+    tx_size: TxSize
+  ) {
+    assert!(bsize > BlockSize::BLOCK_4X4);
+
+    // The cdf category is the number of splits from the largest transform
+    // down to TX_4X4, minus one
+    let mut tx_size_cat = 0;
+    let mut max_tx_size = bsize.largest_tx_size();
+    while max_tx_size.split() != TxSize::TX_4X4 {
+      tx_size_cat += 1;
+      max_tx_size = max_tx_size.split();
+    }
+    let max_depths = bsize.max_tx_depth();
+    let depth = (0..max_depths + 1)
+      .find(|&d| bsize.tx_size_at_depth(d) == tx_size)
+      .unwrap();
+    let ctx = self.bc.tx_size_context(bo, bsize);
+
+    symbol!(
+      self,
+      w,
+      depth as u32,
+      &mut self.fc.tx_size_cdf[tx_size_cat][ctx][..max_depths + 1 + 1]
+    );
+  }
+
+  /// Write the transform partitioning tree of an inter block, whose
+  /// transforms are `tx_tree`.
+  pub fn write_tx_size_inter(
+    &mut self, w: &mut Writer, bo: &BlockOffset, bsize: BlockSize,
+    tx_tree: &TxTree
+  ) {
+    let max_tx_size = bsize.largest_tx_size();
+    let bw = bsize.width_mi() / max_tx_size.width_mi();
+    let bh = bsize.height_mi() / max_tx_size.height_mi();
+
+    for by in 0..bh {
+      for bx in 0..bw {
+        let tx_bo = BlockOffset {
+          x: bo.x + bx * max_tx_size.width_mi(),
+          y: bo.y + by * max_tx_size.height_mi()
+        };
+        self.write_txfm_partition(w, &tx_bo, bsize, max_tx_size, tx_tree, 0);
+      }
+    }
+  }
+
+  fn write_txfm_partition(
+    &mut self, w: &mut Writer, bo: &BlockOffset, bsize: BlockSize,
+    tx_size: TxSize, tx_tree: &TxTree, depth: usize
+  ) {
+    if bo.x >= self.bc.cols || bo.y >= self.bc.rows {
+      return;
+    }
+
+    if depth == MAX_VARTX_DEPTH || tx_size == TxSize::TX_4X4 {
+      self.bc.update_txfm_partition_context(bo, tx_size, tx_size);
+      return;
+    }
+
+    let txfm_split = tx_tree.leaf_size(bo) != tx_size;
+    let ctx = self.bc.txfm_partition_context(bo, bsize, tx_size);
+    symbol!(self, w, txfm_split as u32, &mut self.fc.txfm_partition_cdf[ctx]);
+
+    if !txfm_split {
+      self.bc.update_txfm_partition_context(bo, tx_size, tx_size);
+      return;
+    }
+
+    let sub_tx_size = tx_size.split();
+    if sub_tx_size == TxSize::TX_4X4 {
+      self.bc.update_txfm_partition_context(bo, sub_tx_size, tx_size);
+      return;
+    }
+
+    let bw = tx_size.width_mi() / sub_tx_size.width_mi();
+    let bh = tx_size.height_mi() / sub_tx_size.height_mi();
+    for by in 0..bh {
+      for bx in 0..bw {
+        let sub_bo = BlockOffset {
+          x: bo.x + bx * sub_tx_size.width_mi(),
+          y: bo.y + by * sub_tx_size.height_mi()
+        };
+        self.write_txfm_partition(
+          w,
+          &sub_bo,
+          bsize,
+          sub_tx_size,
+          tx_tree,
+          depth + 1
+        );
+      }
+    }
+  }
 
   pub fn fill_neighbours_ref_counts(&mut self, bo: &BlockOffset) {
       let mut ref_counts = [0; TOTAL_REFS_PER_FRAME];
@@ -1949,11 +4540,44 @@ impl ContextWriter {
     }
   }
 
-  pub fn write_ref_frames(&mut self, w: &mut Writer, bo: &BlockOffset) {
+  fn get_pred_ctx_last2_or_l3gld(&mut self, bo: &BlockOffset) -> usize {
+    let ref_counts = self.bc.at(bo).neighbors_ref_counts;
+
+    let l2_count = ref_counts[LAST2_FRAME];
+
+    let l3_gold_count = ref_counts[LAST3_FRAME] +
+                        ref_counts[GOLDEN_FRAME];
+
+    if l2_count == l3_gold_count {
+      return 1;
+    } else if l2_count < l3_gold_count {
+      return 0;
+    } else {
+      return 2;
+    }
+  }
+
+  // Blocks of at least 8x8 signal whether they use compound references when
+  // the frame allows both. Only single references are coded otherwise.
+  pub fn write_ref_frames(
+    &mut self, w: &mut Writer, reference_select: bool, bo: &BlockOffset,
+    bsize: BlockSize
+  ) {
     let rf = self.bc.at(bo).ref_frames;
-    assert!(rf[0] == LAST_FRAME);
+    let compound = rf[1] > INTRA_FRAME;
+    assert!(compound || rf[0] == LAST_FRAME);
+
+    if reference_select && cmp::min(bsize.width(), bsize.height()) >= 8 {
+      let ctx = self.bc.comp_mode_context(bo);
+      symbol!(self, w, compound as u32, &mut self.fc.comp_inter_cdfs[ctx]);
+    } else {
+      assert!(!compound);
+    }
 
-    /* TODO: Handle multiple references */
+    if compound {
+      self.write_compound_ref_frames(w, bo, rf);
+      return;
+    }
 
     let b0_ctx = self.get_ref_frame_ctx_b0(bo);
     let b0 = rf[0] <= ALTREF_FRAME && rf[0] >= BWDREF_FRAME;
@@ -1989,6 +4613,168 @@ impl ContextWriter {
     }
   }
 
+  // Codes the references of a compound block, either a pair of the ones
+  // allowed on the same side of the frame or one on each side
+  fn write_compound_ref_frames(
+    &mut self, w: &mut Writer, bo: &BlockOffset, rf: [usize; 2]
+  ) {
+    let uni = (rf[0] >= BWDREF_FRAME) == (rf[1] >= BWDREF_FRAME);
+    let ctx = self.bc.comp_ref_type_context(bo);
+    symbol!(self, w, !uni as u32, &mut self.fc.comp_ref_type_cdfs[ctx]);
+
+    if uni {
+      let b0_ctx = self.get_ref_frame_ctx_b0(bo);
+      let b0 = rf[0] == BWDREF_FRAME;
+      assert!(if b0 { rf[1] == ALTREF_FRAME } else { rf[0] == LAST_FRAME });
+
+      symbol!(self, w, b0 as u32, &mut self.fc.uni_comp_ref_cdfs[b0_ctx][0]);
+      if !b0 {
+        let b1_ctx = self.get_pred_ctx_last2_or_l3gld(bo);
+        let b1 = rf[1] != LAST2_FRAME;
+
+        symbol!(self, w, b1 as u32, &mut self.fc.uni_comp_ref_cdfs[b1_ctx][1]);
+        if b1 {
+          let b2_ctx = self.get_pred_ctx_last3_or_gold(bo);
+          let b2 = rf[1] == GOLDEN_FRAME;
+
+          symbol!(
+            self,
+            w,
+            b2 as u32,
+            &mut self.fc.uni_comp_ref_cdfs[b2_ctx][2]
+          );
+        }
+      }
+    } else {
+      let b0_ctx = self.get_pred_ctx_ll2_or_l3gld(bo);
+      let b0 = rf[0] == LAST3_FRAME || rf[0] == GOLDEN_FRAME;
+
+      symbol!(self, w, b0 as u32, &mut self.fc.comp_ref_cdfs[b0_ctx][0]);
+      if !b0 {
+        let b1_ctx = self.get_pred_ctx_last_or_last2(bo);
+        let b1 = rf[0] == LAST2_FRAME;
+
+        symbol!(self, w, b1 as u32, &mut self.fc.comp_ref_cdfs[b1_ctx][1]);
+      } else {
+        let b2_ctx = self.get_pred_ctx_last3_or_gold(bo);
+        let b2 = rf[0] == GOLDEN_FRAME;
+
+        symbol!(self, w, b2 as u32, &mut self.fc.comp_ref_cdfs[b2_ctx][2]);
+      }
+
+      let bwd0_ctx = self.get_pred_ctx_brfarf2_or_arf(bo);
+      let bwd0 = rf[1] == ALTREF_FRAME;
+
+      symbol!(
+        self,
+        w,
+        bwd0 as u32,
+        &mut self.fc.comp_bwdref_cdfs[bwd0_ctx][0]
+      );
+      if !bwd0 {
+        let bwd1_ctx = self.get_pred_ctx_brf_or_arf2(bo);
+        let bwd1 = rf[1] == ALTREF2_FRAME;
+
+        symbol!(
+          self,
+          w,
+          bwd1 as u32,
+          &mut self.fc.comp_bwdref_cdfs[bwd1_ctx][1]
+        );
+      }
+    }
+  }
+
+  pub fn write_use_intrabc(&mut self, w: &mut Writer, use_intrabc: bool) {
+    symbol!(self, w, use_intrabc as u32, &mut self.fc.intrabc_cdf);
+  }
+
+  // Codes the difference between `mv` and `ref_mv` with the CDFs of `mv_ctx`,
+  // down to the sub-pixel `precision`
+  pub fn write_mv(
+    &mut self, w: &mut Writer, mv: MotionVector, ref_mv: MotionVector,
+    mv_ctx: usize, precision: MvSubpelPrecision
+  ) {
+    let diff =
+      MotionVector { row: mv.row - ref_mv.row, col: mv.col - ref_mv.col };
+    let joint = match (diff.row != 0, diff.col != 0) {
+      (false, false) => MV_JOINT_ZERO,
+      (false, true) => MV_JOINT_HNZVZ,
+      (true, false) => MV_JOINT_HZVNZ,
+      (true, true) => MV_JOINT_HNZVNZ
+    };
+    symbol!(
+      self,
+      w,
+      joint as u32,
+      &mut self.fc.nmv_contexts[mv_ctx].joints_cdf
+    );
+
+    if joint == MV_JOINT_HZVNZ || joint == MV_JOINT_HNZVNZ {
+      self.write_mv_component(w, diff.row, mv_ctx, 0, precision);
+    }
+    if joint == MV_JOINT_HNZVZ || joint == MV_JOINT_HNZVNZ {
+      self.write_mv_component(w, diff.col, mv_ctx, 1, precision);
+    }
+  }
+
+  // Codes the non-zero motion vector component `comp` (0 for rows, 1 for
+  // columns) as a sign, a magnitude class and offset bits within the class
+  fn write_mv_component(
+    &mut self, w: &mut Writer, v: i16, mv_ctx: usize, comp: usize,
+    precision: MvSubpelPrecision
+  ) {
+    assert!(v != 0);
+    let sign = v < 0;
+    let z = (v as i32).abs() as usize - 1;
+
+    let mv_class = if z >= CLASS0_SIZE * 4096 {
+      MV_CLASSES - 1
+    } else if z >> 3 == 0 {
+      0
+    } else {
+      (31 - ((z >> 3) as u32).leading_zeros()) as usize
+    };
+    let class_base =
+      if mv_class == 0 { 0 } else { CLASS0_SIZE << (mv_class + 2) };
+    let offset = z - class_base;
+    let d = offset >> 3; // Integer part
+    let fr = (offset >> 1) & 3; // Fractional part
+    let hp = offset & 1; // High precision part
+
+    if precision == MvSubpelPrecision::MV_SUBPEL_NONE {
+      assert!(fr == 3 && hp == 1);
+    }
+
+    let mvcomp = &mut self.fc.nmv_contexts[mv_ctx].comps[comp];
+    symbol!(self, w, sign as u32, &mut mvcomp.sign_cdf);
+    symbol!(self, w, mv_class as u32, &mut mvcomp.classes_cdf);
+    if mv_class == 0 {
+      symbol!(self, w, d as u32, &mut mvcomp.class0_cdf);
+    } else {
+      for i in 0..mv_class + CLASS0_BITS - 1 {
+        symbol!(self, w, ((d >> i) & 1) as u32, &mut mvcomp.bits_cdf[i]);
+      }
+    }
+
+    if precision > MvSubpelPrecision::MV_SUBPEL_NONE {
+      let fp_cdf = if mv_class == 0 {
+        &mut mvcomp.class0_fp_cdf[d]
+      } else {
+        &mut mvcomp.fp_cdf
+      };
+      symbol!(self, w, fr as u32, fp_cdf);
+    }
+    if precision > MvSubpelPrecision::MV_SUBPEL_LOW_PRECISION {
+      let hp_cdf = if mv_class == 0 {
+        &mut mvcomp.class0_hp_cdf
+      } else {
+        &mut mvcomp.hp_cdf
+      };
+      symbol!(self, w, hp as u32, hp_cdf);
+    }
+  }
+
   pub fn write_inter_mode(&mut self, w: &mut Writer, mode: PredictionMode, ctx: usize) {
     let newmv_ctx = ctx & NEWMV_CTX_MASK;
     symbol!(self, w, (mode != PredictionMode::NEWMV) as u32, &mut self.fc.newmv_cdf[newmv_ctx]);
@@ -2002,8 +4788,42 @@ impl ContextWriter {
     }
   }
 
+  // Codes the compound `mode`, whose context merges the NEWMV and REFMV
+  // contexts of the single reference modes
+  pub fn write_compound_mode(
+    &mut self, w: &mut Writer, mode: PredictionMode, ctx: usize
+  ) {
+    let newmv_ctx = ctx & NEWMV_CTX_MASK;
+    let refmv_ctx = (ctx >> REFMV_OFFSET) & REFMV_CTX_MASK;
+    let ctx = compound_mode_ctx_map[refmv_ctx >> 1]
+      [cmp::min(newmv_ctx, COMP_NEWMV_CTXS - 1)];
+    symbol!(
+      self,
+      w,
+      mode as u32 - PredictionMode::NEAREST_NEARESTMV as u32,
+      &mut self.fc.compound_mode_cdf[ctx]
+    );
+  }
+
+  // Codes whether the candidate `idx` of `mv_stack` is used rather than one
+  // further down the stack, the context depending on whether it and the next
+  // candidate were among the nearest
+  pub fn write_drl_mode(
+    &mut self, w: &mut Writer, mv_stack: &[CandidateMV], idx: usize,
+    drl_mode: bool
+  ) {
+    let nearest = |i: usize| mv_stack[i].weight >= REF_CAT_LEVEL;
+    let ctx = match (nearest(idx), nearest(idx + 1)) {
+      (true, true) => 0,
+      (true, false) => 1,
+      (false, false) => 2,
+      (false, true) => 0
+    };
+    symbol!(self, w, drl_mode as u32, &mut self.fc.drl_cdf[ctx]);
+  }
+
   pub fn write_tx_type(
-    &mut self, w: &mut Writer, tx_size: TxSize, tx_type: TxType, y_mode: PredictionMode,
+    &mut self, w: &mut Writer, tx_size: TxSize, tx_type: TxType, intra_dir: PredictionMode,
     is_inter: bool, use_reduced_tx_set: bool
   ) {
     let square_tx_size = tx_size.sqr();
@@ -2026,11 +4846,6 @@ impl ContextWriter {
             [..num_tx_set[tx_set as usize] + 1]
         );
       } else {
-        let intra_dir = y_mode;
-        // TODO: Once use_filter_intra is enabled,
-        // intra_dir =
-        // fimode_to_intradir[mbmi->filter_intra_mode_info.filter_intra_mode];
-
         symbol!(
           self,
           w,
@@ -2047,10 +4862,17 @@ impl ContextWriter {
     symbol!(self, w, skip as u32, &mut self.fc.skip_cdfs[ctx]);
   }
 
+  pub fn write_skip_mode(
+    &mut self, w: &mut Writer, bo: &BlockOffset, skip_mode: bool
+  ) {
+    let ctx = self.bc.skip_mode_context(bo);
+    symbol!(self, w, skip_mode as u32, &mut self.fc.skip_mode_cdfs[ctx]);
+  }
+
   pub fn write_block_cdef(&mut self, w: &mut Writer, bo: &BlockOffset, skip: bool, strength_index: u8, bits: u8) {
-    // Starting a new superblock-- we have to keep track as we don't code
+    // Starting a new filter block-- we have to keep track as we don't code
     // a cdef strength until the first non-skip block
-    let block_mask = (1<<SUPERBLOCK_TO_BLOCK_SHIFT) - 1;
+    let block_mask = (1<<FILTER_BLOCK_TO_BLOCK_SHIFT) - 1;
     if (bo.x & block_mask) == 0 && (bo.y & block_mask) == 0 {
       self.bc.cdef_coded = false;
     }
@@ -2066,7 +4888,7 @@ impl ContextWriter {
   }
 
   pub fn get_txsize_entropy_ctx(&mut self, tx_size: TxSize) -> usize {
-    (tx_size.sqr() as usize + tx_size.sqr() as usize + 1) >> 1
+    (tx_size.sqr() as usize + tx_size.sqr_up() as usize + 1) >> 1
   }
 
   pub fn txb_init_levels(
@@ -2085,19 +4907,13 @@ impl ContextWriter {
   }
 
   pub fn av1_get_adjusted_tx_size(&mut self, tx_size: TxSize) -> TxSize {
-    // TODO: Enable below commented out block if TX64X64 is enabled.
-/*
-      if tx_size == TX_64X64 || tx_size == TX_64X32 || tx_size == TX_32X64 {
-        return TX_32X32
-      }
-      if (tx_size == TX_16X64) {
-        return TX_16X32
-      }
-      if (tx_size == TX_64X16) {
-        return TX_32X16
-      }
-*/
-    tx_size
+    match tx_size {
+      TxSize::TX_64X64 | TxSize::TX_64X32 | TxSize::TX_32X64 =>
+        TxSize::TX_32X32,
+      TxSize::TX_16X64 => TxSize::TX_16X32,
+      TxSize::TX_64X16 => TxSize::TX_32X16,
+      _ => tx_size
+    }
   }
 
   pub fn get_txb_bwl(&mut self, tx_size: TxSize) -> usize {
@@ -2211,9 +5027,8 @@ impl ContextWriter {
     &mut self, levels: &mut [u8], scan: &[u16; 4096], eob: u16,
     tx_size: TxSize, tx_class: TxClass, coeff_contexts: &mut [i8]
   ) {
-    // TODO: If TX_64X64 is enabled, use av1_get_adjusted_tx_size()
-    let bwl = tx_size.width_log2();
-    let height = tx_size.height();
+    let bwl = self.get_txb_bwl(tx_size);
+    let height = self.av1_get_adjusted_tx_size(tx_size).height();
     for i in 0..eob {
       let pos = scan[i as usize];
       coeff_contexts[pos as usize] = self.get_nz_map_ctx(
@@ -2300,17 +5115,19 @@ impl ContextWriter {
     ydec: usize, use_reduced_tx_set: bool
   ) -> bool {
     let pred_mode = self.bc.get_mode(bo);
-    let is_inter = pred_mode >= PredictionMode::NEARESTMV;
+    let is_inter = self.bc.at(bo).is_inter();
     //assert!(!is_inter);
     // Note: Both intra and inter mode uses inter scan order. Surprised?
     let scan_order =
       &av1_scan_orders[tx_size as usize][tx_type as usize];
     let scan = scan_order.scan;
+    // Only the 32x32 lowest frequencies of 64-point transforms are coded
+    let txs = self.av1_get_adjusted_tx_size(tx_size);
     let mut coeffs_storage = [0 as i32; 32 * 32];
-    let coeffs = &mut coeffs_storage[..tx_size.area()];
+    let coeffs = &mut coeffs_storage[..txs.area()];
     let mut cul_level = 0 as u32;
 
-    for i in 0..tx_size.area() {
+    for i in 0..txs.area() {
       coeffs[i] = coeffs_in[scan[i] as usize];
       cul_level += coeffs[i].abs() as u32;
     }
@@ -2343,8 +5160,8 @@ impl ContextWriter {
 
     self.txb_init_levels(
       coeffs_in,
-      tx_size.width(),
-      tx_size.height(),
+      txs.width(),
+      txs.height(),
       &mut levels_buf
     );
 
@@ -2357,11 +5174,16 @@ impl ContextWriter {
 
     // Signal tx_type for luma plane only
     if plane == 0 {
+      // Filter intra blocks use the direction of their filter as context
+      let intra_dir = match self.bc.at(bo).filter_intra_mode {
+        Some(mode) => mode.intra_dir(),
+        None => pred_mode
+      };
       self.write_tx_type(
         w,
         tx_size,
         tx_type,
-        pred_mode,
+        intra_dir,
         is_inter,
         use_reduced_tx_set
       );
@@ -2370,7 +5192,7 @@ impl ContextWriter {
     // Encode EOB
     let mut eob_extra = 0 as u32;
     let eob_pt = self.get_eob_pos_token(eob, &mut eob_extra);
-    let eob_multi_size: usize = tx_size.area_log2() - 4;
+    let eob_multi_size: usize = txs.area_log2() - 4;
     let eob_multi_ctx: usize = if tx_class == TX_CLASS_2D {
       0
     } else {
@@ -2464,7 +5286,7 @@ impl ContextWriter {
 
     let mut coeff_contexts = [0 as i8; MAX_TX_SQUARE];
     let levels =
-      &mut levels_buf[TX_PAD_TOP * (tx_size.width() + TX_PAD_HOR)..];
+      &mut levels_buf[TX_PAD_TOP * (txs.width() + TX_PAD_HOR)..];
 
     self.get_nz_map_contexts(
       levels,
@@ -2586,6 +5408,20 @@ impl ContextWriter {
     }
   }
 
+  /// Save the current contexts in `checkpoint`, reusing its buffers
+  pub fn checkpoint_into(&self, checkpoint: &mut ContextWriterCheckpoint) {
+    checkpoint.fc.clone_from(&self.fc);
+    checkpoint.bc.rollback(&self.bc);
+  }
+
+  /// Roll back to the contexts left by coding a block, keeping the partition
+  /// CDFs, which coding blocks does not adapt
+  pub fn rollback_block(&mut self, checkpoint: &ContextWriterCheckpoint) {
+    let partition_cdf = self.fc.partition_cdf;
+    self.rollback(checkpoint);
+    self.fc.partition_cdf = partition_cdf;
+  }
+
   pub fn rollback(&mut self, checkpoint: &ContextWriterCheckpoint) {
     self.fc = checkpoint.fc.clone();
     self.bc.rollback(&checkpoint.bc);
@@ -0,0 +1,446 @@
+// Copyright (c) 2017-2018, The rav1e contributors. All rights reserved
+//
+// This source code is subject to the terms of the BSD 2 Clause License and
+// the Alliance for Open Media Patent License 1.0. If the BSD 2 Clause License
+// was not distributed with this source code in the LICENSE file, you can
+// obtain it at www.aomedia.org/license/software. If the Alliance for Open
+// Media Patent License 1.0 was not distributed with this source code in the
+// PATENTS file, you can obtain it at www.aomedia.org/license/patent.
+
+#![cfg_attr(feature = "cargo-clippy", allow(cast_lossless))]
+#![cfg_attr(feature = "cargo-clippy", allow(needless_range_loop))]
+
+use plane::*;
+use Tune;
+
+use std::f64;
+
+// Contrast sensitivity of the coefficients of 8x8 luma DCTs, from PSNR-HVS-M
+#[cfg_attr(rustfmt, rustfmt_skip)]
+const CSF: [[f64; 8]; 8] = [
+  [1.6193873005, 2.2901594831, 2.08509755623, 1.48366094411,
+   1.00227514334, 0.678296995242, 0.466224900598, 0.3265091542],
+  [2.2901594831, 1.94321815382, 2.04793073064, 1.68731108984,
+   1.2305666963, 0.868920337363, 0.61280991668, 0.436405793551],
+  [2.08509755623, 2.04793073064, 1.34329019223, 1.09205635862,
+   0.875748795257, 0.670882927016, 0.501731932449, 0.372504254596],
+  [1.48366094411, 1.68731108984, 1.09205635862, 0.772819797575,
+   0.605636379554, 0.48309405692, 0.380429446972, 0.295774038565],
+  [1.00227514334, 1.2305666963, 0.875748795257, 0.605636379554,
+   0.448996256676, 0.352889268808, 0.283006984131, 0.226951348204],
+  [0.678296995242, 0.868920337363, 0.670882927016, 0.48309405692,
+   0.352889268808, 0.27032073772, 0.215017739696, 0.17408067321],
+  [0.466224900598, 0.61280991668, 0.501731932449, 0.380429446972,
+   0.283006984131, 0.215017739696, 0.168869545842, 0.136153931001],
+  [0.3265091542, 0.436405793551, 0.372504254596, 0.295774038565,
+   0.226951348204, 0.17408067321, 0.136153931001, 0.109083846276]
+];
+
+// Halving distance of the contrast sensitivity to chroma, which unlike that to
+// luma falls from the lowest frequencies, in coefficients of 8x8 luma DCTs
+const CHROMA_CSF_HALVING: f64 = 4.0;
+
+// Variance of the source below which errors are about as visible as on flat
+// areas, for the activity masking
+const ACTIVITY_THRESHOLD: f64 = 256.0;
+
+// Constants of the SSIM-like boost of the CDEF distortion, for 8x8 blocks
+const CDEF_DIST_C1: f64 = 400.0;
+const CDEF_DIST_C2: f64 = 20000.0;
+
+// Distortion of a wxh block of plane `p` measured as `tune` asks. The
+// perceptual metrics work on 8x8 blocks, or on 4x4 blocks for blocks smaller
+// than 8 pixels in either dimension, with their units kept the same.
+pub fn distortion_wxh(
+  tune: Tune, p: usize, src1: &PlaneSlice, src2: &PlaneSlice, w: usize,
+  h: usize
+) -> u64 {
+  if tune == Tune::Psnr {
+    return sse_wxh(src1, src2, w, h);
+  }
+  let n = if w < 8 || h < 8 { 4 } else { 8 };
+  let weights = if tune == Tune::PsnrHvs {
+    csf(p, &src1.plane.cfg, n)
+  } else {
+    [[0.0; 8]; 8]
+  };
+
+  let mut sum: u64 = 0;
+  for j in 0..h / n {
+    for i in 0..w / n {
+      let s = src1.subslice(i * n, j * n);
+      let d = src2.subslice(i * n, j * n);
+      sum += match tune {
+        Tune::Psnr => unreachable!(),
+        Tune::Psychovisual => cdef_dist_nxn(&s, &d, n),
+        Tune::PsnrHvs => psnr_hvs_nxn(&s, &d, &weights, n),
+        Tune::Activity => activity_masked_sse_nxn(&s, &d, n)
+      };
+    }
+  }
+  sum
+}
+
+// Scale of the Lagrange multiplier for the distortion of `tune`, by which it
+// weighs the errors the SSE is taken for: white noise for PSNR-HVS, and flat
+// errors over flat blocks for the CDEF and activity masked distortions
+pub fn lambda_scale(tune: Tune) -> f64 {
+  match tune {
+    Tune::Psnr | Tune::Activity => 1.0,
+    Tune::Psychovisual => 0.5 * CDEF_DIST_C1 / CDEF_DIST_C2.sqrt(),
+    Tune::PsnrHvs => csf_mean_square()
+  }
+}
+
+// Mean of the squared contrast sensitivity of 8x8 luma DCT coefficients
+fn csf_mean_square() -> f64 {
+  CSF.iter().flat_map(|row| row.iter()).map(|c| c * c).sum::<f64>() / 64.0
+}
+
+// Contrast sensitivity of the coefficients of nxn DCTs of plane `p`, scaled
+// for white noise to weigh as on 8x8 luma DCTs. Coefficients are looked up
+// at the frequency they have in 8x8 luma DCTs.
+fn csf(p: usize, cfg: &PlaneConfig, n: usize) -> [[f64; 8]; 8] {
+  let step = 8 / n;
+  let mut weights = [[0.0; 8]; 8];
+  for j in 0..n {
+    for i in 0..n {
+      weights[j][i] = if p == 0 {
+        CSF[j * step][i * step]
+      } else {
+        let fx = ((i * step) << cfg.xdec) as f64;
+        let fy = ((j * step) << cfg.ydec) as f64;
+        let f = (fx * fx + fy * fy).sqrt();
+        CSF[0][0] * (-f / CHROMA_CSF_HALVING).exp2()
+      };
+    }
+  }
+
+  let mean_square = weights[..n]
+    .iter()
+    .flat_map(|row| row[..n].iter())
+    .map(|w| w * w)
+    .sum::<f64>()
+    / (n * n) as f64;
+  let scale = (csf_mean_square() / mean_square).sqrt();
+  for row in weights[..n].iter_mut() {
+    for w in row[..n].iter_mut() {
+      *w *= scale;
+    }
+  }
+  weights
+}
+
+fn cdef_dist_nxn(src1: &PlaneSlice, src2: &PlaneSlice, n: usize) -> u64 {
+  //TODO: Handle high bit-depth here by setting coeff_shift
+  let coeff_shift = 0;
+  let area = (n * n) as i64;
+  let mut sum_s: i32 = 0;
+  let mut sum_d: i32 = 0;
+  let mut sum_s2: i64 = 0;
+  let mut sum_d2: i64 = 0;
+  let mut sum_sd: i64 = 0;
+  for j in 0..n {
+    for i in 0..n {
+      let s = src1.p(i, j) as i32;
+      let d = src2.p(i, j) as i32;
+      sum_s += s;
+      sum_d += d;
+      sum_s2 += (s * s) as i64;
+      sum_d2 += (d * d) as i64;
+      sum_sd += (s * d) as i64;
+    }
+  }
+  let svar = (sum_s2 - (sum_s as i64 * sum_s as i64 + area / 2) / area) as f64;
+  let dvar = (sum_d2 - (sum_d as i64 * sum_d as i64 + area / 2) / area) as f64;
+  let sse = (sum_d2 + sum_s2 - 2 * sum_sd) as f64;
+  // The variances are summed over the block, so the constants scale with its
+  // area for the boost to stay the same
+  let scale = area as f64 / 64.0;
+  let c1 = CDEF_DIST_C1 * (1 << 2 * coeff_shift) as f64 * scale;
+  let c2 = CDEF_DIST_C2 * (1 << 4 * coeff_shift) as f64 * scale * scale;
+  //The two constants were tuned for CDEF, but can probably be better tuned for use in general RDO
+  let ssim_boost = 0.5_f64 * (svar + dvar + c1) / f64::sqrt(c2 + svar * dvar);
+  (sse * ssim_boost + 0.5_f64) as u64
+}
+
+// Orthonormal n-point DCT of the first n values of `v`
+fn dct(v: &mut [f64; 8], n: usize) {
+  let mut out = [0.0; 8];
+  for (k, o) in out[..n].iter_mut().enumerate() {
+    let sum: f64 = v[..n]
+      .iter()
+      .enumerate()
+      .map(|(i, x)| {
+        x * ((2 * i + 1) as f64 * k as f64 * f64::consts::PI / (2 * n) as f64)
+          .cos()
+      })
+      .sum();
+    let scale = if k == 0 { 1.0 } else { 2.0 };
+    *o = sum * (scale / n as f64).sqrt();
+  }
+  *v = out;
+}
+
+// Pixels of an nxn block, along with the ratio of the summed variances of its
+// quarters to its own variance, which is lower along edges
+fn block_nxn(src: &PlaneSlice, n: usize) -> ([[f64; 8]; 8], f64) {
+  let mut block = [[0.0; 8]; 8];
+  for (j, row) in block[..n].iter_mut().enumerate() {
+    for (i, v) in row[..n].iter_mut().enumerate() {
+      *v = src.p(i, j) as f64;
+    }
+  }
+  let variance = |x0: usize, y0: usize, m: usize| {
+    let pixels =
+      || block[y0..y0 + m].iter().flat_map(|row| row[x0..x0 + m].iter());
+    let mean = pixels().sum::<f64>() / (m * m) as f64;
+    pixels().map(|v| (v - mean) * (v - mean)).sum::<f64>()
+  };
+  let h = n / 2;
+  let var = variance(0, 0, n);
+  let quarters_var = variance(0, 0, h)
+    + variance(h, 0, h)
+    + variance(0, h, h)
+    + variance(h, h, h);
+  let ratio = if var > 0.0 { quarters_var / var } else { 0.0 };
+  (block, ratio)
+}
+
+fn dct_nxn(block: &mut [[f64; 8]; 8], n: usize) {
+  for row in block[..n].iter_mut() {
+    dct(row, n);
+  }
+  for i in 0..n {
+    let mut col = [0.0; 8];
+    for j in 0..n {
+      col[j] = block[j][i];
+    }
+    dct(&mut col, n);
+    for j in 0..n {
+      block[j][i] = col[j];
+    }
+  }
+}
+
+// Squared error of the DCT coefficients of an nxn block, weighted by their
+// contrast sensitivity `weights`. Errors in the AC coefficients below the
+// masking by the texture of either block are not counted, as in PSNR-HVS-M.
+fn psnr_hvs_nxn(
+  src1: &PlaneSlice, src2: &PlaneSlice, weights: &[[f64; 8]; 8], n: usize
+) -> u64 {
+  let (mut s, s_ratio) = block_nxn(src1, n);
+  let (mut d, d_ratio) = block_nxn(src2, n);
+  dct_nxn(&mut s, n);
+  dct_nxn(&mut d, n);
+
+  let mask_weight = |i: usize, j: usize| {
+    let w = weights[j][i] * 0.3885746225901003;
+    w * w
+  };
+  let mask = |coeffs: &[[f64; 8]; 8], ratio: f64| {
+    let mut energy = 0.0;
+    for j in 0..n {
+      for i in 0..n {
+        if i != 0 || j != 0 {
+          energy += coeffs[j][i] * coeffs[j][i] * mask_weight(i, j);
+        }
+      }
+    }
+    (energy * ratio).sqrt() / (4 * n) as f64
+  };
+  let masking = mask(&s, s_ratio).max(mask(&d, d_ratio));
+
+  let mut dist = 0.0;
+  for j in 0..n {
+    for i in 0..n {
+      let mut err = (s[j][i] - d[j][i]).abs();
+      if i != 0 || j != 0 {
+        err = (err - masking / mask_weight(i, j)).max(0.0);
+      }
+      let weighted = err * weights[j][i];
+      dist += weighted * weighted;
+    }
+  }
+  (dist + 0.5) as u64
+}
+
+// SSE of an nxn block, lowered where the texture of the source masks errors
+fn activity_masked_sse_nxn(
+  src1: &PlaneSlice, src2: &PlaneSlice, n: usize
+) -> u64 {
+  let area = (n * n) as f64;
+  let mut sum: u64 = 0;
+  let mut sum2: u64 = 0;
+  for j in 0..n {
+    for i in 0..n {
+      let s = src1.p(i, j) as u64;
+      sum += s;
+      sum2 += s * s;
+    }
+  }
+  let variance = (sum2 as f64 - (sum * sum) as f64 / area) / area;
+  let masking = (ACTIVITY_THRESHOLD / (ACTIVITY_THRESHOLD + variance)).sqrt();
+  (sse_wxh(src1, src2, n, n) as f64 * masking + 0.5) as u64
+}
+
+// Sum of Squared Error for a wxh block
+pub fn sse_wxh(
+  src1: &PlaneSlice, src2: &PlaneSlice, w: usize, h: usize
+) -> u64 {
+  let mut sse: u64 = 0;
+  for j in 0..h {
+    for i in 0..w {
+      let dist = (src1.p(i, j) as i16 - src2.p(i, j) as i16) as i64;
+      sse += (dist * dist) as u64;
+    }
+  }
+  sse
+}
+
+// In-place 4-point Hadamard transform
+fn hadamard4(v: &mut [i32; 4]) {
+  let (a0, a1) = (v[0] + v[1], v[0] - v[1]);
+  let (a2, a3) = (v[2] + v[3], v[2] - v[3]);
+  *v = [a0 + a2, a1 + a3, a0 - a2, a1 - a3];
+}
+
+// Sum of Absolute Transformed Differences for a wxh block, in 4x4 Hadamard
+// transforms, which estimates the cost of coding a residual
+pub fn satd_wxh(
+  src1: &PlaneSlice, src2: &PlaneSlice, w: usize, h: usize
+) -> u64 {
+  let mut satd: u64 = 0;
+  for by in 0..h / 4 {
+    for bx in 0..w / 4 {
+      let mut d = [[0i32; 4]; 4];
+      for (j, row) in d.iter_mut().enumerate() {
+        for (i, v) in row.iter_mut().enumerate() {
+          let (x, y) = (bx * 4 + i, by * 4 + j);
+          *v = src1.p(x, y) as i32 - src2.p(x, y) as i32;
+        }
+        hadamard4(row);
+      }
+      for i in 0..4 {
+        let mut col = [d[0][i], d[1][i], d[2][i], d[3][i]];
+        hadamard4(&mut col);
+        satd += col.iter().map(|v| v.abs() as u64).sum::<u64>();
+      }
+    }
+  }
+  satd
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+
+  // Plane of 128 plus `v` then minus `v` on alternate pixels
+  fn checkerboard(v: i16) -> Plane {
+    let mut plane = Plane::new(16, 16, 0, 0);
+    for (i, p) in plane.data.iter_mut().enumerate() {
+      let sign = if (i + i / 16) % 2 == 0 { 1 } else { -1 };
+      *p = (128 + sign * v) as u16;
+    }
+    plane
+  }
+
+  #[test]
+  fn identical_blocks() {
+    let src = checkerboard(40);
+    let po = PlaneOffset { x: 0, y: 0 };
+    let tunes = [Tune::Psnr, Tune::Psychovisual, Tune::PsnrHvs, Tune::Activity];
+    for &tune in &tunes {
+      for &(w, h) in &[(4, 4), (8, 8), (16, 8)] {
+        let (s, d) = (src.slice(&po), src.slice(&po));
+        assert_eq!(distortion_wxh(tune, 0, &s, &d, w, h), 0);
+      }
+    }
+  }
+
+  #[test]
+  fn flat_error() {
+    let src = checkerboard(0);
+    let mut dst = src.clone();
+    for v in dst.data.iter_mut() {
+      *v += 2;
+    }
+    let po = PlaneOffset { x: 0, y: 0 };
+    let (s, d) = (src.slice(&po), dst.slice(&po));
+
+    // The whole error is in the DC coefficients
+    assert_eq!(satd_wxh(&s, &d, 8, 8), 4 * 16 * 2);
+    let dc = 8.0 * 2.0 * CSF[0][0];
+    assert_eq!(psnr_hvs_nxn(&s, &d, &CSF, 8), (dc * dc + 0.5) as u64);
+    // Flat sources mask nothing
+    assert_eq!(distortion_wxh(Tune::Activity, 0, &s, &d, 8, 8), 64 * 4);
+  }
+
+  #[test]
+  fn small_blocks() {
+    let src = checkerboard(0);
+    let mut dst = src.clone();
+    for v in dst.data.iter_mut() {
+      *v += 2;
+    }
+    let po = PlaneOffset { x: 0, y: 0 };
+    let (s, d) = (src.slice(&po), dst.slice(&po));
+
+    // Measured in 4x4 blocks, flat errors weigh as in 8x8 blocks, up to the
+    // rounding of each block
+    for &tune in &[Tune::Psychovisual, Tune::Activity] {
+      let dist_8x8 = distortion_wxh(tune, 0, &s, &d, 8, 8) as i64;
+      for &(w, h) in &[(8, 4), (4, 8)] {
+        let dist = distortion_wxh(tune, 0, &s, &d, w, h) as i64;
+        assert!((dist * 2 - dist_8x8).abs() <= 4);
+      }
+    }
+    // and as their SSE once lambda is scaled
+    let dist = distortion_wxh(Tune::Psychovisual, 0, &s, &d, 4, 4) as f64;
+    assert!((dist / lambda_scale(Tune::Psychovisual) - 64.0).abs() < 1.0);
+  }
+
+  #[test]
+  fn chroma_csf() {
+    let plane = Plane::new(16, 16, 1, 1);
+    for &n in &[4, 8] {
+      for p in 0..3 {
+        let weights = csf(p, &plane.cfg, n);
+        let mean_square = weights[..n]
+          .iter()
+          .flat_map(|row| row[..n].iter())
+          .map(|w| w * w)
+          .sum::<f64>()
+          / (n * n) as f64;
+        assert!((mean_square - csf_mean_square()).abs() < 1e-9);
+      }
+
+      // The sensitivity to chroma only falls with the frequency
+      let weights = csf(1, &plane.cfg, n);
+      for j in 0..n {
+        for i in 1..n {
+          assert!(weights[j][i] < weights[j][i - 1]);
+          assert!(weights[i][j] < weights[i - 1][j]);
+        }
+      }
+    }
+  }
+
+  #[test]
+  fn masked_texture() {
+    let flat = checkerboard(0);
+    let textured = checkerboard(40);
+    let po = PlaneOffset { x: 0, y: 0 };
+    let with_error = |plane: &Plane| {
+      let mut rec = plane.clone();
+      for v in rec.data.iter_mut() {
+        *v += 2;
+      }
+      let (s, d) = (plane.slice(&po), rec.slice(&po));
+      distortion_wxh(Tune::Activity, 0, &s, &d, 8, 8)
+    };
+
+    // The same error counts less on a textured source than on a flat one
+    assert!(with_error(&textured) < with_error(&flat));
+  }
+}
@@ -15,17 +15,12 @@
 
 use bitstream_io::{BitWriter, BE};
 use std;
+use std::cmp;
 
 pub const OD_BITRES: u8 = 3;
 const EC_PROB_SHIFT: u32 = 6;
 const EC_MIN_PROB: u32 = 4;
 
-pub struct Writer {
-  enc: od_ec_enc,
-  #[cfg(debug)]
-  debug: bool
-}
-
 pub type od_ec_window = u32;
 
 #[derive(Debug)]
@@ -253,47 +248,114 @@ impl od_ec_enc {
   }
 }
 
-impl Writer {
-  pub fn new() -> Writer {
+/// Writer of the symbols of a tile, either entropy coding them or only
+/// counting the bits they would take
+pub trait Writer {
+  /// Write symbol `s` with the probabilities of `cdf`, then adapt them
+  fn symbol(&mut self, s: u32, cdf: &mut [u16]);
+  /// Write symbol `s` with the probabilities of `cdf`, which has no
+  /// adaptation counter
+  fn cdf(&mut self, s: u32, cdf: &[u16]);
+  /// Write a boolean, with `f` the probability that it is true, scaled by
+  /// 32768
+  fn bool(&mut self, val: bool, f: u16);
+  /// Number of bits written so far, in 1/8 bit units
+  fn tell_frac(&mut self) -> u32;
+  fn checkpoint(&mut self) -> WriterCheckpoint;
+  fn rollback(&mut self, checkpoint: &WriterCheckpoint);
+
+  fn bit(&mut self, bit: u16) {
+    self.bool(bit == 1, 16384);
+  }
+
+  fn literal(&mut self, bits: u8, s: u32) {
+    for bit in (0..bits).rev() {
+      self.bool((1 & (s >> bit)) == 1, 16384);
+    }
+  }
+
+  /// Writes `s` in [0, n) with the quasi-uniform ns(n) code, where the
+  /// smallest values take one bit less than the others.
+  fn ns(&mut self, n: u32, s: u32) {
+    let w = 32 - n.leading_zeros();
+    let m = (1 << w) - n;
+    if s < m {
+      self.literal(w as u8 - 1, s);
+    } else {
+      self.literal(w as u8 - 1, m + ((s - m) >> 1));
+      self.literal(1, (s - m) & 1);
+    }
+  }
+
+  fn write_golomb(&mut self, level: u16) {
+    let x = level + 1;
+    let mut i = x;
+    let mut length = 0;
+
+    while i != 0 {
+      i >>= 1;
+      length += 1;
+    }
+    debug_assert!(length > 0);
+
+    for _ in 0..length - 1 {
+      self.bit(0);
+    }
+
+    for i in (0..length).rev() {
+      self.bit((x >> i) & 0x01);
+    }
+  }
+}
+
+fn update_cdf(cdf: &mut [u16], val: u32) {
+  let nsymbs = cdf.len() - 1;
+  let nsymbs2speed: [usize; 17] =
+    [0, 0, 1, 1, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2];
+  debug_assert!(nsymbs < 17);
+  let rate = 3
+    + (cdf[nsymbs] > 15) as usize
+    + (cdf[nsymbs] > 31) as usize
+    + nsymbs2speed[nsymbs]; // + get_msb(nsymbs);
+  let mut tmp = 32768;
+
+  // Single loop (faster)
+  for (i, v) in cdf[..nsymbs - 1].iter_mut().enumerate() {
+    tmp = if i as u32 == val { 0 } else { tmp };
+    if tmp < *v {
+      *v -= (*v - tmp) >> rate;
+    } else {
+      *v += (tmp - *v) >> rate;
+    }
+  }
+  cdf[nsymbs] += (cdf[nsymbs] < 32) as u16;
+}
+
+/// Writer entropy coding the symbols with od_ec_enc
+pub struct WriterEncoder {
+  enc: od_ec_enc,
+  // Whether symbols adapt their CDFs, unless disable_cdf_update is set
+  update_cdfs: bool,
+  #[cfg(debug)]
+  debug: bool
+}
+
+impl WriterEncoder {
+  pub fn new() -> WriterEncoder {
     // use std::env;
-    Writer {
+    WriterEncoder {
       enc: od_ec_enc::new(),
+      update_cdfs: true,
       #[cfg(debug)]
       debug: std::env::var_os("RAV1E_DEBUG").is_some()
     }
   }
+  pub fn set_update_cdfs(&mut self, update_cdfs: bool) {
+    self.update_cdfs = update_cdfs;
+  }
   pub fn done(&mut self) -> Vec<u8> {
     self.enc.od_ec_enc_done()
   }
-  pub fn cdf(&mut self, s: u32, cdf: &[u16]) {
-    self.enc.od_ec_encode_cdf_q15(s as usize, cdf)
-  }
-  pub fn bool(&mut self, val: bool, f: u16) {
-    self.enc.od_ec_encode_bool_q15(val, f)
-  }
-  fn update_cdf(cdf: &mut [u16], val: u32) {
-    let nsymbs = cdf.len() - 1;
-    let nsymbs2speed: [usize; 17] =
-      [0, 0, 1, 1, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2];
-    debug_assert!(nsymbs < 17);
-    let rate = 3
-      + (cdf[nsymbs] > 15) as usize
-      + (cdf[nsymbs] > 31) as usize
-      + nsymbs2speed[nsymbs]; // + get_msb(nsymbs);
-    let mut tmp = 32768;
-
-    // Single loop (faster)
-    for (i, v) in cdf[..nsymbs - 1].iter_mut().enumerate() {
-      tmp = if i as u32 == val { 0 } else { tmp };
-      if tmp < *v {
-        *v -= (*v - tmp) >> rate;
-      } else {
-        *v += (tmp - *v) >> rate;
-      }
-    }
-    cdf[nsymbs] += (cdf[nsymbs] < 32) as u16;
-  }
-
   #[cfg(debug)]
   fn print_backtrace(&self, s: u32) {
     use backtrace;
@@ -316,7 +378,14 @@ impl Writer {
     });
   }
 
-  pub fn symbol(&mut self, s: u32, cdf: &mut [u16]) {
+  #[allow(dead_code)]
+  pub fn tell(&mut self) -> u32 {
+    self.enc.od_ec_enc_tell_frac()
+  }
+}
+
+impl Writer for WriterEncoder {
+  fn symbol(&mut self, s: u32, cdf: &mut [u16]) {
     let nsymbs = cdf.len() - 1;
     #[cfg(debug)]
     {
@@ -325,62 +394,213 @@ impl Writer {
       }
     }
     self.cdf(s, &cdf[..nsymbs]);
-    Writer::update_cdf(cdf, s);
+    if self.update_cdfs {
+      update_cdf(cdf, s);
+    }
   }
 
-  pub fn bit(&mut self, bit: u16) {
-    self.enc.od_ec_encode_bool_q15(bit == 1, 16384);
+  fn cdf(&mut self, s: u32, cdf: &[u16]) {
+    self.enc.od_ec_encode_cdf_q15(s as usize, cdf)
   }
 
-  pub fn literal(&mut self, bits: u8, s: u32) {
-    for bit in (0..bits).rev() {
-      self.enc.od_ec_encode_bool_q15((1 & (s >> bit)) == 1, 16384);
+  fn bool(&mut self, val: bool, f: u16) {
+    self.enc.od_ec_encode_bool_q15(val, f)
+  }
+
+  fn tell_frac(&mut self) -> u32 {
+    self.enc.od_ec_enc_tell_frac()
+  }
+
+  fn checkpoint(&mut self) -> WriterCheckpoint {
+    WriterCheckpoint {
+      bits: 0,
+      ops_len: 0,
+      precarry_len: self.enc.precarry.len(),
+      low: self.enc.low,
+      rng: self.enc.rng,
+      cnt: self.enc.cnt
     }
   }
 
-  pub fn write_golomb(&mut self, level: u16) {
-    let x = level + 1;
-    let mut i = x;
-    let mut length = 0;
+  fn rollback(&mut self, checkpoint: &WriterCheckpoint) {
+    self.enc.precarry.truncate(checkpoint.precarry_len);
+    self.enc.low = checkpoint.low;
+    self.enc.rng = checkpoint.rng;
+    self.enc.cnt = checkpoint.cnt;
+  }
+}
 
-    while i != 0 {
-      i >>= 1;
-      length += 1;
+// Precision of the bit costs of WriterCounter, as libaom's
+// AV1_PROB_COST_SHIFT
+const PROB_COST_SHIFT: u32 = 9;
+
+// Cost of the probabilities in [128, 256) out of 256, as libaom's
+// av1_prob_cost
+const PROB_COST: [u16; 128] = [
+  512, 506, 501, 495, 489, 484, 478, 473, 467, 462, 456, 451,
+  446, 441, 435, 430, 425, 420, 415, 410, 405, 400, 395, 390,
+  385, 380, 375, 371, 366, 361, 356, 352, 347, 343, 338, 333,
+  329, 324, 320, 316, 311, 307, 302, 298, 294, 289, 285, 281,
+  277, 273, 268, 264, 260, 256, 252, 248, 244, 240, 236, 232,
+  228, 224, 220, 216, 212, 209, 205, 201, 197, 194, 190, 186,
+  182, 179, 175, 171, 168, 164, 161, 157, 153, 150, 146, 143,
+  139, 136, 132, 129, 125, 122, 119, 115, 112, 109, 105, 102,
+  99, 95, 92, 89, 86, 82, 79, 76, 73, 70, 66, 63,
+  60, 57, 54, 51, 48, 45, 42, 38, 35, 32, 29, 26,
+  23, 20, 18, 15, 12, 9, 6, 3
+];
+
+/// Cost in 1/512 bit units of a symbol of probability `p15` out of 32768,
+/// as libaom's av1_cost_symbol
+fn symbol_cost(p15: u32) -> u32 {
+  let p15 = cmp::max(cmp::min(p15, 32767), 1);
+  // Normalize the probability into [16384, 32768), each shift costing a bit
+  let shift = p15.leading_zeros() - 17;
+  let prob = cmp::min(((p15 << shift) + 64) >> 7, 255);
+  PROB_COST[prob as usize - 128] as u32 + (shift << PROB_COST_SHIFT)
+}
+
+/// Writer only estimating the bits the symbols would take from their
+/// probabilities, for the rate of RDO candidates
+#[derive(Clone)]
+pub struct WriterCounter {
+  // Bits written so far, in 1/512 bit units
+  bits: u32,
+  // Whether symbols adapt their CDFs, unless disable_cdf_update is set
+  update_cdfs: bool
+}
+
+impl WriterCounter {
+  pub fn new() -> WriterCounter {
+    WriterCounter { bits: 0, update_cdfs: true }
+  }
+  pub fn set_update_cdfs(&mut self, update_cdfs: bool) {
+    self.update_cdfs = update_cdfs;
+  }
+}
+
+impl Writer for WriterCounter {
+  fn symbol(&mut self, s: u32, cdf: &mut [u16]) {
+    let nsymbs = cdf.len() - 1;
+    self.cdf(s, &cdf[..nsymbs]);
+    if self.update_cdfs {
+      update_cdf(cdf, s);
     }
-    debug_assert!(length > 0);
+  }
 
-    for _ in 0..length - 1 {
-      self.bit(0);
+  fn cdf(&mut self, s: u32, cdf: &[u16]) {
+    // CDFs are stored inverted, as od_ec_encode_cdf_q15 takes them
+    let fl = if s > 0 { cdf[s as usize - 1] as u32 } else { 32768 };
+    let fh = cdf[s as usize] as u32;
+    self.bits += symbol_cost(fl - fh);
+  }
+
+  fn bool(&mut self, val: bool, f: u16) {
+    let p15 = if val { f as u32 } else { 32768 - f as u32 };
+    self.bits += symbol_cost(p15);
+  }
+
+  fn tell_frac(&mut self) -> u32 {
+    self.bits >> (PROB_COST_SHIFT - OD_BITRES as u32)
+  }
+
+  fn checkpoint(&mut self) -> WriterCheckpoint {
+    WriterCheckpoint {
+      bits: self.bits,
+      ops_len: 0,
+      precarry_len: 0,
+      low: 0,
+      rng: 0,
+      cnt: 0
     }
+  }
 
-    for i in (0..length).rev() {
-      self.bit((x >> i) & 0x01);
+  fn rollback(&mut self, checkpoint: &WriterCheckpoint) {
+    self.bits = checkpoint.bits;
+  }
+}
+
+#[derive(Clone)]
+enum WriterOp {
+  Cdf(u32, [u16; 16], usize),
+  Bool(bool, u16)
+}
+
+/// Writer estimating the bits as WriterCounter, while recording the symbols
+/// with their probabilities to replay them into another writer
+pub struct WriterRecorder {
+  counter: WriterCounter,
+  ops: Vec<WriterOp>
+}
+
+impl Clone for WriterRecorder {
+  fn clone(&self) -> Self {
+    WriterRecorder { counter: self.counter.clone(), ops: self.ops.clone() }
+  }
+  // Reuses the buffer of recorded symbols
+  fn clone_from(&mut self, source: &Self) {
+    self.counter = source.counter.clone();
+    self.ops.clone_from(&source.ops);
+  }
+}
+
+impl WriterRecorder {
+  pub fn new() -> WriterRecorder {
+    WriterRecorder { counter: WriterCounter::new(), ops: Vec::new() }
+  }
+  pub fn set_update_cdfs(&mut self, update_cdfs: bool) {
+    self.counter.set_update_cdfs(update_cdfs);
+  }
+  /// Write the recorded symbols into `w`, without adapting any CDF
+  pub fn replay(&self, w: &mut Writer) {
+    for op in &self.ops {
+      match *op {
+        WriterOp::Cdf(s, ref cdf, nsymbs) => w.cdf(s, &cdf[..nsymbs]),
+        WriterOp::Bool(val, f) => w.bool(val, f)
+      }
     }
   }
+}
 
-  #[allow(dead_code)]
-  pub fn tell(&mut self) -> u32 {
-    self.enc.od_ec_enc_tell_frac()
+impl Writer for WriterRecorder {
+  fn symbol(&mut self, s: u32, cdf: &mut [u16]) {
+    let nsymbs = cdf.len() - 1;
+    self.cdf(s, &cdf[..nsymbs]);
+    if self.counter.update_cdfs {
+      update_cdf(cdf, s);
+    }
   }
 
-  pub fn tell_frac(&mut self) -> u32 {
-    self.enc.od_ec_enc_tell_frac()
+  fn cdf(&mut self, s: u32, cdf: &[u16]) {
+    self.counter.cdf(s, cdf);
+    let mut probs = [0; 16];
+    probs[..cdf.len()].copy_from_slice(cdf);
+    self.ops.push(WriterOp::Cdf(s, probs, cdf.len()));
+  }
+
+  fn bool(&mut self, val: bool, f: u16) {
+    self.counter.bool(val, f);
+    self.ops.push(WriterOp::Bool(val, f));
+  }
+
+  fn tell_frac(&mut self) -> u32 {
+    self.counter.tell_frac()
   }
 
-  pub fn checkpoint(&mut self) -> WriterCheckpoint {
+  fn checkpoint(&mut self) -> WriterCheckpoint {
     WriterCheckpoint {
-      precarry_len: self.enc.precarry.len(),
-      low: self.enc.low,
-      rng: self.enc.rng,
-      cnt: self.enc.cnt
+      bits: self.counter.bits,
+      ops_len: self.ops.len(),
+      precarry_len: 0,
+      low: 0,
+      rng: 0,
+      cnt: 0
     }
   }
 
-  pub fn rollback(&mut self, checkpoint: &WriterCheckpoint) {
-    self.enc.precarry.truncate(checkpoint.precarry_len);
-    self.enc.low = checkpoint.low;
-    self.enc.rng = checkpoint.rng;
-    self.enc.cnt = checkpoint.cnt;
+  fn rollback(&mut self, checkpoint: &WriterCheckpoint) {
+    self.counter.bits = checkpoint.bits;
+    self.ops.truncate(checkpoint.ops_len);
   }
 }
 
@@ -424,7 +644,7 @@ impl<'a> BCodeWriter for BitWriter<'a, BE> {
     if n <= 1 {
       return Ok(());
     };
-    let l = 31 ^ ((n - 1) + 1).leading_zeros();
+    let l = 16 - (n - 1).leading_zeros();
     let m = (1 << l) - n;
     if v < m {
       self.write(l - 1, v)
@@ -480,6 +700,10 @@ impl<'a> BCodeWriter for BitWriter<'a, BE> {
 
 #[derive(Clone)]
 pub struct WriterCheckpoint {
+  // State of WriterCounter and WriterRecorder
+  bits: u32,
+  ops_len: usize,
+  // State of WriterEncoder
   precarry_len: usize,
   low: od_ec_window,
   rng: u16,
@@ -547,7 +771,7 @@ mod test {
 
   #[test]
   fn booleans() {
-    let mut w = Writer::new();
+    let mut w = WriterEncoder::new();
 
     w.bool(false, 1);
     w.bool(true, 2);
@@ -572,7 +796,7 @@ mod test {
   fn cdf() {
     let cdf = [7296, 3819, 1716, 0];
 
-    let mut w = Writer::new();
+    let mut w = WriterEncoder::new();
 
     w.cdf(0, &cdf);
     w.cdf(0, &cdf);
@@ -603,7 +827,7 @@ mod test {
   fn mixed() {
     let cdf = [7296, 3819, 1716, 0];
 
-    let mut w = Writer::new();
+    let mut w = WriterEncoder::new();
 
     w.cdf(0, &cdf);
     w.bool(true, 2);
@@ -639,4 +863,107 @@ mod test {
     assert_eq!(r.cdf(&cdf), 2);
     assert_eq!(r.cdf(&cdf), 2);
   }
+  #[test]
+  fn counter() {
+    let mut cdf_enc = [7296, 3819, 1716, 0, 0];
+    let mut cdf_count = cdf_enc;
+
+    let mut enc = WriterEncoder::new();
+    let mut count = WriterCounter::new();
+
+    let start = (enc.tell_frac(), count.tell_frac());
+    for i in 0..1000 {
+      let s = [0, 0, 1, 3, 0, 2, 1, 0][i % 8];
+      enc.symbol(s, &mut cdf_enc);
+      count.symbol(s, &mut cdf_count);
+      enc.bool(i % 3 == 0, 8192);
+      count.bool(i % 3 == 0, 8192);
+      enc.literal(4, i as u32);
+      count.literal(4, i as u32);
+    }
+    assert_eq!(cdf_enc, cdf_count);
+
+    // The estimated rate is within 1% of the coded one
+    let coded = enc.tell_frac() - start.0;
+    let estimated = count.tell_frac() - start.1;
+    assert!(estimated * 100 > coded * 99 && estimated * 100 < coded * 101);
+
+    let checkpoint = count.checkpoint();
+    count.literal(8, 0);
+    assert_eq!(count.tell_frac(), estimated + start.1 + 64);
+    count.rollback(&checkpoint);
+    assert_eq!(count.tell_frac(), estimated + start.1);
+  }
+
+  #[test]
+  fn recorder() {
+    let mut cdf_enc = [7296, 3819, 1716, 0, 0];
+    let mut cdf_rec = cdf_enc;
+
+    let mut enc = WriterEncoder::new();
+    let mut rec = WriterRecorder::new();
+    let mut replayed = WriterEncoder::new();
+
+    for i in 0..100 {
+      let s = [0, 2, 1, 3, 0][i % 5];
+      enc.symbol(s, &mut cdf_enc);
+      rec.symbol(s, &mut cdf_rec);
+      enc.literal(3, i as u32);
+      rec.literal(3, i as u32);
+    }
+    assert_eq!(cdf_enc, cdf_rec);
+
+    // Rolled back symbols are not replayed
+    let checkpoint = rec.checkpoint();
+    rec.literal(8, 0xff);
+    rec.rollback(&checkpoint);
+
+    rec.replay(&mut replayed);
+    assert_eq!(enc.done(), replayed.done());
+  }
+
+  #[test]
+  fn ns() {
+    let mut w = WriterEncoder::new();
+
+    for n in 1..10 {
+      for s in 0..n {
+        w.ns(n, s);
+      }
+    }
+
+    let b = w.done();
+
+    let mut r = Reader::new(&b);
+
+    // Decoding as in the ns(n) descriptor of the specification
+    let literal = |r: &mut Reader, bits: u32| {
+      (0..bits).fold(0, |v, _| (v << 1) | r.bool(16384) as u32)
+    };
+    for n in 1..10u32 {
+      let bits = 32 - n.leading_zeros();
+      let m = (1 << bits) - n;
+      for s in 0..n {
+        let v = literal(&mut r, bits - 1);
+        let v = if v < m { v } else { (v << 1) - m + literal(&mut r, 1) };
+        assert_eq!(v, s);
+      }
+    }
+  }
+  #[test]
+  fn quniform() {
+    let mut buf = Vec::new();
+    {
+      let mut bw = BitWriter::<BE>::new(&mut buf);
+      // 2 bits for the 3 first values of 5, 3 bits for the 2 last ones
+      bw.write_quniform(5, 1).unwrap();
+      bw.write_quniform(5, 4).unwrap();
+      bw.write_quniform(5, 3).unwrap();
+      // Powers of two are coded with a fixed number of bits
+      bw.write_quniform(8, 6).unwrap();
+      bw.write_quniform(1, 0).unwrap();
+      bw.byte_align().unwrap();
+    }
+    assert_eq!(buf, [0b0111_1110, 0b1100_0000]);
+  }
 }
@@ -0,0 +1,423 @@
+// Copyright (c) 2017-2018, The rav1e contributors. All rights reserved
+//
+// This source code is subject to the terms of the BSD 2 Clause License and
+// the Alliance for Open Media Patent License 1.0. If the BSD 2 Clause License
+// was not distributed with this source code in the LICENSE file, you can
+// obtain it at www.aomedia.org/license/software. If the Alliance for Open
+// Media Patent License 1.0 was not distributed with this source code in the
+// PATENTS file, you can obtain it at www.aomedia.org/license/patent.
+
+use context::*;
+use partition::*;
+use plane::*;
+use std::cmp::Ordering;
+use FrameInvariants;
+
+// Sizes of the square blocks that are hashed for intra block copy
+const HASH_MIN_SIZE_LOG2: usize = 3;
+const HASH_MAX_SIZE_LOG2: usize = 6;
+
+const FNV_OFFSET_BASIS: u32 = 2_166_136_261;
+const FNV_PRIME: u32 = 16_777_619;
+
+fn hash_combine(values: [u32; 4]) -> u32 {
+  values.iter().fold(FNV_OFFSET_BASIS, |h, &v| (h ^ v).wrapping_mul(FNV_PRIME))
+}
+
+/// Whether the displacement vector `dv` of the block at `bo` only references
+/// the part of the frame that a decoder has already reconstructed, outside
+/// of the area still in flight in the in-loop pipeline
+pub fn is_dv_valid(
+  fi: &FrameInvariants, sb_size: BlockSize, bo: &BlockOffset,
+  bsize: BlockSize, dv: MotionVector, xdec: usize, ydec: usize
+) -> bool {
+  let (row, col) = (dv.row as isize, dv.col as isize);
+  // Only integer positions are allowed
+  if row & 7 != 0 || col & 7 != 0 {
+    return false;
+  }
+
+  let x = (bo.x << MI_SIZE_LOG2) as isize;
+  let y = (bo.y << MI_SIZE_LOG2) as isize;
+  let bw = bsize.width() as isize;
+  let bh = bsize.height() as isize;
+  let src_top_edge = y * 8 + row;
+  let src_left_edge = x * 8 + col;
+  let src_bottom_edge = (y + bh) * 8 + row;
+  let src_right_edge = (x + bw) * 8 + col;
+  if src_top_edge < 0
+    || src_left_edge < 0
+    || src_bottom_edge > ((fi.h_in_b << MI_SIZE_LOG2) * 8) as isize
+    || src_right_edge > ((fi.w_in_b << MI_SIZE_LOG2) * 8) as isize
+  {
+    return false;
+  }
+
+  // The chroma of sub-8x8 blocks also covers the block above or to the left
+  if has_chroma(bo, bsize, xdec, ydec) {
+    if bw < 8 && xdec > 0 && src_left_edge < 4 * 8 {
+      return false;
+    }
+    if bh < 8 && ydec > 0 && src_top_edge < 4 * 8 {
+      return false;
+    }
+  }
+
+  // The source must be at least INTRABC_DELAY_SB64 superblocks of 64x64
+  // behind, in coding order
+  let sb_size_log2 = sb_size.width_log2();
+  let total_sb64_per_row = (((fi.w_in_b - 1) >> 4) + 1) as isize;
+  let active_sb_row = y >> sb_size_log2;
+  let active_sb64_col = x >> 6;
+  let active_sb64 = active_sb_row * total_sb64_per_row + active_sb64_col;
+  let src_sb_row = ((src_bottom_edge >> 3) - 1) >> sb_size_log2;
+  let src_sb64_col = ((src_right_edge >> 3) - 1) >> 6;
+  let src_sb64 = src_sb_row * total_sb64_per_row + src_sb64_col;
+  if src_sb64 >= active_sb64 - INTRABC_DELAY_SB64 as isize {
+    return false;
+  }
+
+  // Wavefront constraint: rows of superblocks may be decoded in parallel
+  let gradient =
+    1 + INTRABC_DELAY_SB64 as isize + if sb_size_log2 > 6 { 1 } else { 0 };
+  let wf_offset = gradient * (active_sb_row - src_sb_row);
+  src_sb_row <= active_sb_row
+    && src_sb64_col < active_sb64_col - INTRABC_DELAY_SB64 as isize + wf_offset
+}
+
+/// Hashes of all the square blocks of 8x8 to 64x64 pixels of a plane, for
+/// finding the blocks identical to a given one. Blocks of a single color are
+/// left out, since they are cheaply coded by the intra modes.
+#[derive(Debug)]
+pub struct BlockHashes {
+  // Hash and position of the blocks of each size, sorted by hash
+  tables: Vec<Vec<(u32, u16, u16)>>
+}
+
+impl BlockHashes {
+  pub fn new(plane: &Plane) -> BlockHashes {
+    let PlaneConfig { width, height, .. } = plane.cfg;
+    let mut tables =
+      vec![Vec::new(); HASH_MAX_SIZE_LOG2 - HASH_MIN_SIZE_LOG2 + 1];
+    if width < 2 || height < 2 {
+      return BlockHashes { tables };
+    }
+
+    // Hash and single color, if any, of the block of the current size at
+    // each position. Each level is computed in place from the four quarters,
+    // which are never at a position before the block itself.
+    let mut hashes = vec![0u32; width * height];
+    let mut colors = vec![None; width * height];
+    for y in 0..height - 1 {
+      for x in 0..width - 1 {
+        let p = [
+          plane.p(x, y),
+          plane.p(x + 1, y),
+          plane.p(x, y + 1),
+          plane.p(x + 1, y + 1)
+        ];
+        hashes[y * width + x] =
+          hash_combine([p[0] as u32, p[1] as u32, p[2] as u32, p[3] as u32]);
+        colors[y * width + x] =
+          if p.iter().all(|&v| v == p[0]) { Some(p[0]) } else { None };
+      }
+    }
+
+    for size_log2 in 2..HASH_MAX_SIZE_LOG2 + 1 {
+      let size = 1 << size_log2;
+      let half = size >> 1;
+      if size > width || size > height {
+        break;
+      }
+      for y in 0..height - size + 1 {
+        for x in 0..width - size + 1 {
+          let quarters = [
+            y * width + x,
+            y * width + x + half,
+            (y + half) * width + x,
+            (y + half) * width + x + half
+          ];
+          let color = colors[quarters[0]];
+          hashes[quarters[0]] = hash_combine([
+            hashes[quarters[0]],
+            hashes[quarters[1]],
+            hashes[quarters[2]],
+            hashes[quarters[3]]
+          ]);
+          colors[quarters[0]] = if color.is_some()
+            && quarters[1..].iter().all(|&i| colors[i] == color)
+          {
+            color
+          } else {
+            None
+          };
+          if size_log2 >= HASH_MIN_SIZE_LOG2 && colors[quarters[0]].is_none() {
+            tables[size_log2 - HASH_MIN_SIZE_LOG2].push((
+              hashes[quarters[0]],
+              x as u16,
+              y as u16
+            ));
+          }
+        }
+      }
+    }
+
+    for table in tables.iter_mut() {
+      table.sort_by_key(|&(hash, _, _)| hash);
+    }
+
+    BlockHashes { tables }
+  }
+
+  // Hash and single color, if any, of the square block at `po`
+  fn hash_block(
+    plane: &Plane, po: &PlaneOffset, size_log2: usize
+  ) -> (u32, Option<u16>) {
+    if size_log2 == 1 {
+      let p = [
+        plane.p(po.x, po.y),
+        plane.p(po.x + 1, po.y),
+        plane.p(po.x, po.y + 1),
+        plane.p(po.x + 1, po.y + 1)
+      ];
+      let hash =
+        hash_combine([p[0] as u32, p[1] as u32, p[2] as u32, p[3] as u32]);
+      let color = if p.iter().all(|&v| v == p[0]) { Some(p[0]) } else { None };
+      return (hash, color);
+    }
+
+    let half = 1 << (size_log2 - 1);
+    let quarters = [
+      BlockHashes::hash_block(plane, po, size_log2 - 1),
+      BlockHashes::hash_block(
+        plane,
+        &PlaneOffset { x: po.x + half, y: po.y },
+        size_log2 - 1
+      ),
+      BlockHashes::hash_block(
+        plane,
+        &PlaneOffset { x: po.x, y: po.y + half },
+        size_log2 - 1
+      ),
+      BlockHashes::hash_block(
+        plane,
+        &PlaneOffset { x: po.x + half, y: po.y + half },
+        size_log2 - 1
+      )
+    ];
+    let hash = hash_combine([
+      quarters[0].0,
+      quarters[1].0,
+      quarters[2].0,
+      quarters[3].0
+    ]);
+    let color = quarters[0].1;
+    (
+      hash,
+      if color.is_some() && quarters[1..].iter().all(|q| q.1 == color) {
+        color
+      } else {
+        None
+      }
+    )
+  }
+
+  /// Positions of the blocks of the hashed plane with the same hash as the
+  /// square block of `plane` at `po`
+  pub fn find(
+    &self, plane: &Plane, po: &PlaneOffset, size_log2: usize
+  ) -> &[(u32, u16, u16)] {
+    if size_log2 < HASH_MIN_SIZE_LOG2 || size_log2 > HASH_MAX_SIZE_LOG2 {
+      return &[];
+    }
+    let (hash, color) = BlockHashes::hash_block(plane, po, size_log2);
+    if color.is_some() {
+      return &[];
+    }
+
+    let table = &self.tables[size_log2 - HASH_MIN_SIZE_LOG2];
+    let start = table
+      .binary_search_by(|&(h, _, _)| {
+        if h < hash { Ordering::Less } else { Ordering::Greater }
+      }).unwrap_err();
+    let len = table[start..].iter().take_while(|&&(h, _, _)| h == hash).count();
+    &table[start..start + len]
+  }
+}
+
+/// Displacement vector of the closest to `ref_dv` of the valid copies of the
+/// block at `bo` from an identical block of `input`, if any. Only square
+/// blocks that are hashed are searched, and only vectors that keep the
+/// chroma copy at integer positions are considered.
+pub fn search_dv(
+  fi: &FrameInvariants, sb_size: BlockSize, hashes: &BlockHashes,
+  input: &Plane, bo: &BlockOffset, bsize: BlockSize, ref_dv: MotionVector,
+  xdec: usize, ydec: usize
+) -> Option<MotionVector> {
+  if bsize.width() != bsize.height() {
+    return None;
+  }
+
+  let po = bo.plane_offset(&input.cfg);
+  let mut best: Option<(MotionVector, i32)> = None;
+  for &(_, x, y) in hashes.find(input, &po, bsize.width_log2()) {
+    let row = (y as isize - po.y as isize) * 8;
+    let col = (x as isize - po.x as isize) * 8;
+    if row % (8 << ydec) != 0 || col % (8 << xdec) != 0 {
+      continue;
+    }
+    if row < i16::min_value() as isize
+      || row > i16::max_value() as isize
+      || col < i16::min_value() as isize
+      || col > i16::max_value() as isize
+    {
+      continue;
+    }
+    let dv = MotionVector { row: row as i16, col: col as i16 };
+    if !is_dv_valid(fi, sb_size, bo, bsize, dv, xdec, ydec) {
+      continue;
+    }
+    let dist = (dv.row as i32 - ref_dv.row as i32).abs()
+      + (dv.col as i32 - ref_dv.col as i32).abs();
+    if best.map_or(true, |(_, best_dist)| dist < best_dist) {
+      best = Some((dv, dist));
+    }
+  }
+  best.map(|(dv, _)| dv)
+}
+
+/// Predicts the `w`x`h` block at `po` by copying the already reconstructed
+/// pixels of the same plane at displacement `dv`
+pub fn predict_intrabc(
+  dst: &mut Plane, po: &PlaneOffset, w: usize, h: usize, dv: MotionVector
+) {
+  let PlaneConfig { stride, xdec, ydec, .. } = dst.cfg;
+  let src_x = (po.x as isize + (dv.col as isize >> (3 + xdec))) as usize;
+  let src_y = (po.y as isize + (dv.row as isize >> (3 + ydec))) as usize;
+  for r in 0..h {
+    for c in 0..w {
+      dst.data[(po.y + r) * stride + po.x + c] =
+        dst.data[(src_y + r) * stride + src_x + c];
+    }
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+  use EncoderConfig;
+
+  fn frame_invariants(width: usize, height: usize) -> FrameInvariants {
+    FrameInvariants::new(width, height, EncoderConfig::default())
+  }
+
+  #[test]
+  fn dv_validity() {
+    let fi = frame_invariants(512, 256);
+    let sb_size = BlockSize::BLOCK_64X64;
+    let bsize = BlockSize::BLOCK_16X16;
+
+    // In the first row of superblocks, the source must be 4 superblocks to
+    // the left
+    let bo = BlockOffset { x: 80, y: 0 };
+    let dv = |x: isize, y: isize| MotionVector {
+      row: ((y - (bo.y << MI_SIZE_LOG2) as isize) * 8) as i16,
+      col: ((x - (bo.x << MI_SIZE_LOG2) as isize) * 8) as i16
+    };
+    assert!(is_dv_valid(&fi, sb_size, &bo, bsize, dv(0, 0), 1, 1));
+    assert!(is_dv_valid(&fi, sb_size, &bo, bsize, dv(48, 48), 1, 1));
+    assert!(!is_dv_valid(&fi, sb_size, &bo, bsize, dv(64, 0), 1, 1));
+    assert!(!is_dv_valid(&fi, sb_size, &bo, bsize, dv(-8, 0), 1, 1));
+    let mut subpel = dv(0, 0);
+    subpel.col += 4;
+    assert!(!is_dv_valid(&fi, sb_size, &bo, bsize, subpel, 1, 1));
+
+    // In the second row, the superblocks above and to the right are in the
+    // wavefront
+    let bo = BlockOffset { x: 32, y: 16 };
+    let dv = |x: isize, y: isize| MotionVector {
+      row: ((y - (bo.y << MI_SIZE_LOG2) as isize) * 8) as i16,
+      col: ((x - (bo.x << MI_SIZE_LOG2) as isize) * 8) as i16
+    };
+    assert!(is_dv_valid(&fi, sb_size, &bo, bsize, dv(0, 48), 1, 1));
+    assert!(is_dv_valid(&fi, sb_size, &bo, bsize, dv(176, 0), 1, 1));
+    assert!(!is_dv_valid(&fi, sb_size, &bo, bsize, dv(192, 0), 1, 1));
+    assert!(!is_dv_valid(&fi, sb_size, &bo, bsize, dv(0, 64), 1, 1));
+  }
+
+  #[test]
+  fn hash_search() {
+    let fi = frame_invariants(512, 128);
+    let mut plane = Plane::new(512, 128, 0, 0);
+    // Noise everywhere, and a copy at (384, 64) of the 16x16 block at (16, 0)
+    let mut state = 1u32;
+    for v in plane.data.iter_mut() {
+      state = state.wrapping_mul(1_103_515_245).wrapping_add(12345);
+      *v = (state >> 16) as u16 & 0xff;
+    }
+    let stride = plane.cfg.stride;
+    for r in 0..16 {
+      for c in 0..16 {
+        plane.data[(64 + r) * stride + 384 + c] =
+          plane.data[r * stride + 16 + c];
+      }
+    }
+    let hashes = BlockHashes::new(&plane);
+
+    let bo = BlockOffset { x: 96, y: 16 };
+    let bsize = BlockSize::BLOCK_16X16;
+    let dv = search_dv(
+      &fi,
+      BlockSize::BLOCK_64X64,
+      &hashes,
+      &plane,
+      &bo,
+      bsize,
+      MotionVector::default(),
+      1,
+      1
+    );
+    assert_eq!(dv, Some(MotionVector { row: -64 * 8, col: -368 * 8 }));
+
+    let bo = BlockOffset { x: 96, y: 20 };
+    let dv = search_dv(
+      &fi,
+      BlockSize::BLOCK_64X64,
+      &hashes,
+      &plane,
+      &bo,
+      bsize,
+      MotionVector::default(),
+      1,
+      1
+    );
+    assert_eq!(dv, None);
+  }
+
+  #[test]
+  fn solid_blocks_not_hashed() {
+    let plane = Plane::new(64, 64, 0, 0);
+    let hashes = BlockHashes::new(&plane);
+    assert!(hashes.tables.iter().all(|table| table.is_empty()));
+    assert!(hashes.find(&plane, &PlaneOffset { x: 0, y: 0 }, 3).is_empty());
+  }
+
+  #[test]
+  fn prediction_copy() {
+    let mut plane = Plane::new(32, 32, 1, 1);
+    let stride = plane.cfg.stride;
+    for (i, v) in plane.data.iter_mut().enumerate() {
+      *v = i as u16;
+    }
+    let dv = MotionVector { row: -16 * 8, col: -16 * 8 };
+    predict_intrabc(&mut plane, &PlaneOffset { x: 8, y: 8 }, 4, 4, dv);
+    for r in 0..4 {
+      for c in 0..4 {
+        assert_eq!(
+          plane.data[(8 + r) * stride + 8 + c],
+          (r * stride + c) as u16
+        );
+      }
+    }
+  }
+}
@@ -26,6 +26,7 @@ use std::io::prelude::*;
 use bitstream_io::{BE, LE, BitWriter};
 use clap::{App, Arg};
 use std::rc::Rc;
+use std::cmp;
 
 // for benchmarking purpose
 pub mod ec;
@@ -36,8 +37,15 @@ pub mod transform;
 pub mod quantize;
 pub mod predict;
 pub mod rdo;
+pub mod dist;
 pub mod util;
 pub mod cdef;
+pub mod palette;
+pub mod intrabc;
+pub mod mc;
+pub mod me;
+pub mod compound;
+pub mod mvs;
 
 use context::*;
 use partition::*;
@@ -49,6 +57,12 @@ use ec::*;
 use std::fmt;
 use util::*;
 use cdef::*;
+use palette::*;
+use intrabc::*;
+use mc::*;
+use mvs::*;
+use me::*;
+use compound::*;
 
 extern {
     pub fn av1_rtcd();
@@ -70,17 +84,45 @@ impl Frame {
             ]
         }
     }
+
+    /// A frame of camera-like noise in the luma, the same for every call,
+    /// shared by the tests
+    #[cfg(test)]
+    pub fn noise(width: usize, height: usize) -> Frame {
+        let mut frame = Frame::new(width, height);
+        let mut state = 1u32;
+        for v in frame.planes[0].data.iter_mut() {
+            state = state.wrapping_mul(1_103_515_245).wrapping_add(12345);
+            *v = (state >> 16) as u16 & 0xff;
+        }
+        frame
+    }
 }
 
 #[derive(Debug)]
 pub struct ReferenceFramesSet {
-    pub frames: [Option<Rc<Frame>>; (REF_FRAMES as usize)]
+    pub frames: [Option<Rc<Frame>>; (REF_FRAMES as usize)],
+    // Global motion parameters of the frames, the reference values of the
+    // parameters of the frames predicted from them
+    pub gm_params: [[[i32; 6]; ALTREF_FRAME + 1]; (REF_FRAMES as usize)],
+    pub order_hints: [u32; (REF_FRAMES as usize)],
+    // Order hints of the references of the frames, and their motion vectors,
+    // from which the motion of the frames predicted from them is projected
+    pub ref_order_hints: [[u32; INTER_REFS_PER_FRAME]; (REF_FRAMES as usize)],
+    pub frame_mvs: [Option<Rc<FrameMotionVectors>>; (REF_FRAMES as usize)],
+    // CDFs the frames using them as primary reference frame start from
+    pub cdfs: [Option<Rc<CDFContext>>; (REF_FRAMES as usize)]
 }
 
 impl ReferenceFramesSet {
     pub fn new() -> ReferenceFramesSet {
         ReferenceFramesSet {
-            frames: Default::default()
+            frames: Default::default(),
+            gm_params: [[GM_IDENTITY_PARAMS; ALTREF_FRAME + 1]; REF_FRAMES as usize],
+            order_hints: [0; REF_FRAMES as usize],
+            ref_order_hints: [[0; INTER_REFS_PER_FRAME]; REF_FRAMES as usize],
+            frame_mvs: Default::default(),
+            cdfs: Default::default()
         }
     }
 }
@@ -96,7 +138,9 @@ arg_enum!{
     #[derive(Copy, Clone, Debug, PartialEq)]
     pub enum Tune {
         Psnr,
-        Psychovisual
+        Psychovisual,
+        PsnrHvs,
+        Activity
     }
 }
 
@@ -106,6 +150,93 @@ impl Default for Tune {
     }
 }
 
+arg_enum!{
+    #[derive(Copy, Clone, Debug, PartialEq)]
+    pub enum PredictionModesSetting {
+        Simple,
+        ComplexKeyframes,
+        ComplexAll
+    }
+}
+
+/// Square block sizes the partitions are decided between, larger blocks
+/// always being split and smaller ones never
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct PartitionRange {
+    pub min: BlockSize,
+    pub max: BlockSize
+}
+
+impl PartitionRange {
+    pub fn new(min: BlockSize, max: BlockSize) -> PartitionRange {
+        assert!(min.is_sqr() && max.is_sqr() && min <= max);
+        PartitionRange { min, max }
+    }
+}
+
+/// Search depth and coding tools of the encoder, fewer being used at higher
+/// speed levels
+#[derive(Copy, Clone, Debug)]
+pub struct SpeedSettings {
+    pub partition_range: PartitionRange,
+    /// Whether the partitions into 3 or 4 rectangles are searched, besides
+    /// those into 1, 2 or 4 squares
+    pub ext_partition_types: bool,
+    /// Whether the partitions are decided from the smallest blocks up, rather
+    /// than from the superblocks down
+    pub encode_bottomup: bool,
+    /// Whether the partition search stops splitting blocks coded whole within
+    /// the quantization noise, or whose quadrants look alike
+    pub prune_partitions: bool,
+    /// Frames searching the complex prediction modes: all the intra modes
+    /// with their own chroma modes, angle deltas, filter intra, palettes and
+    /// intra block copy, and the overlapped, warped and inter-intra motion
+    pub prediction_modes: PredictionModesSetting,
+    pub reduced_tx_set: bool,
+    /// Whether the transform sizes and types are decided per block. With
+    /// `rdo_mode_candidates`, this sets the RDO depth: how many candidates
+    /// are fully coded to measure their rate and distortion.
+    pub rdo_tx_decision: bool,
+    /// Number of intra modes, for the luma and for the chroma, kept for RDO
+    /// out of those predicting the block best by SATD, or else all of them.
+    /// At least one mode must be kept.
+    pub rdo_mode_candidates: Option<usize>,
+    /// Whether the global motion of the reference frame is estimated
+    pub global_motion: bool
+}
+
+impl SpeedSettings {
+    /// Settings of the speed level `speed`, from 0 (slow) to 10 (fast). At
+    /// speed 0, the partitions are searched down to 4x4, from the bottom up
+    /// and without pruning.
+    pub fn from_preset(speed: usize) -> SpeedSettings {
+        // Higher speed levels stop splitting at larger block sizes
+        let min = if speed <= 1 { BlockSize::BLOCK_4X4 }
+                  else if speed <= 2 { BlockSize::BLOCK_8X8 }
+                  else if speed <= 3 { BlockSize::BLOCK_16X16 }
+                  else { BlockSize::BLOCK_32X32 };
+
+        SpeedSettings {
+            partition_range: PartitionRange::new(min, BlockSize::BLOCK_128X128),
+            ext_partition_types: speed <= 2,
+            encode_bottomup: speed == 0,
+            prune_partitions: speed >= 1,
+            prediction_modes: if speed <= 3 { PredictionModesSetting::ComplexAll }
+                              else { PredictionModesSetting::Simple },
+            reduced_tx_set: speed > 1,
+            rdo_tx_decision: speed <= 3,
+            rdo_mode_candidates: match speed {
+                0 => None,
+                1 => Some(6),
+                2 => Some(4),
+                3 => Some(3),
+                _ => Some(2)
+            },
+            global_motion: speed <= 3
+        }
+    }
+}
+
 #[derive(Copy,Clone)]
 pub struct Sequence {
   // OBU Sequence header of AV1
@@ -163,7 +294,7 @@ pub struct Sequence {
 }
 
 impl Sequence {
-    pub fn new(width: usize, height: usize) -> Sequence {
+    pub fn new(width: usize, height: usize, config: EncoderConfig) -> Sequence {
         let width_bits = 32 - (width as u32).leading_zeros();
         let height_bits = 32 - (height as u32).leading_zeros();
         assert!(width_bits <= 16);
@@ -189,22 +320,22 @@ impl Sequence {
             frame_id_numbers_present_flag: false,
             frame_id_length: 0,
             delta_frame_id_length: 0,
-            use_128x128_superblock: false,
-            order_hint_bits_minus_1: 0,
+            use_128x128_superblock: config.use_128x128_superblock,
+            order_hint_bits_minus_1: 6,
             force_screen_content_tools: 2,  // 2: adaptive
             force_integer_mv: 2,            // 2: adaptive
             still_picture: false,
             reduced_still_picture_hdr: false,
             monochrome: false,
-            enable_filter_intra: false,
-            enable_intra_edge_filter: false,
-            enable_interintra_compound: false,
-            enable_masked_compound: false,
+            enable_filter_intra: true,
+            enable_intra_edge_filter: true,
+            enable_interintra_compound: true,
+            enable_masked_compound: true,
             enable_dual_filter: false,
-            enable_order_hint: false,
+            enable_order_hint: true,
             enable_jnt_comp: false,
-            enable_ref_frame_mvs: false,
-            enable_warped_motion: false,
+            enable_ref_frame_mvs: true,
+            enable_warped_motion: true,
             enable_superres: false,
             enable_cdef: true,
             enable_restoration: false,
@@ -218,6 +349,22 @@ impl Sequence {
             separate_uv_delta_q: false,
         }
     }
+
+    pub fn sb_size(&self) -> BlockSize {
+        if self.use_128x128_superblock { BlockSize::BLOCK_128X128 } else { BlockSize::BLOCK_64X64 }
+    }
+
+    /// Signed distance from the frame with the order hint `b` to the one with
+    /// `a`, order hints wrapping around
+    pub fn get_relative_dist(&self, a: u32, b: u32) -> i32 {
+        if !self.enable_order_hint {
+            return 0;
+        }
+        let bits = self.order_hint_bits_minus_1 + 1;
+        let diff = a.wrapping_sub(b) & ((1 << bits) - 1);
+        let m = 1 << (bits - 1);
+        (diff & (m - 1)) as i32 - (diff & m) as i32
+    }
 }
 
 #[derive(Debug)]
@@ -225,6 +372,9 @@ pub struct FrameState {
     pub input: Frame,
     pub rec: Frame,
     pub qc: QuantizationContext,
+    pub block_hashes: Option<BlockHashes>,
+    pub frame_mvs: Option<FrameMotionVectors>,
+    pub cdfs: Option<CDFContext>,
 }
 
 impl FrameState {
@@ -233,6 +383,9 @@ impl FrameState {
             input: Frame::new(fi.padded_w, fi.padded_h),
             rec: Frame::new(fi.padded_w, fi.padded_h),
             qc: Default::default(),
+            block_hashes: None,
+            frame_mvs: None,
+            cdfs: None,
         }
     }
 }
@@ -250,6 +403,7 @@ pub struct FrameInvariants {
     pub w_in_b: usize,
     pub h_in_b: usize,
     pub number: u64,
+    pub order_hint: u32,
     pub show_frame: bool,
     pub showable_frame: bool,
     pub error_resilient: bool,
@@ -258,10 +412,13 @@ pub struct FrameInvariants {
     pub frame_type: FrameType,
     pub show_existing_frame: bool,
     pub use_reduced_tx_set: bool,
+    pub tx_mode_select: bool,
     pub reference_mode: ReferenceMode,
-    pub use_prev_frame_mvs: bool,
-    pub min_partition_size: BlockSize,
+    pub skip_mode_present: bool,
+    // References of skip mode blocks, in increasing order
+    pub skip_mode_frames: [usize; 2],
     pub globalmv_transformation_type: [GlobalMVMode; ALTREF_FRAME + 1],
+    pub gm_params: [[i32; 6]; ALTREF_FRAME + 1],
     pub num_tg: usize,
     pub large_scale_tile: bool,
     pub disable_cdf_update: bool,
@@ -288,50 +445,52 @@ pub struct FrameInvariants {
 
 impl FrameInvariants {
     pub fn new(width: usize, height: usize, config: EncoderConfig) -> FrameInvariants {
-        // Speed level decides the minimum partition size, i.e. higher speed --> larger min partition size,
-        // with exception that SBs on right or bottom frame borders split down to BLOCK_4X4.
-        // At speed = 0, RDO search is exhaustive.
-        let min_partition_size = if config.speed <= 1 { BlockSize::BLOCK_4X4 }
-                                 else if config.speed <= 2 { BlockSize::BLOCK_8X8 }
-                                 else if config.speed <= 3 { BlockSize::BLOCK_16X16 }
-                                 else { BlockSize::BLOCK_32X32 };
-        let use_reduced_tx_set = config.speed > 1;
+        let use_reduced_tx_set = config.speed_settings.reduced_tx_set;
+        // Transform sizes are searched per block only when RDO is thorough enough
+        let tx_mode_select = config.speed_settings.rdo_tx_decision;
+        // Overlapped and warped motion are chosen per block by RDO
+        let is_motion_mode_switchable =
+            config.speed_settings.prediction_modes == PredictionModesSetting::ComplexAll;
+        let sb_size_log2 = if config.use_128x128_superblock { 7 } else { 6 };
 
         FrameInvariants {
             width,
             height,
             padded_w: width.align_power_of_two(3),
             padded_h: height.align_power_of_two(3),
-            sb_width: width.align_power_of_two_and_shift(6),
-            sb_height: height.align_power_of_two_and_shift(6),
+            sb_width: width.align_power_of_two_and_shift(sb_size_log2),
+            sb_height: height.align_power_of_two_and_shift(sb_size_log2),
             w_in_b: 2 * width.align_power_of_two_and_shift(3), // MiCols, ((width+7)/8)<<3 >> MI_SIZE_LOG2
             h_in_b: 2 * height.align_power_of_two_and_shift(3), // MiRows, ((height+7)/8)<<3 >> MI_SIZE_LOG2
             number: 0,
+            order_hint: 0,
             show_frame: true,
             showable_frame: true,
-            error_resilient: true,
+            error_resilient: false,
             intra_only: false,
             allow_high_precision_mv: true,
             frame_type: FrameType::KEY,
             show_existing_frame: false,
             use_reduced_tx_set,
+            tx_mode_select,
             reference_mode: ReferenceMode::SINGLE,
-            use_prev_frame_mvs: false,
-            min_partition_size,
+            skip_mode_present: false,
+            skip_mode_frames: [INTRA_FRAME; 2],
             globalmv_transformation_type: [GlobalMVMode::IDENTITY; ALTREF_FRAME + 1],
+            gm_params: [GM_IDENTITY_PARAMS; ALTREF_FRAME + 1],
             num_tg: 1,
             large_scale_tile: false,
-            disable_cdf_update: true,
-            allow_screen_content_tools: 0,
+            disable_cdf_update: false,
+            allow_screen_content_tools: config.screen_content as u32,
             force_integer_mv: 0,
             primary_ref_frame: PRIMARY_REF_NONE,
             refresh_frame_flags: 0,
             allow_intrabc: false,
             use_ref_frame_mvs: false,
             is_filter_switchable: false,
-            is_motion_mode_switchable: false, // 0: only the SIMPLE motion mode will be used.
-            disable_frame_end_update_cdf: true,
-            allow_warped_motion: true,
+            is_motion_mode_switchable,
+            disable_frame_end_update_cdf: false,
+            allow_warped_motion: false,
             cdef_damping: 3,
             cdef_bits: 3,
             cdef_y_strengths: [0*4+0, 1*4+0, 2*4+1, 3*4+1, 5*4+2, 7*4+3, 10*4+3, 13*4+3],
@@ -342,11 +501,69 @@ impl FrameInvariants {
         }
     }
 
+    /// Reference values of the global motion parameters of `ref_frame`,
+    /// saved with the primary reference frame
+    pub fn prev_gm_params(&self, ref_frame: usize) -> [i32; 6] {
+        if self.primary_ref_frame == PRIMARY_REF_NONE {
+            GM_IDENTITY_PARAMS
+        } else {
+            self.rec_buffer.gm_params[self.ref_frames[self.primary_ref_frame as usize]][ref_frame]
+        }
+    }
+
+    /// Whether the complex prediction modes are searched in this frame
+    pub fn complex_prediction_modes(&self) -> bool {
+        match self.config.speed_settings.prediction_modes {
+            PredictionModesSetting::Simple => false,
+            PredictionModesSetting::ComplexKeyframes => self.intra_only,
+            PredictionModesSetting::ComplexAll => true
+        }
+    }
+
+    /// Order hint of the reference frame `ref_frame`
+    pub fn ref_order_hint(&self, ref_frame: usize) -> u32 {
+        self.rec_buffer.order_hints[self.ref_frames[ref_frame - LAST_FRAME]]
+    }
+
+    /// References skip mode blocks could be predicted from: the nearest ones
+    /// before and after the frame, or else the two nearest before it
+    pub fn find_skip_mode_frames(&self, seq: &Sequence) -> Option<[usize; 2]> {
+        if self.intra_only || !seq.enable_order_hint {
+            return None;
+        }
+        // The nearest reference in a direction, the first of equally near ones
+        let nearest = |before: bool, limit: u32| {
+            let mut nearest: Option<(usize, u32)> = None;
+            for ref_frame in LAST_FRAME..ALTREF_FRAME + 1 {
+                let hint = self.ref_order_hint(ref_frame);
+                let dist = seq.get_relative_dist(hint, limit);
+                if (before && dist >= 0) || (!before && dist <= 0) {
+                    continue;
+                }
+                let nearer = nearest.map_or(true, |(_, nearest_hint)| {
+                    let d = seq.get_relative_dist(hint, nearest_hint);
+                    if before { d > 0 } else { d < 0 }
+                });
+                if nearer {
+                    nearest = Some((ref_frame, hint));
+                }
+            }
+            nearest
+        };
+
+        let (forward, forward_hint) = nearest(true, self.order_hint)?;
+        let (second, _) = nearest(false, self.order_hint).or_else(|| nearest(true, forward_hint))?;
+        Some([cmp::min(forward, second), cmp::max(forward, second)])
+    }
+
     pub fn new_frame_state(&self) -> FrameState {
         FrameState {
             input: Frame::new(self.padded_w, self.padded_h),
             rec: Frame::new(self.padded_w, self.padded_h),
             qc: Default::default(),
+            block_hashes: None,
+            frame_mvs: None,
+            cdfs: None,
         }
     }
 }
@@ -423,8 +640,10 @@ pub struct EncoderIO {
 pub struct EncoderConfig {
     pub limit: u64,
     pub quantizer: usize,
-    pub speed: usize,
-    pub tune: Tune
+    pub speed_settings: SpeedSettings,
+    pub tune: Tune,
+    pub use_128x128_superblock: bool,
+    pub screen_content: bool
 }
 
 impl Default for EncoderConfig {
@@ -432,13 +651,20 @@ impl Default for EncoderConfig {
         EncoderConfig {
             limit: 0,
             quantizer: 100,
-            speed: 0,
+            speed_settings: SpeedSettings::from_preset(0),
             tune: Tune::Psnr,
+            use_128x128_superblock: false,
+            screen_content: false,
         }
     }
 }
 
 impl EncoderConfig {
+    /// Default configuration with the settings of the speed level `speed`
+    pub fn with_speed_preset(speed: usize) -> EncoderConfig {
+        EncoderConfig { speed_settings: SpeedSettings::from_preset(speed), ..Default::default() }
+    }
+
     pub fn from_cli() -> (EncoderIO, EncoderConfig) {
         let matches = App::new("rav1e")
             .version("0.1.0")
@@ -473,12 +699,78 @@ impl EncoderConfig {
                 .long("speed")
                 .takes_value(true)
                 .default_value("3"))
+            .arg(Arg::with_name("MIN_PARTITION_SIZE")
+                .help("Smallest block size of the partition search, overriding the speed level")
+                .long("min-partition-size")
+                .possible_values(&BLOCK_SIZE_VALUES)
+                .takes_value(true))
+            .arg(Arg::with_name("MAX_PARTITION_SIZE")
+                .help("Largest block size of the partition search, overriding the speed level")
+                .long("max-partition-size")
+                .possible_values(&BLOCK_SIZE_VALUES)
+                .takes_value(true))
+            .arg(Arg::with_name("EXT_PARTITION_TYPES")
+                .help("Search the partitions into 3 or 4 rectangles, overriding the speed level")
+                .long("ext-partition-types")
+                .possible_values(&["true", "false"])
+                .takes_value(true))
+            .arg(Arg::with_name("BOTTOMUP")
+                .help("Decide the partitions from the smallest blocks up, overriding the speed level")
+                .long("bottomup")
+                .possible_values(&["true", "false"])
+                .takes_value(true))
+            .arg(Arg::with_name("PRUNE_PARTITIONS")
+                .help("Stop splitting blocks coded whole within the quantization noise, or whose quadrants look alike, \
+                       overriding the speed level")
+                .long("prune-partitions")
+                .possible_values(&["true", "false"])
+                .takes_value(true))
+            .arg(Arg::with_name("PREDICTION_MODES")
+                .help("Frames searching the complex prediction modes, overriding the speed level")
+                .long("prediction-modes")
+                .possible_values(&PredictionModesSetting::variants())
+                .case_insensitive(true)
+                .takes_value(true))
+            .arg(Arg::with_name("REDUCED_TX_SET")
+                .help("Use the reduced transform type sets, overriding the speed level")
+                .long("reduced-tx-set")
+                .possible_values(&["true", "false"])
+                .takes_value(true))
+            .arg(Arg::with_name("RDO_TX_DECISION")
+                .help("Decide the transform sizes and types per block, overriding the speed level")
+                .long("rdo-tx-decision")
+                .possible_values(&["true", "false"])
+                .takes_value(true))
+            .arg(Arg::with_name("RDO_MODE_CANDIDATES")
+                .help("Number of intra modes kept for RDO after SATD screening, or \"all\", \
+                       overriding the speed level")
+                .long("rdo-mode-candidates")
+                .validator(|v| match v.as_str() {
+                    "all" => Ok(()),
+                    n => match n.parse::<usize>() {
+                        Ok(n) if n > 0 => Ok(()),
+                        _ => Err(String::from("expected a positive integer or \"all\""))
+                    }
+                })
+                .takes_value(true))
+            .arg(Arg::with_name("GLOBAL_MOTION")
+                .help("Estimate the global motion, overriding the speed level")
+                .long("global-motion")
+                .possible_values(&["true", "false"])
+                .takes_value(true))
             .arg(Arg::with_name("TUNE")
                 .help("Quality tuning")
                 .long("tune")
                 .possible_values(&Tune::variants())
                 .default_value("psnr")
                 .case_insensitive(true))
+            .arg(Arg::with_name("SB128")
+                .help("Use 128x128 superblocks, which suit 4K content")
+                .long("sb128"))
+            .arg(Arg::with_name("SCREEN_CONTENT")
+                .help("Always enable the screen content coding tools, such as palettes, \
+                       instead of only on frames detected as screen content")
+                .long("screen-content"))
             .get_matches();
 
 
@@ -496,17 +788,76 @@ impl EncoderConfig {
             })
         };
 
+        let speed: usize = matches.value_of("SPEED").unwrap().parse().unwrap();
+        if speed > 10 {
+            panic!("argument out of range");
+        }
+
+        // The speed level settings can be overridden one by one
+        let mut speed_settings = SpeedSettings::from_preset(speed);
+        let block_sizes = [BlockSize::BLOCK_4X4, BlockSize::BLOCK_8X8, BlockSize::BLOCK_16X16,
+                           BlockSize::BLOCK_32X32, BlockSize::BLOCK_64X64, BlockSize::BLOCK_128X128];
+        let block_size_value = |bsize: BlockSize| {
+            BLOCK_SIZE_VALUES[block_sizes.iter().position(|&b| b == bsize).unwrap()]
+        };
+        let block_size = |name: &str| {
+            matches.value_of(name).map(|v| {
+                block_sizes[BLOCK_SIZE_VALUES.iter().position(|&s| s == v).unwrap()]
+            })
+        };
+        let flag = |name: &str| matches.value_of(name).map(|v| v.parse::<bool>().unwrap());
+        let min = block_size("MIN_PARTITION_SIZE").unwrap_or(speed_settings.partition_range.min);
+        let max = block_size("MAX_PARTITION_SIZE").unwrap_or(speed_settings.partition_range.max);
+        // Either bound may come from the speed level
+        if min > max {
+            clap::Error::with_description(
+                &format!("The minimum partition size {} is larger than the maximum partition size {}",
+                         block_size_value(min), block_size_value(max)),
+                clap::ErrorKind::ArgumentConflict
+            ).exit();
+        }
+        speed_settings.partition_range = PartitionRange::new(min, max);
+        if let Some(v) = flag("EXT_PARTITION_TYPES") {
+            speed_settings.ext_partition_types = v;
+        }
+        if let Some(v) = flag("BOTTOMUP") {
+            speed_settings.encode_bottomup = v;
+        }
+        if let Some(v) = flag("PRUNE_PARTITIONS") {
+            speed_settings.prune_partitions = v;
+        }
+        if let Some(v) = matches.value_of("PREDICTION_MODES") {
+            speed_settings.prediction_modes = v.parse().unwrap();
+        }
+        if let Some(v) = flag("REDUCED_TX_SET") {
+            speed_settings.reduced_tx_set = v;
+        }
+        if let Some(v) = flag("RDO_TX_DECISION") {
+            speed_settings.rdo_tx_decision = v;
+        }
+        if let Some(v) = matches.value_of("RDO_MODE_CANDIDATES") {
+            speed_settings.rdo_mode_candidates = match v {
+                "all" => None,
+                n => Some(n.parse().unwrap())
+            };
+        }
+        if let Some(v) = flag("GLOBAL_MOTION") {
+            speed_settings.global_motion = v;
+        }
+
         let config = EncoderConfig {
             limit: matches.value_of("LIMIT").unwrap().parse().unwrap(),
             quantizer: matches.value_of("QP").unwrap().parse().unwrap(),
-            speed: matches.value_of("SPEED").unwrap().parse().unwrap(),
-            tune: matches.value_of("TUNE").unwrap().parse().unwrap()
+            speed_settings,
+            tune: matches.value_of("TUNE").unwrap().parse().unwrap(),
+            use_128x128_superblock: matches.is_present("SB128"),
+            screen_content: matches.is_present("SCREEN_CONTENT")
         };
 
         // Validate arguments
         if config.quantizer == 0 {
             unimplemented!();
-        } else if config.quantizer > 255 || config.speed > 10 {
+        } else if config.quantizer > 255 {
             panic!("argument out of range");
         }
 
@@ -514,6 +865,9 @@ impl EncoderConfig {
     }
 }
 
+// Square block sizes selectable on the command line
+static BLOCK_SIZE_VALUES: [&str; 6] = ["4x4", "8x8", "16x16", "32x32", "64x64", "128x128"];
+
 pub fn write_ivf_header(output_file: &mut Write, width: usize, height: usize, num: usize, den: usize) {
     let mut bw = BitWriter::<LE>::new(output_file);
     bw.write_bytes(b"DKIF").unwrap();
@@ -549,12 +903,14 @@ trait UncompressedHeader {
     // End of OBU Headers
 
     fn write_frame_size(&mut self, fi: &FrameInvariants) -> Result<(), std::io::Error>;
-    fn write_sequence_header(&mut self, fi: &FrameInvariants)
+    fn write_sequence_header(&mut self, seq: &Sequence, fi: &FrameInvariants)
                                     -> Result<(), std::io::Error>;
     fn write_bitdepth_colorspace_sampling(&mut self) -> Result<(), std::io::Error>;
     fn write_frame_setup(&mut self) -> Result<(), std::io::Error>;
     fn write_loop_filter(&mut self) -> Result<(), std::io::Error>;
     fn write_frame_cdef(&mut self, seq: &Sequence, fi: &FrameInvariants) -> Result<(), std::io::Error>;
+    fn write_global_motion_params(&mut self, fi: &FrameInvariants) -> Result<(), std::io::Error>;
+    fn write_ref_order_hints(&mut self, seq: &Sequence, fi: &FrameInvariants) -> Result<(), std::io::Error>;
 }
 #[allow(unused)]
 const OP_POINTS_IDC_BITS:usize = 12;
@@ -632,7 +988,7 @@ impl<'a> UncompressedHeader for BitWriter<'a, BE> {
             */
         }
 
-        self.write_sequence_header(fi);
+        self.write_sequence_header(seq, fi);
 
         self.write_bitdepth_colorspace_sampling();
 
@@ -798,15 +1154,14 @@ impl<'a> UncompressedHeader for BitWriter<'a, BE> {
                 seq.force_screen_content_tools);
       }
 
-      if fi.allow_screen_content_tools == 2 {
+      if fi.allow_screen_content_tools != 0 {
         if seq.force_integer_mv == 2 {
           self.write_bit(fi.force_integer_mv != 0)?;
         } else {
           assert!(fi.force_integer_mv == seq.force_integer_mv);
         }
       } else {
-        assert!(fi.allow_screen_content_tools ==
-                seq.force_screen_content_tools);
+        assert!(fi.force_integer_mv == 0);
       }
 
       if seq.frame_id_numbers_present_flag {
@@ -826,7 +1181,7 @@ impl<'a> UncompressedHeader for BitWriter<'a, BE> {
       }
 
       if seq.enable_order_hint {
-        assert!(false); // Not supported by rav1e yet!
+        self.write(seq.order_hint_bits_minus_1 + 1, fi.order_hint)?;
       }
       if fi.error_resilient || fi.intra_only {
 
@@ -858,13 +1213,7 @@ impl<'a> UncompressedHeader for BitWriter<'a, BE> {
       };
 
       if (!fi.intra_only || fi.refresh_frame_flags != ALL_REF_FRAMES_MASK) {
-        // Write all ref frame order hints if error_resilient_mode == 1
-        if (fi.error_resilient && seq.enable_order_hint) {
-          assert!(false); // Not supported by rav1e yet!
-          //for _ in 0..REF_FRAMES {
-          //  self.write(order_hint_bits_minus_1,ref_order_hint[i])?; // order_hint
-          //}
-        }
+        self.write_ref_order_hints(seq, fi)?;
       }
 
       // if KEY or INTRA_ONLY frame
@@ -883,7 +1232,7 @@ impl<'a> UncompressedHeader for BitWriter<'a, BE> {
         }
       }
 
-      let mut frame_refs_short_signaling = false;
+      let frame_refs_short_signaling = false;
       if fi.frame_type == FrameType::KEY {
         // Done by above
       } else {
@@ -891,12 +1240,11 @@ impl<'a> UncompressedHeader for BitWriter<'a, BE> {
           // Done by above
         } else {
           if seq.enable_order_hint {
-            assert!(false); // Not supported by rav1e yet!
             self.write_bit(frame_refs_short_signaling)?;
             if frame_refs_short_signaling {
               assert!(false); // Not supported by rav1e yet!
             }
-          } else { frame_refs_short_signaling = true; }
+          }
 
           for i in LAST_FRAME..ALTREF_FRAME+1 {
             if !frame_refs_short_signaling {
@@ -966,16 +1314,19 @@ impl<'a> UncompressedHeader for BitWriter<'a, BE> {
       // delta_q
       self.write_bit(false)?; // delta_q_present_flag: no delta q
 
-      // loop filter
-      self.write_loop_filter()?;
-      // cdef
-      self.write_frame_cdef(seq, fi)?;
-      // loop restoration
-      // If seq.enable_restoration is false, don't signal about loop restoration
-      if seq.enable_restoration {
-        //self.write(6,0)?; // no y, u or v loop restoration
+      // In-loop filters are disabled in frames with intra block copy
+      if !fi.allow_intrabc {
+        // loop filter
+        self.write_loop_filter()?;
+        // cdef
+        self.write_frame_cdef(seq, fi)?;
+        // loop restoration
+        // If seq.enable_restoration is false, don't signal about loop restoration
+        if seq.enable_restoration {
+          //self.write(6,0)?; // no y, u or v loop restoration
+        }
       }
-      self.write_bit(false)?; // tx mode == TX_MODE_SELECT ?
+      self.write_bit(fi.tx_mode_select)?; // tx mode == TX_MODE_SELECT ?
 
       // frame_reference_mode : reference_select?
       let mut reference_select = false;
@@ -984,11 +1335,8 @@ impl<'a> UncompressedHeader for BitWriter<'a, BE> {
         self.write_bit(reference_select)?;
       }
 
-      let skip_mode_allowed =
-        !(fi.intra_only  || !reference_select || !seq.enable_order_hint);
-      if skip_mode_allowed {
-        assert!(false, "skip_mode_params() is not fully implemented");
-        self.write_bit(false)?; // skip_mode_present
+      if reference_select && fi.find_skip_mode_frames(seq).is_some() {
+        self.write_bit(fi.skip_mode_present)?;
       }
 
       if fi.intra_only || fi.error_resilient || !seq.enable_warped_motion {
@@ -1001,37 +1349,7 @@ impl<'a> UncompressedHeader for BitWriter<'a, BE> {
 
       // global motion
       if fi.intra_only == false {
-        for i in LAST_FRAME..ALTREF_FRAME+1 {
-          let mode = fi.globalmv_transformation_type[i];
-          self.write_bit(mode != GlobalMVMode::IDENTITY)?;
-          if mode != GlobalMVMode::IDENTITY {
-            self.write_bit(mode == GlobalMVMode::ROTZOOM)?;
-            if mode != GlobalMVMode::ROTZOOM {
-                self.write_bit(mode == GlobalMVMode::TRANSLATION)?;
-            }
-          }
-
-          if mode >= GlobalMVMode::ROTZOOM {
-            unimplemented!();
-          }
-          if mode >= GlobalMVMode::AFFINE {
-            unimplemented!();
-          }
-          if mode >= GlobalMVMode::TRANSLATION {
-              let mv_x = 0;
-              let mv_x_ref = 0;
-              let mv_y = 0;
-              let mv_y_ref = 0;
-              let bits = 12 - 6 + 3 - !fi.allow_high_precision_mv as u8;
-              let bits_diff = 12 - 3 + fi.allow_high_precision_mv as u8;
-              BCodeWriter::write_s_refsubexpfin(self, (1 << bits) + 1,
-                                                3, mv_x_ref >> bits_diff,
-                                                mv_x >> bits_diff)?;
-              BCodeWriter::write_s_refsubexpfin(self, (1 << bits) + 1,
-                                                3, mv_y_ref >> bits_diff,
-                                                mv_y >> bits_diff)?;
-          };
-        }
+        self.write_global_motion_params(fi)?;
       }
 
       if seq.film_grain_params_present && fi.show_frame {
@@ -1064,20 +1382,39 @@ impl<'a> UncompressedHeader for BitWriter<'a, BE> {
         self.write(height_bits, (fi.height - 1) as u16)?;
         Ok(())
     }
-  fn write_sequence_header(&mut self, fi: &FrameInvariants)
+  fn write_sequence_header(&mut self, seq: &Sequence, fi: &FrameInvariants)
         -> Result<(), std::io::Error> {
         self.write_frame_size(fi)?;
         self.write_bit(false)?; // don't use frame ids
-        self.write_bit(false)?; // use_128x128_superblock = 0
-        self.write_bit(true)?; // disable intra edge filter
-        self.write_bit(true)?; // allow filter intra
-        self.write_bit(false)?; // interintra_compound
-        self.write_bit(false)?; // masked_compound
-        self.write_bit(false)?; // warped_motion
+        self.write_bit(seq.use_128x128_superblock)?;
+        self.write_bit(seq.enable_filter_intra)?;
+        self.write_bit(seq.enable_intra_edge_filter)?;
+        self.write_bit(seq.enable_interintra_compound)?;
+        self.write_bit(seq.enable_masked_compound)?;
+        self.write_bit(seq.enable_warped_motion)?;
         self.write_bit(false)?; // dual_filter
-        self.write_bit(false)?; // order_hint
-        self.write_bit(false)?; // screen content tools forced
-        self.write_bit(false)?; // screen content tools forced off
+        self.write_bit(seq.enable_order_hint)?;
+        if seq.enable_order_hint {
+            self.write_bit(seq.enable_jnt_comp)?;
+            self.write_bit(seq.enable_ref_frame_mvs)?;
+        }
+        if seq.force_screen_content_tools == 2 {
+            self.write_bit(true)?; // screen content tools chosen per frame
+        } else {
+            self.write_bit(false)?;
+            self.write_bit(seq.force_screen_content_tools != 0)?;
+        }
+        if seq.force_screen_content_tools > 0 {
+            if seq.force_integer_mv == 2 {
+                self.write_bit(true)?; // integer mv chosen per frame
+            } else {
+                self.write_bit(false)?;
+                self.write_bit(seq.force_integer_mv != 0)?;
+            }
+        }
+        if seq.enable_order_hint {
+            self.write(3, seq.order_hint_bits_minus_1)?;
+        }
         self.write_bit(false)?; // no superres
         self.write_bit(true)?; // cdef
         self.write_bit(true)?; // lr
@@ -1118,6 +1455,46 @@ impl<'a> UncompressedHeader for BitWriter<'a, BE> {
         }
         Ok(())
     }
+    fn write_global_motion_params(&mut self, fi: &FrameInvariants) -> Result<(), std::io::Error> {
+        for i in LAST_FRAME..ALTREF_FRAME+1 {
+            let mode = fi.globalmv_transformation_type[i];
+            self.write_bit(mode != GlobalMVMode::IDENTITY)?;
+            if mode != GlobalMVMode::IDENTITY {
+                self.write_bit(mode == GlobalMVMode::ROTZOOM)?;
+                if mode != GlobalMVMode::ROTZOOM {
+                    self.write_bit(mode == GlobalMVMode::TRANSLATION)?;
+                }
+            }
+
+            // Rotation and zoom models derive the last row of the matrix
+            let coded_params: &[usize] = match mode {
+                GlobalMVMode::IDENTITY => &[],
+                GlobalMVMode::TRANSLATION => &[0, 1],
+                GlobalMVMode::ROTZOOM => &[2, 3, 0, 1],
+                GlobalMVMode::AFFINE => &[2, 3, 4, 5, 0, 1]
+            };
+            let prev_params = fi.prev_gm_params(i);
+            for &j in coded_params {
+                let (abs_bits, prec_bits) = gm_param_bits(j, mode, fi.allow_high_precision_mv);
+                let prec_diff = WARPEDMODEL_PREC_BITS - prec_bits;
+                let offset = if j % 3 == 2 { 1 << prec_bits } else { 0 };
+                let r = (prev_params[j] >> prec_diff) - offset;
+                let v = (fi.gm_params[i][j] >> prec_diff) - offset;
+                BCodeWriter::write_s_refsubexpfin(self, (1 << abs_bits) + 1, 3, r as i16, v as i16)?;
+            }
+        }
+        Ok(())
+    }
+    // Error resilient frames signal the order hints of all the reference slots,
+    // which the decoder may have lost
+    fn write_ref_order_hints(&mut self, seq: &Sequence, fi: &FrameInvariants) -> Result<(), std::io::Error> {
+        if fi.error_resilient && seq.enable_order_hint {
+            for i in 0..(REF_FRAMES as usize) {
+                self.write(seq.order_hint_bits_minus_1 + 1, fi.rec_buffer.order_hints[i])?;
+            }
+        }
+        Ok(())
+    }
 }
 
 #[allow(non_camel_case_types)]
@@ -1302,37 +1679,63 @@ fn write_uncompressed_header(packet: &mut Write,
     if fi.intra_only {
     //    bw.write_bit(true)?; // disable_intra_edge_filter = true
     }
-    bw.write_bit(false)?; // disable_cdf_update
+    bw.write_bit(fi.disable_cdf_update)?;
+    if seq.force_screen_content_tools == 2 {
+        bw.write_bit(fi.allow_screen_content_tools != 0)?;
+    } else {
+        assert!(fi.allow_screen_content_tools == seq.force_screen_content_tools);
+    }
+    if fi.allow_screen_content_tools != 0 && seq.force_integer_mv == 2 {
+        bw.write_bit(fi.force_integer_mv != 0)?;
+    }
     //bw.write(8+7,0)?; // frame id
 
     bw.write_bit(false)?; // no override frame size
+    if seq.enable_order_hint {
+        bw.write(seq.order_hint_bits_minus_1 + 1, fi.order_hint)?;
+    }
 
     if fi.frame_type == FrameType::KEY {
         bw.write_frame_setup()?;
+        if fi.allow_screen_content_tools != 0 {
+            bw.write_bit(fi.allow_intrabc)?;
+        }
     } else { // Inter frame info goes here
         if fi.intra_only {
             bw.write(8, fi.refresh_frame_flags)?;
+            bw.write_ref_order_hints(seq, fi)?;
             bw.write_frame_setup()?;
+            if fi.allow_screen_content_tools != 0 {
+                bw.write_bit(fi.allow_intrabc)?;
+            }
         } else {
             bw.write(8, fi.refresh_frame_flags)?;
+            bw.write_ref_order_hints(seq, fi)?;
+            if seq.enable_order_hint {
+                bw.write_bit(false)?; // no frame_refs_short_signaling
+            }
             // TODO: More Inter frame info goes here
             for _ in 0..7 {
                 bw.write(3,0)?; // dummy ref_frame = 0 until real MC happens
             }
             bw.write_frame_setup()?;
-            bw.write_bit(fi.allow_high_precision_mv)?;
+            if fi.force_integer_mv == 0 {
+                bw.write_bit(fi.allow_high_precision_mv)?;
+            }
             bw.write_bit(false)?; // frame_interp_filter is NOT switchable
             bw.write_bit(fi.is_motion_mode_switchable)?;
             bw.write(2,0)?;	// EIGHTTAP_REGULAR
-            if !fi.intra_only && !fi.error_resilient {
-                bw.write_bit(false)?; // do not use_ref_frame_mvs
+            if !fi.error_resilient && seq.enable_ref_frame_mvs {
+                bw.write_bit(fi.use_ref_frame_mvs)?;
             }
         }
     };
 
-    bw.write_bit(false)?; // don't disable backward update
+    if !fi.disable_cdf_update {
+        bw.write_bit(fi.disable_frame_end_update_cdf)?;
+    }
     if !fi.error_resilient && !fi.intra_only {
-        bw.write(3,0x0)?; // frame context
+        bw.write(PRIMARY_REF_BITS, fi.primary_ref_frame)?;
     }
 
     bw.write_bit(true)?; // uniform tile spacing
@@ -1349,17 +1752,22 @@ fn write_uncompressed_header(packet: &mut Write,
     bw.write_bit(false)?; // no qm
     bw.write_bit(false)?; // segmentation off
     bw.write_bit(false)?; // no delta q
-    bw.write_loop_filter()?;
-    bw.write_frame_cdef(seq,fi)?;
-    bw.write(6,0)?; // no y, u or v loop restoration
-    bw.write_bit(false)?; // tx mode select
+    if !fi.allow_intrabc { // in-loop filters are disabled with intra block copy
+        bw.write_loop_filter()?;
+        bw.write_frame_cdef(seq,fi)?;
+        bw.write(6,0)?; // no y, u or v loop restoration
+    }
+    bw.write_bit(fi.tx_mode_select)?; // tx mode select
 
-    //fi.reference_mode = ReferenceMode::SINGLE;
+    let reference_select = !fi.intra_only && fi.reference_mode != ReferenceMode::SINGLE;
     if !fi.intra_only {
-        bw.write_bit(false)?; // single reference mode
+        bw.write_bit(reference_select)?;
     }
-    if fi.reference_mode != ReferenceMode::SINGLE {
-        // setup_compound_reference_mode();
+    if reference_select && fi.find_skip_mode_frames(seq).is_some() {
+        bw.write_bit(fi.skip_mode_present)?;
+    }
+    if !fi.intra_only && !fi.error_resilient && seq.enable_warped_motion {
+        bw.write_bit(fi.allow_warped_motion)?;
     }
 /*
     if !fi.intra_only {
@@ -1370,35 +1778,7 @@ fn write_uncompressed_header(packet: &mut Write,
     bw.write_bit(fi.use_reduced_tx_set)?; // reduced tx
 
     if !fi.intra_only {
-        for i in LAST_FRAME..ALTREF_FRAME+1 {
-            let mode = fi.globalmv_transformation_type[i];
-            bw.write_bit(mode != GlobalMVMode::IDENTITY)?;
-            if mode != GlobalMVMode::IDENTITY {
-                bw.write_bit(mode == GlobalMVMode::ROTZOOM)?;
-                if mode != GlobalMVMode::ROTZOOM {
-                    bw.write_bit(mode == GlobalMVMode::TRANSLATION)?;
-                }
-            }
-            match mode {
-                GlobalMVMode::IDENTITY => { /* Nothing to do */ }
-                GlobalMVMode::TRANSLATION => {
-                    let mv_x = 0;
-                    let mv_x_ref = 0;
-                    let mv_y = 0;
-                    let mv_y_ref = 0;
-                    let bits = 12 - 6 + 3 - !fi.allow_high_precision_mv as u8;
-                    let bits_diff = 12 - 3 + fi.allow_high_precision_mv as u8;
-                    BCodeWriter::write_s_refsubexpfin(&mut bw, (1 << bits) + 1,
-                                                      3, mv_x_ref >> bits_diff,
-                                                      mv_x >> bits_diff)?;
-                    BCodeWriter::write_s_refsubexpfin(&mut bw, (1 << bits) + 1,
-                                                      3, mv_y_ref >> bits_diff,
-                                                      mv_y >> bits_diff)?;
-                }
-                GlobalMVMode::ROTZOOM => unimplemented!(),
-                GlobalMVMode::AFFINE => unimplemented!(),
-            };
-        }
+        bw.write_global_motion_params(fi)?;
     }
     bw.write_bit(true)?; // trailing bit
     // if tile_cols * tile_rows > 1
@@ -1417,27 +1797,75 @@ fn diff(dst: &mut [i16], src1: &PlaneSlice, src2: &PlaneSlice, width: usize, hei
     }
 }
 
+// Luma AC contribution to the chroma-from-luma prediction of the chroma transform
+// block at `po`: the reconstructed luma averaged down to the chroma resolution,
+// scaled by 8, minus its mean
+pub fn luma_ac(ac: &mut [i16], fs: &FrameState, po: &PlaneOffset, tx_size: TxSize,
+               xdec: usize, ydec: usize) {
+    let luma = fs.rec.planes[0].slice(&PlaneOffset { x: po.x << xdec, y: po.y << ydec });
+    let w = tx_size.width();
+    let h = tx_size.height();
+
+    let mut sum: i32 = 0;
+    for j in 0..h {
+        for i in 0..w {
+            let mut t: i32 = 0;
+            for dy in 0..ydec + 1 {
+                for dx in 0..xdec + 1 {
+                    t += luma.p((i << xdec) + dx, (j << ydec) + dy) as i32;
+                }
+            }
+            let v = t << (3 - xdec - ydec);
+            ac[j * w + i] = v as i16;
+            sum += v;
+        }
+    }
+
+    let shift = tx_size.area_log2();
+    let avg = ((sum + (1 << (shift - 1))) >> shift) as i16;
+    for v in ac[..w * h].iter_mut() {
+        *v -= avg;
+    }
+}
+
 // For a transform block,
 // predict, transform, quantize, write coefficients to a bitstream,
 // dequantize, inverse-transform.
 pub fn encode_tx_block(fi: &FrameInvariants, fs: &mut FrameState, cw: &mut ContextWriter, w: &mut Writer,
-                  p: usize, bo: &BlockOffset, mode: PredictionMode, tx_size: TxSize, tx_type: TxType,
+                  p: usize, bo: &BlockOffset, mode: PredictionMode, angle_delta: i8,
+                  edge_filter: IntraEdgeFilter, cfl: CFLParams, filter_intra: Option<FilterIntraMode>,
+                  palette: PaletteParams, intrabc: Option<MotionVector>, tx_size: TxSize, tx_type: TxType,
                   plane_bsize: BlockSize, po: &PlaneOffset, skip: bool) -> bool {
-    let rec = &mut fs.rec.planes[p];
     let PlaneConfig { stride, xdec, ydec, .. } = fs.input.planes[p].cfg;
 
-    if mode.is_intra() {
-      mode.predict_intra(&mut rec.mut_slice(po), tx_size);
+    let mut ac: AlignedArray<[i16; 32 * 32]> = UninitializedAlignedArray();
+    let alpha = if mode == PredictionMode::UV_CFL_PRED {
+        luma_ac(&mut ac.array, fs, po, tx_size, xdec, ydec);
+        cfl.alpha[p - 1]
     } else {
-      mode.predict_inter(fi, p, po, &mut rec.mut_slice(po), tx_size);
+        0
+    };
+
+    let rec = &mut fs.rec.planes[p];
+
+    if let Some(dv) = intrabc {
+      predict_intrabc(rec, po, tx_size.width(), tx_size.height(), dv);
+    } else if mode == PredictionMode::DC_PRED && palette.size[(p > 0) as usize] > 0 {
+      predict_palette(rec, &fs.input, p, po, tx_size.width(), tx_size.height(), &palette);
+    } else if mode.is_intra() {
+      let (have_above_right, have_below_left) = cw.bc.tx_edges_decoded(p, po, tx_size, xdec, ydec);
+      mode.predict_intra(&mut rec.mut_slice(po), tx_size, angle_delta, edge_filter,
+                         have_above_right, have_below_left, &ac.array, alpha, filter_intra);
     }
+    // Inter blocks are predicted as a whole by write_tx_tree
+
+    cw.bc.set_tx_decoded(p, po, tx_size, xdec, ydec);
 
     if skip { return false; }
 
     let mut residual: AlignedArray<[i16; 64 * 64]> = UninitializedAlignedArray();
     let mut coeffs_storage: AlignedArray<[i32; 64 * 64]> = UninitializedAlignedArray();
     let mut rcoeffs: AlignedArray<[i32; 64 * 64]> = UninitializedAlignedArray();
-    let coeffs = &mut coeffs_storage.array[..tx_size.area()];
 
     diff(&mut residual.array,
          &fs.input.planes[p].slice(po),
@@ -1445,7 +1873,11 @@ pub fn encode_tx_block(fi: &FrameInvariants, fs: &mut FrameState, cw: &mut Conte
          tx_size.width(),
          tx_size.height());
 
-    forward_transform(&residual.array, coeffs, tx_size.width(), tx_size, tx_type);
+    forward_transform(&residual.array, &mut coeffs_storage.array, tx_size.width(), tx_size, tx_type);
+
+    // 64-point transforms leave their coefficients packed in at most 32x32
+    let coded_tx_size = cw.av1_get_adjusted_tx_size(tx_size);
+    let coeffs = &mut coeffs_storage.array[..coded_tx_size.area()];
     fs.qc.quantize(coeffs);
 
     let has_coeff = cw.write_coeffs_lv_map(w, p, bo, &coeffs, tx_size, tx_type, plane_bsize, xdec, ydec,
@@ -1460,226 +1892,633 @@ pub fn encode_tx_block(fi: &FrameInvariants, fs: &mut FrameState, cw: &mut Conte
 
 fn encode_block(seq: &Sequence, fi: &FrameInvariants, fs: &mut FrameState,
             cw: &mut ContextWriter, w: &mut Writer,
-            luma_mode: PredictionMode, chroma_mode: PredictionMode,
-            bsize: BlockSize, bo: &BlockOffset, skip: bool, cdef_index: u8) {
-    let is_inter = !luma_mode.is_intra();
+            luma_mode: PredictionMode, chroma_mode: PredictionMode, angle_delta: AngleDelta,
+            cfl: CFLParams, filter_intra: Option<FilterIntraMode>, palette: PaletteParams,
+            intrabc: Option<MotionVector>, motion_mode: MotionMode, interintra: Option<InterIntraParams>,
+            compound_mask: Option<CompoundMask>, tx_tree: &TxTree, bsize: BlockSize, bo: &BlockOffset,
+            skip: bool, cdef_index: u8) {
+    let is_inter = !luma_mode.is_intra() || intrabc.is_some();
+    // The only compound mode rav1e codes predicts from the skip mode references.
+    // Blocks averaging the predictions are coded in skip mode, and those
+    // blending them with a mask as regular inter blocks.
+    let is_compound = luma_mode == PredictionMode::NEAREST_NEARESTMV;
+    let skip_mode = is_compound && compound_mask.is_none();
+    assert!(compound_mask.is_none() || is_compound);
+
+    if skip_mode_allowed(fi, bsize) {
+        cw.write_skip_mode(w, bo, skip_mode);
+    } else {
+        assert!(!skip_mode);
+    }
+    cw.bc.set_skip_mode(bo, bsize, skip_mode);
 
+    // Skip mode blocks have no residual, and signal nothing else
+    assert!(!skip_mode || skip);
     cw.bc.set_skip(bo, bsize, skip);
-    cw.write_skip(w, bo, skip);
-    if seq.enable_cdef {
+    if !skip_mode {
+        cw.write_skip(w, bo, skip);
+    }
+    if seq.enable_cdef && !fi.allow_intrabc {
         cw.bc.set_cdef(bo, bsize, cdef_index);
         cw.write_block_cdef(w, bo, skip, cdef_index, fi.cdef_bits);
     }
 
-    if fi.frame_type == FrameType::INTER {
+    cw.bc.set_intrabc(bo, bsize, intrabc);
+
+    if skip_mode {
+        cw.bc.set_ref_frames(bo, bsize, fi.skip_mode_frames);
+        let (mvs, _) = compound_mvs(fi, &cw.bc, bo, bsize);
+        cw.bc.set_motion_vectors(bo, bsize, mvs);
+        cw.bc.set_interintra(bo, bsize, None);
+        cw.bc.set_motion_mode(bo, bsize, MotionMode::SIMPLE_TRANSLATION);
+    } else if fi.frame_type == FrameType::INTER {
         cw.write_is_inter(w, bo, is_inter);
-        if is_inter {
+        if is_compound {
+            cw.fill_neighbours_ref_counts(bo);
+            cw.bc.set_ref_frames(bo, bsize, fi.skip_mode_frames);
+            cw.write_ref_frames(w, fi.reference_mode != ReferenceMode::SINGLE, bo, bsize);
+            let (mvs, mode_context) = compound_mvs(fi, &cw.bc, bo, bsize);
+            cw.write_compound_mode(w, luma_mode, mode_context);
+            cw.bc.set_motion_vectors(bo, bsize, mvs);
+
+            // Compound blocks are neither blended with intra nor overlapped
+            assert!(interintra.is_none());
+            cw.bc.set_interintra(bo, bsize, None);
+            cw.bc.set_motion_mode(bo, bsize, MotionMode::SIMPLE_TRANSLATION);
+
+            if seq.enable_masked_compound {
+                cw.write_compound_type(w, bo, compound_mask, bsize);
+            } else {
+                assert!(compound_mask.is_none());
+            }
+        } else if is_inter {
             cw.fill_neighbours_ref_counts(bo);
-            cw.bc.set_ref_frame(bo, bsize, LAST_FRAME);
-            cw.write_ref_frames(w, bo);
-            // FIXME: need more generic context derivation
-            let mode_context = if bo.x == 0 && bo.y == 0 { 0 } else if bo.x ==0 || bo.y == 0 { 51 } else { 85 };
-            // NOTE: Until rav1e supports other inter modes than GLOBALMV
-            assert!(luma_mode == PredictionMode::GLOBALMV);
+            cw.bc.set_ref_frames(bo, bsize, [LAST_FRAME, INTRA_FRAME]);
+            cw.write_ref_frames(w, fi.reference_mode != ReferenceMode::SINGLE, bo, bsize);
+            let gm_mv = global_mv(&fi.gm_params[LAST_FRAME], fi.globalmv_transformation_type[LAST_FRAME],
+                                  bo, bsize, fi.allow_high_precision_mv, fi.force_integer_mv != 0);
+            let (mv_stack, mode_context) = cw.bc.find_mv_stack(bo, bsize, LAST_FRAME, gm_mv);
             cw.write_inter_mode(w, luma_mode, mode_context);
+            // NEARMV only uses the first candidate after the nearest one
+            if luma_mode == PredictionMode::NEARMV && mv_stack.len() > 2 {
+                cw.write_drl_mode(w, &mv_stack, 1, false);
+            }
+            let mv = inter_mv(luma_mode, &mv_stack, gm_mv);
+            cw.bc.set_motion_vectors(bo, bsize, [mv, MotionVector::default()]);
+
+            if seq.enable_interintra_compound && interintra_allowed(bsize) {
+                cw.write_interintra(w, interintra, bsize);
+            } else {
+                assert!(interintra.is_none());
+            }
+            cw.bc.set_interintra(bo, bsize, interintra);
+
+            // The neighbours may have changed since the motion mode was decided
+            let max_mode = max_motion_mode(fi, &cw.bc, luma_mode, bsize, bo, mv, interintra.is_some());
+            let motion_mode = if motion_mode <= max_mode { motion_mode } else { MotionMode::SIMPLE_TRANSLATION };
+            match max_mode {
+                MotionMode::SIMPLE_TRANSLATION => (),
+                MotionMode::OBMC_CAUSAL => cw.write_use_obmc(w, motion_mode == MotionMode::OBMC_CAUSAL, bsize),
+                MotionMode::WARPED_CAUSAL => cw.write_motion_mode(w, motion_mode, bsize)
+            }
+            cw.bc.set_motion_mode(bo, bsize, motion_mode);
         } else {
+            cw.bc.set_ref_frames(bo, bsize, [INTRA_FRAME; 2]);
             cw.write_intra_mode(w, bsize, luma_mode);
         }
     } else {
-        cw.write_intra_mode_kf(w, bo, luma_mode);
+        if fi.allow_intrabc {
+            cw.write_use_intrabc(w, intrabc.is_some());
+        }
+        if let Some(dv) = intrabc {
+            let ref_dv = cw.bc.ref_dv(bo, bsize);
+            cw.write_mv(w, dv, ref_dv, MV_INTRABC_CONTEXT, MvSubpelPrecision::MV_SUBPEL_NONE);
+        } else {
+            cw.write_intra_mode_kf(w, bo, luma_mode);
+        }
+    }
+    if luma_mode.is_intra() {
+        cw.bc.set_motion_mode(bo, bsize, MotionMode::SIMPLE_TRANSLATION);
+        cw.bc.set_interintra(bo, bsize, None);
     }
+    cw.bc.set_compound_mask(bo, bsize, compound_mask);
 
+    cw.bc.set_block_size(bo, bsize);
     cw.bc.set_mode(bo, bsize, luma_mode);
 
     let PlaneConfig { xdec, ydec, .. } = fs.input.planes[1].cfg;
 
     if luma_mode.is_directional() && bsize >= BlockSize::BLOCK_8X8 {
-        cw.write_angle_delta(w, 0, luma_mode);
+        cw.write_angle_delta(w, angle_delta.y, luma_mode);
     }
 
-      if has_chroma(bo, bsize, xdec, ydec) && !is_inter {
+    let is_chroma_block = has_chroma(bo, bsize, xdec, ydec);
+
+    if is_chroma_block && !is_inter {
         cw.write_intra_uv_mode(w, chroma_mode, luma_mode, bsize);
+        if chroma_mode == PredictionMode::UV_CFL_PRED {
+            cw.write_cfl_alphas(w, cfl);
+        }
         if chroma_mode.is_directional() && bsize >= BlockSize::BLOCK_8X8 {
-            cw.write_angle_delta(w, 0, chroma_mode);
+            cw.write_angle_delta(w, angle_delta.uv, chroma_mode);
         }
+        cw.bc.set_uv_mode(bo, bsize, chroma_mode);
+    } else {
+        cw.bc.set_uv_mode(bo, bsize, PredictionMode::DC_PRED);
     }
 
+    if !is_inter && palette_allowed(fi, bsize) {
+        cw.write_palette_mode_info(w, bo, bsize, luma_mode, chroma_mode, is_chroma_block, &palette);
+    } else {
+        assert!(palette.size == [0, 0]);
+    }
+    cw.bc.set_palette(bo, bsize, palette);
+
     if skip {
         cw.bc.reset_skip_context(bo, bsize, xdec, ydec);
     }
 
-    // these rules follow TX_MODE_LARGEST
-    let tx_size = match bsize {
-        BlockSize::BLOCK_4X4 => TxSize::TX_4X4,
-        BlockSize::BLOCK_8X8 => TxSize::TX_8X8,
-        BlockSize::BLOCK_16X16 => TxSize::TX_16X16,
-        _ => TxSize::TX_32X32
-    };
+    // Filter intra replaces DC_PRED, unless a palette does
+    if !is_inter && filter_intra_allowed(seq, luma_mode, bsize) && palette.size[0] == 0 {
+        cw.write_use_filter_intra(w, filter_intra.is_some(), bsize);
+        if let Some(mode) = filter_intra {
+            cw.write_filter_intra_mode(w, mode);
+        }
+    } else {
+        assert!(filter_intra.is_none());
+    }
+    cw.bc.set_filter_intra_mode(bo, bsize, filter_intra);
 
-    // TODO: Extra condition related to palette mode, see `read_filter_intra_mode_info` in decodemv.c
-    if luma_mode == PredictionMode::DC_PRED && bsize.width() <= 32 && bsize.height() <= 32 {
-        cw.write_use_filter_intra(w,false, bsize); // Always turn off FILTER_INTRA
+    for plane in 0..2 {
+        if palette.size[plane] > 0 {
+            write_palette_color_map(fs, cw, w, bo, bsize, plane, &palette);
+        }
     }
 
-    // Luma plane transform type decision
+    // RDO only splits the transforms of the blocks signaling their size, and
+    // those of intra blocks all alike
+    let tx_size_coded = tx_size_coded(fi, bsize, is_inter, skip);
+    assert!(is_inter || tx_tree.uniform_size().is_some());
+    let tx_size = tx_tree.max_size();
+    assert!(tx_size_coded || tx_size == bsize.largest_tx_size());
+
+    // Luma plane transform type decision, among the types the largest
+    // transforms allow, which the smaller ones split from them allow too
     let tx_set = get_tx_set(tx_size, is_inter, fi.use_reduced_tx_set);
 
-    let tx_type = if tx_set > TxSet::TX_SET_DCTONLY && fi.config.speed <= 3 {
+    let tx_type = if tx_set > TxSet::TX_SET_DCTONLY && fi.config.speed_settings.rdo_tx_decision {
         // FIXME: there is one redundant transform type decision per encoded block
-        rdo_tx_type_decision(fi, fs, cw, w, luma_mode, bsize, bo, tx_size, tx_set)
+        rdo_tx_type_decision(seq, fi, fs, cw, luma_mode, angle_delta, filter_intra, palette, intrabc,
+                             bsize, bo, tx_tree, tx_set)
     } else {
         TxType::DCT_DCT
     };
 
+    if tx_size_coded {
+        if is_inter {
+            cw.write_tx_size_inter(w, bo, bsize, tx_tree);
+        } else {
+            cw.write_tx_size_intra(w, bo, bsize, tx_size);
+            cw.bc.update_tx_size_context(bo, bsize, tx_size, false);
+        }
+    } else {
+        cw.bc.update_tx_size_context(bo, bsize, tx_size, is_inter && skip);
+    }
+
     // if inter, predict here
     //mode.predict(&mut rec.mut_slice(po), tx_size);
 
     if is_inter {
-        write_tx_tree(fi, fs, cw, w, luma_mode, chroma_mode, bo, bsize, tx_size, tx_type, skip); // i.e. var-tx if inter mode
+        write_tx_tree(fi, fs, cw, w, luma_mode, chroma_mode, intrabc, bo, bsize, tx_tree, tx_type,
+                      skip); // i.e. var-tx if inter mode
     } else {
-        write_tx_blocks(fi, fs, cw, w, luma_mode, chroma_mode, bo, bsize, tx_size, tx_type, skip);
+        write_tx_blocks(seq, fi, fs, cw, w, luma_mode, chroma_mode, angle_delta, cfl, filter_intra,
+                        palette, bo, bsize, tx_size, tx_type, skip);
     }
 }
 
-pub fn write_tx_blocks(fi: &FrameInvariants, fs: &mut FrameState,
-                       cw: &mut ContextWriter, w: &mut Writer,
-                       luma_mode: PredictionMode, chroma_mode: PredictionMode, bo: &BlockOffset,
-                       bsize: BlockSize, tx_size: TxSize, tx_type: TxType, skip: bool) {
-    let bw = bsize.width_mi() / tx_size.width_mi();
-    let bh = bsize.height_mi() / tx_size.height_mi();
+// Whether the luma of a block can be predicted with filter intra instead of DC_PRED
+pub fn filter_intra_allowed(seq: &Sequence, luma_mode: PredictionMode, bsize: BlockSize) -> bool {
+    seq.enable_filter_intra && luma_mode == PredictionMode::DC_PRED &&
+        bsize.width() <= 32 && bsize.height() <= 32
+}
 
-    let PlaneConfig { xdec, ydec, .. } = fs.input.planes[1].cfg;
+// Whether the intra blocks of size `bsize` can be predicted with palettes
+pub fn palette_allowed(fi: &FrameInvariants, bsize: BlockSize) -> bool {
+    fi.allow_screen_content_tools != 0 && bsize.palette_allowed()
+}
 
-    fs.qc.update(fi.config.quantizer, tx_size);
+// Whether the transform size of the blocks of size `bsize` is signaled, which
+// needs TX_MODE_SELECT. Skipped inter blocks always use the largest transform.
+pub fn tx_size_coded(fi: &FrameInvariants, bsize: BlockSize, is_inter: bool, skip: bool) -> bool {
+    fi.tx_mode_select && bsize > BlockSize::BLOCK_4X4 && !(is_inter && skip)
+}
 
-    for by in 0..bh {
-        for bx in 0..bw {
-            let tx_bo = BlockOffset {
-                x: bo.x + bx * tx_size.width_mi(),
-                y: bo.y + by * tx_size.height_mi()
-            };
+// Whether the blocks of size `bsize` can use skip mode
+pub fn skip_mode_allowed(fi: &FrameInvariants, bsize: BlockSize) -> bool {
+    fi.skip_mode_present && bsize.width() >= 8 && bsize.height() >= 8
+}
 
-            let po = tx_bo.plane_offset(&fs.input.planes[0].cfg);
-            encode_tx_block(fi, fs, cw, w, 0, &tx_bo, luma_mode, tx_size, tx_type, bsize, &po, skip);
-        }
-    }
+// Motion vectors of the compound block at `bo`, the nearest candidates for the
+// skip mode references, and the context of its mode
+pub fn compound_mvs(fi: &FrameInvariants, bc: &BlockContext, bo: &BlockOffset,
+                    bsize: BlockSize) -> ([MotionVector; 2], usize) {
+    let ref_frames = fi.skip_mode_frames;
+    let gm_mv = |ref_frame: usize| {
+        global_mv(&fi.gm_params[ref_frame], fi.globalmv_transformation_type[ref_frame],
+                  bo, bsize, fi.allow_high_precision_mv, fi.force_integer_mv != 0)
+    };
+    let (mv_stack, mode_context) =
+        bc.find_compound_mv_stack(bo, bsize, ref_frames, [gm_mv(ref_frames[0]), gm_mv(ref_frames[1])]);
+    ([mv_stack[0].this_mv, mv_stack[0].comp_mv], mode_context)
+}
+
+// Whether the blocks of size `bsize` can blend the predictions from the skip
+// mode references with a mask. Only some pairs of references on the same side
+// of the frame can be coded outside skip mode.
+pub fn masked_compound_allowed(seq: &Sequence, fi: &FrameInvariants, bsize: BlockSize) -> bool {
+    let rf = fi.skip_mode_frames;
+    let codable = match (rf[0] >= BWDREF_FRAME, rf[1] >= BWDREF_FRAME) {
+        (false, true) => true,
+        (false, false) => rf[0] == LAST_FRAME,
+        (true, true) => rf == [BWDREF_FRAME, ALTREF_FRAME],
+        (true, false) => false
+    };
+    seq.enable_masked_compound && skip_mode_allowed(fi, bsize) && codable
+}
 
-    // these are only valid for 4:2:0
-    let uv_tx_size = match bsize {
-        BlockSize::BLOCK_4X4 | BlockSize::BLOCK_8X8 => TxSize::TX_4X4,
-        BlockSize::BLOCK_16X16 => TxSize::TX_8X8,
-        BlockSize::BLOCK_32X32 => TxSize::TX_16X16,
-        _ => TxSize::TX_32X32
+// Motion vector of the inter `mode` from the candidates in `mv_stack`, the global
+// motion vector `gm_mv` standing in for missing ones. Only the single reference
+// modes without a coded motion vector, which are the ones RDO tries, have one.
+pub fn inter_mv(mode: PredictionMode, mv_stack: &[CandidateMV], gm_mv: MotionVector) -> MotionVector {
+    let idx = match mode {
+        PredictionMode::GLOBALMV => return gm_mv,
+        PredictionMode::NEARESTMV => 0,
+        PredictionMode::NEARMV => 1,
+        _ => unreachable!("{:?} does not take its motion vector from the candidates", mode)
     };
+    mv_stack.get(idx).map_or(gm_mv, |c| c.this_mv)
+}
+
+// The most elaborate motion mode the inter block at `bo` with the motion vector
+// `mv` can use, the simpler ones being allowed too. Overlapping needs inter
+// neighbours, and warping needs neighbours with the same reference. Blocks
+// blended with an intra prediction are only translated.
+pub fn max_motion_mode(fi: &FrameInvariants, bc: &BlockContext, luma_mode: PredictionMode,
+                       bsize: BlockSize, bo: &BlockOffset, mv: MotionVector, interintra: bool) -> MotionMode {
+    let ref_frame = LAST_FRAME;
+    if !fi.is_motion_mode_switchable || cmp::min(bsize.width(), bsize.height()) < 8 || interintra {
+        return MotionMode::SIMPLE_TRANSLATION;
+    }
+    // Non-translational global motion already warps GLOBALMV blocks
+    if fi.force_integer_mv == 0 && luma_mode == PredictionMode::GLOBALMV &&
+        fi.globalmv_transformation_type[ref_frame] > GlobalMVMode::TRANSLATION {
+        return MotionMode::SIMPLE_TRANSLATION;
+    }
+    if !bc.has_overlappable_candidates(bo, bsize) {
+        return MotionMode::SIMPLE_TRANSLATION;
+    }
+    if fi.force_integer_mv != 0 || !fi.allow_warped_motion ||
+        bc.find_warp_samples(bo, bsize, ref_frame, mv).is_empty() {
+        MotionMode::OBMC_CAUSAL
+    } else {
+        MotionMode::WARPED_CAUSAL
+    }
+}
 
-    let mut bw_uv = (bw * tx_size.width_mi()) >> xdec;
-    let mut bh_uv = (bh * tx_size.height_mi()) >> ydec;
+// Codes the color index map of the luma (plane 0) or chroma (plane 1) palette
+// of the block at `bo`
+fn write_palette_color_map(fs: &FrameState, cw: &mut ContextWriter, w: &mut Writer,
+                           bo: &BlockOffset, bsize: BlockSize, plane: usize,
+                           palette: &PaletteParams) {
+    let cfg = &fs.input.planes[plane].cfg;
+    let (po, block_w, block_h) = color_map_area(bo, bsize, cfg);
 
-    if (bw_uv == 0 || bh_uv == 0) && has_chroma(bo, bsize, xdec, ydec) {
-        bw_uv = 1;
-        bh_uv = 1;
+    // The decoder extends the map past the edges of the frame
+    let onscreen_w = cmp::min(block_w, cfg.width - po.x);
+    let onscreen_h = cmp::min(block_h, cfg.height - po.y);
+
+    let mut map = [0u8; 64 * 64];
+    color_map(&mut map, &fs.input, plane, &po, block_w, block_h, palette);
+
+    cw.write_palette_color_map(w, plane, &map, block_w, onscreen_w, onscreen_h,
+                               palette.size[plane]);
+}
+
+// Edge filtering of the directional intra predictions in plane `p` of the block at `bo`
+fn intra_edge_filter(seq: &Sequence, cw: &ContextWriter, bo: &BlockOffset,
+                     p: usize, xdec: usize, ydec: usize) -> IntraEdgeFilter {
+    if !seq.enable_intra_edge_filter {
+        IntraEdgeFilter::Disabled
+    } else if cw.bc.has_smooth_neighbor(bo, p, xdec, ydec) {
+        IntraEdgeFilter::Smooth
+    } else {
+        IntraEdgeFilter::Regular
     }
+}
 
-    bw_uv /= uv_tx_size.width_mi();
-    bh_uv /= uv_tx_size.height_mi();
+// Blocks larger than 64x64 are coded in 64x64 units, each with its luma then its chroma
+const MAX_CODING_UNIT_MI: usize = 64 >> MI_SIZE_LOG2;
 
-    let plane_bsize = get_plane_block_size(bsize, xdec, ydec);
+pub fn write_tx_blocks(seq: &Sequence, fi: &FrameInvariants, fs: &mut FrameState,
+                       cw: &mut ContextWriter, w: &mut Writer,
+                       luma_mode: PredictionMode, chroma_mode: PredictionMode, angle_delta: AngleDelta,
+                       cfl: CFLParams, filter_intra: Option<FilterIntraMode>, palette: PaletteParams,
+                       bo: &BlockOffset, bsize: BlockSize, tx_size: TxSize, tx_type: TxType, skip: bool) {
+    let unit_w = cmp::min(bsize.width_mi(), MAX_CODING_UNIT_MI);
+    let unit_h = cmp::min(bsize.height_mi(), MAX_CODING_UNIT_MI);
+    let bw = unit_w / tx_size.width_mi();
+    let bh = unit_h / tx_size.height_mi();
+
+    let uv_tx_type = uv_intra_mode_to_tx_type_context(chroma_mode);
+
+    let PlaneConfig { xdec, ydec, .. } = fs.input.planes[1].cfg;
+    let edge_filter_y = intra_edge_filter(seq, cw, bo, 0, xdec, ydec);
+    let edge_filter_uv = intra_edge_filter(seq, cw, bo, 1, xdec, ydec);
+
+    for uy in 0..bsize.height_mi() / unit_h {
+        for ux in 0..bsize.width_mi() / unit_w {
+            let unit_bo = BlockOffset {
+                x: bo.x + ux * unit_w,
+                y: bo.y + uy * unit_h
+            };
 
-    if bw_uv > 0 && bh_uv > 0 {
-        let uv_tx_type = uv_intra_mode_to_tx_type_context(chroma_mode);
-        let partition_x = (bo.x & LOCAL_BLOCK_MASK) >> xdec << MI_SIZE_LOG2;
-        let partition_y = (bo.y & LOCAL_BLOCK_MASK) >> ydec << MI_SIZE_LOG2;
-
-        fs.qc.update(fi.config.quantizer, uv_tx_size);
-
-        for p in 1..3 {
-            let sb_offset = bo.sb_offset().plane_offset(&fs.input.planes[p].cfg);
-
-            for by in 0..bh_uv {
-                for bx in 0..bw_uv {
-                    let tx_bo =
-                        BlockOffset {
-                            x: bo.x + ((bx * uv_tx_size.width_mi()) << xdec) -
-                                ((bw * tx_size.width_mi() == 1) as usize),
-                            y: bo.y + ((by * uv_tx_size.height_mi()) << ydec) -
-                                ((bh * tx_size.height_mi() == 1) as usize)
-                        };
-
-                    let po = PlaneOffset {
-                        x: sb_offset.x + partition_x + bx * uv_tx_size.width(),
-                        y: sb_offset.y + partition_y + by * uv_tx_size.height()
+            fs.qc.update(fi.config.quantizer, tx_size);
+
+            for by in 0..bh {
+                for bx in 0..bw {
+                    let tx_bo = BlockOffset {
+                        x: unit_bo.x + bx * tx_size.width_mi(),
+                        y: unit_bo.y + by * tx_size.height_mi()
                     };
 
-                    encode_tx_block(fi, fs, cw, w, p, &tx_bo, chroma_mode, uv_tx_size, uv_tx_type,
-                                    plane_bsize, &po, skip);
+                    let po = tx_bo.plane_offset(&fs.input.planes[0].cfg);
+                    encode_tx_block(fi, fs, cw, w, 0, &tx_bo, luma_mode, angle_delta.y, edge_filter_y,
+                                    CFLParams::default(), filter_intra, palette, None, tx_size, tx_type,
+                                    bsize, &po, skip);
                 }
             }
+
+            write_uv_tx_blocks(fi, fs, cw, w, chroma_mode, angle_delta.uv, edge_filter_uv, cfl, palette,
+                               None, bo, bsize, &unit_bo, unit_w, unit_h, uv_tx_type, skip);
         }
     }
 }
 
-// FIXME: For now, assume tx_mode is LARGEST_TX, so var-tx is not implemented yet
-// but only one tx block exist for a inter mode partition.
-pub fn write_tx_tree(fi: &FrameInvariants, fs: &mut FrameState, cw: &mut ContextWriter, w: &mut Writer,
-                       luma_mode: PredictionMode, chroma_mode: PredictionMode, bo: &BlockOffset,
-                       bsize: BlockSize, tx_size: TxSize, tx_type: TxType, skip: bool) {
-    let bw = bsize.width_mi() / tx_size.width_mi();
-    let bh = bsize.height_mi() / tx_size.height_mi();
-
+// Codes the chroma transform blocks of the unit_w x unit_h coding unit at `unit_bo`
+// inside the block at `bo`. Sub-8x8 blocks with chroma code the chroma of the whole
+// 8x8 area.
+fn write_uv_tx_blocks(fi: &FrameInvariants, fs: &mut FrameState,
+                      cw: &mut ContextWriter, w: &mut Writer,
+                      chroma_mode: PredictionMode, angle_delta: i8, edge_filter: IntraEdgeFilter,
+                      cfl: CFLParams, palette: PaletteParams, intrabc: Option<MotionVector>,
+                      bo: &BlockOffset, bsize: BlockSize,
+                      unit_bo: &BlockOffset, unit_w: usize, unit_h: usize,
+                      uv_tx_type: TxType, skip: bool) {
     let PlaneConfig { xdec, ydec, .. } = fs.input.planes[1].cfg;
 
-    fs.qc.update(fi.config.quantizer, tx_size);
+    if !has_chroma(bo, bsize, xdec, ydec) {
+        return;
+    }
+
+    let uv_tx_size = bsize.largest_uv_tx_size(xdec, ydec);
+    let plane_bsize = get_plane_block_size(bsize, xdec, ydec);
+
+    let bw_uv = cmp::min(plane_bsize.width_mi(), cmp::max(unit_w >> xdec, 1)) / uv_tx_size.width_mi();
+    let bh_uv = cmp::min(plane_bsize.height_mi(), cmp::max(unit_h >> ydec, 1)) / uv_tx_size.height_mi();
+
+    let partition_x = (unit_bo.x & LOCAL_BLOCK_MASK) >> xdec << MI_SIZE_LOG2;
+    let partition_y = (unit_bo.y & LOCAL_BLOCK_MASK) >> ydec << MI_SIZE_LOG2;
+
+    fs.qc.update(fi.config.quantizer, uv_tx_size);
 
-    let po = bo.plane_offset(&fs.input.planes[0].cfg);
-    let has_coeff = encode_tx_block(fi, fs, cw, w, 0, &bo, luma_mode, tx_size, tx_type, bsize, &po, skip);
+    for p in 1..3 {
+        let sb_offset = unit_bo.sb_offset().plane_offset(&fs.input.planes[p].cfg);
 
-    // these are only valid for 4:2:0
-    let uv_tx_size = match bsize {
-        BlockSize::BLOCK_4X4 | BlockSize::BLOCK_8X8 => TxSize::TX_4X4,
-        BlockSize::BLOCK_16X16 => TxSize::TX_8X8,
-        BlockSize::BLOCK_32X32 => TxSize::TX_16X16,
-        _ => TxSize::TX_32X32
+        for by in 0..bh_uv {
+            for bx in 0..bw_uv {
+                let tx_bo =
+                    BlockOffset {
+                        x: unit_bo.x + ((bx * uv_tx_size.width_mi()) << xdec) -
+                            ((bsize.width_mi() == 1) as usize),
+                        y: unit_bo.y + ((by * uv_tx_size.height_mi()) << ydec) -
+                            ((bsize.height_mi() == 1) as usize)
+                    };
+
+                let po = PlaneOffset {
+                    x: sb_offset.x + partition_x + bx * uv_tx_size.width(),
+                    y: sb_offset.y + partition_y + by * uv_tx_size.height()
+                };
+
+                encode_tx_block(fi, fs, cw, w, p, &tx_bo, chroma_mode, angle_delta, edge_filter, cfl, None,
+                                palette, intrabc, uv_tx_size, uv_tx_type, plane_bsize, &po, skip);
+            }
+        }
+    }
+}
+
+// Predicts all the planes of the inter block at `bo`. The chroma of sub-8x8
+// blocks covers the whole 8x8 area, each block of which is predicted with its
+// own motion vector, unless one of them is intra. Overlapped blocks are then
+// blended with the predictions of their neighbours along the edges.
+fn predict_inter_block(fi: &FrameInvariants, fs: &mut FrameState, cw: &mut ContextWriter,
+                       mode: PredictionMode, bo: &BlockOffset, bsize: BlockSize) {
+    let PlaneConfig { xdec, ydec, .. } = fs.input.planes[1].cfg;
+    let num_planes = if has_chroma(bo, bsize, xdec, ydec) { 3 } else { 1 };
+
+    let block = *cw.bc.at(bo);
+    if block.has_second_ref() {
+        predict_compound_block(fi, fs, &block, bo, bsize);
+        return;
+    }
+    let local_warp = if block.motion_mode == MotionMode::WARPED_CAUSAL {
+        let samples = cw.bc.find_warp_samples(bo, bsize, LAST_FRAME, block.mvs[0]);
+        local_warp_params(&samples, bo, bsize, block.mvs[0])
+    } else {
+        None
     };
+    let overlaps = if block.motion_mode == MotionMode::OBMC_CAUSAL {
+        cw.bc.overlap_candidates(bo, bsize)
+    } else {
+        Vec::new()
+    };
+
+    for p in 0..num_planes {
+        let (xdec, ydec) = if p == 0 { (0, 0) } else { (xdec, ydec) };
+        let plane_bsize = if p == 0 { bsize } else { get_plane_block_size(bsize, xdec, ydec) };
+        let po = bo.plane_offset(&fs.input.planes[p].cfg);
+        let cand_bo = BlockOffset { x: bo.x >> xdec << xdec, y: bo.y >> ydec << ydec };
+
+        let mut pred_w = bsize.width() >> xdec;
+        let mut pred_h = bsize.height() >> ydec;
+        let mut cands_inter = true;
+        for r in 0..plane_bsize.height_mi() << ydec {
+            for c in 0..plane_bsize.width_mi() << xdec {
+                cands_inter &= cw.bc.at(&BlockOffset { x: cand_bo.x + c, y: cand_bo.y + r }).is_inter();
+            }
+        }
+        if !cands_inter {
+            pred_w = plane_bsize.width();
+            pred_h = plane_bsize.height();
+        }
 
-    let mut bw_uv = (bw * tx_size.width_mi()) >> xdec;
-    let mut bh_uv = (bh * tx_size.height_mi()) >> ydec;
+        for r in 0..plane_bsize.height() / pred_h {
+            for c in 0..plane_bsize.width() / pred_w {
+                let pred_bo = if cands_inter {
+                    BlockOffset { x: cand_bo.x + c, y: cand_bo.y + r }
+                } else {
+                    bo.clone()
+                };
+                let pred_po = PlaneOffset { x: po.x + c * pred_w, y: po.y + r * pred_h };
+                let mv = cw.bc.at(&pred_bo).mvs[0];
+                mode.predict_inter(fi, p, &pred_po, &mut fs.rec.planes[p], pred_w, pred_h, mv,
+                                   local_warp.as_ref());
+            }
+        }
 
-    if (bw_uv == 0 || bh_uv == 0) && has_chroma(bo, bsize, xdec, ydec) {
-        bw_uv = 1;
-        bh_uv = 1;
+        // The intra prediction is made in place of the inter one, which is
+        // kept aside to be blended back
+        if let Some(ii) = block.interintra {
+            let (w, h) = (plane_bsize.width(), plane_bsize.height());
+            let rec = &mut fs.rec.planes[p];
+            let stride = rec.cfg.stride;
+            let mut inter = Vec::with_capacity(w * h);
+            for r in 0..h {
+                let row = (po.y + r) * stride + po.x;
+                inter.extend_from_slice(&rec.data[row..row + w]);
+            }
+            ii.mode.intra_mode().predict_intra(&mut rec.mut_slice(&po), plane_bsize.largest_tx_size(), 0,
+                                               IntraEdgeFilter::Disabled, false, false, &[], 0, None);
+            match ii.wedge_index {
+                Some(index) => {
+                    let mask = wedge_mask(bsize, index, false);
+                    blend_interintra(rec, &po, w, h, &inter, &mask, bsize.width(), xdec, ydec);
+                }
+                None => {
+                    let mask = interintra_mask(ii.mode, w, h);
+                    blend_interintra(rec, &po, w, h, &inter, &mask, w, 0, 0);
+                }
+            }
+        }
+
+        // The top edge of chroma blocks smaller than 8x8 is not overlapped
+        for cand in &overlaps {
+            if cand.above && plane_bsize < BlockSize::BLOCK_8X8 {
+                continue;
+            }
+            let (x, y, w, h) = if cand.above {
+                ((cand.offset << MI_SIZE_LOG2) >> xdec, 0,
+                 (cand.len << MI_SIZE_LOG2) >> xdec, cmp::min(bsize.height(), 64) >> 1 >> ydec)
+            } else {
+                (0, (cand.offset << MI_SIZE_LOG2) >> ydec,
+                 cmp::min(bsize.width(), 64) >> 1 >> xdec, (cand.len << MI_SIZE_LOG2) >> ydec)
+            };
+            let overlap_po = PlaneOffset { x: po.x + x, y: po.y + y };
+            if let Some(ref rec) = fi.rec_buffer.frames[fi.ref_frames[cand.ref_frame - LAST_FRAME]] {
+                predict_overlap(&mut fs.rec.planes[p], &overlap_po, w, h, &rec.planes[p], cand.mv,
+                                cand.above, fi.width, fi.height, 8);
+            }
+        }
     }
+}
 
-    bw_uv /= uv_tx_size.width_mi();
-    bh_uv /= uv_tx_size.height_mi();
+// Predicts all the planes of the compound `block` at `bo`, of at least 8x8, by
+// blending the predictions from its two references with its mask, made over
+// the luma, or else averaging them
+fn predict_compound_block(fi: &FrameInvariants, fs: &mut FrameState, block: &Block,
+                          bo: &BlockOffset, bsize: BlockSize) {
+    let reference = |i: usize| &fi.rec_buffer.frames[fi.ref_frames[block.ref_frames[i] - LAST_FRAME]];
+    if let (&Some(ref rec0), &Some(ref rec1)) = (reference(0), reference(1)) {
+        let mut mask = None;
+        for p in 0..3 {
+            let PlaneConfig { xdec, ydec, .. } = fs.input.planes[p].cfg;
+            let plane_bsize = get_plane_block_size(bsize, xdec, ydec);
+            let (w, h) = (plane_bsize.width(), plane_bsize.height());
+            let po = bo.plane_offset(&fs.input.planes[p].cfg);
+            let preds = compound_predictions(&po, w, h, [&rec0.planes[p], &rec1.planes[p]], block.mvs,
+                                             fi.width, fi.height);
+            if p == 0 {
+                mask = block.compound_mask.map(|mask| match mask {
+                    CompoundMask::Wedge { index, sign } => wedge_mask(bsize, index, sign),
+                    CompoundMask::DiffWeighted { inverse } =>
+                        diff_weighted_mask(&preds[0], &preds[1], inverse, COMPOUND_POST_ROUND)
+                });
+            }
+            let mask = mask.as_ref().map(|mask| (&mask[..], bsize.width()));
+            blend_compound(&mut fs.rec.planes[p], &po, w, h, &preds, mask, xdec, ydec, 8);
+        }
+    }
+}
 
-    let plane_bsize = get_plane_block_size(bsize, xdec, ydec);
+// Inter blocks code luma in the order of the transform partitioning tree, with
+// the transforms of `tx_tree`
+pub fn write_tx_tree(fi: &FrameInvariants, fs: &mut FrameState, cw: &mut ContextWriter, w: &mut Writer,
+                       luma_mode: PredictionMode, chroma_mode: PredictionMode, intrabc: Option<MotionVector>,
+                       bo: &BlockOffset, bsize: BlockSize, tx_tree: &TxTree, tx_type: TxType, skip: bool) {
+    let unit_w = cmp::min(bsize.width_mi(), MAX_CODING_UNIT_MI);
+    let unit_h = cmp::min(bsize.height_mi(), MAX_CODING_UNIT_MI);
 
-    if bw_uv > 0 && bh_uv > 0 {
-        let uv_tx_type = if has_coeff {tx_type} else {TxType::DCT_DCT}; // if inter mode, uv_tx_type == tx_type
-        let partition_x = (bo.x & LOCAL_BLOCK_MASK) >> xdec << MI_SIZE_LOG2;
-        let partition_y = (bo.y & LOCAL_BLOCK_MASK) >> ydec << MI_SIZE_LOG2;
+    let PlaneConfig { xdec, ydec, .. } = fs.input.planes[1].cfg;
+    let uv_tx_size = bsize.largest_uv_tx_size(xdec, ydec);
+    let uv_tx_set = get_tx_set(uv_tx_size, true, fi.use_reduced_tx_set);
 
-        fs.qc.update(fi.config.quantizer, uv_tx_size);
+    // Chroma takes the transform type of the luma transform block at the top-left
+    let mut uv_tx_type = TxType::DCT_DCT;
 
-        for p in 1..3 {
-            let sb_offset = bo.sb_offset().plane_offset(&fs.input.planes[p].cfg);
+    if !luma_mode.is_intra() {
+        predict_inter_block(fi, fs, cw, luma_mode, bo, bsize);
+    }
 
-            let tx_bo = BlockOffset {
-                x: bo.x  - ((bw * tx_size.width_mi() == 1) as usize),
-                y: bo.y  - ((bh * tx_size.height_mi() == 1) as usize)
+    for uy in 0..bsize.height_mi() / unit_h {
+        for ux in 0..bsize.width_mi() / unit_w {
+            let unit_bo = BlockOffset {
+                x: bo.x + ux * unit_w,
+                y: bo.y + uy * unit_h
             };
 
-            let po = PlaneOffset {
-                x: sb_offset.x + partition_x,
-                y: sb_offset.y + partition_y
+            let in_unit = |tx_bo: &BlockOffset| {
+                tx_bo.x >= unit_bo.x && tx_bo.x < unit_bo.x + unit_w &&
+                    tx_bo.y >= unit_bo.y && tx_bo.y < unit_bo.y + unit_h
             };
+            let leaves = tx_tree.leaves.iter().filter(|&&(ref tx_bo, _)| in_unit(tx_bo));
+
+            for (i, &(ref tx_bo, tx_size)) in leaves.enumerate() {
+                fs.qc.update(fi.config.quantizer, tx_size);
+                let po = tx_bo.plane_offset(&fs.input.planes[0].cfg);
+                let has_coeff = encode_tx_block(fi, fs, cw, w, 0, tx_bo, luma_mode, 0, IntraEdgeFilter::Disabled,
+                                                CFLParams::default(), None, PaletteParams::default(),
+                                                intrabc, tx_size, tx_type, bsize, &po, skip);
+
+                // if inter mode, uv_tx_type == tx_type
+                if ux == 0 && uy == 0 && i == 0 &&
+                    has_coeff && av1_tx_used[uv_tx_set as usize][tx_type as usize] != 0 {
+                    uv_tx_type = tx_type;
+                }
+            }
 
-            encode_tx_block(fi, fs, cw, w, p, &tx_bo, chroma_mode, uv_tx_size, uv_tx_type,
-                            plane_bsize, &po, skip);
+            write_uv_tx_blocks(fi, fs, cw, w, chroma_mode, 0, IntraEdgeFilter::Disabled, CFLParams::default(),
+                               PaletteParams::default(), intrabc, bo, bsize, &unit_bo, unit_w, unit_h, uv_tx_type, skip);
         }
     }
 }
 
+// Code a block with the modes RDO chose for it, restoring the state RDO left
+// and replaying its symbols instead when they still apply
+fn encode_block_decision(seq: &Sequence, fi: &FrameInvariants, fs: &mut FrameState,
+                         cw: &mut ContextWriter, w: &mut Writer, bsize: BlockSize,
+                         mode_decision: &RDOPartitionOutput, cdef_index: u8) {
+    if let Some(ref coded) = mode_decision.coded {
+        coded.restore(fs, cw);
+        coded.symbols.replay(w);
+    } else {
+        encode_block(seq, fi, fs, cw, w, mode_decision.pred_mode_luma, mode_decision.pred_mode_chroma,
+                     mode_decision.angle_delta, mode_decision.cfl_params, mode_decision.filter_intra_mode,
+                     mode_decision.palette_params, mode_decision.intrabc, mode_decision.motion_mode,
+                     mode_decision.interintra, mode_decision.compound_mask, &mode_decision.tx_tree, bsize,
+                     &mode_decision.bo, mode_decision.skip, cdef_index);
+    }
+}
+
 fn encode_partition_bottomup(seq: &Sequence, fi: &FrameInvariants, fs: &mut FrameState,
                              cw: &mut ContextWriter, w: &mut Writer,
                              bsize: BlockSize, bo: &BlockOffset) -> f64 {
@@ -1691,45 +2530,70 @@ fn encode_partition_bottomup(seq: &Sequence, fi: &FrameInvariants, fs: &mut Fram
 
     let bs = bsize.width_mi();
 
+    let partition_range = fi.config.speed_settings.partition_range;
+
     // Always split if the current partition is too large
     let must_split = bo.x + bs as usize > fi.w_in_b ||
-        bo.y + bs as usize > fi.h_in_b ||
-        bsize >= BlockSize::BLOCK_64X64;
+        bo.y + bs as usize > fi.h_in_b || bsize > partition_range.max;
 
     // must_split overrides the minimum partition size when applicable
-    let can_split = bsize > fi.min_partition_size || must_split;
+    let mut can_split = bsize > partition_range.min || must_split;
 
     let mut partition = PartitionType::PARTITION_NONE;
-    let mut best_decision = RDOPartitionOutput {
-        rd_cost,
-        bo: bo.clone(),
-        pred_mode_luma: PredictionMode::DC_PRED,
-        pred_mode_chroma: PredictionMode::DC_PRED,
-        skip: false
-    }; // Best decision that is not PARTITION_SPLIT
-
-    let hbs = bs >> 1; // Half the block size in blocks
-    let mut subsize: BlockSize;
+    let mut best_partition = PartitionType::PARTITION_INVALID;
+    let mut best_decisions = Vec::new(); // Best decisions that are not PARTITION_SPLIT
+    let mut nosplit_rd_cost = rd_cost;
 
     let cw_checkpoint = cw.checkpoint();
     let w_checkpoint = w.checkpoint();
     let cdef_index = 5;  // The hardwired cdef index is temporary
 
-    // Code the whole block
+    let partition_types = if fi.config.speed_settings.ext_partition_types {
+        RAV1E_PARTITION_TYPES
+    } else {
+        RAV1E_PARTITION_TYPES_MINIMAL
+    };
+
+    // Code the whole block, then the partitions into blocks that are not split further
     if !must_split {
-        partition = PartitionType::PARTITION_NONE;
+        for &p in partition_types {
+            if p == PartitionType::PARTITION_SPLIT || !partition_allowed(bsize, p) ||
+                (p != PartitionType::PARTITION_NONE && !can_split) {
+                continue;
+            }
 
-        if bsize >= BlockSize::BLOCK_8X8 {
-            cw.write_partition(w, bo, partition, bsize);
-        }
-        let mode_decision = rdo_mode_decision(seq, fi, fs, cw, w, bsize, bo, cdef_index).part_modes[0].clone();
-        let (mode_luma, mode_chroma) = (mode_decision.pred_mode_luma, mode_decision.pred_mode_chroma);
-        let skip = mode_decision.skip;
-        rd_cost = mode_decision.rd_cost;
+            cw.rollback(&cw_checkpoint);
+            w.rollback(&w_checkpoint);
 
-        encode_block(seq, fi, fs, cw, w, mode_luma, mode_chroma, bsize, bo, skip, cdef_index);
+            if bsize >= BlockSize::BLOCK_8X8 {
+                cw.write_partition(w, bo, p, bsize);
+            }
+
+            let mut decisions = Vec::new();
+            let mut cost = 0.0;
+            for (subsize, offset) in get_partition_blocks(bsize, p, bo) {
+                let mode_decision = rdo_mode_decision(seq, fi, fs, cw, subsize, &offset, cdef_index).part_modes[0].clone();
+                cost += mode_decision.rd_cost;
+
+                encode_block_decision(seq, fi, fs, cw, w, subsize, &mode_decision, cdef_index);
+
+                decisions.push((subsize, mode_decision));
+            }
 
-        best_decision = mode_decision;
+            // Blocks coded well enough whole are not split further
+            if p == PartitionType::PARTITION_NONE && can_split && fi.config.speed_settings.prune_partitions {
+                can_split = !prune_split(fi, fs, bsize, bo, &decisions[0].1);
+            }
+
+            if cost < nosplit_rd_cost {
+                nosplit_rd_cost = cost;
+                best_partition = p;
+                best_decisions = decisions;
+            }
+        }
+
+        partition = best_partition;
+        rd_cost = nosplit_rd_cost;
     }
 
     // Code a split partition and compare RD costs
@@ -1738,43 +2602,44 @@ fn encode_partition_bottomup(seq: &Sequence, fi: &FrameInvariants, fs: &mut Fram
         w.rollback(&w_checkpoint);
 
         partition = PartitionType::PARTITION_SPLIT;
-        subsize = get_subsize(bsize, partition);
-
-        let nosplit_rd_cost = rd_cost;
+        let subsize = get_subsize(bsize, partition);
+        let hbs = bs >> 1; // Half the block size in blocks
 
         if bsize >= BlockSize::BLOCK_8X8 {
             cw.write_partition(w, bo, partition, bsize);
         }
 
-        rd_cost = encode_partition_bottomup(seq, fi, fs, cw, w, subsize, bo);
-        rd_cost += encode_partition_bottomup(seq, fi, fs, cw, w, subsize, &BlockOffset { x: bo.x + hbs as usize, y: bo.y });
-        rd_cost += encode_partition_bottomup(seq, fi, fs, cw, w, subsize, &BlockOffset { x: bo.x, y: bo.y + hbs as usize });
-        rd_cost += encode_partition_bottomup(seq, fi, fs, cw, w, subsize, &BlockOffset { x: bo.x + hbs as usize, y: bo.y + hbs as usize });
+        rd_cost = 0.0;
+        for &(x, y) in &[(0, 0), (hbs, 0), (0, hbs), (hbs, hbs)] {
+            // The split cannot win once it costs more than the block coded whole
+            if !must_split && rd_cost > nosplit_rd_cost {
+                break;
+            }
+            rd_cost += encode_partition_bottomup(seq, fi, fs, cw, w, subsize,
+                                                 &BlockOffset { x: bo.x + x, y: bo.y + y });
+        }
 
-        // Recode the full block if it is more efficient
+        // Recode the best partition without split if it is more efficient
         if !must_split && nosplit_rd_cost < rd_cost {
             cw.rollback(&cw_checkpoint);
             w.rollback(&w_checkpoint);
 
-            partition = PartitionType::PARTITION_NONE;
+            partition = best_partition;
+            rd_cost = nosplit_rd_cost;
 
             if bsize >= BlockSize::BLOCK_8X8 {
                 cw.write_partition(w, bo, partition, bsize);
             }
 
-            // FIXME: redundant block re-encode
-            let (mode_luma, mode_chroma) = (best_decision.pred_mode_luma, best_decision.pred_mode_chroma);
-            let skip = best_decision.skip;
-            encode_block(seq, fi, fs, cw, w, mode_luma, mode_chroma, bsize, bo, skip, cdef_index);
+            // The blocks are restored as they were coded before the split
+            for &(subsize, ref mode_decision) in &best_decisions {
+                encode_block_decision(seq, fi, fs, cw, w, subsize, mode_decision, cdef_index);
+            }
         }
     }
 
-    subsize = get_subsize(bsize, partition);
-
-    if bsize >= BlockSize::BLOCK_8X8 &&
-        (bsize == BlockSize::BLOCK_8X8 || partition != PartitionType::PARTITION_SPLIT) {
-        cw.bc.update_partition_context(bo, subsize, bsize);
-    }
+    let subsize = get_subsize(bsize, partition);
+    cw.bc.update_ext_partition_context(bo, subsize, bsize, partition);
 
     rd_cost
 }
@@ -1789,10 +2654,11 @@ fn encode_partition_topdown(seq: &Sequence, fi: &FrameInvariants, fs: &mut Frame
 
     let bs = bsize.width_mi();
 
+    let partition_range = fi.config.speed_settings.partition_range;
+
     // Always split if the current partition is too large
     let must_split = bo.x + bs as usize > fi.w_in_b ||
-        bo.y + bs as usize > fi.h_in_b ||
-        bsize >= BlockSize::BLOCK_64X64;
+        bo.y + bs as usize > fi.h_in_b || bsize > partition_range.max;
 
     let mut rdo_output = block_output.clone().unwrap_or(RDOOutput {
         part_type: PartitionType::PARTITION_INVALID,
@@ -1805,9 +2671,9 @@ fn encode_partition_topdown(seq: &Sequence, fi: &FrameInvariants, fs: &mut Frame
     if must_split {
         // Oversized blocks are split automatically
         partition = PartitionType::PARTITION_SPLIT;
-    } else if bsize > fi.min_partition_size {
+    } else if bsize > partition_range.min {
         // Blocks of sizes within the supported range are subjected to a partitioning decision
-        rdo_output = rdo_partition_decision(seq, fi, fs, cw, w, bsize, bo, &rdo_output, cdef_index);
+        rdo_output = rdo_partition_decision(seq, fi, fs, cw, bsize, bo, &rdo_output, cdef_index);
         partition = rdo_output.part_type;
     } else {
         // Blocks of sizes below the supported range are encoded directly
@@ -1832,14 +2698,10 @@ fn encode_partition_topdown(seq: &Sequence, fi: &FrameInvariants, fs: &mut Frame
                     rdo_output.part_modes[0].clone()
                 } else {
                     // Make a prediction mode decision for blocks encoded with no rdo_partition_decision call (e.g. edges)
-                    rdo_mode_decision(seq, fi, fs, cw, w, bsize, bo, cdef_index).part_modes[0].clone()
+                    rdo_mode_decision(seq, fi, fs, cw, bsize, bo, cdef_index).part_modes[0].clone()
                 };
 
-            let (mode_luma, mode_chroma) = (part_decision.pred_mode_luma, part_decision.pred_mode_chroma);
-            let skip = part_decision.skip;
-
-            // FIXME: every final block that has gone through the RDO decision process is encoded twice
-            encode_block(seq, fi, fs, cw, w, mode_luma, mode_chroma, bsize, bo, skip, cdef_index);
+            encode_block_decision(seq, fi, fs, cw, w, bsize, &part_decision, cdef_index);
         },
         PartitionType::PARTITION_SPLIT => {
             if rdo_output.part_modes.len() >= 4 {
@@ -1864,41 +2726,104 @@ fn encode_partition_topdown(seq: &Sequence, fi: &FrameInvariants, fs: &mut Frame
                 encode_partition_topdown(seq, fi, fs, cw, w, subsize, &BlockOffset{x: bo.x + hbs as usize, y: bo.y + hbs as usize}, &None);
             }
         },
-        _ => { assert!(false); },
+        _ => {
+            // The other partition types are made of blocks that are not partitioned further
+            let blocks = get_partition_blocks(bsize, partition, bo);
+            assert!(rdo_output.part_modes.len() == blocks.len());
+
+            for (&(subsize, _), part_decision) in blocks.iter().zip(rdo_output.part_modes.iter()) {
+                encode_block_decision(seq, fi, fs, cw, w, subsize, part_decision, cdef_index);
+            }
+        },
     }
 
-    if bsize >= BlockSize::BLOCK_8X8 &&
-        (bsize == BlockSize::BLOCK_8X8 || partition != PartitionType::PARTITION_SPLIT) {
-            cw.bc.update_partition_context(bo, subsize, bsize);
+    cw.bc.update_ext_partition_context(bo, subsize, bsize, partition);
+}
+
+// CDFs a frame starts from: those saved with its primary reference frame, or
+// the default ones
+fn initial_cdfs(fi: &FrameInvariants) -> CDFContext {
+    let primary_cdfs = if fi.primary_ref_frame == PRIMARY_REF_NONE {
+        None
+    } else {
+        fi.rec_buffer.cdfs[fi.ref_frames[fi.primary_ref_frame as usize]].clone()
+    };
+    match primary_cdfs {
+        Some(cdfs) => (*cdfs).clone(),
+        None => CDFContext::new(fi.config.quantizer as u8)
     }
 }
 
+// CDFs saved with a frame for the frames predicted from it, from those its
+// tile ended with. Without the frame end update, the CDFs the frame started
+// from are saved instead of the adapted ones.
+fn saved_cdfs(fi: &FrameInvariants, tile_cdfs: &CDFContext) -> CDFContext {
+    let mut cdfs = if fi.disable_frame_end_update_cdf {
+        initial_cdfs(fi)
+    } else {
+        tile_cdfs.clone()
+    };
+    cdfs.reset_counts();
+    cdfs
+}
+
 fn encode_tile(sequence: &mut Sequence, fi: &FrameInvariants, fs: &mut FrameState) -> Vec<u8> {
-    let mut w = ec::Writer::new();
-    let fc = CDFContext::new(fi.config.quantizer as u8);
-    let bc = BlockContext::new(fi.w_in_b, fi.h_in_b);
+    let mut w = ec::WriterEncoder::new();
+    w.set_update_cdfs(!fi.disable_cdf_update);
+    let fc = initial_cdfs(fi);
+    let mut bc = BlockContext::new(fi.w_in_b, fi.h_in_b);
+    if !fi.intra_only {
+        for ref_frame in LAST_FRAME..ALTREF_FRAME + 1 {
+            let dist = sequence.get_relative_dist(fi.ref_order_hint(ref_frame), fi.order_hint);
+            bc.ref_frame_sign_bias[ref_frame] = dist > 0;
+        }
+    }
+    if fi.use_ref_frame_mvs {
+        let motion_field = MotionField::new(sequence, fi);
+        bc.temporal_mvs = (LAST_FRAME..ALTREF_FRAME + 1).map(|ref_frame| {
+            let ref_dist = sequence.get_relative_dist(fi.order_hint, fi.ref_order_hint(ref_frame));
+            motion_field.projected(ref_dist, fi.allow_high_precision_mv, fi.force_integer_mv != 0)
+        }).collect();
+    }
     let mut cw = ContextWriter::new(fc,  bc);
 
+    let sb_size = sequence.sb_size();
+
+    fs.block_hashes = if fi.allow_intrabc {
+        Some(BlockHashes::new(&fs.input.planes[0]))
+    } else {
+        None
+    };
+
     for sby in 0..fi.sb_height {
         cw.bc.reset_left_contexts();
 
         for sbx in 0..fi.sb_width {
-            let sbo = SuperBlockOffset { x: sbx, y: sby };
-            let bo = sbo.block_offset(0, 0);
+            let bo = BlockOffset { x: sbx * sb_size.width_mi(), y: sby * sb_size.height_mi() };
+
+            let PlaneConfig { xdec, ydec, .. } = fs.input.planes[1].cfg;
+            cw.bc.reset_block_decoded(&bo, sb_size, xdec, ydec);
 
             // Encode SuperBlock
-            if fi.config.speed == 0 {
-                encode_partition_bottomup(sequence, fi, fs, &mut cw, &mut w, BlockSize::BLOCK_64X64, &bo);
+            if fi.config.speed_settings.encode_bottomup {
+                encode_partition_bottomup(sequence, fi, fs, &mut cw, &mut w, sb_size, &bo);
             }
             else {
-                encode_partition_topdown(sequence, fi, fs, &mut cw, &mut w, BlockSize::BLOCK_64X64, &bo, &None);
+                encode_partition_topdown(sequence, fi, fs, &mut cw, &mut w, sb_size, &bo, &None);
             }
         }
     }
     /* TODO: Don't apply if lossless */
-    if sequence.enable_cdef {
+    if sequence.enable_cdef && !fi.allow_intrabc {
         cdef_frame(fi, &mut fs.rec, &mut cw.bc);
     }
+    // Intra frames have no motion to project
+    fs.frame_mvs = if fi.intra_only {
+        None
+    } else {
+        Some(FrameMotionVectors::new(sequence, fi, &mut cw.bc))
+    };
+    fs.cdfs = Some(saved_cdfs(fi, &cw.fc));
     let mut h = w.done();
     h.push(0); // superframe anti emulation
     h
@@ -1916,8 +2841,56 @@ fn write_tile_group_header(tile_start_and_end_present_flag: bool) ->
     buf.clone()
 }
 
-fn encode_frame(sequence: &mut Sequence, fi: &mut FrameInvariants, fs: &mut FrameState) -> Vec<u8> {
+/// Codes the input frame of `fs` into a temporal unit, reconstructing it
+/// in `fs`
+pub fn encode_frame(sequence: &mut Sequence, fi: &mut FrameInvariants, fs: &mut FrameState) -> Vec<u8> {
     let mut packet = Vec::new();
+    // Shown key frames are always error resilient, the other frames reuse the
+    // CDFs and the motion of their last reference
+    fi.error_resilient = fi.frame_type == FrameType::KEY && fi.show_frame;
+    fi.primary_ref_frame = if fi.intra_only || fi.error_resilient {
+        PRIMARY_REF_NONE
+    } else {
+        (LAST_FRAME - LAST_FRAME) as u32
+    };
+    // Frames are shown in coding order
+    fi.order_hint = if sequence.enable_order_hint {
+        (fi.number % (1 << (sequence.order_hint_bits_minus_1 + 1))) as u32
+    } else {
+        0
+    };
+    // Error resilient frames cannot use the motion of their references
+    fi.use_ref_frame_mvs = sequence.enable_ref_frame_mvs && !fi.intra_only && !fi.error_resilient;
+    // Compound references are only selected for skip mode, which bidirectional
+    // frames use
+    let skip_mode_frames = fi.find_skip_mode_frames(sequence);
+    fi.skip_mode_present = skip_mode_frames.is_some();
+    fi.skip_mode_frames = skip_mode_frames.unwrap_or([INTRA_FRAME; 2]);
+    fi.reference_mode = if fi.skip_mode_present { ReferenceMode::SELECT } else { ReferenceMode::SINGLE };
+    // The screen content tools are decided for each frame, unless forced on
+    let (screen_content, integer_mv) = detect_screen_content(&fs.input, fi.width, fi.height);
+    fi.allow_screen_content_tools = (fi.config.screen_content || screen_content) as u32;
+    fi.force_integer_mv = (fi.allow_screen_content_tools != 0 && integer_mv) as u32;
+    fi.allow_high_precision_mv = fi.force_integer_mv == 0;
+    // Intra block copy disables the in-loop filters, which only pays off on
+    // screen content
+    fi.allow_intrabc = fi.intra_only && fi.allow_screen_content_tools != 0
+        && fi.complex_prediction_modes();
+    // Error resilient frames cannot use warped motion
+    fi.allow_warped_motion = sequence.enable_warped_motion && !fi.intra_only
+        && !fi.error_resilient && fi.is_motion_mode_switchable;
+    // Inter blocks only reference the last frame, so only its global motion
+    // is estimated
+    fi.globalmv_transformation_type = [GlobalMVMode::IDENTITY; ALTREF_FRAME + 1];
+    fi.gm_params = [GM_IDENTITY_PARAMS; ALTREF_FRAME + 1];
+    if !fi.intra_only && fi.config.speed_settings.global_motion {
+        let reference = fi.rec_buffer.frames[fi.ref_frames[0]].clone();
+        if let Some(ref rec) = reference {
+            let (gm_type, params) = estimate_global_motion(fi, &fs.input, rec);
+            fi.globalmv_transformation_type[LAST_FRAME] = gm_type;
+            fi.gm_params[LAST_FRAME] = params;
+        }
+    }
     //write_uncompressed_header(&mut packet, sequence, fi).unwrap();
     write_obus(&mut packet, sequence, fi).unwrap();
     if fi.show_existing_frame {
@@ -1958,9 +2931,20 @@ fn encode_frame(sequence: &mut Sequence, fi: &mut FrameInvariants, fs: &mut Fram
 
 pub fn update_rec_buffer(fi: &mut FrameInvariants, fs: FrameState) {
   let rfs = Rc::new(fs.rec);
+  let frame_mvs = fs.frame_mvs.map(Rc::new);
+  let cdfs = fs.cdfs.map(Rc::new);
+  let mut ref_order_hints = [0; INTER_REFS_PER_FRAME];
+  for i in 0..INTER_REFS_PER_FRAME {
+    ref_order_hints[i] = fi.ref_order_hint(LAST_FRAME + i);
+  }
   for i in 0..(REF_FRAMES as usize) {
     if (fi.refresh_frame_flags & (1 << i)) != 0 {
       fi.rec_buffer.frames[i] = Some(Rc::clone(&rfs));
+      fi.rec_buffer.gm_params[i] = fi.gm_params;
+      fi.rec_buffer.order_hints[i] = fi.order_hint;
+      fi.rec_buffer.ref_order_hints[i] = ref_order_hints;
+      fi.rec_buffer.frame_mvs[i] = frame_mvs.clone();
+      fi.rec_buffer.cdfs[i] = cdfs.clone();
     }
   }
 }
@@ -2052,6 +3036,70 @@ pub fn process_frame(sequence: &mut Sequence, fi: &mut FrameInvariants,
 #[cfg(feature="decode_test")]
 mod aom;
 
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    // Codes a 64x64 frame of a diagonal gradient as a key frame, or else as an
+    // inter frame predicted from the last one
+    fn encode_gradient(seq: &mut Sequence, fi: &mut FrameInvariants, key: bool) -> FrameState {
+        let mut fs = fi.new_frame_state();
+        for plane in fs.input.planes.iter_mut() {
+            let stride = plane.cfg.stride;
+            for (y, row) in plane.data.chunks_mut(stride).enumerate() {
+                for (x, pixel) in row.iter_mut().enumerate() {
+                    *pixel = ((x + y + fi.number as usize) % 256) as u16;
+                }
+            }
+        }
+        fi.frame_type = if key { FrameType::KEY } else { FrameType::INTER };
+        fi.intra_only = key;
+        fi.refresh_frame_flags = if key { ALL_REF_FRAMES_MASK } else { 1 };
+        encode_frame(seq, fi, &mut fs);
+        fs
+    }
+
+    #[test]
+    fn cdfs_across_frames() {
+        unsafe {
+            av1_rtcd();
+            aom_dsp_rtcd();
+        }
+
+        let config = EncoderConfig::with_speed_preset(10);
+        let mut fi = FrameInvariants::new(64, 64, config);
+        let mut seq = Sequence::new(64, 64, config);
+
+        // Key frames start from the default CDFs, and save those their tile
+        // adapted with the counters reset
+        let fs = encode_gradient(&mut seq, &mut fi, true);
+        let default_cdfs = CDFContext::new(fi.config.quantizer as u8);
+        assert_eq!(initial_cdfs(&fi), default_cdfs);
+        let key_cdfs = fs.cdfs.clone().unwrap();
+        assert!(key_cdfs != default_cdfs);
+        let mut reset_cdfs = key_cdfs.clone();
+        reset_cdfs.reset_counts();
+        assert_eq!(key_cdfs, reset_cdfs);
+        update_rec_buffer(&mut fi, fs);
+        fi.number += 1;
+
+        // The next frame starts from them
+        let fs = encode_gradient(&mut seq, &mut fi, false);
+        assert_eq!(initial_cdfs(&fi), key_cdfs);
+        let inter_cdfs = fs.cdfs.clone().unwrap();
+        assert!(inter_cdfs != key_cdfs);
+        update_rec_buffer(&mut fi, fs);
+        fi.number += 1;
+
+        // Without the frame end update, the CDFs the frame started from are
+        // saved unchanged
+        fi.disable_frame_end_update_cdf = true;
+        let fs = encode_gradient(&mut seq, &mut fi, false);
+        assert_eq!(initial_cdfs(&fi), inter_cdfs);
+        assert_eq!(fs.cdfs.unwrap(), inter_cdfs);
+    }
+}
+
 #[cfg(all(test, feature="decode_test"))]
 mod test_encode_decode {
     use super::*;
@@ -2112,14 +3160,13 @@ mod test_encode_decode {
 
         let config = EncoderConfig {
             quantizer: quantizer,
-            speed: speed,
-            ..Default::default()
+            ..EncoderConfig::with_speed_preset(speed)
         };
         let mut fi = FrameInvariants::new(w, h, config);
 
         fi.use_reduced_tx_set = true;
         // fi.min_partition_size =
-        let seq = Sequence::new(w, h);
+        let seq = Sequence::new(w, h, config);
 
         (fi, seq)
     }
@@ -2238,7 +3285,6 @@ mod test_encode_decode {
             fi.refresh_frame_flags = if fi.frame_type == FrameType::KEY { ALL_REF_FRAMES_MASK } else { 1 };
 
             fi.intra_only = fi.frame_type == FrameType::KEY || fi.frame_type == FrameType::INTRA_ONLY;
-            fi.use_prev_frame_mvs = !(fi.intra_only || fi.error_resilient);
             println!("Encoding frame {}", fi.number);
             let packet = encode_frame(&mut seq, &mut fi, &mut fs);
             println!("Encoded.");
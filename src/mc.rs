@@ -0,0 +1,1018 @@
+// Copyright (c) 2017-2018, The rav1e contributors. All rights reserved
+//
+// This source code is subject to the terms of the BSD 2 Clause License and
+// the Alliance for Open Media Patent License 1.0. If the BSD 2 Clause License
+// was not distributed with this source code in the LICENSE file, you can
+// obtain it at www.aomedia.org/license/software. If the Alliance for Open
+// Media Patent License 1.0 was not distributed with this source code in the
+// PATENTS file, you can obtain it at www.aomedia.org/license/patent.
+
+#![cfg_attr(feature = "cargo-clippy", allow(cast_lossless))]
+
+use compound::*;
+use context::*;
+use partition::*;
+use plane::*;
+use std::cmp;
+
+pub const WARPEDMODEL_PREC_BITS: usize = 16;
+
+/// Parameters of the identity global motion
+pub const GM_IDENTITY_PARAMS: [i32; 6] =
+  [0, 0, 1 << WARPEDMODEL_PREC_BITS, 0, 0, 1 << WARPEDMODEL_PREC_BITS];
+
+const GM_ABS_ALPHA_BITS: usize = 12;
+const GM_ALPHA_PREC_BITS: usize = 15;
+const GM_ABS_TRANS_ONLY_BITS: usize = 9;
+const GM_TRANS_ONLY_PREC_BITS: usize = 3;
+const GM_ABS_TRANS_BITS: usize = 12;
+const GM_TRANS_PREC_BITS: usize = 6;
+
+const SUBPEL_BITS: usize = 4;
+const SUBPEL_MASK: isize = (1 << SUBPEL_BITS) - 1;
+
+// Rounding of the horizontal and vertical filter stages, for 8-bit
+// single-reference prediction
+const INTER_ROUND0: usize = 3;
+const INTER_ROUND1: usize = 11;
+// Rounding of the vertical stage of compound predictions, which are left
+// with this many more bits than pixels until blended
+const COMPOUND_ROUND1: usize = 7;
+pub const COMPOUND_POST_ROUND: usize = 14 - INTER_ROUND0 - COMPOUND_ROUND1;
+
+const WARPEDDIFF_PREC_BITS: usize = 10;
+const WARPEDPIXEL_PREC_SHIFTS: i32 = 1 << 6;
+const WARP_PARAM_REDUCE_BITS: usize = 6;
+
+const DIV_LUT_BITS: usize = 8;
+const DIV_LUT_PREC_BITS: usize = 14;
+const DIV_LUT_NUM: usize = (1 << DIV_LUT_BITS) + 1;
+
+// Largest difference between the motion of a warp sample and of the block,
+// in 1/8 pixels, for the sample to be used in the least squares fit
+const LS_MV_MAX: i64 = 256;
+const WARPEDMODEL_NONDIAGAFFINE_CLAMP: i64 = 1 << 13;
+const WARPEDMODEL_TRANS_CLAMP: i64 = 1 << 23;
+
+// Weights of the prediction of a block over the overlap with the prediction
+// of a neighbour, in 1/64ths, from the shared edge inward
+const OBMC_MASK_2: [i32; 2] = [45, 64];
+const OBMC_MASK_4: [i32; 4] = [39, 50, 59, 64];
+const OBMC_MASK_8: [i32; 8] = [36, 42, 48, 53, 57, 61, 64, 64];
+const OBMC_MASK_16: [i32; 16] =
+  [34, 37, 40, 43, 46, 49, 52, 54, 56, 58, 60, 61, 64, 64, 64, 64];
+const OBMC_MASK_32: [i32; 32] = [
+  33, 35, 36, 38, 40, 41, 43, 44, 45, 47, 48, 50, 51, 52, 53, 55, 56, 57, 58,
+  59, 60, 60, 61, 62, 64, 64, 64, 64, 64, 64, 64, 64
+];
+
+// Regular interpolation filters, 8-tap and 4-tap, indexed by the subpixel
+// position in 1/16 pixels
+const SUBPEL_FILTERS: [[[i32; 8]; 16]; 2] = [
+  [
+    [0, 0, 0, 128, 0, 0, 0, 0],
+    [0, 2, -6, 126, 8, -2, 0, 0],
+    [0, 2, -10, 122, 18, -4, 0, 0],
+    [0, 2, -12, 116, 28, -8, 2, 0],
+    [0, 2, -14, 110, 38, -10, 2, 0],
+    [0, 2, -14, 102, 48, -12, 2, 0],
+    [0, 2, -16, 94, 58, -12, 2, 0],
+    [0, 2, -14, 84, 66, -12, 2, 0],
+    [0, 2, -14, 76, 76, -14, 2, 0],
+    [0, 2, -12, 66, 84, -14, 2, 0],
+    [0, 2, -12, 58, 94, -16, 2, 0],
+    [0, 2, -12, 48, 102, -14, 2, 0],
+    [0, 2, -10, 38, 110, -14, 2, 0],
+    [0, 2, -8, 28, 116, -12, 2, 0],
+    [0, 0, -4, 18, 122, -10, 2, 0],
+    [0, 0, -2, 8, 126, -6, 2, 0],
+  ],
+  [
+    [0, 0, 0, 128, 0, 0, 0, 0],
+    [0, 0, -4, 126, 8, -2, 0, 0],
+    [0, 0, -8, 122, 18, -4, 0, 0],
+    [0, 0, -10, 116, 28, -6, 0, 0],
+    [0, 0, -12, 110, 38, -8, 0, 0],
+    [0, 0, -12, 102, 48, -10, 0, 0],
+    [0, 0, -14, 94, 58, -10, 0, 0],
+    [0, 0, -12, 84, 66, -10, 0, 0],
+    [0, 0, -12, 76, 76, -12, 0, 0],
+    [0, 0, -10, 66, 84, -12, 0, 0],
+    [0, 0, -10, 58, 94, -14, 0, 0],
+    [0, 0, -10, 48, 102, -12, 0, 0],
+    [0, 0, -8, 38, 110, -12, 0, 0],
+    [0, 0, -6, 28, 116, -10, 0, 0],
+    [0, 0, -4, 18, 122, -8, 0, 0],
+    [0, 0, -2, 8, 126, -4, 0, 0],
+  ]
+];
+
+// Warp filters, indexed by the subpixel position in 1/64 pixels, offset by
+// one pixel
+const WARPED_FILTERS: [[i32; 8]; 193] = [
+  // [-1, 0)
+  [0, 0, 127, 1, 0, 0, 0, 0],
+  [0, -1, 127, 2, 0, 0, 0, 0],
+  [1, -3, 127, 4, -1, 0, 0, 0],
+  [1, -4, 126, 6, -2, 1, 0, 0],
+  [1, -5, 126, 8, -3, 1, 0, 0],
+  [1, -6, 125, 11, -4, 1, 0, 0],
+  [1, -7, 124, 13, -4, 1, 0, 0],
+  [2, -8, 123, 15, -5, 1, 0, 0],
+  [2, -9, 122, 18, -6, 1, 0, 0],
+  [2, -10, 121, 20, -6, 1, 0, 0],
+  [2, -11, 120, 22, -7, 2, 0, 0],
+  [2, -12, 119, 25, -8, 2, 0, 0],
+  [3, -13, 117, 27, -8, 2, 0, 0],
+  [3, -13, 116, 29, -9, 2, 0, 0],
+  [3, -14, 114, 32, -10, 3, 0, 0],
+  [3, -15, 113, 35, -10, 2, 0, 0],
+  [3, -15, 111, 37, -11, 3, 0, 0],
+  [3, -16, 109, 40, -11, 3, 0, 0],
+  [3, -16, 108, 42, -12, 3, 0, 0],
+  [4, -17, 106, 45, -13, 3, 0, 0],
+  [4, -17, 104, 47, -13, 3, 0, 0],
+  [4, -17, 102, 50, -14, 3, 0, 0],
+  [4, -17, 100, 52, -14, 3, 0, 0],
+  [4, -18, 98, 55, -15, 4, 0, 0],
+  [4, -18, 96, 58, -15, 3, 0, 0],
+  [4, -18, 94, 60, -16, 4, 0, 0],
+  [4, -18, 91, 63, -16, 4, 0, 0],
+  [4, -18, 89, 65, -16, 4, 0, 0],
+  [4, -18, 87, 68, -17, 4, 0, 0],
+  [4, -18, 85, 70, -17, 4, 0, 0],
+  [4, -18, 82, 73, -17, 4, 0, 0],
+  [4, -18, 80, 75, -17, 4, 0, 0],
+  [4, -18, 78, 78, -18, 4, 0, 0],
+  [4, -17, 75, 80, -18, 4, 0, 0],
+  [4, -17, 73, 82, -18, 4, 0, 0],
+  [4, -17, 70, 85, -18, 4, 0, 0],
+  [4, -17, 68, 87, -18, 4, 0, 0],
+  [4, -16, 65, 89, -18, 4, 0, 0],
+  [4, -16, 63, 91, -18, 4, 0, 0],
+  [4, -16, 60, 94, -18, 4, 0, 0],
+  [3, -15, 58, 96, -18, 4, 0, 0],
+  [4, -15, 55, 98, -18, 4, 0, 0],
+  [3, -14, 52, 100, -17, 4, 0, 0],
+  [3, -14, 50, 102, -17, 4, 0, 0],
+  [3, -13, 47, 104, -17, 4, 0, 0],
+  [3, -13, 45, 106, -17, 4, 0, 0],
+  [3, -12, 42, 108, -16, 3, 0, 0],
+  [3, -11, 40, 109, -16, 3, 0, 0],
+  [3, -11, 37, 111, -15, 3, 0, 0],
+  [2, -10, 35, 113, -15, 3, 0, 0],
+  [3, -10, 32, 114, -14, 3, 0, 0],
+  [2, -9, 29, 116, -13, 3, 0, 0],
+  [2, -8, 27, 117, -13, 3, 0, 0],
+  [2, -8, 25, 119, -12, 2, 0, 0],
+  [2, -7, 22, 120, -11, 2, 0, 0],
+  [1, -6, 20, 121, -10, 2, 0, 0],
+  [1, -6, 18, 122, -9, 2, 0, 0],
+  [1, -5, 15, 123, -8, 2, 0, 0],
+  [1, -4, 13, 124, -7, 1, 0, 0],
+  [1, -4, 11, 125, -6, 1, 0, 0],
+  [1, -3, 8, 126, -5, 1, 0, 0],
+  [1, -2, 6, 126, -4, 1, 0, 0],
+  [0, -1, 4, 127, -3, 1, 0, 0],
+  [0, 0, 2, 127, -1, 0, 0, 0],
+  // [0, 1)
+  [0, 0, 0, 127, 1, 0, 0, 0],
+  [0, 0, -1, 127, 2, 0, 0, 0],
+  [0, 1, -3, 127, 4, -2, 1, 0],
+  [0, 1, -5, 127, 6, -2, 1, 0],
+  [0, 2, -6, 126, 8, -3, 1, 0],
+  [-1, 2, -7, 126, 11, -4, 2, -1],
+  [-1, 3, -8, 125, 13, -5, 2, -1],
+  [-1, 3, -10, 124, 16, -6, 3, -1],
+  [-1, 4, -11, 123, 18, -7, 3, -1],
+  [-1, 4, -12, 122, 20, -7, 3, -1],
+  [-1, 4, -13, 121, 23, -8, 3, -1],
+  [-2, 5, -14, 120, 25, -9, 4, -1],
+  [-1, 5, -15, 119, 27, -10, 4, -1],
+  [-1, 5, -16, 118, 30, -11, 4, -1],
+  [-2, 6, -17, 116, 33, -12, 5, -1],
+  [-2, 6, -17, 114, 35, -12, 5, -1],
+  [-2, 6, -18, 113, 38, -13, 5, -1],
+  [-2, 7, -19, 111, 41, -14, 6, -2],
+  [-2, 7, -19, 110, 43, -15, 6, -2],
+  [-2, 7, -20, 108, 46, -15, 6, -2],
+  [-2, 7, -20, 106, 49, -16, 6, -2],
+  [-2, 7, -21, 104, 51, -16, 7, -2],
+  [-2, 7, -21, 102, 54, -17, 7, -2],
+  [-2, 8, -21, 100, 56, -18, 7, -2],
+  [-2, 8, -22, 98, 59, -18, 7, -2],
+  [-2, 8, -22, 96, 62, -19, 7, -2],
+  [-2, 8, -22, 94, 64, -19, 7, -2],
+  [-2, 8, -22, 91, 67, -20, 8, -2],
+  [-2, 8, -22, 89, 69, -20, 8, -2],
+  [-2, 8, -22, 87, 72, -21, 8, -2],
+  [-2, 8, -21, 84, 74, -21, 8, -2],
+  [-2, 8, -22, 82, 77, -21, 8, -2],
+  [-2, 8, -21, 79, 79, -21, 8, -2],
+  [-2, 8, -21, 77, 82, -22, 8, -2],
+  [-2, 8, -21, 74, 84, -21, 8, -2],
+  [-2, 8, -21, 72, 87, -22, 8, -2],
+  [-2, 8, -20, 69, 89, -22, 8, -2],
+  [-2, 8, -20, 67, 91, -22, 8, -2],
+  [-2, 7, -19, 64, 94, -22, 8, -2],
+  [-2, 7, -19, 62, 96, -22, 8, -2],
+  [-2, 7, -18, 59, 98, -22, 8, -2],
+  [-2, 7, -18, 56, 100, -21, 8, -2],
+  [-2, 7, -17, 54, 102, -21, 7, -2],
+  [-2, 7, -16, 51, 104, -21, 7, -2],
+  [-2, 6, -16, 49, 106, -20, 7, -2],
+  [-2, 6, -15, 46, 108, -20, 7, -2],
+  [-2, 6, -15, 43, 110, -19, 7, -2],
+  [-2, 6, -14, 41, 111, -19, 7, -2],
+  [-1, 5, -13, 38, 113, -18, 6, -2],
+  [-1, 5, -12, 35, 114, -17, 6, -2],
+  [-1, 5, -12, 33, 116, -17, 6, -2],
+  [-1, 4, -11, 30, 118, -16, 5, -1],
+  [-1, 4, -10, 27, 119, -15, 5, -1],
+  [-1, 4, -9, 25, 120, -14, 5, -2],
+  [-1, 3, -8, 23, 121, -13, 4, -1],
+  [-1, 3, -7, 20, 122, -12, 4, -1],
+  [-1, 3, -7, 18, 123, -11, 4, -1],
+  [-1, 3, -6, 16, 124, -10, 3, -1],
+  [-1, 2, -5, 13, 125, -8, 3, -1],
+  [-1, 2, -4, 11, 126, -7, 2, -1],
+  [0, 1, -3, 8, 126, -6, 2, 0],
+  [0, 1, -2, 6, 127, -5, 1, 0],
+  [0, 1, -2, 4, 127, -3, 1, 0],
+  [0, 0, 0, 2, 127, -1, 0, 0],
+  // [1, 2)
+  [0, 0, 0, 1, 127, 0, 0, 0],
+  [0, 0, 0, -1, 127, 2, 0, 0],
+  [0, 0, 1, -3, 127, 4, -1, 0],
+  [0, 0, 1, -4, 126, 6, -2, 1],
+  [0, 0, 1, -5, 126, 8, -3, 1],
+  [0, 0, 1, -6, 125, 11, -4, 1],
+  [0, 0, 1, -7, 124, 13, -4, 1],
+  [0, 0, 2, -8, 123, 15, -5, 1],
+  [0, 0, 2, -9, 122, 18, -6, 1],
+  [0, 0, 2, -10, 121, 20, -6, 1],
+  [0, 0, 2, -11, 120, 22, -7, 2],
+  [0, 0, 2, -12, 119, 25, -8, 2],
+  [0, 0, 3, -13, 117, 27, -8, 2],
+  [0, 0, 3, -13, 116, 29, -9, 2],
+  [0, 0, 3, -14, 114, 32, -10, 3],
+  [0, 0, 3, -15, 113, 35, -10, 2],
+  [0, 0, 3, -15, 111, 37, -11, 3],
+  [0, 0, 3, -16, 109, 40, -11, 3],
+  [0, 0, 3, -16, 108, 42, -12, 3],
+  [0, 0, 4, -17, 106, 45, -13, 3],
+  [0, 0, 4, -17, 104, 47, -13, 3],
+  [0, 0, 4, -17, 102, 50, -14, 3],
+  [0, 0, 4, -17, 100, 52, -14, 3],
+  [0, 0, 4, -18, 98, 55, -15, 4],
+  [0, 0, 4, -18, 96, 58, -15, 3],
+  [0, 0, 4, -18, 94, 60, -16, 4],
+  [0, 0, 4, -18, 91, 63, -16, 4],
+  [0, 0, 4, -18, 89, 65, -16, 4],
+  [0, 0, 4, -18, 87, 68, -17, 4],
+  [0, 0, 4, -18, 85, 70, -17, 4],
+  [0, 0, 4, -18, 82, 73, -17, 4],
+  [0, 0, 4, -18, 80, 75, -17, 4],
+  [0, 0, 4, -18, 78, 78, -18, 4],
+  [0, 0, 4, -17, 75, 80, -18, 4],
+  [0, 0, 4, -17, 73, 82, -18, 4],
+  [0, 0, 4, -17, 70, 85, -18, 4],
+  [0, 0, 4, -17, 68, 87, -18, 4],
+  [0, 0, 4, -16, 65, 89, -18, 4],
+  [0, 0, 4, -16, 63, 91, -18, 4],
+  [0, 0, 4, -16, 60, 94, -18, 4],
+  [0, 0, 3, -15, 58, 96, -18, 4],
+  [0, 0, 4, -15, 55, 98, -18, 4],
+  [0, 0, 3, -14, 52, 100, -17, 4],
+  [0, 0, 3, -14, 50, 102, -17, 4],
+  [0, 0, 3, -13, 47, 104, -17, 4],
+  [0, 0, 3, -13, 45, 106, -17, 4],
+  [0, 0, 3, -12, 42, 108, -16, 3],
+  [0, 0, 3, -11, 40, 109, -16, 3],
+  [0, 0, 3, -11, 37, 111, -15, 3],
+  [0, 0, 2, -10, 35, 113, -15, 3],
+  [0, 0, 3, -10, 32, 114, -14, 3],
+  [0, 0, 2, -9, 29, 116, -13, 3],
+  [0, 0, 2, -8, 27, 117, -13, 3],
+  [0, 0, 2, -8, 25, 119, -12, 2],
+  [0, 0, 2, -7, 22, 120, -11, 2],
+  [0, 0, 1, -6, 20, 121, -10, 2],
+  [0, 0, 1, -6, 18, 122, -9, 2],
+  [0, 0, 1, -5, 15, 123, -8, 2],
+  [0, 0, 1, -4, 13, 124, -7, 1],
+  [0, 0, 1, -4, 11, 125, -6, 1],
+  [0, 0, 1, -3, 8, 126, -5, 1],
+  [0, 0, 1, -2, 6, 126, -4, 1],
+  [0, 0, 0, -1, 4, 127, -3, 1],
+  [0, 0, 0, 0, 2, 127, -1, 0],
+  // dummy, replicates the last filter
+  [0, 0, 0, 0, 2, 127, -1, 0]
+];
+
+// Reciprocals 2^14 / (1 + i / 256), rounded
+const DIV_LUT: [i32; DIV_LUT_NUM] = [
+  16384, 16320, 16257, 16194, 16132, 16070, 16009, 15948, 15888, 15828, 15768,
+  15709, 15650, 15592, 15534, 15477, 15420, 15364, 15308, 15252, 15197, 15142,
+  15087, 15033, 14980, 14926, 14873, 14821, 14769, 14717, 14665, 14614, 14564,
+  14513, 14463, 14413, 14364, 14315, 14266, 14218, 14170, 14122, 14075, 14028,
+  13981, 13935, 13888, 13843, 13797, 13752, 13707, 13662, 13618, 13574, 13530,
+  13487, 13443, 13400, 13358, 13315, 13273, 13231, 13190, 13148, 13107, 13066,
+  13026, 12985, 12945, 12906, 12866, 12827, 12788, 12749, 12710, 12672, 12633,
+  12596, 12558, 12520, 12483, 12446, 12409, 12373, 12336, 12300, 12264, 12228,
+  12193, 12157, 12122, 12087, 12053, 12018, 11984, 11950, 11916, 11882, 11848,
+  11815, 11782, 11749, 11716, 11683, 11651, 11619, 11586, 11555, 11523, 11491,
+  11460, 11429, 11398, 11367, 11336, 11305, 11275, 11245, 11215, 11185, 11155,
+  11125, 11096, 11067, 11038, 11009, 10980, 10951, 10923, 10894, 10866, 10838,
+  10810, 10782, 10755, 10727, 10700, 10673, 10645, 10618, 10592, 10565, 10538,
+  10512, 10486, 10460, 10434, 10408, 10382, 10356, 10331, 10305, 10280, 10255,
+  10230, 10205, 10180, 10156, 10131, 10107, 10082, 10058, 10034, 10010, 9986,
+  9963, 9939, 9916, 9892, 9869, 9846, 9823, 9800, 9777, 9754, 9732, 9709, 9687,
+  9664, 9642, 9620, 9598, 9576, 9554, 9533, 9511, 9489, 9468, 9447, 9425, 9404,
+  9383, 9362, 9341, 9321, 9300, 9279, 9259, 9239, 9218, 9198, 9178, 9158, 9138,
+  9118, 9098, 9079, 9059, 9039, 9020, 9001, 8981, 8962, 8943, 8924, 8905, 8886,
+  8867, 8849, 8830, 8812, 8793, 8775, 8756, 8738, 8720, 8702, 8684, 8666, 8648,
+  8630, 8613, 8595, 8577, 8560, 8542, 8525, 8508, 8490, 8473, 8456, 8439, 8422,
+  8405, 8389, 8372, 8355, 8339, 8322, 8306, 8289, 8273, 8257, 8240, 8224, 8208,
+  8192
+];
+
+/// Shear parameters of the two filter stages of a warp
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub struct WarpShear {
+  pub alpha: i32,
+  pub beta: i32,
+  pub gamma: i32,
+  pub delta: i32
+}
+
+fn round2(x: i64, n: usize) -> i64 {
+  if n == 0 {
+    x
+  } else {
+    (x + (1 << (n - 1))) >> n
+  }
+}
+
+pub fn round2signed(x: i64, n: usize) -> i64 {
+  if x >= 0 {
+    round2(x, n)
+  } else {
+    -round2(-x, n)
+  }
+}
+
+fn clamp16(x: i64) -> i32 {
+  cmp::max(-32768, cmp::min(32767, x)) as i32
+}
+
+// Fixed-point reciprocal of `d`, as a factor and a right shift
+fn resolve_divisor(d: i64) -> (usize, i64) {
+  let n = 63 - (d.abs() as u64).leading_zeros() as usize;
+  let e = d.abs() - (1 << n);
+  let f = if n > DIV_LUT_BITS {
+    round2(e, n - DIV_LUT_BITS)
+  } else {
+    e << (DIV_LUT_BITS - n)
+  } as usize;
+  let factor = DIV_LUT[f] as i64;
+  (n + DIV_LUT_PREC_BITS, if d < 0 { -factor } else { factor })
+}
+
+/// Decomposes the affine model `params` into the shears of a warp, if the
+/// model is small enough to be applied by the 8-tap warp filters.
+pub fn setup_shear(params: &[i32; 6]) -> Option<WarpShear> {
+  if params[2] <= 0 {
+    return None;
+  }
+
+  let (shift, factor) = resolve_divisor(params[2] as i64);
+  let alpha = clamp16(params[2] as i64 - (1 << WARPEDMODEL_PREC_BITS));
+  let beta = clamp16(params[3] as i64);
+  let gamma = clamp16(round2signed(
+    ((params[4] as i64) << WARPEDMODEL_PREC_BITS) * factor,
+    shift
+  ));
+  let delta = clamp16(
+    params[5] as i64
+      - round2signed(params[3] as i64 * params[4] as i64 * factor, shift)
+      - (1 << WARPEDMODEL_PREC_BITS)
+  );
+
+  let reduce = |v: i32| {
+    (round2signed(v as i64, WARP_PARAM_REDUCE_BITS) << WARP_PARAM_REDUCE_BITS)
+      as i32
+  };
+  let shear = WarpShear {
+    alpha: reduce(alpha),
+    beta: reduce(beta),
+    gamma: reduce(gamma),
+    delta: reduce(delta)
+  };
+
+  if 4 * shear.alpha.abs() + 7 * shear.beta.abs() >= 1 << WARPEDMODEL_PREC_BITS
+    || 4 * shear.gamma.abs() + 4 * shear.delta.abs()
+      >= 1 << WARPEDMODEL_PREC_BITS
+  {
+    return None;
+  }
+
+  Some(shear)
+}
+
+/// Affine model fitted by least squares to the warp `samples` of the block
+/// at `bo`, from `BlockContext::find_warp_samples`, going through the motion
+/// vector `mv` of the center of the block. None if the samples are degenerate
+/// or the model is too large to be applied by the warp filters.
+pub fn local_warp_params(
+  samples: &[[i32; 4]], bo: &BlockOffset, bsize: BlockSize,
+  mv: MotionVector
+) -> Option<[i32; 6]> {
+  let mid_y = ((bo.y << MI_SIZE_LOG2) + bsize.height() / 2) as i64 - 1;
+  let mid_x = ((bo.x << MI_SIZE_LOG2) + bsize.width() / 2) as i64 - 1;
+  let suy = mid_y * 8;
+  let sux = mid_x * 8;
+  let duy = suy + mv.row as i64;
+  let dux = sux + mv.col as i64;
+
+  let ls_product = |a: i64, b: i64| ((a * b) >> 2) + (a + b);
+
+  let mut a = [[0i64; 2]; 2];
+  let mut bx = [0i64; 2];
+  let mut by = [0i64; 2];
+  for sample in samples {
+    let sy = sample[0] as i64 - suy;
+    let sx = sample[1] as i64 - sux;
+    let dy = sample[2] as i64 - duy;
+    let dx = sample[3] as i64 - dux;
+    if (sx - dx).abs() < LS_MV_MAX && (sy - dy).abs() < LS_MV_MAX {
+      a[0][0] += ls_product(sx, sx) + 8;
+      a[0][1] += ls_product(sx, sy) + 4;
+      a[1][1] += ls_product(sy, sy) + 8;
+      bx[0] += ls_product(sx, dx) + 8;
+      bx[1] += ls_product(sy, dx) + 4;
+      by[0] += ls_product(sx, dy) + 4;
+      by[1] += ls_product(sy, dy) + 8;
+    }
+  }
+
+  let det = a[0][0] * a[1][1] - a[0][1] * a[0][1];
+  if det == 0 {
+    return None;
+  }
+
+  let (mut shift, mut factor) = resolve_divisor(det);
+  if shift < WARPEDMODEL_PREC_BITS {
+    factor <<= WARPEDMODEL_PREC_BITS - shift;
+    shift = 0;
+  } else {
+    shift -= WARPEDMODEL_PREC_BITS;
+  }
+
+  let one = 1 << WARPEDMODEL_PREC_BITS;
+  let max = WARPEDMODEL_NONDIAGAFFINE_CLAMP - 1;
+  let nondiag = |v: i64| {
+    cmp::max(-max, cmp::min(max, round2signed(v * factor, shift)))
+  };
+  let diag = |v: i64| {
+    cmp::max(one - max, cmp::min(one + max, round2signed(v * factor, shift)))
+  };
+
+  let mut params = [0i32; 6];
+  params[2] = diag(a[1][1] * bx[0] - a[0][1] * bx[1]) as i32;
+  params[3] = nondiag(-a[0][1] * bx[0] + a[0][0] * bx[1]) as i32;
+  params[4] = nondiag(a[1][1] * by[0] - a[0][1] * by[1]) as i32;
+  params[5] = diag(-a[0][1] * by[0] + a[0][0] * by[1]) as i32;
+
+  let vx = ((mv.col as i64) << (WARPEDMODEL_PREC_BITS - 3))
+    - (mid_x * (params[2] as i64 - one) + mid_y * params[3] as i64);
+  let vy = ((mv.row as i64) << (WARPEDMODEL_PREC_BITS - 3))
+    - (mid_x * params[4] as i64 + mid_y * (params[5] as i64 - one));
+  let trans_clamp = |v: i64| {
+    cmp::max(-WARPEDMODEL_TRANS_CLAMP, cmp::min(WARPEDMODEL_TRANS_CLAMP - 1, v))
+      as i32
+  };
+  params[0] = trans_clamp(vx);
+  params[1] = trans_clamp(vy);
+
+  setup_shear(&params).map(|_| params)
+}
+
+/// Range and precision, in bits, with which the global motion parameter `i`
+/// of a model of type `gm_type` is coded. The diagonal of the matrix is coded
+/// relative to 1.
+pub fn gm_param_bits(
+  i: usize, gm_type: GlobalMVMode, allow_high_precision_mv: bool
+) -> (usize, usize) {
+  if i >= 2 {
+    (GM_ABS_ALPHA_BITS, GM_ALPHA_PREC_BITS)
+  } else if gm_type == GlobalMVMode::TRANSLATION {
+    let hp = allow_high_precision_mv as usize;
+    (GM_ABS_TRANS_ONLY_BITS - 1 + hp, GM_TRANS_ONLY_PREC_BITS - 1 + hp)
+  } else {
+    (GM_ABS_TRANS_BITS, GM_TRANS_PREC_BITS)
+  }
+}
+
+/// Motion vector of the GLOBALMV mode for the block at `bo`, with the global
+/// motion `params` of type `gm_type`. Non-translational models use the motion
+/// of the center of the block.
+pub fn global_mv(
+  params: &[i32; 6], gm_type: GlobalMVMode, bo: &BlockOffset,
+  bsize: BlockSize, allow_high_precision_mv: bool, force_integer_mv: bool
+) -> MotionVector {
+  let (row, col) = match gm_type {
+    GlobalMVMode::IDENTITY => (0, 0),
+    // The translation is stored as (row, col), unlike the affine models
+    GlobalMVMode::TRANSLATION => (
+      params[0] >> (WARPEDMODEL_PREC_BITS - 3),
+      params[1] >> (WARPEDMODEL_PREC_BITS - 3)
+    ),
+    _ => {
+      let x = ((bo.x << MI_SIZE_LOG2) + bsize.width() / 2 - 1) as i64;
+      let y = ((bo.y << MI_SIZE_LOG2) + bsize.height() / 2 - 1) as i64;
+      let xc = (params[2] as i64 - (1 << WARPEDMODEL_PREC_BITS)) * x
+        + params[3] as i64 * y
+        + params[0] as i64;
+      let yc = params[4] as i64 * x
+        + (params[5] as i64 - (1 << WARPEDMODEL_PREC_BITS)) * y
+        + params[1] as i64;
+      if allow_high_precision_mv {
+        (
+          round2signed(yc, WARPEDMODEL_PREC_BITS - 3) as i32,
+          round2signed(xc, WARPEDMODEL_PREC_BITS - 3) as i32
+        )
+      } else {
+        (
+          round2signed(yc, WARPEDMODEL_PREC_BITS - 2) as i32 * 2,
+          round2signed(xc, WARPEDMODEL_PREC_BITS - 2) as i32 * 2
+        )
+      }
+    }
+  };
+
+  lower_mv_precision(
+    MotionVector { row: row as i16, col: col as i16 },
+    allow_high_precision_mv,
+    force_integer_mv
+  )
+}
+
+/// Rounds `mv` to the precision of the motion vectors of the frame. Integer
+/// motion vectors round to the nearest full pixel, ties toward zero.
+pub fn lower_mv_precision(
+  mv: MotionVector, allow_high_precision_mv: bool, force_integer_mv: bool
+) -> MotionVector {
+  let lower = |v: i16| {
+    if force_integer_mv {
+      let a = (v.abs() + 3) >> 3;
+      if v > 0 {
+        a << 3
+      } else {
+        -(a << 3)
+      }
+    } else if !allow_high_precision_mv && v & 1 != 0 {
+      v - v.signum()
+    } else {
+      v
+    }
+  };
+
+  MotionVector { row: lower(mv.row), col: lower(mv.col) }
+}
+
+// Last column and row of a plane of a frame of the given luma size. Motion
+// compensation extends frames by replicating these.
+fn last_pixel(
+  cfg: &PlaneConfig, frame_width: usize, frame_height: usize
+) -> (isize, isize) {
+  (
+    ((frame_width + cfg.xdec) >> cfg.xdec) as isize - 1,
+    ((frame_height + cfg.ydec) >> cfg.ydec) as isize - 1
+  )
+}
+
+fn ref_pixel(
+  reference: &Plane, last_x: isize, last_y: isize, x: isize, y: isize
+) -> i32 {
+  let x = cmp::max(0, cmp::min(last_x, x)) as usize;
+  let y = cmp::max(0, cmp::min(last_y, y)) as usize;
+  reference.p(x, y) as i32
+}
+
+// Subpixel interpolation of the `w`x`h` block at `po` of the reference plane
+// displaced by `mv`, with the vertical filter rounded by `round1` bits. Blocks
+// of width or height 4 use the 4-tap filters in that direction.
+fn convolve(
+  po: &PlaneOffset, w: usize, h: usize, reference: &Plane, mv: MotionVector,
+  frame_width: usize, frame_height: usize, round1: usize
+) -> Vec<i32> {
+  let (last_x, last_y) =
+    last_pixel(&reference.cfg, frame_width, frame_height);
+  let x16 = ((po.x as isize) << SUBPEL_BITS)
+    + ((2 * mv.col as isize) >> reference.cfg.xdec);
+  let y16 = ((po.y as isize) << SUBPEL_BITS)
+    + ((2 * mv.row as isize) >> reference.cfg.ydec);
+  let (x0, y0) = (x16 >> SUBPEL_BITS, y16 >> SUBPEL_BITS);
+  let filter_x =
+    &SUBPEL_FILTERS[(w <= 4) as usize][(x16 & SUBPEL_MASK) as usize];
+  let filter_y =
+    &SUBPEL_FILTERS[(h <= 4) as usize][(y16 & SUBPEL_MASK) as usize];
+
+  let mut intermediate = vec![0i32; (h + 7) * w];
+  for r in 0..h + 7 {
+    for c in 0..w {
+      let s: i32 = (0..8)
+        .map(|t| {
+          filter_x[t] * ref_pixel(
+            reference,
+            last_x,
+            last_y,
+            x0 + (c + t) as isize - 3,
+            y0 + r as isize - 3
+          )
+        }).sum();
+      intermediate[r * w + c] = round2(s as i64, INTER_ROUND0) as i32;
+    }
+  }
+
+  let mut pred = Vec::with_capacity(w * h);
+  for r in 0..h {
+    for c in 0..w {
+      let s: i32 =
+        (0..8).map(|t| filter_y[t] * intermediate[(r + t) * w + c]).sum();
+      pred.push(round2(s as i64, round1) as i32);
+    }
+  }
+  pred
+}
+
+/// Predicts the `w`x`h` block at `po` of `dst` from the same plane of the
+/// reference frame, displaced by `mv`. Blocks of width or height 4 use the
+/// 4-tap filters in that direction.
+pub fn predict_translation(
+  dst: &mut Plane, po: &PlaneOffset, w: usize, h: usize, reference: &Plane,
+  mv: MotionVector, frame_width: usize, frame_height: usize,
+  bit_depth: usize
+) {
+  let pred = convolve(
+    po, w, h, reference, mv, frame_width, frame_height, INTER_ROUND1
+  );
+
+  let max = (1 << bit_depth) - 1;
+  let stride = dst.cfg.stride;
+  for r in 0..h {
+    let row = (po.y + r) * stride + po.x;
+    for c in 0..w {
+      dst.data[row + c] = clamp(pred[r * w + c], 0, max) as u16;
+    }
+  }
+}
+
+/// Predictions of the `w`x`h` block at `po` from the same plane of the two
+/// references of a compound block, displaced by `mvs`. They keep
+/// COMPOUND_POST_ROUND more bits than pixels for blending.
+pub fn compound_predictions(
+  po: &PlaneOffset, w: usize, h: usize, refs: [&Plane; 2],
+  mvs: [MotionVector; 2], frame_width: usize, frame_height: usize
+) -> [Vec<i32>; 2] {
+  let pred = |i: usize| {
+    convolve(
+      po, w, h, refs[i], mvs[i], frame_width, frame_height, COMPOUND_ROUND1
+    )
+  };
+  [pred(0), pred(1)]
+}
+
+/// Blends the compound predictions `preds` into the `w`x`h` block at `po`
+/// of `dst`, averaging them or weighting the first by the luma `mask` of
+/// width `stride`, subsampled by `xdec` and `ydec`
+pub fn blend_compound(
+  dst: &mut Plane, po: &PlaneOffset, w: usize, h: usize,
+  preds: &[Vec<i32>; 2], mask: Option<(&[u8], usize)>, xdec: usize,
+  ydec: usize, bit_depth: usize
+) {
+  let max = (1 << bit_depth) - 1;
+  let dst_stride = dst.cfg.stride;
+  for r in 0..h {
+    for c in 0..w {
+      let (p0, p1) = (preds[0][r * w + c] as i64, preds[1][r * w + c] as i64);
+      let v = match mask {
+        Some((mask, stride)) => {
+          let m = subsampled_weight(mask, stride, c, r, xdec, ydec) as i64;
+          round2(m * p0 + (64 - m) * p1, 6 + COMPOUND_POST_ROUND)
+        }
+        None => round2(p0 + p1, 1 + COMPOUND_POST_ROUND)
+      };
+      dst.data[(po.y + r) * dst_stride + po.x + c] =
+        clamp(v as i32, 0, max) as u16;
+    }
+  }
+}
+
+/// Predicts the `w`x`h` block at `po` of `dst` by warping the same plane of
+/// the reference frame with the affine model `params`, in 8x8 units.
+pub fn predict_warp(
+  dst: &mut Plane, po: &PlaneOffset, w: usize, h: usize, reference: &Plane,
+  params: &[i32; 6], shear: WarpShear, frame_width: usize,
+  frame_height: usize, bit_depth: usize
+) {
+  let PlaneConfig { xdec, ydec, .. } = reference.cfg;
+  let (last_x, last_y) =
+    last_pixel(&reference.cfg, frame_width, frame_height);
+  let max = (1 << bit_depth) - 1;
+  let stride = dst.cfg.stride;
+  let mask = (1 << WARPEDMODEL_PREC_BITS) - 1;
+
+  let filter = |pos: i32| {
+    let offs = round2(pos as i64, WARPEDDIFF_PREC_BITS) as i32
+      + WARPEDPIXEL_PREC_SHIFTS;
+    &WARPED_FILTERS[offs as usize]
+  };
+
+  let mut intermediate = [[0i32; 8]; 15];
+  for i8 in 0..(h + 7) >> 3 {
+    for j8 in 0..(w + 7) >> 3 {
+      // The model is applied to the center of the unit, in luma pixels
+      let src_x = ((po.x + j8 * 8 + 4) << xdec) as i64;
+      let src_y = ((po.y + i8 * 8 + 4) << ydec) as i64;
+      let dst_x =
+        params[2] as i64 * src_x + params[3] as i64 * src_y + params[0] as i64;
+      let dst_y =
+        params[4] as i64 * src_x + params[5] as i64 * src_y + params[1] as i64;
+      let x4 = dst_x >> xdec;
+      let y4 = dst_y >> ydec;
+      let ix4 = (x4 >> WARPEDMODEL_PREC_BITS) as isize;
+      let sx4 = (x4 & mask) as i32;
+      let iy4 = (y4 >> WARPEDMODEL_PREC_BITS) as isize;
+      let sy4 = (y4 & mask) as i32;
+
+      for i1 in 0..15 {
+        for i2 in 0..8 {
+          let f = filter(
+            sx4 + shear.alpha * (i2 as i32 - 4) + shear.beta * (i1 as i32 - 7)
+          );
+          let s: i32 = (0..8)
+            .map(|i3| {
+              f[i3] * ref_pixel(
+                reference,
+                last_x,
+                last_y,
+                ix4 + (i2 + i3) as isize - 7,
+                iy4 + i1 as isize - 7
+              )
+            }).sum();
+          intermediate[i1][i2] = round2(s as i64, INTER_ROUND0) as i32;
+        }
+      }
+
+      for i1 in 0..cmp::min(8, h - i8 * 8) {
+        for i2 in 0..cmp::min(8, w - j8 * 8) {
+          let f = filter(
+            sy4 + shear.gamma * (i2 as i32 - 4) + shear.delta * (i1 as i32 - 4)
+          );
+          let s: i32 =
+            (0..8).map(|i3| f[i3] * intermediate[i1 + i3][i2]).sum();
+          dst.data[(po.y + i8 * 8 + i1) * stride + po.x + j8 * 8 + i2] =
+            clamp(round2(s as i64, INTER_ROUND1) as i32, 0, max) as u16;
+        }
+      }
+    }
+  }
+}
+
+fn obmc_mask(len: usize) -> &'static [i32] {
+  match len {
+    2 => &OBMC_MASK_2,
+    4 => &OBMC_MASK_4,
+    8 => &OBMC_MASK_8,
+    16 => &OBMC_MASK_16,
+    32 => &OBMC_MASK_32,
+    _ => unreachable!()
+  }
+}
+
+/// Blends into the `w`x`h` block at `po` of `dst` its prediction from the
+/// same plane of the reference frame with the motion vector `mv` of the
+/// neighbour above if `above`, or else left. The weight of the neighbour
+/// decreases away from the shared edge.
+pub fn predict_overlap(
+  dst: &mut Plane, po: &PlaneOffset, w: usize, h: usize, reference: &Plane,
+  mv: MotionVector, above: bool, frame_width: usize, frame_height: usize,
+  bit_depth: usize
+) {
+  let stride = dst.cfg.stride;
+  let mut current = Vec::with_capacity(w * h);
+  for r in 0..h {
+    let row = (po.y + r) * stride + po.x;
+    current.extend_from_slice(&dst.data[row..row + w]);
+  }
+
+  predict_translation(
+    dst, po, w, h, reference, mv, frame_width, frame_height, bit_depth
+  );
+
+  let mask = obmc_mask(if above { h } else { w });
+  for r in 0..h {
+    for c in 0..w {
+      let m = if above { mask[r] } else { mask[c] };
+      let i = (po.y + r) * stride + po.x + c;
+      let blend = m * current[r * w + c] as i32 + (64 - m) * dst.data[i] as i32;
+      dst.data[i] = round2(blend as i64, 6) as u16;
+    }
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+
+  fn ramp_plane(w: usize, h: usize) -> Plane {
+    let mut plane = Plane::new(w, h, 0, 0);
+    let stride = plane.cfg.stride;
+    for y in 0..h {
+      for x in 0..w {
+        plane.data[y * stride + x] = (x + 2 * y) as u16;
+      }
+    }
+    plane
+  }
+
+  #[test]
+  fn translation_full_pel() {
+    let reference = ramp_plane(32, 32);
+    let mut dst = Plane::new(32, 32, 0, 0);
+    let po = PlaneOffset { x: 4, y: 8 };
+    let mv = MotionVector { row: 8, col: -48 };
+    predict_translation(&mut dst, &po, 8, 4, &reference, mv, 32, 32, 8);
+    for r in 0..4 {
+      for c in 0..8 {
+        // Columns left of the frame replicate its first column
+        let x = cmp::max(0, c as isize - 2) as usize;
+        assert_eq!(dst.p(4 + c, 8 + r), reference.p(x, 9 + r));
+      }
+    }
+  }
+
+  #[test]
+  fn translation_subpel() {
+    let reference = ramp_plane(32, 32);
+    let mut dst = Plane::new(32, 32, 0, 0);
+    let po = PlaneOffset { x: 8, y: 8 };
+    // Half a pixel right, an eighth of a pixel down
+    let mv = MotionVector { row: 1, col: 4 };
+    for &(w, h) in &[(8, 8), (4, 16), (16, 4)] {
+      predict_translation(&mut dst, &po, w, h, &reference, mv, 32, 32, 8);
+      for r in 0..h {
+        for c in 0..w {
+          let expected = (8 + c) as f64 + 0.5 + 2. * ((8 + r) as f64 + 0.125);
+          assert!((dst.p(8 + c, 8 + r) as f64 - expected).abs() <= 0.5);
+        }
+      }
+    }
+  }
+
+  #[test]
+  fn compound_blending() {
+    let reference = ramp_plane(32, 32);
+    let black = Plane::new(32, 32, 0, 0);
+    let po = PlaneOffset { x: 8, y: 8 };
+    let mv = MotionVector { row: 3, col: -5 };
+    let mut single = Plane::new(32, 32, 0, 0);
+    predict_translation(&mut single, &po, 8, 8, &reference, mv, 32, 32, 8);
+
+    // Averaging a prediction with itself, or weighting it fully, only differs
+    // from the single prediction by the rounding
+    let same = compound_predictions(
+      &po, 8, 8, [&reference, &reference], [mv, mv], 32, 32
+    );
+    let other =
+      compound_predictions(&po, 8, 8, [&reference, &black], [mv, mv], 32, 32);
+    let mask = [64u8; 8 * 8];
+    for &(preds, mask) in &[(&same, None), (&other, Some((&mask[..], 8)))] {
+      let mut dst = Plane::new(32, 32, 0, 0);
+      blend_compound(&mut dst, &po, 8, 8, preds, mask, 0, 0, 8);
+      for r in 0..8 {
+        for c in 0..8 {
+          let d = dst.p(8 + c, 8 + r) as i32 - single.p(8 + c, 8 + r) as i32;
+          assert!(d.abs() <= 1);
+        }
+      }
+    }
+  }
+
+  #[test]
+  fn shear_validity() {
+    assert_eq!(setup_shear(&GM_IDENTITY_PARAMS), Some(WarpShear::default()));
+
+    // A 1% zoom
+    let zoom = [0, 0, 66191, 0, 0, 66191];
+    let shear = setup_shear(&zoom).unwrap();
+    assert_eq!(shear.alpha, 640);
+    assert_eq!(shear.beta, 0);
+    assert_eq!(shear.gamma, 0);
+    assert_eq!(shear.delta, 640);
+
+    // A quarter of a turn is too much for the warp filters
+    let rotation = [0, 0, 0, 1 << 16, -(1 << 16), 0];
+    assert!(setup_shear(&rotation).is_none());
+    let rotation = [0, 0, 56756, 32768, -32768, 56756];
+    assert!(setup_shear(&rotation).is_none());
+  }
+
+  #[test]
+  fn warp_translation() {
+    let reference = ramp_plane(64, 64);
+    let mut dst = Plane::new(64, 64, 0, 0);
+    let po = PlaneOffset { x: 16, y: 24 };
+    let mut params = GM_IDENTITY_PARAMS;
+    params[0] = 3 << 16;
+    params[1] = -(1 << 15);
+    let shear = setup_shear(&params).unwrap();
+    predict_warp(&mut dst, &po, 16, 8, &reference, &params, shear, 64, 64, 8);
+    for r in 0..8 {
+      for c in 0..16 {
+        let expected = (19 + c) as f64 + 2. * ((24 + r) as f64 - 0.5);
+        assert!((dst.p(16 + c, 24 + r) as f64 - expected).abs() <= 1.);
+      }
+    }
+  }
+
+  #[test]
+  fn global_motion_vectors() {
+    let bo = BlockOffset { x: 16, y: 16 };
+    let bsize = BlockSize::BLOCK_32X32;
+
+    let mut translation = GM_IDENTITY_PARAMS;
+    translation[0] = 3 << 13;
+    translation[1] = -5 << 13;
+    let mv = global_mv(
+      &translation, GlobalMVMode::TRANSLATION, &bo, bsize, true, false
+    );
+    assert_eq!(mv, MotionVector { row: 3, col: -5 });
+    let mv = global_mv(
+      &translation, GlobalMVMode::TRANSLATION, &bo, bsize, false, true
+    );
+    assert_eq!(mv, MotionVector { row: 0, col: -8 });
+
+    // The motion of a zoom depends on the center of the block
+    let zoom = [0, 0, 66191, 0, 0, 66191];
+    let mv = global_mv(&zoom, GlobalMVMode::ROTZOOM, &bo, bsize, true, false);
+    assert_eq!(mv, MotionVector { row: 6, col: 6 });
+    let mv = global_mv(&zoom, GlobalMVMode::ROTZOOM, &bo, bsize, false, false);
+    assert_eq!(mv, MotionVector { row: 6, col: 6 });
+    let bo = BlockOffset { x: 0, y: 0 };
+    let mv = global_mv(&zoom, GlobalMVMode::ROTZOOM, &bo, bsize, true, false);
+    assert_eq!(mv, MotionVector { row: 1, col: 1 });
+  }
+
+  #[test]
+  fn overlap_blending() {
+    let reference = ramp_plane(32, 32);
+    let mut dst = Plane::new(32, 32, 0, 0);
+    let po = PlaneOffset { x: 8, y: 8 };
+    let mv = MotionVector { row: 4, col: -12 };
+
+    // Overlapping with the same motion changes nothing
+    predict_translation(&mut dst, &po, 16, 8, &reference, mv, 32, 32, 8);
+    let expected = dst.clone();
+    predict_overlap(&mut dst, &po, 16, 4, &reference, mv, true, 32, 32, 8);
+    assert_eq!(dst.data, expected.data);
+
+    let flat = Plane::new(32, 32, 0, 0);
+    for r in 0..4 {
+      for c in 0..16 {
+        dst.data[(8 + r) * dst.cfg.stride + 8 + c] = 0;
+      }
+    }
+    predict_overlap(&mut dst, &po, 16, 4, &flat, mv, true, 32, 32, 8);
+    for (r, &v) in [50, 28, 10, 0].iter().enumerate() {
+      for c in 0..16 {
+        assert_eq!(dst.p(8 + c, 8 + r), v);
+      }
+    }
+  }
+
+  #[test]
+  fn local_warp_zoom() {
+    let bo = BlockOffset { x: 8, y: 8 };
+    let bsize = BlockSize::BLOCK_16X16;
+
+    // Centers of the 16x16 blocks around, zoomed by 1/16th
+    let zoom = |p: i32| p + p / 16;
+    let samples: Vec<[i32; 4]> = [(23, 39), (39, 23), (23, 23), (23, 55)]
+      .iter()
+      .map(|&(y, x)| [y * 8, x * 8, zoom(y * 8), zoom(x * 8)])
+      .collect();
+    let mv = MotionVector { row: 20, col: 20 };
+
+    let params = local_warp_params(&samples, &bo, bsize, mv).unwrap();
+    let expected = (1 << WARPEDMODEL_PREC_BITS) * 17 / 16;
+    assert!((params[2] - expected).abs() < expected / 50);
+    assert!((params[5] - expected).abs() < expected / 50);
+    assert!(params[3].abs() < expected / 50);
+    assert!(params[4].abs() < expected / 50);
+
+    // No samples leave nothing to fit
+    assert!(local_warp_params(&[], &bo, bsize, mv).is_none());
+  }
+}
@@ -0,0 +1,529 @@
+// Copyright (c) 2017-2018, The rav1e contributors. All rights reserved
+//
+// This source code is subject to the terms of the BSD 2 Clause License and
+// the Alliance for Open Media Patent License 1.0. If the BSD 2 Clause License
+// was not distributed with this source code in the LICENSE file, you can
+// obtain it at www.aomedia.org/license/software. If the Alliance for Open
+// Media Patent License 1.0 was not distributed with this source code in the
+// PATENTS file, you can obtain it at www.aomedia.org/license/patent.
+
+#![cfg_attr(feature = "cargo-clippy", allow(cast_lossless))]
+
+use context::*;
+use mc::*;
+use partition::*;
+use plane::*;
+use std::cmp;
+use Frame;
+use FrameInvariants;
+
+// Corners are the strongest of each cell of this size
+const CORNER_CELL: usize = 16;
+const MAX_CORNERS: usize = 1024;
+// Minimum of the smallest eigenvalue of the structure tensor over a 5x5
+// window, from central differences
+const MIN_CORNER_SCORE: f64 = 100.;
+
+// Corners are matched with 13x13 patches
+const PATCH_RADIUS: usize = 6;
+const MIN_NCC: f64 = 0.75;
+
+const RANSAC_ITERATIONS: usize = 64;
+const INLIER_THRESHOLD: f64 = 1.25;
+const MIN_INLIERS: usize = 8;
+
+// A more complex model must reduce the prediction error by this factor
+const GM_ERROR_ADVANTAGE: f64 = 0.95;
+
+/// A corner of the current frame matched in a reference frame
+#[derive(Copy, Clone, Debug)]
+struct Correspondence {
+  x: f64,
+  y: f64,
+  rx: f64,
+  ry: f64
+}
+
+// Shi-Tomasi corners: the pixels of each cell with the largest smallest
+// eigenvalue of the structure tensor, strongest first
+fn detect_corners(
+  plane: &Plane, width: usize, height: usize
+) -> Vec<(usize, usize)> {
+  let margin = PATCH_RADIUS + 2;
+  if width <= 2 * margin || height <= 2 * margin {
+    return Vec::new();
+  }
+
+  // Horizontal 5-pixel sums of the products of the gradients
+  let mut sxx = vec![0i32; width * height];
+  let mut syy = vec![0i32; width * height];
+  let mut sxy = vec![0i32; width * height];
+  let mut gxx = vec![0i32; width];
+  let mut gyy = vec![0i32; width];
+  let mut gxy = vec![0i32; width];
+  for y in 1..height - 1 {
+    for x in 1..width - 1 {
+      let gx = plane.p(x + 1, y) as i32 - plane.p(x - 1, y) as i32;
+      let gy = plane.p(x, y + 1) as i32 - plane.p(x, y - 1) as i32;
+      gxx[x] = gx * gx;
+      gyy[x] = gy * gy;
+      gxy[x] = gx * gy;
+    }
+    for x in 3..width - 3 {
+      sxx[y * width + x] = gxx[x - 2..x + 3].iter().sum();
+      syy[y * width + x] = gyy[x - 2..x + 3].iter().sum();
+      sxy[y * width + x] = gxy[x - 2..x + 3].iter().sum();
+    }
+  }
+
+  let score = |x: usize, y: usize| {
+    let window = |s: &[i32]| {
+      (0..5).map(|i| s[(y + i - 2) * width + x]).sum::<i32>() as f64
+    };
+    let (a, b, c) = (window(&sxx), window(&sxy), window(&syy));
+    (a + c) / 2. - ((a - c) * (a - c) / 4. + b * b).sqrt()
+  };
+
+  let mut corners = Vec::new();
+  for cy in 0..height / CORNER_CELL {
+    for cx in 0..width / CORNER_CELL {
+      let mut best = (MIN_CORNER_SCORE, None);
+      let y0 = cmp::max(margin, cy * CORNER_CELL);
+      let y1 = cmp::min(height - margin, (cy + 1) * CORNER_CELL);
+      let x0 = cmp::max(margin, cx * CORNER_CELL);
+      let x1 = cmp::min(width - margin, (cx + 1) * CORNER_CELL);
+      for y in y0..y1 {
+        for x in x0..x1 {
+          let s = score(x, y);
+          if s > best.0 {
+            best = (s, Some((x, y)));
+          }
+        }
+      }
+      if let Some(corner) = best.1 {
+        corners.push((best.0, corner));
+      }
+    }
+  }
+
+  corners.sort_by(|a, b| b.0.partial_cmp(&a.0).unwrap());
+  corners.truncate(MAX_CORNERS);
+  corners.into_iter().map(|(_, corner)| corner).collect()
+}
+
+// Normalized cross-correlation of the patches centered on (ax, ay) of `a`
+// and on (bx, by) of `b`
+fn patch_ncc(
+  a: &Plane, ax: usize, ay: usize, b: &Plane, bx: usize, by: usize
+) -> f64 {
+  let (mut sa, mut sb, mut saa, mut sbb, mut sab) = (0i64, 0i64, 0, 0, 0);
+  for dy in 0..2 * PATCH_RADIUS + 1 {
+    for dx in 0..2 * PATCH_RADIUS + 1 {
+      let pa = a.p(ax + dx - PATCH_RADIUS, ay + dy - PATCH_RADIUS) as i64;
+      let pb = b.p(bx + dx - PATCH_RADIUS, by + dy - PATCH_RADIUS) as i64;
+      sa += pa;
+      sb += pb;
+      saa += pa * pa;
+      sbb += pb * pb;
+      sab += pa * pb;
+    }
+  }
+  let n = ((2 * PATCH_RADIUS + 1) * (2 * PATCH_RADIUS + 1)) as i64;
+  let var_a = n * saa - sa * sa;
+  let var_b = n * sbb - sb * sb;
+  if var_a == 0 || var_b == 0 {
+    return 0.;
+  }
+  (n * sab - sa * sb) as f64 / ((var_a as f64) * (var_b as f64)).sqrt()
+}
+
+// Subpixel offset of the match (rx, ry) of the corner (x, y), from a
+// Lucas-Kanade step over the patch of the match
+fn refine_match(
+  plane: &Plane, x: usize, y: usize, reference: &Plane, rx: usize, ry: usize
+) -> (f64, f64) {
+  let (mut gxx, mut gxy, mut gyy, mut ex, mut ey) = (0i64, 0i64, 0, 0, 0);
+  for dy in 0..2 * PATCH_RADIUS + 1 {
+    for dx in 0..2 * PATCH_RADIUS + 1 {
+      let (px, py) = (rx + dx - PATCH_RADIUS, ry + dy - PATCH_RADIUS);
+      let gx = reference.p(px + 1, py) as i64 - reference.p(px - 1, py) as i64;
+      let gy = reference.p(px, py + 1) as i64 - reference.p(px, py - 1) as i64;
+      let e = plane.p(x + dx - PATCH_RADIUS, y + dy - PATCH_RADIUS) as i64
+        - reference.p(px, py) as i64;
+      gxx += gx * gx;
+      gxy += gx * gy;
+      gyy += gy * gy;
+      ex += gx * e;
+      ey += gy * e;
+    }
+  }
+  // The gradients are twice the derivatives
+  let det = (gxx * gyy - gxy * gxy) as f64;
+  if det <= 0. {
+    return (0., 0.);
+  }
+  let dx = 2. * (gyy * ex - gxy * ey) as f64 / det;
+  let dy = 2. * (gxx * ey - gxy * ex) as f64 / det;
+  (dx.max(-1.).min(1.), dy.max(-1.).min(1.))
+}
+
+// Matches each corner of `plane` to the most similar corner of `reference`
+// within `radius` pixels
+fn match_corners(
+  plane: &Plane, corners: &[(usize, usize)], reference: &Plane,
+  ref_corners: &[(usize, usize)], radius: usize
+) -> Vec<Correspondence> {
+  let mut matches = Vec::new();
+  for &(x, y) in corners {
+    let mut best = (MIN_NCC, None);
+    for &(rx, ry) in ref_corners {
+      if (rx as isize - x as isize).abs() > radius as isize
+        || (ry as isize - y as isize).abs() > radius as isize
+      {
+        continue;
+      }
+      let ncc = patch_ncc(plane, x, y, reference, rx, ry);
+      if ncc > best.0 {
+        best = (ncc, Some((rx, ry)));
+      }
+    }
+    if let Some((rx, ry)) = best.1 {
+      let (dx, dy) = refine_match(plane, x, y, reference, rx, ry);
+      matches.push(Correspondence {
+        x: x as f64,
+        y: y as f64,
+        rx: rx as f64 + dx,
+        ry: ry as f64 + dy
+      });
+    }
+  }
+  matches
+}
+
+// Solves the n x n linear system of augmented matrix `a`
+fn solve(a: &mut [[f64; 5]; 4], n: usize) -> Option<[f64; 4]> {
+  for i in 0..n {
+    let pivot = (i..n)
+      .max_by(|&j, &k| a[j][i].abs().partial_cmp(&a[k][i].abs()).unwrap())
+      .unwrap();
+    if a[pivot][i].abs() < 1e-6 {
+      return None;
+    }
+    a.swap(i, pivot);
+    for j in i + 1..n {
+      let f = a[j][i] / a[i][i];
+      for k in i..n + 1 {
+        a[j][k] -= f * a[i][k];
+      }
+    }
+  }
+
+  let mut x = [0.; 4];
+  for i in (0..n).rev() {
+    let s: f64 = (i + 1..n).map(|j| a[i][j] * x[j]).sum();
+    x[i] = (a[i][n] - s) / a[i][i];
+  }
+  Some(x)
+}
+
+// Least squares solution of the overdetermined system of `rows`, each made
+// of n coefficients and a right-hand side
+fn least_squares(rows: &[([f64; 4], f64)], n: usize) -> Option<[f64; 4]> {
+  let mut a = [[0.; 5]; 4];
+  for &(ref r, rhs) in rows {
+    for i in 0..n {
+      for j in 0..n {
+        a[i][j] += r[i] * r[j];
+      }
+      a[i][n] += r[i] * rhs;
+    }
+  }
+  solve(&mut a, n)
+}
+
+// Fits a model of type `gm_type` to `points`, in the order of the AV1
+// parameters: the model maps (x, y) to (m[2] x + m[3] y + m[0],
+// m[4] x + m[5] y + m[1])
+fn fit_model(
+  gm_type: GlobalMVMode, points: &[Correspondence]
+) -> Option<[f64; 6]> {
+  match gm_type {
+    GlobalMVMode::TRANSLATION => {
+      let n = points.len() as f64;
+      let tx: f64 = points.iter().map(|p| p.rx - p.x).sum();
+      let ty: f64 = points.iter().map(|p| p.ry - p.y).sum();
+      Some([tx / n, ty / n, 1., 0., 0., 1.])
+    }
+    GlobalMVMode::ROTZOOM => {
+      let mut rows = Vec::with_capacity(2 * points.len());
+      for p in points {
+        rows.push(([p.x, p.y, 1., 0.], p.rx));
+        rows.push(([p.y, -p.x, 0., 1.], p.ry));
+      }
+      least_squares(&rows, 4).map(|m| [m[2], m[3], m[0], m[1], -m[1], m[0]])
+    }
+    GlobalMVMode::AFFINE => {
+      let rows_x: Vec<_> =
+        points.iter().map(|p| ([p.x, p.y, 1., 0.], p.rx)).collect();
+      let rows_y: Vec<_> =
+        points.iter().map(|p| ([p.x, p.y, 1., 0.], p.ry)).collect();
+      let mx = least_squares(&rows_x, 3)?;
+      let my = least_squares(&rows_y, 3)?;
+      Some([mx[2], my[2], mx[0], mx[1], my[0], my[1]])
+    }
+    GlobalMVMode::IDENTITY => unreachable!()
+  }
+}
+
+fn find_inliers(
+  model: &[f64; 6], matches: &[Correspondence]
+) -> Vec<Correspondence> {
+  matches
+    .iter()
+    .filter(|p| {
+      let dx = model[2] * p.x + model[3] * p.y + model[0] - p.rx;
+      let dy = model[4] * p.x + model[5] * p.y + model[1] - p.ry;
+      dx * dx + dy * dy < INLIER_THRESHOLD * INLIER_THRESHOLD
+    }).cloned()
+    .collect()
+}
+
+// Robust fit of a model of type `gm_type` to `matches`. Random samples of the
+// minimum number of points are drawn deterministically.
+fn ransac(
+  gm_type: GlobalMVMode, matches: &[Correspondence]
+) -> Option<[f64; 6]> {
+  // Each point determines two parameters
+  let min_points = gm_type as usize;
+  if matches.len() < cmp::max(MIN_INLIERS, min_points) {
+    return None;
+  }
+
+  let mut state = 1u32;
+  let mut best_inliers = Vec::new();
+  let mut sample: Vec<usize> = Vec::with_capacity(min_points);
+  for _ in 0..RANSAC_ITERATIONS {
+    sample.clear();
+    while sample.len() < min_points {
+      state = state.wrapping_mul(1_103_515_245).wrapping_add(12345);
+      let i = (state >> 16) as usize % matches.len();
+      if !sample.contains(&i) {
+        sample.push(i);
+      }
+    }
+    let points: Vec<_> = sample.iter().map(|&i| matches[i]).collect();
+    if let Some(model) = fit_model(gm_type, &points) {
+      let inliers = find_inliers(&model, matches);
+      if inliers.len() > best_inliers.len() {
+        best_inliers = inliers;
+      }
+    }
+  }
+
+  // Refine the best model with all of its inliers
+  let min_inliers = cmp::max(MIN_INLIERS, matches.len() / 10);
+  if best_inliers.len() < min_inliers {
+    return None;
+  }
+  let model = fit_model(gm_type, &best_inliers)?;
+  let inliers = find_inliers(&model, matches);
+  if inliers.len() < min_inliers {
+    return None;
+  }
+  fit_model(gm_type, &inliers)
+}
+
+/// Rounds a model fitted in pixels to global motion parameters of type
+/// `gm_type` that can be coded in the frame header.
+pub fn quantize_global_motion(
+  gm_type: GlobalMVMode, model: &[f64; 6], allow_high_precision_mv: bool
+) -> [i32; 6] {
+  let quantize = |i: usize, v: f64| {
+    let (abs_bits, prec_bits) =
+      gm_param_bits(i, gm_type, allow_high_precision_mv);
+    let offset = if i % 3 == 2 { 1 << prec_bits } else { 0 };
+    let max = 1 << abs_bits;
+    let q = (v * (1 << prec_bits) as f64).round() as i32 - offset;
+    (cmp::max(-max, cmp::min(max, q)) + offset)
+      << (WARPEDMODEL_PREC_BITS - prec_bits)
+  };
+
+  let mut params = GM_IDENTITY_PARAMS;
+  match gm_type {
+    GlobalMVMode::IDENTITY => {}
+    // The translation is stored as (row, col)
+    GlobalMVMode::TRANSLATION => {
+      params[0] = quantize(0, model[1]);
+      params[1] = quantize(1, model[0]);
+    }
+    _ => {
+      for i in 0..6 {
+        params[i] = quantize(i, model[i]);
+      }
+      if gm_type == GlobalMVMode::ROTZOOM {
+        params[4] = -params[3];
+        params[5] = params[2];
+      }
+    }
+  }
+  params
+}
+
+// Sum of absolute differences between the luma of `frame` and its prediction
+// from `reference` with global motion
+fn global_motion_error(
+  fi: &FrameInvariants, frame: &Frame, reference: &Frame,
+  gm_type: GlobalMVMode, params: &[i32; 6], pred: &mut Plane
+) -> u64 {
+  let po = PlaneOffset { x: 0, y: 0 };
+  let shear = if gm_type > GlobalMVMode::TRANSLATION {
+    setup_shear(params)
+  } else {
+    None
+  };
+  if let Some(shear) = shear {
+    predict_warp(
+      pred, &po, fi.width, fi.height, &reference.planes[0], params, shear,
+      fi.width, fi.height, 8
+    );
+  } else {
+    let mv = global_mv(
+      params,
+      gm_type,
+      &BlockOffset { x: 0, y: 0 },
+      BlockSize::BLOCK_64X64,
+      fi.allow_high_precision_mv,
+      fi.force_integer_mv != 0
+    );
+    predict_translation(
+      pred, &po, fi.width, fi.height, &reference.planes[0], mv, fi.width,
+      fi.height, 8
+    );
+  }
+
+  let mut sad = 0u64;
+  for y in 0..fi.height {
+    for x in 0..fi.width {
+      sad += (frame.planes[0].p(x, y) as i32 - pred.p(x, y) as i32).abs()
+        as u64;
+    }
+  }
+  sad
+}
+
+/// Estimates the global motion of `frame` from `reference` on their luma
+/// planes. Corners of both frames are matched, then the translation,
+/// rotation and zoom, and affine models are fitted to the matches. The model
+/// that predicts the frame best is returned, as coded parameters.
+pub fn estimate_global_motion(
+  fi: &FrameInvariants, frame: &Frame, reference: &Frame
+) -> (GlobalMVMode, [i32; 6]) {
+  let corners = detect_corners(&frame.planes[0], fi.width, fi.height);
+  let ref_corners = detect_corners(&reference.planes[0], fi.width, fi.height);
+  let radius = cmp::max(CORNER_CELL, cmp::max(fi.width, fi.height) >> 4);
+  let matches = match_corners(
+    &frame.planes[0],
+    &corners,
+    &reference.planes[0],
+    &ref_corners,
+    radius
+  );
+
+  let mut pred = Plane::new(fi.width, fi.height, 0, 0);
+  let mut best = (GlobalMVMode::IDENTITY, GM_IDENTITY_PARAMS);
+  let mut best_error = global_motion_error(
+    fi, frame, reference, best.0, &best.1, &mut pred
+  ) as f64;
+
+  // Warps are not applied with integer motion vectors
+  let gm_types: &[GlobalMVMode] = if fi.force_integer_mv != 0 {
+    &[GlobalMVMode::TRANSLATION]
+  } else {
+    &[GlobalMVMode::TRANSLATION, GlobalMVMode::ROTZOOM, GlobalMVMode::AFFINE]
+  };
+
+  for &gm_type in gm_types {
+    if let Some(model) = ransac(gm_type, &matches) {
+      let params =
+        quantize_global_motion(gm_type, &model, fi.allow_high_precision_mv);
+      if gm_type > GlobalMVMode::TRANSLATION && setup_shear(&params).is_none()
+      {
+        continue;
+      }
+      let error = global_motion_error(
+        fi, frame, reference, gm_type, &params, &mut pred
+      ) as f64;
+      if error < best_error * GM_ERROR_ADVANTAGE {
+        best = (gm_type, params);
+        best_error = error;
+      }
+    }
+  }
+
+  best
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+  use EncoderConfig;
+
+  #[test]
+  fn ransac_rotzoom() {
+    // A rotation of about 1 degree with a 2% zoom, and a few outliers
+    let model = [3.5, -2.25, 1.0197, 0.0178, -0.0178, 1.0197];
+    let mut matches = Vec::new();
+    for i in 0..40 {
+      let x = (i * 37 % 320) as f64;
+      let y = (i * 91 % 240) as f64;
+      let (dx, dy) = if i % 5 == 0 { (7., -9.) } else { (0., 0.) };
+      matches.push(Correspondence {
+        x,
+        y,
+        rx: model[2] * x + model[3] * y + model[0] + dx,
+        ry: model[4] * x + model[5] * y + model[1] + dy
+      });
+    }
+
+    let fitted = ransac(GlobalMVMode::ROTZOOM, &matches).unwrap();
+    for i in 0..6 {
+      assert!((fitted[i] - model[i]).abs() < 1e-6);
+    }
+    assert!(ransac(GlobalMVMode::AFFINE, &matches[..5]).is_none());
+
+    let params = quantize_global_motion(GlobalMVMode::ROTZOOM, &fitted, true);
+    assert_eq!(params, [229376, -147456, 66828, 1166, -1166, 66828]);
+  }
+
+  #[test]
+  fn translation_estimation() {
+    let (width, height) = (128, 128);
+    let fi = FrameInvariants::new(width, height, EncoderConfig::default());
+    let reference = Frame::noise(width, height);
+    let mut frame = Frame::new(width, height);
+    for y in 0..height {
+      for x in 0..width {
+        let rx = cmp::min(x + 3, width - 1);
+        let ry = cmp::max(y as isize - 2, 0) as usize;
+        frame.planes[0].data[y * frame.planes[0].cfg.stride + x] =
+          reference.planes[0].p(rx, ry);
+      }
+    }
+
+    let (gm_type, params) = estimate_global_motion(&fi, &frame, &reference);
+    assert_eq!(gm_type, GlobalMVMode::TRANSLATION);
+    let mv = global_mv(
+      &params,
+      gm_type,
+      &BlockOffset { x: 0, y: 0 },
+      BlockSize::BLOCK_8X8,
+      true,
+      false
+    );
+    assert_eq!(mv, MotionVector { row: -16, col: 24 });
+
+    // Identical frames have no global motion
+    let (gm_type, _) = estimate_global_motion(&fi, &reference, &reference);
+    assert_eq!(gm_type, GlobalMVMode::IDENTITY);
+  }
+}
@@ -0,0 +1,272 @@
+// Copyright (c) 2017-2018, The rav1e contributors. All rights reserved
+//
+// This source code is subject to the terms of the BSD 2 Clause License and
+// the Alliance for Open Media Patent License 1.0. If the BSD 2 Clause License
+// was not distributed with this source code in the LICENSE file, you can
+// obtain it at www.aomedia.org/license/software. If the Alliance for Open
+// Media Patent License 1.0 was not distributed with this source code in the
+// PATENTS file, you can obtain it at www.aomedia.org/license/patent.
+
+use context::*;
+use mc::*;
+use partition::*;
+use FrameInvariants;
+use Sequence;
+
+use std::cmp;
+
+// Saved motion vectors with a larger component are not projected
+const REFMVS_LIMIT: i32 = (1 << 12) - 1;
+const MAX_FRAME_DISTANCE: i32 = 31;
+// Number of the nearest references projected, LAST_FRAME taking one
+const MFMV_STACK_SIZE: isize = 3;
+// Projected motion vectors stay in the 64 rows of the block they are saved
+// with, and within 64 columns left or right of them
+const MAX_OFFSET_WIDTH: isize = 64;
+const MAX_OFFSET_HEIGHT: isize = 0;
+// Projected motion vectors are kept within the range of coded ones
+const MV_PROJECTION_MAX: i32 = (1 << 14) - 1;
+
+// 2^14 / d, for the division of motion vectors by frame distances d
+static DIV_MULT: [i32; MAX_FRAME_DISTANCE as usize + 1] = [
+  0, 16384, 8192, 5461, 4096, 3276, 2730, 2340, 2048, 1820, 1638, 1489, 1365,
+  1260, 1170, 1092, 1024, 963, 910, 862, 819, 780, 744, 712, 682, 655, 630,
+  606, 585, 564, 546, 528
+];
+
+/// Scales `mv`, spanning `den` frames, to span `num` frames instead
+pub fn mv_projection(mv: MotionVector, num: i32, den: i32) -> MotionVector {
+  let den = cmp::min(den, MAX_FRAME_DISTANCE);
+  let num = clamp(num, -MAX_FRAME_DISTANCE, MAX_FRAME_DISTANCE);
+  let project = |v: i16| {
+    let v = v as i64 * num as i64 * DIV_MULT[den as usize] as i64;
+    let v = round2signed(v, 14);
+    clamp(v as i32, -MV_PROJECTION_MAX, MV_PROJECTION_MAX) as i16
+  };
+  MotionVector { row: project(mv.row), col: project(mv.col) }
+}
+
+/// Motion vectors a frame leaves to the frames referencing it, one for each
+/// 8x8 block, along with their reference. Only the motion from references
+/// preceding the frame is kept.
+#[derive(Debug)]
+pub struct FrameMotionVectors {
+  pub cols: usize,
+  pub rows: usize,
+  mvs: Vec<Option<(usize, MotionVector)>>
+}
+
+impl FrameMotionVectors {
+  /// Saves the motion vectors of the blocks of the frame `fi` in `bc`. Each
+  /// 8x8 block keeps the motion of its bottom-right 4x4 block.
+  pub fn new(
+    seq: &Sequence, fi: &FrameInvariants, bc: &mut BlockContext
+  ) -> FrameMotionVectors {
+    let cols = bc.cols >> 1;
+    let rows = bc.rows >> 1;
+    let mut mvs = Vec::with_capacity(cols * rows);
+
+    for y8 in 0..rows {
+      for x8 in 0..cols {
+        let block = *bc.at(&BlockOffset { x: 2 * x8 + 1, y: 2 * y8 + 1 });
+        let mut saved = None;
+        for i in 0..1 + block.has_second_ref() as usize {
+          let ref_frame = block.ref_frames[i];
+          if ref_frame == INTRA_FRAME {
+            continue;
+          }
+          let mv = block.mvs[i];
+          let dist =
+            seq.get_relative_dist(fi.ref_order_hint(ref_frame), fi.order_hint);
+          if dist < 0
+            && (mv.row as i32).abs() <= REFMVS_LIMIT
+            && (mv.col as i32).abs() <= REFMVS_LIMIT
+          {
+            saved = Some((ref_frame, mv));
+          }
+        }
+        mvs.push(saved);
+      }
+    }
+
+    FrameMotionVectors { cols, rows, mvs }
+  }
+}
+
+/// Motion of the reference frames projected onto the 8x8 blocks of the
+/// current frame: the saved motion vectors crossing each block, with the
+/// number of frames they span
+pub struct MotionField {
+  cols: usize,
+  rows: usize,
+  mvs: Vec<Option<(MotionVector, i32)>>
+}
+
+impl MotionField {
+  /// Projects the motion vectors saved with the references of the frame
+  /// `fi`. LAST_FRAME goes first, then the references following the frame,
+  /// and LAST2_FRAME last if fewer than MFMV_STACK_SIZE were projected. Later
+  /// projections take precedence.
+  pub fn new(seq: &Sequence, fi: &FrameInvariants) -> MotionField {
+    let cols = fi.w_in_b >> 1;
+    let rows = fi.h_in_b >> 1;
+    let mut mf = MotionField { cols, rows, mvs: vec![None; cols * rows] };
+
+    let mut ref_stamp = MFMV_STACK_SIZE - 1;
+
+    let last = fi.ref_frames[LAST_FRAME - LAST_FRAME];
+    if fi.rec_buffer.frames[last].is_some() {
+      // LAST_FRAME overlaying the ALTREF_FRAME it references, now the
+      // GOLDEN_FRAME, has no motion of its own
+      let last_alt_hint =
+        fi.rec_buffer.ref_order_hints[last][ALTREF_FRAME - LAST_FRAME];
+      if last_alt_hint != fi.ref_order_hint(GOLDEN_FRAME) {
+        mf.project(seq, fi, LAST_FRAME, true);
+      }
+      ref_stamp -= 1;
+    }
+
+    for &ref_frame in &[BWDREF_FRAME, ALTREF2_FRAME, ALTREF_FRAME] {
+      let dist =
+        seq.get_relative_dist(fi.ref_order_hint(ref_frame), fi.order_hint);
+      if dist > 0 && (ref_frame != ALTREF_FRAME || ref_stamp >= 0)
+        && mf.project(seq, fi, ref_frame, false)
+      {
+        ref_stamp -= 1;
+      }
+    }
+
+    if ref_stamp >= 0 {
+      mf.project(seq, fi, LAST2_FRAME, true);
+    }
+
+    mf
+  }
+
+  // Projects the motion vectors saved with `src`, preceding the current frame
+  // if `backward`, onto the blocks of the current frame they cross. Returns
+  // whether `src` has saved motion vectors.
+  fn project(
+    &mut self, seq: &Sequence, fi: &FrameInvariants, src: usize,
+    backward: bool
+  ) -> bool {
+    let idx = fi.ref_frames[src - LAST_FRAME];
+    let saved = match fi.rec_buffer.frame_mvs[idx] {
+      Some(ref saved) if saved.cols == self.cols && saved.rows == self.rows =>
+        saved,
+      _ => return false
+    };
+    let src_hint = fi.rec_buffer.order_hints[idx];
+    let mut src_to_cur = seq.get_relative_dist(src_hint, fi.order_hint);
+    if backward {
+      src_to_cur = -src_to_cur;
+    }
+
+    for y8 in 0..self.rows {
+      for x8 in 0..self.cols {
+        if let Some((ref_frame, mv)) = saved.mvs[y8 * self.cols + x8] {
+          let ref_hint =
+            fi.rec_buffer.ref_order_hints[idx][ref_frame - LAST_FRAME];
+          let ref_offset = seq.get_relative_dist(src_hint, ref_hint);
+          if ref_offset <= 0
+            || ref_offset > MAX_FRAME_DISTANCE
+            || src_to_cur.abs() > MAX_FRAME_DISTANCE
+          {
+            continue;
+          }
+          let proj_mv = mv_projection(mv, src_to_cur, ref_offset);
+          if let Some((x, y)) = self.block_position(x8, y8, proj_mv, backward)
+          {
+            self.mvs[y * self.cols + x] = Some((mv, ref_offset));
+          }
+        }
+      }
+    }
+    true
+  }
+
+  // 8x8 block of the current frame the 8x8 block at (`x8`, `y8`) of a
+  // reference moves to with `mv`, if it stays in range
+  fn block_position(
+    &self, x8: usize, y8: usize, mv: MotionVector, backward: bool
+  ) -> Option<(usize, usize)> {
+    // Whole 8x8 blocks, rounded toward zero
+    let offset = |v: i16| {
+      let shift = 3 + MI_SIZE_LOG2 + 1;
+      if v >= 0 {
+        (v >> shift) as isize
+      } else {
+        -((-v) >> shift) as isize
+      }
+    };
+    let (dx, dy) = (offset(mv.col), offset(mv.row));
+    let (x8, y8) = (x8 as isize, y8 as isize);
+    let (x, y) =
+      if backward { (x8 - dx, y8 - dy) } else { (x8 + dx, y8 + dy) };
+
+    if x < 0 || y < 0 || x >= self.cols as isize || y >= self.rows as isize {
+      return None;
+    }
+    let base_x = x8 >> 3 << 3;
+    let base_y = y8 >> 3 << 3;
+    if y < base_y - (MAX_OFFSET_HEIGHT >> 3)
+      || y >= base_y + 8 + (MAX_OFFSET_HEIGHT >> 3)
+      || x < base_x - (MAX_OFFSET_WIDTH >> 3)
+      || x >= base_x + 8 + (MAX_OFFSET_WIDTH >> 3)
+    {
+      return None;
+    }
+    Some((x as usize, y as usize))
+  }
+
+  /// Motion vectors of the 8x8 blocks of the current frame for a reference
+  /// `ref_dist` frames away, at the precision of the frame
+  pub fn projected(
+    &self, ref_dist: i32, allow_high_precision_mv: bool,
+    force_integer_mv: bool
+  ) -> Vec<Option<MotionVector>> {
+    self
+      .mvs
+      .iter()
+      .map(|m| {
+        m.map(|(mv, ref_offset)| {
+          lower_mv_precision(
+            mv_projection(mv, ref_dist, ref_offset),
+            allow_high_precision_mv,
+            force_integer_mv
+          )
+        })
+      }).collect()
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+
+  #[test]
+  fn projection() {
+    let mv = MotionVector { row: -24, col: 40 };
+    assert_eq!(mv_projection(mv, 1, 1), mv);
+    assert_eq!(mv_projection(mv, 2, 1), MotionVector { row: -48, col: 80 });
+    assert_eq!(mv_projection(mv, -1, 2), MotionVector { row: 12, col: -20 });
+    // Distances are capped
+    assert_eq!(mv_projection(mv, 64, 64), mv);
+  }
+
+  #[test]
+  fn block_positions() {
+    let mf = MotionField { cols: 32, rows: 16, mvs: Vec::new() };
+    let mv = MotionVector { row: 0, col: 3 * 64 };
+    assert_eq!(mf.block_position(4, 2, mv, false), Some((7, 2)));
+    assert_eq!(mf.block_position(4, 2, mv, true), Some((1, 2)));
+    assert_eq!(mf.block_position(1, 2, mv, true), None);
+    // Projections are confined to the same 64 rows
+    let down = MotionVector { row: 64, col: 0 };
+    assert_eq!(mf.block_position(4, 2, down, false), Some((4, 3)));
+    assert_eq!(mf.block_position(4, 7, down, false), None);
+    // Parts of 8x8 blocks do not move them
+    let small = MotionVector { row: -63, col: 63 };
+    assert_eq!(mf.block_position(4, 2, small, false), Some((4, 2)));
+  }
+}
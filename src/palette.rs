@@ -0,0 +1,296 @@
+// Copyright (c) 2017-2018, The rav1e contributors. All rights reserved
+//
+// This source code is subject to the terms of the BSD 2 Clause License and
+// the Alliance for Open Media Patent License 1.0. If the BSD 2 Clause License
+// was not distributed with this source code in the LICENSE file, you can
+// obtain it at www.aomedia.org/license/software. If the Alliance for Open
+// Media Patent License 1.0 was not distributed with this source code in the
+// PATENTS file, you can obtain it at www.aomedia.org/license/patent.
+
+use context::*;
+use partition::*;
+use plane::*;
+use std::cmp;
+use Frame;
+
+// Blocks with more distinct values than this in a plane are not searched for
+// palettes
+const PALETTE_MAX_BLOCK_COLORS: usize = 64;
+
+const K_MEANS_MAX_ITERATIONS: usize = 50;
+
+// Size of the luma blocks whose colors are counted by the screen content
+// detection, and most colors of those counted as rendered text or graphics
+const SCREEN_CONTENT_BLOCK_SIZE: usize = 16;
+const SCREEN_CONTENT_MAX_COLORS: usize = 4;
+
+/// Offset and size of the color index map of the luma (plane 0) or chroma
+/// (plane 1) of the block at `bo`. The chroma of sub-8x8 blocks covers the
+/// whole 8x8 area.
+pub fn color_map_area(
+  bo: &BlockOffset, bsize: BlockSize, cfg: &PlaneConfig
+) -> (PlaneOffset, usize, usize) {
+  let po = PlaneOffset {
+    x: (bo.x >> cfg.xdec) << MI_SIZE_LOG2,
+    y: (bo.y >> cfg.ydec) << MI_SIZE_LOG2
+  };
+  let w = cmp::max(bsize.width() >> cfg.xdec, 4);
+  let h = cmp::max(bsize.height() >> cfg.ydec, 4);
+  (po, w, h)
+}
+
+/// Index of the palette color closest to the input pixel at (x, y) of plane
+/// `p`, chroma pixels being matched on both their U and V values. Pixels
+/// past the right and bottom edges of the plane take the index of the last
+/// pixel inside, as the decoder extends the color index map.
+pub fn color_index(
+  input: &Frame, p: usize, x: usize, y: usize, palette: &PaletteParams
+) -> u8 {
+  let cfg = &input.planes[p].cfg;
+  let x = cmp::min(x, cfg.width - 1);
+  let y = cmp::min(y, cfg.height - 1);
+  let planes: &[usize] = if p == 0 { &[0] } else { &[1, 2] };
+
+  let dist = |i: usize| -> i32 {
+    planes.iter().map(|&pl| {
+      let d = input.planes[pl].p(x, y) as i32 - palette.colors[pl][i] as i32;
+      d * d
+    }).sum()
+  };
+
+  (0..palette.size[(p > 0) as usize]).min_by_key(|&i| dist(i)).unwrap() as u8
+}
+
+/// Fills the `w`x`h` color index map of the area at `po` of plane `p`
+pub fn color_map(
+  map: &mut [u8], input: &Frame, p: usize, po: &PlaneOffset, w: usize,
+  h: usize, palette: &PaletteParams
+) {
+  for y in 0..h {
+    for x in 0..w {
+      map[y * w + x] = color_index(input, p, po.x + x, po.y + y, palette);
+    }
+  }
+}
+
+/// Predicts the `w`x`h` area at `po` of plane `p` with the palette colors of
+/// its color index map
+pub fn predict_palette(
+  dst: &mut Plane, input: &Frame, p: usize, po: &PlaneOffset, w: usize,
+  h: usize, palette: &PaletteParams
+) {
+  let stride = dst.cfg.stride;
+  for y in 0..h {
+    for x in 0..w {
+      let i = color_index(input, p, po.x + x, po.y + y, palette) as usize;
+      dst.data[(po.y + y) * stride + po.x + x] = palette.colors[p][i];
+    }
+  }
+}
+
+// Index of the centroid closest to `point`
+fn nearest_centroid(point: &[u16], centroids: &[u16], dim: usize) -> usize {
+  let dist = |c: &[u16]| -> i64 {
+    point.iter().zip(c).map(|(&a, &b)| {
+      let d = a as i64 - b as i64;
+      d * d
+    }).sum()
+  };
+  (0..centroids.len() / dim)
+    .min_by_key(|&i| dist(&centroids[i * dim..(i + 1) * dim]))
+    .unwrap()
+}
+
+/// Moves the `dim`-dimensional `centroids` to the means of the points of
+/// `data` closest to them, until they are stable
+pub fn k_means(data: &[u16], dim: usize, centroids: &mut [u16]) {
+  let n = data.len() / dim;
+  let k = centroids.len() / dim;
+  let mut indices = vec![0; n];
+
+  for iteration in 0..K_MEANS_MAX_ITERATIONS {
+    let mut changed = false;
+    for i in 0..n {
+      let idx = nearest_centroid(&data[i * dim..(i + 1) * dim], centroids, dim);
+      changed |= idx != indices[i];
+      indices[i] = idx;
+    }
+
+    if iteration > 0 && !changed {
+      break;
+    }
+
+    let mut sums = vec![0u64; k * dim];
+    let mut counts = vec![0u64; k];
+    for i in 0..n {
+      counts[indices[i]] += 1;
+      for d in 0..dim {
+        sums[indices[i] * dim + d] += data[i * dim + d] as u64;
+      }
+    }
+
+    // Centroids without points are left in place
+    for c in 0..k {
+      if counts[c] > 0 {
+        for d in 0..dim {
+          centroids[c * dim + d] =
+            ((sums[c * dim + d] + counts[c] / 2) / counts[c]) as u16;
+        }
+      }
+    }
+  }
+}
+
+/// Palettes of 2 up to PALETTE_MAX_SIZE distinct colors for the
+/// `dim`-dimensional pixels of `data`, sorted by their first dimension.
+/// Blocks with a single color, or too many to fit a palette, get none.
+pub fn palette_candidates(data: &[u16], dim: usize) -> Vec<Vec<u16>> {
+  let n = data.len() / dim;
+
+  let mut colors = 0;
+  for d in 0..dim {
+    let mut values: Vec<u16> = (0..n).map(|i| data[i * dim + d]).collect();
+    values.sort();
+    values.dedup();
+    colors = cmp::max(colors, values.len());
+  }
+
+  let mut candidates: Vec<Vec<u16>> = Vec::new();
+
+  if colors < PALETTE_MIN_SIZE || colors > PALETTE_MAX_BLOCK_COLORS {
+    return candidates;
+  }
+
+  for k in PALETTE_MIN_SIZE..cmp::min(colors, PALETTE_MAX_SIZE) + 1 {
+    // Start from centroids evenly spread over the range of each dimension
+    let mut centroids = vec![0u16; k * dim];
+    for d in 0..dim {
+      let lb = (0..n).map(|i| data[i * dim + d]).min().unwrap() as usize;
+      let ub = (0..n).map(|i| data[i * dim + d]).max().unwrap() as usize;
+      for c in 0..k {
+        centroids[c * dim + d] = (lb + (2 * c + 1) * (ub - lb) / (2 * k)) as u16;
+      }
+    }
+
+    k_means(data, dim, &mut centroids);
+
+    let mut palette: Vec<&[u16]> = centroids.chunks(dim).collect();
+    palette.sort();
+    palette.dedup();
+
+    let palette: Vec<u16> =
+      palette.iter().flat_map(|c| c.iter().cloned()).collect();
+
+    if palette.len() / dim >= PALETTE_MIN_SIZE &&
+      candidates.last().map_or(true, |last| *last != palette)
+    {
+      candidates.push(palette);
+    }
+  }
+
+  candidates
+}
+
+/// Whether the `width`x`height` frame looks like screen content, which the
+/// palette and intra block copy tools suit, and then whether it also looks
+/// rendered enough to only move by whole pixels, for `force_integer_mv`.
+/// Frames are screen content when at least 10% of their luma blocks have 2
+/// to SCREEN_CONTENT_MAX_COLORS colors, and rendered when most blocks have no
+/// more colors, flat ones included.
+pub fn detect_screen_content(
+  frame: &Frame, width: usize, height: usize
+) -> (bool, bool) {
+  let plane = &frame.planes[0];
+  let size = SCREEN_CONTENT_BLOCK_SIZE;
+
+  let mut blocks = 0;
+  let mut flat_blocks = 0;
+  let mut few_color_blocks = 0;
+  let mut values = Vec::with_capacity(size * size);
+  for by in 0..height / size {
+    for bx in 0..width / size {
+      values.clear();
+      for y in by * size..(by + 1) * size {
+        values.extend_from_slice(
+          &plane.data[y * plane.cfg.stride + bx * size..][..size]
+        );
+      }
+      values.sort();
+      values.dedup();
+
+      blocks += 1;
+      if values.len() == 1 {
+        flat_blocks += 1;
+      } else if values.len() <= SCREEN_CONTENT_MAX_COLORS {
+        few_color_blocks += 1;
+      }
+    }
+  }
+
+  let screen_content = few_color_blocks * 10 >= blocks && blocks > 0;
+  let integer_mv =
+    screen_content && (flat_blocks + few_color_blocks) * 2 > blocks;
+  (screen_content, integer_mv)
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+
+  #[test]
+  fn k_means_two_clusters() {
+    let data = [10, 12, 11, 200, 202, 10, 201, 12];
+    let mut centroids = [0, 255];
+    k_means(&data, 1, &mut centroids);
+    assert_eq!(centroids, [11, 201]);
+  }
+
+  #[test]
+  fn palette_candidates_exact() {
+    let data = [5, 90, 90, 5, 250, 5, 90, 250];
+    let candidates = palette_candidates(&data, 1);
+    assert_eq!(candidates.last().unwrap(), &vec![5, 90, 250]);
+    for c in &candidates {
+      assert!(c.windows(2).all(|w| w[0] < w[1]));
+    }
+  }
+
+  #[test]
+  fn palette_candidates_chroma_pairs() {
+    let data = [100, 20, 30, 200, 100, 20, 30, 200];
+    let candidates = palette_candidates(&data, 2);
+    assert_eq!(candidates, vec![vec![30, 200, 100, 20]]);
+  }
+
+  #[test]
+  fn screen_content_detection() {
+    // Camera-like noise
+    let mut frame = Frame::noise(128, 128);
+    assert_eq!(detect_screen_content(&frame, 128, 128), (false, false));
+
+    // Two-color text over an eighth of the frame, the rest being noise
+    let stride = frame.planes[0].cfg.stride;
+    for y in 0..16 {
+      for x in 0..128 {
+        frame.planes[0].data[y * stride + x] =
+          if (x / 3 + y / 2) % 4 == 0 { 16 } else { 235 };
+      }
+    }
+    assert_eq!(detect_screen_content(&frame, 128, 128), (true, false));
+
+    // Text over a flat background
+    for y in 16..128 {
+      for x in 0..128 {
+        frame.planes[0].data[y * stride + x] = 235;
+      }
+    }
+    assert_eq!(detect_screen_content(&frame, 128, 128), (true, true));
+  }
+
+  #[test]
+  fn palette_candidates_flat() {
+    assert!(palette_candidates(&[7; 64], 1).is_empty());
+    let noise: Vec<u16> = (0..256).collect();
+    assert!(palette_candidates(&noise, 1).is_empty());
+  }
+}
@@ -83,8 +83,13 @@ impl BlockSize {
     [2, 3, 2, 3, 4, 3, 4, 5, 4, 5, 6, 5, 6, 7, 6, 7, 4, 2, 5, 3, 6, 4, 7, 5];
 
   pub fn cfl_allowed(self) -> bool {
-    // TODO: fix me when enabling EXT_PARTITION_TYPES
-    self <= BlockSize::BLOCK_32X32
+    self.width() <= 32 && self.height() <= 32
+  }
+
+  /// Whether palettes can be used, from 8x8 (including 4x16 and 16x4) up to
+  /// 64x64
+  pub fn palette_allowed(self) -> bool {
+    self >= BLOCK_8X8 && self.width() <= 64 && self.height() <= 64
   }
 
   pub fn width(self) -> usize {
@@ -114,6 +119,72 @@ impl BlockSize {
   pub fn is_sqr(self) -> bool {
     self.width_log2() == self.height_log2()
   }
+
+  /// Largest transform size that fits this block size, which is the
+  /// transform size used with TX_MODE_LARGEST.
+  pub fn largest_tx_size(self) -> TxSize {
+    const MAX_TXSIZE_RECT_LOOKUP: [TxSize; BlockSize::BLOCK_SIZES_ALL] = [
+      TX_4X4,   // BLOCK_4X4
+      TX_4X8,   // BLOCK_4X8
+      TX_8X4,   // BLOCK_8X4
+      TX_8X8,   // BLOCK_8X8
+      TX_8X16,  // BLOCK_8X16
+      TX_16X8,  // BLOCK_16X8
+      TX_16X16, // BLOCK_16X16
+      TX_16X32, // BLOCK_16X32
+      TX_32X16, // BLOCK_32X16
+      TX_32X32, // BLOCK_32X32
+      TX_32X64, // BLOCK_32X64
+      TX_64X32, // BLOCK_64X32
+      TX_64X64, // BLOCK_64X64
+      TX_64X64, // BLOCK_64X128
+      TX_64X64, // BLOCK_128X64
+      TX_64X64, // BLOCK_128X128
+      TX_4X16,  // BLOCK_4X16
+      TX_16X4,  // BLOCK_16X4
+      TX_8X32,  // BLOCK_8X32
+      TX_32X8,  // BLOCK_32X8
+      TX_16X64, // BLOCK_16X64
+      TX_64X16, // BLOCK_64X16
+      TX_32X64, // BLOCK_32X128
+      TX_64X32  // BLOCK_128X32
+    ];
+    MAX_TXSIZE_RECT_LOOKUP[self as usize]
+  }
+
+  /// Number of times the largest transform of this block size can be split
+  /// when the transform size is signaled per block (at most MAX_TX_DEPTH).
+  pub fn max_tx_depth(self) -> usize {
+    let mut tx_size = self.largest_tx_size();
+    let mut depth = 0;
+    while depth < MAX_TX_DEPTH && tx_size != TX_4X4 {
+      depth += 1;
+      tx_size = tx_size.split();
+    }
+    depth
+  }
+
+  /// Transform size obtained by splitting the largest transform `depth`
+  /// times.
+  pub fn tx_size_at_depth(self, depth: usize) -> TxSize {
+    let mut tx_size = self.largest_tx_size();
+    for _ in 0..depth {
+      tx_size = tx_size.split();
+    }
+    tx_size
+  }
+
+  /// Largest transform size used by the chroma planes of this block, which
+  /// is never larger than a 32-point transform.
+  pub fn largest_uv_tx_size(self, xdec: usize, ydec: usize) -> TxSize {
+    let plane_bsize = get_plane_block_size(self, xdec, ydec);
+    match plane_bsize.largest_tx_size() {
+      TX_64X64 | TX_64X32 | TX_32X64 => TX_32X32,
+      TX_16X64 => TX_16X32,
+      TX_64X16 => TX_32X16,
+      t => t
+    }
+  }
 }
 
 /// Transform Size
@@ -264,11 +335,45 @@ impl TxSize {
         ];
     TX_SIZE_SQR_UP[self as usize]
   }
+
+  /// Size of the transforms obtained by splitting this transform once.
+  pub fn split(self) -> TxSize {
+    #[cfg_attr(rustfmt, rustfmt_skip)]
+        const SUB_TX_SIZE_MAP: [TxSize; TxSize::TX_SIZES_ALL] = [
+            TX_4X4,   // TX_4X4
+            TX_4X4,   // TX_8X8
+            TX_8X8,   // TX_16X16
+            TX_16X16, // TX_32X32
+            TX_32X32, // TX_64X64
+            TX_4X4,   // TX_4X8
+            TX_4X4,   // TX_8X4
+            TX_8X8,   // TX_8X16
+            TX_8X8,   // TX_16X8
+            TX_16X16, // TX_16X32
+            TX_16X16, // TX_32X16
+            TX_32X32, // TX_32X64
+            TX_32X32, // TX_64X32
+            TX_4X8,   // TX_4X16
+            TX_8X4,   // TX_16X4
+            TX_8X16,  // TX_8X32
+            TX_16X8,  // TX_32X8
+            TX_16X32, // TX_16X64
+            TX_32X16  // TX_64X16
+        ];
+    SUB_TX_SIZE_MAP[self as usize]
+  }
 }
 
+/// Maximum number of splits of the largest transform when the transform size
+/// of an intra block is signaled.
+pub const MAX_TX_DEPTH: usize = 2;
+
+/// Maximum depth of the transform partitioning tree of an inter block.
+pub const MAX_VARTX_DEPTH: usize = 2;
+
 pub const TX_TYPES: usize = 16;
 
-#[derive(Copy, Clone, PartialEq, PartialOrd)]
+#[derive(Copy, Clone, Debug, PartialEq, PartialOrd)]
 #[repr(C)]
 pub enum TxType {
   DCT_DCT = 0,   // DCT  in both horizontal and vertical
@@ -304,6 +409,7 @@ pub enum PredictionMode {
   SMOOTH_V_PRED,
   SMOOTH_H_PRED,
   PAETH_PRED,
+  UV_CFL_PRED,
   NEARESTMV,
   NEARMV,
   GLOBALMV,
@@ -319,6 +425,165 @@ pub enum PredictionMode {
   NEW_NEWMV
 }
 
+// Unit of the angle deltas of directional intra prediction, in degrees
+pub const ANGLE_STEP: i8 = 3;
+
+/// Offsets from the nominal angles of directional luma and chroma intra
+/// prediction modes, in units of ANGLE_STEP
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub struct AngleDelta {
+  pub y: i8,
+  pub uv: i8
+}
+
+/// Filtering of the edges of directional intra predictions, which is
+/// stronger next to blocks predicted with a smooth mode
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum IntraEdgeFilter {
+  Disabled,
+  Regular,
+  Smooth
+}
+
+/// Recursive filters replacing DC_PRED in luma blocks up to 32x32
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum FilterIntraMode {
+  FILTER_DC_PRED,
+  FILTER_V_PRED,
+  FILTER_H_PRED,
+  FILTER_D157_PRED,
+  FILTER_PAETH_PRED
+}
+
+impl FilterIntraMode {
+  /// Intra prediction mode whose transform type statistics the filter shares
+  pub fn intra_dir(self) -> PredictionMode {
+    match self {
+      FilterIntraMode::FILTER_DC_PRED => PredictionMode::DC_PRED,
+      FilterIntraMode::FILTER_V_PRED => PredictionMode::V_PRED,
+      FilterIntraMode::FILTER_H_PRED => PredictionMode::H_PRED,
+      FilterIntraMode::FILTER_D157_PRED => PredictionMode::D153_PRED,
+      FilterIntraMode::FILTER_PAETH_PRED => PredictionMode::DC_PRED
+    }
+  }
+}
+
+// Largest magnitude of the chroma-from-luma scaling factors, in 1/8ths
+pub const CFL_ALPHA_MAX: i16 = 16;
+
+/// Scaling factors of the luma AC contribution to the U and V chroma-from-luma
+/// predictions, in 1/8ths. At least one of them is non-zero when coded.
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub struct CFLParams {
+  pub alpha: [i16; 2]
+}
+
+impl CFLParams {
+  // 0 for a zero factor, 1 for a negative one and 2 for a positive one
+  fn sign(self, uv: usize) -> usize {
+    match self.alpha[uv] {
+      0 => 0,
+      a if a < 0 => 1,
+      _ => 2
+    }
+  }
+
+  /// Combination of the U and V signs, which are never both zero
+  pub fn joint_sign(self) -> u32 {
+    assert!(self.alpha != [0, 0]);
+    (self.sign(0) * 3 + self.sign(1) - 1) as u32
+  }
+
+  /// Context of the magnitude of the factor of plane `uv`, which is only
+  /// coded when non-zero
+  pub fn context(self, uv: usize) -> usize {
+    assert!(self.alpha[uv] != 0);
+    (self.sign(uv) - 1) * 3 + self.sign(1 - uv)
+  }
+
+  /// Coded magnitude of the non-zero factor of plane `uv`
+  pub fn index(self, uv: usize) -> u32 {
+    assert!(self.alpha[uv] != 0);
+    (self.alpha[uv].abs() - 1) as u32
+  }
+}
+
+pub const PALETTE_MIN_SIZE: usize = 2;
+pub const PALETTE_MAX_SIZE: usize = 8;
+
+/// Palettes replacing the DC_PRED prediction of the luma and of the chroma of
+/// a block. The chroma palette pairs U and V colors, and is sorted by U.
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub struct PaletteParams {
+  /// Number of luma and chroma colors, 0 when the palette is unused
+  pub size: [usize; 2],
+  /// Y, U and V colors
+  pub colors: [[u16; PALETTE_MAX_SIZE]; 3]
+}
+
+impl PaletteParams {
+  /// Colors of plane `p`
+  pub fn plane_colors(&self, p: usize) -> &[u16] {
+    &self.colors[p][..self.size[(p > 0) as usize]]
+  }
+}
+
+/// Motion vector, in 1/8 luma pixels
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub struct MotionVector {
+  pub row: i16,
+  pub col: i16
+}
+
+impl MotionVector {
+  pub fn is_zero(self) -> bool {
+    self.row == 0 && self.col == 0
+  }
+}
+
+/// Motion compensation of inter blocks of at least 8x8. Overlapped blocks
+/// blend their edges with the predictions from the motion of the neighbours
+/// above and left, warped blocks fit an affine model to that motion.
+#[derive(Copy, Clone, Debug, PartialEq, PartialOrd)]
+pub enum MotionMode {
+  SIMPLE_TRANSLATION,
+  OBMC_CAUSAL,
+  WARPED_CAUSAL
+}
+
+/// Intra predictions blended with the single-reference inter prediction of
+/// blocks from 8x8 to 32x32
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum InterIntraMode {
+  II_DC_PRED,
+  II_V_PRED,
+  II_H_PRED,
+  II_SMOOTH_PRED
+}
+
+impl InterIntraMode {
+  pub fn intra_mode(self) -> PredictionMode {
+    match self {
+      InterIntraMode::II_DC_PRED => PredictionMode::DC_PRED,
+      InterIntraMode::II_V_PRED => PredictionMode::V_PRED,
+      InterIntraMode::II_H_PRED => PredictionMode::H_PRED,
+      InterIntraMode::II_SMOOTH_PRED => PredictionMode::SMOOTH_PRED
+    }
+  }
+}
+
+/// How the two predictions of a block are combined. Only the wedge and
+/// difference-weighted types are coded as compound_type, the others follow
+/// from the other syntax elements of the block.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum CompoundType {
+  COMPOUND_WEDGE,
+  COMPOUND_DIFFWTD,
+  COMPOUND_AVERAGE,
+  COMPOUND_INTRA,
+  COMPOUND_DISTANCE
+}
+
 pub const NEWMV_MODE_CONTEXTS: usize = 7;
 pub const GLOBALMV_MODE_CONTEXTS: usize = 2;
 pub const REFMV_MODE_CONTEXTS: usize = 9;
@@ -329,8 +594,26 @@ pub const NEWMV_CTX_MASK: usize = ((1 << GLOBALMV_OFFSET) - 1);
 pub const GLOBALMV_CTX_MASK: usize = ((1 << (REFMV_OFFSET - GLOBALMV_OFFSET)) - 1);
 pub const REFMV_CTX_MASK: usize = ((1 << (8 - REFMV_OFFSET)) - 1);
 
-pub static RAV1E_PARTITION_TYPES: &'static [PartitionType] =
-  &[PartitionType::PARTITION_NONE, PartitionType::PARTITION_SPLIT];
+pub static RAV1E_PARTITION_TYPES: &'static [PartitionType] = &[
+  PartitionType::PARTITION_NONE,
+  PartitionType::PARTITION_HORZ,
+  PartitionType::PARTITION_VERT,
+  PartitionType::PARTITION_HORZ_A,
+  PartitionType::PARTITION_HORZ_B,
+  PartitionType::PARTITION_VERT_A,
+  PartitionType::PARTITION_VERT_B,
+  PartitionType::PARTITION_HORZ_4,
+  PartitionType::PARTITION_VERT_4,
+  PartitionType::PARTITION_SPLIT
+];
+
+// Partition types tested at high speed levels
+pub static RAV1E_PARTITION_TYPES_MINIMAL: &'static [PartitionType] = &[
+  PartitionType::PARTITION_NONE,
+  PartitionType::PARTITION_HORZ,
+  PartitionType::PARTITION_VERT,
+  PartitionType::PARTITION_SPLIT
+];
 
 pub static RAV1E_TX_TYPES: &'static [TxType] = &[
   TxType::DCT_DCT,
@@ -351,24 +634,72 @@ pub enum GlobalMVMode {
 }
 
 use context::*;
+use mc::*;
 use plane::*;
 use predict::*;
+use std::cmp;
 
 impl PredictionMode {
-  pub fn predict_intra<'a>(self, dst: &'a mut PlaneMutSlice<'a>, tx_size: TxSize) {
+  // The above-right and below-left neighbours are only used when decoded
+  pub fn predict_intra<'a>(
+    self, dst: &'a mut PlaneMutSlice<'a>, tx_size: TxSize, angle_delta: i8,
+    edge_filter: IntraEdgeFilter, have_above_right: bool, have_below_left: bool,
+    ac: &[i16], alpha: i16, filter_intra: Option<FilterIntraMode>
+  ) {
     assert!(self.is_intra());
 
+    let edges = (angle_delta, edge_filter, have_above_right, have_below_left);
+    let cfl = (ac, alpha);
+
     match tx_size {
-      TxSize::TX_4X4 => self.predict_intra_inner::<Block4x4>(dst),
-      TxSize::TX_8X8 => self.predict_intra_inner::<Block8x8>(dst),
-      TxSize::TX_16X16 => self.predict_intra_inner::<Block16x16>(dst),
-      TxSize::TX_32X32 => self.predict_intra_inner::<Block32x32>(dst),
-      _ => unimplemented!()
+      TxSize::TX_4X4 =>
+        self.predict_intra_inner::<Block4x4>(dst, edges, cfl, filter_intra),
+      TxSize::TX_8X8 =>
+        self.predict_intra_inner::<Block8x8>(dst, edges, cfl, filter_intra),
+      TxSize::TX_16X16 =>
+        self.predict_intra_inner::<Block16x16>(dst, edges, cfl, filter_intra),
+      TxSize::TX_32X32 =>
+        self.predict_intra_inner::<Block32x32>(dst, edges, cfl, filter_intra),
+      TxSize::TX_64X64 =>
+        self.predict_intra_inner::<Block64x64>(dst, edges, cfl, filter_intra),
+      TxSize::TX_4X8 =>
+        self.predict_intra_inner::<Block4x8>(dst, edges, cfl, filter_intra),
+      TxSize::TX_8X4 =>
+        self.predict_intra_inner::<Block8x4>(dst, edges, cfl, filter_intra),
+      TxSize::TX_8X16 =>
+        self.predict_intra_inner::<Block8x16>(dst, edges, cfl, filter_intra),
+      TxSize::TX_16X8 =>
+        self.predict_intra_inner::<Block16x8>(dst, edges, cfl, filter_intra),
+      TxSize::TX_16X32 =>
+        self.predict_intra_inner::<Block16x32>(dst, edges, cfl, filter_intra),
+      TxSize::TX_32X16 =>
+        self.predict_intra_inner::<Block32x16>(dst, edges, cfl, filter_intra),
+      TxSize::TX_32X64 =>
+        self.predict_intra_inner::<Block32x64>(dst, edges, cfl, filter_intra),
+      TxSize::TX_64X32 =>
+        self.predict_intra_inner::<Block64x32>(dst, edges, cfl, filter_intra),
+      TxSize::TX_4X16 =>
+        self.predict_intra_inner::<Block4x16>(dst, edges, cfl, filter_intra),
+      TxSize::TX_16X4 =>
+        self.predict_intra_inner::<Block16x4>(dst, edges, cfl, filter_intra),
+      TxSize::TX_8X32 =>
+        self.predict_intra_inner::<Block8x32>(dst, edges, cfl, filter_intra),
+      TxSize::TX_32X8 =>
+        self.predict_intra_inner::<Block32x8>(dst, edges, cfl, filter_intra),
+      TxSize::TX_16X64 =>
+        self.predict_intra_inner::<Block16x64>(dst, edges, cfl, filter_intra),
+      TxSize::TX_64X16 =>
+        self.predict_intra_inner::<Block64x16>(dst, edges, cfl, filter_intra),
     }
   }
 
   #[inline(always)]
-  fn predict_intra_inner<'a, B: Intra>(self, dst: &'a mut PlaneMutSlice<'a>) {
+  fn predict_intra_inner<'a, B: Intra>(
+    self, dst: &'a mut PlaneMutSlice<'a>,
+    (angle_delta, edge_filter, have_above_right, have_below_left):
+      (i8, IntraEdgeFilter, bool, bool),
+    (ac, alpha): (&[i16], i16), filter_intra: Option<FilterIntraMode>
+  ) {
     // above and left arrays include above-left sample
     // above array includes above-right samples
     // left array includes below-left samples
@@ -378,88 +709,72 @@ impl PredictionMode {
     let stride = dst.plane.cfg.stride;
     let x = dst.x;
     let y = dst.y;
+    // Neighbours past the decoded area of the plane, or not decoded yet,
+    // repeat the last available one
+    let max_x = dst.plane.cfg.width - 1;
+    let max_y = dst.plane.cfg.height - 1;
     // TODO: pass bd (bitdepth) as a parameter
     let bd = 8;
     let base = 128 << (bd - 8);
 
     if y != 0 {
-      if self != PredictionMode::H_PRED {
-        above[1..B::W + 1].copy_from_slice(&dst.go_up(1).as_slice()[..B::W]);
-      } else if self == PredictionMode::H_PRED && x == 0 {
-        for i in 0..B::W {
-          above[i + 1] = dst.go_up(1).p(0, 0);
-        }
+      let above_w = if have_above_right { 2 * B::W } else { B::W };
+      let above_limit = cmp::min(max_x, x + above_w - 1);
+      for i in 0..B::W + B::H {
+        above[i + 1] = dst.plane.p(cmp::min(above_limit, x + i), y - 1);
       }
-    }
-
-    if x != 0 {
-      if self != PredictionMode::V_PRED {
-        let left_slice = dst.go_left(1);
-        for i in 0..B::H {
-          left[i + 1] = left_slice.p(0, i);
-        }
-      } else if self == PredictionMode::V_PRED && y == 0 {
-        for i in 0..B::H {
-          left[i + 1] = dst.go_left(1).p(0, 0);
-          // FIXME(yushin): Figure out why below does not work??
-          //left[i + 1] = dst.go_left(1).plane.data[0];
-        }
+    } else if x != 0 {
+      let left_px = dst.go_left(1).p(0, 0);
+      for v in above[1..].iter_mut() {
+        *v = left_px;
       }
     }
 
-    if self == PredictionMode::PAETH_PRED && x != 0 && y != 0 {
-      above[0] = dst.go_up(1).go_left(1).p(0, 0);
-    }
-
-    if self == PredictionMode::SMOOTH_H_PRED ||
-      self == PredictionMode::SMOOTH_V_PRED ||
-      self == PredictionMode::SMOOTH_PRED ||
-      self == PredictionMode::PAETH_PRED {
-      if x == 0 && y != 0 {
-        for i in 0..B::H {
-          left[i + 1] = dst.go_up(1).p(0, 0);
-        }
+    if x != 0 {
+      let left_h = if have_below_left { 2 * B::H } else { B::H };
+      let left_limit = cmp::min(max_y, y + left_h - 1);
+      for i in 0..B::H + B::W {
+        left[i + 1] = dst.plane.p(x - 1, cmp::min(left_limit, y + i));
       }
-      if x != 0 && y == 0 {
-        for i in 0..B::W {
-          above[i + 1] = dst.go_left(1).p(0, 0);
-        }
+    } else if y != 0 {
+      let above_px = dst.go_up(1).p(0, 0);
+      for v in left[1..].iter_mut() {
+        *v = above_px;
       }
     }
 
-    if self == PredictionMode::PAETH_PRED {
-      if x == 0 && y != 0 {
-        above[0] = dst.go_up(1).p(0, 0);
-      }
-      if x != 0 && y == 0 {
-        above[0] = dst.go_left(1).p(0, 0);
-      }
-      if x == 0 && y == 0 {
-        above[0] = base;
-      }      
-    }
+    above[0] = match (x, y) {
+      (0, 0) => base,
+      (_, 0) => dst.go_left(1).p(0, 0),
+      (0, _) => dst.go_up(1).p(0, 0),
+      _ => dst.go_up(1).go_left(1).p(0, 0)
+    };
+    left[0] = above[0];
 
     let slice = dst.as_mut_slice();
     let above_slice = &above[1..B::W + 1];
     let left_slice = &left[1..B::H + 1];
 
     match self {
-      PredictionMode::DC_PRED => match (x, y) {
-        (0, 0) => B::pred_dc_128(slice, stride),
-        (_, 0) => B::pred_dc_left(slice, stride, above_slice, left_slice),
-        (0, _) => B::pred_dc_top(slice, stride, above_slice, left_slice),
-        _ => B::pred_dc(slice, stride, above_slice, left_slice)
-      },
-      PredictionMode::H_PRED => match (x, y) {
-        (0, 0) => B::pred_h(slice, stride, left_slice),
-        (0, _) => B::pred_h(slice, stride, above_slice),
-        (_, _) => B::pred_h(slice, stride, left_slice)
-      },
-      PredictionMode::V_PRED => match (x, y) {
-        (0, 0) => B::pred_v(slice, stride, above_slice),
-        (_, 0) => B::pred_v(slice, stride, left_slice),
-        (_, _) => B::pred_v(slice, stride, above_slice)
+      PredictionMode::DC_PRED if filter_intra.is_some() => B::pred_filter(
+        slice, stride, above_slice, left_slice, above[0],
+        filter_intra.unwrap(), bd
+      ),
+      PredictionMode::DC_PRED | PredictionMode::UV_CFL_PRED => {
+        match (x, y) {
+          (0, 0) => B::pred_dc_128(slice, stride),
+          (_, 0) => B::pred_dc_left(slice, stride, left_slice),
+          (0, _) => B::pred_dc_top(slice, stride, above_slice),
+          _ => B::pred_dc(slice, stride, above_slice, left_slice)
+        }
+        if self == PredictionMode::UV_CFL_PRED {
+          B::pred_cfl(slice, stride, ac, alpha, bd);
+        }
       },
+      PredictionMode::H_PRED if angle_delta == 0 =>
+        B::pred_h(slice, stride, left_slice),
+      PredictionMode::V_PRED if angle_delta == 0 =>
+        B::pred_v(slice, stride, above_slice),
       PredictionMode::PAETH_PRED =>
         B::pred_paeth(slice, stride, above_slice, left_slice, above[0]),
       PredictionMode::SMOOTH_PRED =>
@@ -468,6 +783,73 @@ impl PredictionMode {
         B::pred_smooth_h(slice, stride, above_slice, left_slice),
       PredictionMode::SMOOTH_V_PRED =>
         B::pred_smooth_v(slice, stride, above_slice, left_slice),
+      _ if self.is_directional() => {
+        let angle =
+          (self.angle() as isize + (angle_delta * ANGLE_STEP) as isize) as usize;
+
+        let mut dr_above = [0u16; 2 * MAX_TX_SIZE + DR_EDGE_OFFSET];
+        let mut dr_left = [0u16; 2 * MAX_TX_SIZE + DR_EDGE_OFFSET];
+        dr_above[DR_EDGE_OFFSET - 1..][..B::W + B::H + 1].copy_from_slice(above);
+        dr_left[DR_EDGE_OFFSET - 1..][..B::H + B::W + 1].copy_from_slice(left);
+
+        let mut upsample_above = false;
+        let mut upsample_left = false;
+
+        if edge_filter != IntraEdgeFilter::Disabled {
+          let smooth = edge_filter == IntraEdgeFilter::Smooth;
+
+          if angle != 90 && angle != 180 {
+            if angle > 90 && angle < 180 && B::W + B::H >= 24 {
+              let corner = filter_intra_edge_corner(
+                dr_above[DR_EDGE_OFFSET - 1],
+                dr_above[DR_EDGE_OFFSET],
+                dr_left[DR_EDGE_OFFSET]
+              );
+              dr_above[DR_EDGE_OFFSET - 1] = corner;
+              dr_left[DR_EDGE_OFFSET - 1] = corner;
+            }
+
+            if y != 0 {
+              let strength = intra_edge_filter_strength(
+                B::W, B::H, smooth, angle as isize - 90
+              );
+              let size = cmp::min(B::W, (max_x + 1).saturating_sub(x))
+                + if angle < 90 { B::H } else { 0 }
+                + 1;
+              filter_intra_edge(&mut dr_above[DR_EDGE_OFFSET - 1..], size, strength);
+            }
+
+            if x != 0 {
+              let strength = intra_edge_filter_strength(
+                B::W, B::H, smooth, angle as isize - 180
+              );
+              let size = cmp::min(B::H, (max_y + 1).saturating_sub(y))
+                + if angle > 180 { B::W } else { 0 }
+                + 1;
+              filter_intra_edge(&mut dr_left[DR_EDGE_OFFSET - 1..], size, strength);
+            }
+          }
+
+          upsample_above =
+            use_intra_edge_upsample(B::W, B::H, smooth, angle as isize - 90);
+          if upsample_above {
+            let size = B::W + if angle < 90 { B::H } else { 0 };
+            upsample_intra_edge(&mut dr_above, size, bd);
+          }
+
+          upsample_left =
+            use_intra_edge_upsample(B::W, B::H, smooth, angle as isize - 180);
+          if upsample_left {
+            let size = B::H + if angle > 180 { B::W } else { 0 };
+            upsample_intra_edge(&mut dr_left, size, bd);
+          }
+        }
+
+        B::pred_directional(
+          slice, stride, &dr_above, &dr_left, angle, upsample_above,
+          upsample_left
+        )
+      },
       _ => unimplemented!()
     }
   }
@@ -476,35 +858,77 @@ impl PredictionMode {
     return self < PredictionMode::NEARESTMV;
   }
 
+  // Whether any of the motion vectors of an inter mode is coded
+  pub fn has_newmv(self) -> bool {
+    match self {
+      PredictionMode::NEWMV
+      | PredictionMode::NEW_NEWMV
+      | PredictionMode::NEAREST_NEWMV
+      | PredictionMode::NEW_NEARESTMV
+      | PredictionMode::NEAR_NEWMV
+      | PredictionMode::NEW_NEARMV => true,
+      _ => false
+    }
+  }
+
   pub fn is_directional(self) -> bool {
     self >= PredictionMode::V_PRED && self <= PredictionMode::D63_PRED
   }
 
-  pub fn predict_inter<'a>(self, fi: &FrameInvariants, p: usize, po: &PlaneOffset,
-                           dst: &'a mut PlaneMutSlice<'a>, tx_size: TxSize) {
+  // Nominal angle of a directional mode, in degrees
+  pub fn angle(self) -> usize {
+    match self {
+      PredictionMode::V_PRED => 90,
+      PredictionMode::H_PRED => 180,
+      PredictionMode::D45_PRED => 45,
+      PredictionMode::D135_PRED => 135,
+      PredictionMode::D117_PRED => 113,
+      PredictionMode::D153_PRED => 157,
+      PredictionMode::D207_PRED => 203,
+      PredictionMode::D63_PRED => 67,
+      _ => 0
+    }
+  }
+
+  // Global motion of GLOBALMV blocks, or the local warp model `local_warp` of
+  // a warped block, warps the prediction blocks of at least 8x8 pixels, and
+  // the others are displaced by the motion vector of their coding block
+  pub fn predict_inter(
+    self, fi: &FrameInvariants, p: usize, po: &PlaneOffset, dst: &mut Plane,
+    width: usize, height: usize, mv: MotionVector,
+    local_warp: Option<&[i32; 6]>
+  ) {
     assert!(!self.is_intra());
-    assert!(self == PredictionMode::GLOBALMV); // Other modes not implemented
 
-    let ref_frame_idx = LAST_FRAME;
+    let ref_frame = LAST_FRAME;
 
-    match fi.rec_buffer.frames[fi.ref_frames[ref_frame_idx - LAST_FRAME]] {
+    match fi.rec_buffer.frames[fi.ref_frames[ref_frame - LAST_FRAME]] {
       Some(ref rec) => {
-        let ref_stride = rec.planes[p].cfg.stride;
-        let src = rec.planes[p].slice(po);
-        let ref_slice = src.as_slice();
-        let stride = dst.plane.cfg.stride;
-        let slice = dst.as_mut_slice();
-        for r in 0..tx_size.height() {
-          for c in 0..tx_size.width() {
-            let input_index = r * ref_stride + c;
-            let output_index = r * stride + c;
-            slice[output_index] = ref_slice[input_index];
-          }
+        let global_warp = self == PredictionMode::GLOBALMV
+          && fi.globalmv_transformation_type[ref_frame]
+            > GlobalMVMode::TRANSLATION;
+        let params = match local_warp {
+          Some(params) => Some(params),
+          None if global_warp => Some(&fi.gm_params[ref_frame]),
+          None => None
+        };
+        let warp = if width >= 8 && height >= 8 && fi.force_integer_mv == 0 {
+          params.and_then(|params| setup_shear(params).map(|s| (params, s)))
+        } else {
+          None
+        };
+        match warp {
+          Some((params, shear)) => predict_warp(
+            dst, po, width, height, &rec.planes[p], params, shear, fi.width,
+            fi.height, 8
+          ),
+          None => predict_translation(
+            dst, po, width, height, &rec.planes[p], mv, fi.width, fi.height, 8
+          )
         }
-      },
-      None => (),
+      }
+      None => ()
     }
-
   }
 }
 
@@ -535,3 +959,64 @@ pub enum TxSet {
 pub fn get_subsize(bsize: BlockSize, partition: PartitionType) -> BlockSize {
   subsize_lookup[partition as usize][bsize as usize]
 }
+
+/// Whether `partition` can be signaled for a block of size `bsize`. 8x8
+/// blocks only support the four basic partition types.
+pub fn partition_allowed(bsize: BlockSize, partition: PartitionType) -> bool {
+  if bsize <= BlockSize::BLOCK_8X8 && partition > PartitionType::PARTITION_SPLIT {
+    return false;
+  }
+  get_subsize(bsize, partition) != BlockSize::BLOCK_INVALID
+}
+
+/// Sizes and offsets of the blocks `partition` divides the block at `bo`
+/// into, in coding order. The quadrants of PARTITION_SPLIT are partitioned
+/// further, all other blocks are coded as is.
+pub fn get_partition_blocks(
+  bsize: BlockSize, partition: PartitionType, bo: &BlockOffset
+) -> Vec<(BlockSize, BlockOffset)> {
+  let subsize = get_subsize(bsize, partition);
+  let split_size = get_subsize(bsize, PartitionType::PARTITION_SPLIT);
+  let hbs = bsize.width_mi() >> 1; // Half the block size in blocks
+  let qbs = bsize.width_mi() >> 2; // Quarter of the block size in blocks
+  let at = |x: usize, y: usize| BlockOffset { x: bo.x + x, y: bo.y + y };
+
+  match partition {
+    PartitionType::PARTITION_NONE => vec![(subsize, at(0, 0))],
+    PartitionType::PARTITION_HORZ =>
+      vec![(subsize, at(0, 0)), (subsize, at(0, hbs))],
+    PartitionType::PARTITION_VERT =>
+      vec![(subsize, at(0, 0)), (subsize, at(hbs, 0))],
+    PartitionType::PARTITION_SPLIT => vec![
+      (subsize, at(0, 0)),
+      (subsize, at(hbs, 0)),
+      (subsize, at(0, hbs)),
+      (subsize, at(hbs, hbs)),
+    ],
+    PartitionType::PARTITION_HORZ_A => vec![
+      (split_size, at(0, 0)),
+      (split_size, at(hbs, 0)),
+      (subsize, at(0, hbs)),
+    ],
+    PartitionType::PARTITION_HORZ_B => vec![
+      (subsize, at(0, 0)),
+      (split_size, at(0, hbs)),
+      (split_size, at(hbs, hbs)),
+    ],
+    PartitionType::PARTITION_VERT_A => vec![
+      (split_size, at(0, 0)),
+      (split_size, at(0, hbs)),
+      (subsize, at(hbs, 0)),
+    ],
+    PartitionType::PARTITION_VERT_B => vec![
+      (subsize, at(0, 0)),
+      (split_size, at(hbs, 0)),
+      (split_size, at(hbs, hbs)),
+    ],
+    PartitionType::PARTITION_HORZ_4 =>
+      (0..4).map(|i| (subsize, at(0, i * qbs))).collect(),
+    PartitionType::PARTITION_VERT_4 =>
+      (0..4).map(|i| (subsize, at(i * qbs, 0))).collect(),
+    PartitionType::PARTITION_INVALID => unreachable!()
+  }
+}
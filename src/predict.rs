@@ -11,10 +11,9 @@
 #![cfg_attr(feature = "cargo-clippy", allow(cast_lossless))]
 #![cfg_attr(feature = "cargo-clippy", allow(needless_range_loop))]
 
-use libc;
-
 use context::MAX_TX_SIZE;
 use partition::*;
+use std::cmp;
 use std::mem::*;
 use context::INTRA_MODES;
 
@@ -22,12 +21,29 @@ pub static RAV1E_INTRA_MODES: &'static [PredictionMode] = &[
   PredictionMode::DC_PRED,
   PredictionMode::H_PRED,
   PredictionMode::V_PRED,
+  PredictionMode::D45_PRED,
+  PredictionMode::D135_PRED,
+  PredictionMode::D117_PRED,
+  PredictionMode::D153_PRED,
+  PredictionMode::D207_PRED,
+  PredictionMode::D63_PRED,
   PredictionMode::SMOOTH_PRED,
   PredictionMode::SMOOTH_H_PRED,
   PredictionMode::SMOOTH_V_PRED,
   PredictionMode::PAETH_PRED
 ];
 
+// Angle deltas tried around the nominal angle of directional modes
+pub static RAV1E_ANGLE_DELTAS: &'static [i8] = &[-3, -2, -1, 1, 2, 3];
+
+pub static RAV1E_FILTER_INTRA_MODES: &'static [FilterIntraMode] = &[
+  FilterIntraMode::FILTER_DC_PRED,
+  FilterIntraMode::FILTER_V_PRED,
+  FilterIntraMode::FILTER_H_PRED,
+  FilterIntraMode::FILTER_D157_PRED,
+  FilterIntraMode::FILTER_PAETH_PRED
+];
+
 // Intra prediction modes tested at high speed levels
 #[cfg_attr(rustfmt, rustfmt_skip)]
 pub static RAV1E_INTRA_MODES_MINIMAL: &'static [PredictionMode] = &[
@@ -38,6 +54,15 @@ pub static RAV1E_INTRA_MODES_MINIMAL: &'static [PredictionMode] = &[
 
 pub static RAV1E_INTER_MODES: &'static [PredictionMode] = &[
   PredictionMode::GLOBALMV,
+  PredictionMode::NEARESTMV,
+  PredictionMode::NEARMV,
+];
+
+pub static RAV1E_INTERINTRA_MODES: &'static [InterIntraMode] = &[
+  InterIntraMode::II_DC_PRED,
+  InterIntraMode::II_V_PRED,
+  InterIntraMode::II_H_PRED,
+  InterIntraMode::II_SMOOTH_PRED
 ];
 
 // Weights are quadratic from '1' to '1 / block_size', scaled by 2^sm_weight_log2_scale.
@@ -59,12 +84,11 @@ static sm_weight_arrays: [u8; 2 * MAX_TX_SIZE] = [
     // bs = 32
     255, 240, 225, 210, 196, 182, 169, 157, 145, 133, 122, 111, 101, 92, 83, 74,
     66, 59, 52, 45, 39, 34, 29, 25, 21, 17, 14, 12, 10, 9, 8, 8,
-    // TODO: enable extra weights for TX64X64
     // bs = 64
-    /*255, 248, 240, 233, 225, 218, 210, 203, 196, 189, 182, 176, 169, 163, 156,
+    255, 248, 240, 233, 225, 218, 210, 203, 196, 189, 182, 176, 169, 163, 156,
     150, 144, 138, 133, 127, 121, 116, 111, 106, 101, 96, 91, 86, 82, 77, 73, 69,
     65, 61, 57, 54, 50, 47, 44, 41, 38, 35, 32, 29, 27, 25, 22, 20, 18, 16, 15,
-    13, 12, 10, 9, 8, 7, 6, 6, 5, 5, 4, 4, 4,*/
+    13, 12, 10, 9, 8, 7, 6, 6, 5, 5, 4, 4, 4,
 ];
 
 
@@ -74,9 +98,110 @@ const NEED_ABOVERIGHT: u8 = 1 << 3;
 const NEED_ABOVELEFT: u8 = 1 << 4;
 const NEED_BOTTOMLEFT: u8 = 1 << 5;
 
-/*const INTRA_EDGE_FILT: usize = 3;
+const INTRA_EDGE_FILT: usize = 3;
 const INTRA_EDGE_TAPS: usize = 5;
-const MAX_UPSAMPLE_SZ: usize = 16;*/
+const MAX_UPSAMPLE_SZ: usize = 16;
+
+// Directional prediction edges start with two samples before the first
+// above or left neighbour: the above-left sample and its upsampled neighbour
+pub const DR_EDGE_OFFSET: usize = 2;
+
+// Position change per row (columns) or per column (rows) of a directional
+// prediction, in 1/64th of a sample, indexed by angle in degrees
+#[cfg_attr(rustfmt, rustfmt_skip)]
+static dr_intra_derivative: [u16; 90] = [
+    0, 0, 0,
+    1023, 0, 0,
+    547, 0, 0,
+    372, 0, 0, 0, 0,
+    273, 0, 0,
+    215, 0, 0,
+    178, 0, 0,
+    151, 0, 0,
+    132, 0, 0,
+    116, 0, 0,
+    102, 0, 0, 0,
+    90, 0, 0,
+    80, 0, 0,
+    71, 0, 0,
+    64, 0, 0,
+    57, 0, 0,
+    51, 0, 0,
+    45, 0, 0, 0,
+    40, 0, 0,
+    35, 0, 0,
+    31, 0, 0,
+    27, 0, 0,
+    23, 0, 0,
+    19, 0, 0,
+    15, 0, 0, 0, 0,
+    11, 0, 0,
+    7, 0, 0,
+    3, 0, 0
+];
+
+#[cfg_attr(rustfmt, rustfmt_skip)]
+static intra_edge_kernel: [[u32; INTRA_EDGE_TAPS]; INTRA_EDGE_FILT] = [
+    [0, 4, 8, 4, 0],
+    [0, 5, 6, 5, 0],
+    [2, 4, 4, 4, 2]
+];
+
+// Weights of the 7 neighbours of each 4x2 sub-block in the prediction of its
+// 8 samples, in 1/16ths
+#[cfg_attr(rustfmt, rustfmt_skip)]
+static filter_intra_taps: [[[i8; 7]; 8]; 5] = [
+  [
+    [-6, 10, 0, 0, 0, 12, 0],
+    [-5, 2, 10, 0, 0, 9, 0],
+    [-3, 1, 1, 10, 0, 7, 0],
+    [-3, 1, 1, 2, 10, 5, 0],
+    [-4, 6, 0, 0, 0, 2, 12],
+    [-3, 2, 6, 0, 0, 2, 9],
+    [-3, 2, 2, 6, 0, 2, 7],
+    [-3, 1, 2, 2, 6, 3, 5]
+  ],
+  [
+    [-10, 16, 0, 0, 0, 10, 0],
+    [-6, 0, 16, 0, 0, 6, 0],
+    [-4, 0, 0, 16, 0, 4, 0],
+    [-2, 0, 0, 0, 16, 2, 0],
+    [-10, 16, 0, 0, 0, 0, 10],
+    [-6, 0, 16, 0, 0, 0, 6],
+    [-4, 0, 0, 16, 0, 0, 4],
+    [-2, 0, 0, 0, 16, 0, 2]
+  ],
+  [
+    [-8, 8, 0, 0, 0, 16, 0],
+    [-8, 0, 8, 0, 0, 16, 0],
+    [-8, 0, 0, 8, 0, 16, 0],
+    [-8, 0, 0, 0, 8, 16, 0],
+    [-4, 4, 0, 0, 0, 0, 16],
+    [-4, 0, 4, 0, 0, 0, 16],
+    [-4, 0, 0, 4, 0, 0, 16],
+    [-4, 0, 0, 0, 4, 0, 16]
+  ],
+  [
+    [-2, 8, 0, 0, 0, 10, 0],
+    [-1, 3, 8, 0, 0, 6, 0],
+    [-1, 2, 3, 8, 0, 4, 0],
+    [0, 1, 2, 3, 8, 2, 0],
+    [-1, 4, 0, 0, 0, 3, 10],
+    [-1, 3, 4, 0, 0, 4, 6],
+    [-1, 2, 3, 4, 0, 4, 4],
+    [-1, 2, 2, 3, 4, 3, 3]
+  ],
+  [
+    [-12, 14, 0, 0, 0, 14, 0],
+    [-10, 0, 14, 0, 0, 12, 0],
+    [-9, 0, 0, 14, 0, 11, 0],
+    [-8, 0, 0, 0, 14, 10, 0],
+    [-10, 12, 0, 0, 0, 0, 14],
+    [-9, 1, 12, 0, 0, 0, 12],
+    [-8, 0, 0, 12, 0, 1, 11],
+    [-7, 0, 0, 1, 12, 1, 9]
+  ]
+];
 
 pub static extend_modes: [u8; INTRA_MODES] = [
   NEED_ABOVE | NEED_LEFT,                   // DC
@@ -94,58 +219,110 @@ pub static extend_modes: [u8; INTRA_MODES] = [
   NEED_LEFT | NEED_ABOVE | NEED_ABOVELEFT,  // PAETH
 ];
 
-extern {
-  #[cfg(test)]
-  fn highbd_dc_predictor(
-    dst: *mut u16, stride: libc::ptrdiff_t, bw: libc::c_int, bh: libc::c_int,
-    above: *const u16, left: *const u16, bd: libc::c_int
-  );
+// Strength of the filter smoothing an edge of a w x h directional prediction
+// whose angle is `delta` degrees away from the edge normal (90 for the above
+// edge, 180 for the left edge)
+pub fn intra_edge_filter_strength(
+  w: usize, h: usize, smooth: bool, delta: isize
+) -> usize {
+  let d = delta.abs();
+  let blk_wh = w + h;
+  let mut strength = 0;
+
+  if !smooth {
+    if blk_wh <= 8 {
+      if d >= 56 { strength = 1; }
+    } else if blk_wh <= 16 {
+      if d >= 40 { strength = 1; }
+    } else if blk_wh <= 24 {
+      if d >= 8 { strength = 1; }
+      if d >= 16 { strength = 2; }
+      if d >= 32 { strength = 3; }
+    } else if blk_wh <= 32 {
+      if d >= 1 { strength = 1; }
+      if d >= 4 { strength = 2; }
+      if d >= 32 { strength = 3; }
+    } else if d >= 1 {
+      strength = 3;
+    }
+  } else if blk_wh <= 8 {
+    if d >= 40 { strength = 1; }
+    if d >= 64 { strength = 2; }
+  } else if blk_wh <= 16 {
+    if d >= 20 { strength = 1; }
+    if d >= 48 { strength = 2; }
+  } else if blk_wh <= 24 {
+    if d >= 4 { strength = 3; }
+  } else if d >= 1 {
+    strength = 3;
+  }
 
-  fn highbd_dc_left_predictor(
-    dst: *mut u16, stride: libc::ptrdiff_t, bw: libc::c_int, bh: libc::c_int,
-    above: *const u16, left: *const u16, bd: libc::c_int
-  );
+  strength
+}
 
-  fn highbd_dc_top_predictor(
-    dst: *mut u16, stride: libc::ptrdiff_t, bw: libc::c_int, bh: libc::c_int,
-    above: *const u16, left: *const u16, bd: libc::c_int
-  );
+// Whether an edge of a w x h directional prediction is upsampled, with
+// `delta` as in intra_edge_filter_strength()
+pub fn use_intra_edge_upsample(
+  w: usize, h: usize, smooth: bool, delta: isize
+) -> bool {
+  let d = delta.abs();
+  let blk_wh = w + h;
+
+  if d <= 0 || d >= 40 {
+    false
+  } else if smooth {
+    blk_wh <= 8
+  } else {
+    blk_wh <= 16
+  }
+}
 
-  #[cfg(test)]
-  fn highbd_h_predictor(
-    dst: *mut u16, stride: libc::ptrdiff_t, bw: libc::c_int, bh: libc::c_int,
-    above: *const u16, left: *const u16, bd: libc::c_int
-  );
+// Smooth the first `size` samples of an edge starting with the above-left sample
+pub fn filter_intra_edge(edge: &mut [u16], size: usize, strength: usize) {
+  if strength == 0 {
+    return;
+  }
 
-  #[cfg(test)]
-  fn highbd_v_predictor(
-    dst: *mut u16, stride: libc::ptrdiff_t, bw: libc::c_int, bh: libc::c_int,
-    above: *const u16, left: *const u16, bd: libc::c_int
-  );
+  let kernel = &intra_edge_kernel[strength - 1];
+  let mut copy = [0u16; 2 * MAX_TX_SIZE + 1];
+  copy[..size].copy_from_slice(&edge[..size]);
 
-  #[cfg(test)]
-  fn highbd_paeth_predictor(
-    dst: *mut u16, stride: libc::ptrdiff_t, bw: libc::c_int, bh: libc::c_int,
-    above: *const u16, left: *const u16, bd: libc::c_int
-  );
+  for i in 1..size {
+    let mut s = 0;
+    for j in 0..INTRA_EDGE_TAPS {
+      let k = cmp::min((i + j).saturating_sub(2), size - 1);
+      s += kernel[j] * copy[k] as u32;
+    }
+    edge[i] = ((s + 8) >> 4) as u16;
+  }
+}
 
-  #[cfg(test)]
-  fn highbd_smooth_predictor(
-    dst: *mut u16, stride: libc::ptrdiff_t, bw: libc::c_int, bh: libc::c_int,
-    above: *const u16, left: *const u16, bd: libc::c_int
-  );
+// Double the resolution of the above-left sample and of the first `size`
+// neighbours of an edge starting DR_EDGE_OFFSET samples before them
+pub fn upsample_intra_edge(edge: &mut [u16], size: usize, bit_depth: usize) {
+  assert!(size <= MAX_UPSAMPLE_SZ);
 
-  #[cfg(test)]
-  fn highbd_smooth_h_predictor(
-    dst: *mut u16, stride: libc::ptrdiff_t, bw: libc::c_int, bh: libc::c_int,
-    above: *const u16, left: *const u16, bd: libc::c_int
-  );
+  // The above-left sample and the neighbours, with both ends repeated
+  let mut dup = [0i32; MAX_UPSAMPLE_SZ + 3];
+  dup[0] = edge[DR_EDGE_OFFSET - 1] as i32;
+  for i in 0..size + 1 {
+    dup[i + 1] = edge[DR_EDGE_OFFSET - 1 + i] as i32;
+  }
+  dup[size + 2] = edge[DR_EDGE_OFFSET + size - 1] as i32;
+
+  let max = (1 << bit_depth) - 1;
+  edge[DR_EDGE_OFFSET - 2] = dup[0] as u16;
+  for i in 0..size {
+    let s = -dup[i] + 9 * dup[i + 1] + 9 * dup[i + 2] - dup[i + 3];
+    let s = cmp::min(cmp::max((s + 8) >> 4, 0), max);
+    edge[DR_EDGE_OFFSET + 2 * i - 1] = s as u16;
+    edge[DR_EDGE_OFFSET + 2 * i] = dup[i + 2] as u16;
+  }
+}
 
-  #[cfg(test)]
-  fn highbd_smooth_v_predictor(
-    dst: *mut u16, stride: libc::ptrdiff_t, bw: libc::c_int, bh: libc::c_int,
-    above: *const u16, left: *const u16, bd: libc::c_int
-  );
+// Above-left sample of zone 2 directional predictions of large blocks
+pub fn filter_intra_edge_corner(above_left: u16, above: u16, left: u16) -> u16 {
+  ((left as u32 * 5 + above_left as u32 * 6 + above as u32 * 5 + 8) >> 4) as u16
 }
 
 pub trait Dim {
@@ -181,6 +358,111 @@ impl Dim for Block32x32 {
   const H: usize = 32;
 }
 
+pub struct Block64x64;
+
+impl Dim for Block64x64 {
+  const W: usize = 64;
+  const H: usize = 64;
+}
+
+pub struct Block4x8;
+
+impl Dim for Block4x8 {
+  const W: usize = 4;
+  const H: usize = 8;
+}
+
+pub struct Block8x4;
+
+impl Dim for Block8x4 {
+  const W: usize = 8;
+  const H: usize = 4;
+}
+
+pub struct Block8x16;
+
+impl Dim for Block8x16 {
+  const W: usize = 8;
+  const H: usize = 16;
+}
+
+pub struct Block16x8;
+
+impl Dim for Block16x8 {
+  const W: usize = 16;
+  const H: usize = 8;
+}
+
+pub struct Block16x32;
+
+impl Dim for Block16x32 {
+  const W: usize = 16;
+  const H: usize = 32;
+}
+
+pub struct Block32x16;
+
+impl Dim for Block32x16 {
+  const W: usize = 32;
+  const H: usize = 16;
+}
+
+pub struct Block32x64;
+
+impl Dim for Block32x64 {
+  const W: usize = 32;
+  const H: usize = 64;
+}
+
+pub struct Block64x32;
+
+impl Dim for Block64x32 {
+  const W: usize = 64;
+  const H: usize = 32;
+}
+
+pub struct Block4x16;
+
+impl Dim for Block4x16 {
+  const W: usize = 4;
+  const H: usize = 16;
+}
+
+pub struct Block16x4;
+
+impl Dim for Block16x4 {
+  const W: usize = 16;
+  const H: usize = 4;
+}
+
+pub struct Block8x32;
+
+impl Dim for Block8x32 {
+  const W: usize = 8;
+  const H: usize = 32;
+}
+
+pub struct Block32x8;
+
+impl Dim for Block32x8 {
+  const W: usize = 32;
+  const H: usize = 8;
+}
+
+pub struct Block16x64;
+
+impl Dim for Block16x64 {
+  const W: usize = 16;
+  const H: usize = 64;
+}
+
+pub struct Block64x16;
+
+impl Dim for Block64x16 {
+  const W: usize = 64;
+  const H: usize = 16;
+}
+
 pub trait Intra: Dim {
   #[cfg_attr(feature = "comparative_bench", inline(never))]
   fn pred_dc(output: &mut [u16], stride: usize, above: &[u16], left: &[u16]) {
@@ -206,36 +488,26 @@ pub trait Intra: Dim {
   }
 
   #[cfg_attr(feature = "comparative_bench", inline(never))]
-  fn pred_dc_left(
-    output: &mut [u16], stride: usize, above: &[u16], left: &[u16]
-  ) {
-    unsafe {
-      highbd_dc_left_predictor(
-        output.as_mut_ptr(),
-        stride as libc::ptrdiff_t,
-        Self::W as libc::c_int,
-        Self::H as libc::c_int,
-        above.as_ptr(),
-        left.as_ptr(),
-        8
-      );
+  fn pred_dc_left(output: &mut [u16], stride: usize, left: &[u16]) {
+    let sum = left[..Self::H].iter().fold(0, |acc, &v| acc + v as u32);
+    let avg = ((sum + (Self::H >> 1) as u32) / Self::H as u32) as u16;
+
+    for line in output.chunks_mut(stride).take(Self::H) {
+      for v in &mut line[..Self::W] {
+        *v = avg;
+      }
     }
   }
 
   #[cfg_attr(feature = "comparative_bench", inline(never))]
-  fn pred_dc_top(
-    output: &mut [u16], stride: usize, above: &[u16], left: &[u16]
-  ) {
-    unsafe {
-      highbd_dc_top_predictor(
-        output.as_mut_ptr(),
-        stride as libc::ptrdiff_t,
-        Self::W as libc::c_int,
-        Self::H as libc::c_int,
-        above.as_ptr(),
-        left.as_ptr(),
-        8
-      );
+  fn pred_dc_top(output: &mut [u16], stride: usize, above: &[u16]) {
+    let sum = above[..Self::W].iter().fold(0, |acc, &v| acc + v as u32);
+    let avg = ((sum + (Self::W >> 1) as u32) / Self::W as u32) as u16;
+
+    for line in output.chunks_mut(stride).take(Self::H) {
+      for v in &mut line[..Self::W] {
+        *v = avg;
+      }
     }
   }
 
@@ -410,6 +682,154 @@ pub trait Intra: Dim {
       }
     }
   }
+
+  // The above and left edges start DR_EDGE_OFFSET samples before the first
+  // neighbour, and hold twice as many samples when upsampled
+  #[cfg_attr(feature = "comparative_bench", inline(never))]
+  fn pred_directional(
+    output: &mut [u16], stride: usize, above: &[u16], left: &[u16],
+    angle: usize, upsample_above: bool, upsample_left: bool
+  ) {
+    let upsample_above = upsample_above as usize;
+    let upsample_left = upsample_left as usize;
+
+    // Interpolate between the edge samples at `base` and `base + 1`,
+    // `shift` being the weight of the latter in 1/32nds
+    let interpolate = |edge: &[u16], base: isize, shift: isize| {
+      let i = (base + DR_EDGE_OFFSET as isize) as usize;
+      let v = edge[i] as isize * (32 - shift) + edge[i + 1] as isize * shift;
+      ((v + 16) >> 5) as u16
+    };
+
+    if angle < 90 {
+      // Zone 1 only uses the above and above-right neighbours
+      let dx = dr_intra_derivative[angle] as isize;
+      let max_base_x = ((Self::W + Self::H - 1) << upsample_above) as isize;
+
+      for r in 0..Self::H {
+        let idx = (r as isize + 1) * dx;
+        let shift = ((idx << upsample_above) >> 1) & 0x1f;
+
+        for c in 0..Self::W {
+          let base =
+            (idx >> (6 - upsample_above)) + ((c as isize) << upsample_above);
+          output[r * stride + c] = if base < max_base_x {
+            interpolate(above, base, shift)
+          } else {
+            above[max_base_x as usize + DR_EDGE_OFFSET]
+          };
+        }
+      }
+    } else if angle == 90 {
+      for line in output.chunks_mut(stride).take(Self::H) {
+        line[..Self::W]
+          .clone_from_slice(&above[DR_EDGE_OFFSET..DR_EDGE_OFFSET + Self::W])
+      }
+    } else if angle < 180 {
+      // Zone 2 uses the above, above-left and left neighbours
+      let dx = dr_intra_derivative[180 - angle] as isize;
+      let dy = dr_intra_derivative[angle - 90] as isize;
+
+      for r in 0..Self::H {
+        for c in 0..Self::W {
+          let idx = ((c as isize) << 6) - (r as isize + 1) * dx;
+          let base = idx >> (6 - upsample_above);
+
+          output[r * stride + c] = if base >= -(1 << upsample_above) {
+            interpolate(above, base, ((idx << upsample_above) >> 1) & 0x1f)
+          } else {
+            let idx = ((r as isize) << 6) - (c as isize + 1) * dy;
+            let base = idx >> (6 - upsample_left);
+            interpolate(left, base, ((idx << upsample_left) >> 1) & 0x1f)
+          };
+        }
+      }
+    } else if angle == 180 {
+      for (r, line) in output.chunks_mut(stride).take(Self::H).enumerate() {
+        for v in &mut line[..Self::W] {
+          *v = left[DR_EDGE_OFFSET + r];
+        }
+      }
+    } else {
+      // Zone 3 only uses the left and below-left neighbours
+      let dy = dr_intra_derivative[270 - angle] as isize;
+
+      for c in 0..Self::W {
+        let idx = (c as isize + 1) * dy;
+        let shift = ((idx << upsample_left) >> 1) & 0x1f;
+
+        for r in 0..Self::H {
+          let base =
+            (idx >> (6 - upsample_left)) + ((r as isize) << upsample_left);
+          output[r * stride + c] = interpolate(left, base, shift);
+        }
+      }
+    }
+  }
+
+  // Predict each 4x2 sub-block in raster order from the 7 neighbouring
+  // samples above and to the left of it, some of which are predicted
+  #[cfg_attr(feature = "comparative_bench", inline(never))]
+  fn pred_filter(
+    output: &mut [u16], stride: usize, above: &[u16], left: &[u16],
+    above_left: u16, mode: FilterIntraMode, bit_depth: usize
+  ) {
+    let taps = &filter_intra_taps[mode as usize];
+    let max = (1 << bit_depth) - 1;
+
+    for r in (0..Self::H / 2).map(|i| i * 2) {
+      for c in (0..Self::W / 4).map(|j| j * 4) {
+        // Above-left, 4 above and 2 left neighbours
+        let mut p = [0i32; 7];
+        for i in 0..5 {
+          p[i] = match (r, c + i) {
+            (0, 0) => above_left,
+            (0, x) => above[x - 1],
+            (y, 0) => left[y - 1],
+            (y, x) => output[(y - 1) * stride + x - 1]
+          } as i32;
+        }
+        for i in 0..2 {
+          p[5 + i] = if c == 0 {
+            left[r + i]
+          } else {
+            output[(r + i) * stride + c - 1]
+          } as i32;
+        }
+
+        for (i, t) in taps.iter().enumerate() {
+          let pr: i32 = t.iter().zip(p.iter()).map(|(&t, &v)| t as i32 * v).sum();
+          let pr = if pr < 0 { -((-pr + 8) >> 4) } else { (pr + 8) >> 4 };
+          output[(r + (i >> 2)) * stride + c + (i & 3)] =
+            cmp::min(cmp::max(pr, 0), max) as u16;
+        }
+      }
+    }
+  }
+
+  // Add the scaled luma AC contribution to a DC prediction, `alpha` being
+  // in 1/8ths and `ac` holding the luma at 8 times its resolution
+  #[cfg_attr(feature = "comparative_bench", inline(never))]
+  fn pred_cfl(
+    output: &mut [u16], stride: usize, ac: &[i16], alpha: i16,
+    bit_depth: usize
+  ) {
+    let max = (1 << bit_depth) - 1;
+
+    for (line, luma) in
+      output.chunks_mut(stride).zip(ac.chunks(Self::W)).take(Self::H)
+    {
+      for (v, &l) in line[..Self::W].iter_mut().zip(luma) {
+        let scaled = alpha as i32 * l as i32;
+        let scaled = if scaled < 0 {
+          -((-scaled + 32) >> 6)
+        } else {
+          (scaled + 32) >> 6
+        };
+        *v = cmp::min(cmp::max(*v as i32 + scaled, 0), max) as u16;
+      }
+    }
+  }
 }
 
 pub trait Inter: Dim {
@@ -420,243 +840,209 @@ impl Intra for Block4x4 {}
 impl Intra for Block8x8 {}
 impl Intra for Block16x16 {}
 impl Intra for Block32x32 {}
+impl Intra for Block64x64 {}
+impl Intra for Block4x8 {}
+impl Intra for Block8x4 {}
+impl Intra for Block8x16 {}
+impl Intra for Block16x8 {}
+impl Intra for Block16x32 {}
+impl Intra for Block32x16 {}
+impl Intra for Block32x64 {}
+impl Intra for Block64x32 {}
+impl Intra for Block4x16 {}
+impl Intra for Block16x4 {}
+impl Intra for Block8x32 {}
+impl Intra for Block32x8 {}
+impl Intra for Block16x64 {}
+impl Intra for Block64x16 {}
 
 #[cfg(test)]
 pub mod test {
   use super::*;
+  use libc;
   use rand::{ChaChaRng, Rng, SeedableRng};
 
+  extern {
+    fn highbd_dc_predictor(
+      dst: *mut u16, stride: libc::ptrdiff_t, bw: libc::c_int,
+      bh: libc::c_int, above: *const u16, left: *const u16, bd: libc::c_int
+    );
+
+    fn highbd_dc_left_predictor(
+      dst: *mut u16, stride: libc::ptrdiff_t, bw: libc::c_int,
+      bh: libc::c_int, above: *const u16, left: *const u16, bd: libc::c_int
+    );
+
+    fn highbd_dc_top_predictor(
+      dst: *mut u16, stride: libc::ptrdiff_t, bw: libc::c_int,
+      bh: libc::c_int, above: *const u16, left: *const u16, bd: libc::c_int
+    );
+
+    fn highbd_h_predictor(
+      dst: *mut u16, stride: libc::ptrdiff_t, bw: libc::c_int,
+      bh: libc::c_int, above: *const u16, left: *const u16, bd: libc::c_int
+    );
+
+    fn highbd_v_predictor(
+      dst: *mut u16, stride: libc::ptrdiff_t, bw: libc::c_int,
+      bh: libc::c_int, above: *const u16, left: *const u16, bd: libc::c_int
+    );
+
+    fn highbd_paeth_predictor(
+      dst: *mut u16, stride: libc::ptrdiff_t, bw: libc::c_int,
+      bh: libc::c_int, above: *const u16, left: *const u16, bd: libc::c_int
+    );
+
+    fn highbd_smooth_predictor(
+      dst: *mut u16, stride: libc::ptrdiff_t, bw: libc::c_int,
+      bh: libc::c_int, above: *const u16, left: *const u16, bd: libc::c_int
+    );
+
+    fn highbd_smooth_h_predictor(
+      dst: *mut u16, stride: libc::ptrdiff_t, bw: libc::c_int,
+      bh: libc::c_int, above: *const u16, left: *const u16, bd: libc::c_int
+    );
+
+    fn highbd_smooth_v_predictor(
+      dst: *mut u16, stride: libc::ptrdiff_t, bw: libc::c_int,
+      bh: libc::c_int, above: *const u16, left: *const u16, bd: libc::c_int
+    );
+  }
+
+  type CPredictor = unsafe extern "C" fn(
+    dst: *mut u16,
+    stride: libc::ptrdiff_t,
+    bw: libc::c_int,
+    bh: libc::c_int,
+    above: *const u16,
+    left: *const u16,
+    bd: libc::c_int
+  );
+
   const MAX_ITER: usize = 50000;
+  // Pixels predicted per mode and block size when comparing with libaom
+  const MAX_PIXELS: usize = 4 * 4 * 5000;
+  const STRIDE: usize = 64;
 
+  // The above edge starts with the above-left sample, which libaom reads
+  // at above[-1]
   fn setup_pred(
     ra: &mut ChaChaRng
   ) -> (Vec<u16>, Vec<u16>, Vec<u16>, Vec<u16>) {
-    let output = vec![0u16; 32 * 32];
-    let above: Vec<u16> = (0..32).map(|_| ra.gen()).collect();
-    let left: Vec<u16> = (0..32).map(|_| ra.gen()).collect();
+    let output = vec![0u16; STRIDE * 64];
+    let edge: Vec<u16> = (0..64 + 1).map(|_| ra.gen::<u8>() as u16).collect();
+    let left: Vec<u16> = (0..64).map(|_| ra.gen::<u8>() as u16).collect();
 
     let o1 = output.clone();
     let o2 = output.clone();
 
-    (above, left, o1, o2)
+    (edge, left, o1, o2)
   }
 
-  fn pred_dc_4x4(
-    output: &mut [u16], stride: usize, above: &[u16], left: &[u16]
+  fn pred_aom<B: Dim>(
+    predictor: CPredictor, output: &mut [u16], edge: &[u16], left: &[u16]
   ) {
     unsafe {
-      highbd_dc_predictor(
+      predictor(
         output.as_mut_ptr(),
-        stride as libc::ptrdiff_t,
-        4,
-        4,
-        above.as_ptr(),
+        STRIDE as libc::ptrdiff_t,
+        B::W as libc::c_int,
+        B::H as libc::c_int,
+        edge[1..].as_ptr(),
         left.as_ptr(),
         8
       );
     }
   }
 
-  pub fn pred_h_4x4(
-    output: &mut [u16], stride: usize, above: &[u16], left: &[u16]
-  ) {
-    unsafe {
-      highbd_h_predictor(
-        output.as_mut_ptr(),
-        stride as libc::ptrdiff_t,
-        4,
-        4,
-        above.as_ptr(),
-        left.as_ptr(),
-        8
-      );
-    }
-  }
+  fn check_pred<B: Intra>(ra: &mut ChaChaRng) {
+    let (edge, left, mut o1, mut o2) = setup_pred(ra);
+    let above_left = edge[0];
+    let above = &edge[1..B::W + 1];
+    let left = &left[..B::H];
 
-  pub fn pred_v_4x4(
-    output: &mut [u16], stride: usize, above: &[u16], left: &[u16]
-  ) {
-    unsafe {
-      highbd_v_predictor(
-        output.as_mut_ptr(),
-        stride as libc::ptrdiff_t,
-        4,
-        4,
-        above.as_ptr(),
-        left.as_ptr(),
-        8
-      );
-    }
-  }
+    pred_aom::<B>(highbd_dc_predictor, &mut o1, &edge, left);
+    B::pred_dc(&mut o2, STRIDE, above, left);
+    assert_eq!(o1, o2, "DC_PRED {}x{}", B::W, B::H);
 
-  pub fn pred_paeth_4x4(
-    output: &mut [u16], stride: usize, above: &[u16], left: &[u16]
-  ) {
-    unsafe {
-      highbd_paeth_predictor(
-        output.as_mut_ptr(),
-        stride as libc::ptrdiff_t,
-        4,
-        4,
-        above.as_ptr(),
-        left.as_ptr(),
-        8
-      );
-    }
-  }
+    pred_aom::<B>(highbd_dc_left_predictor, &mut o1, &edge, left);
+    B::pred_dc_left(&mut o2, STRIDE, left);
+    assert_eq!(o1, o2, "DC_LEFT_PRED {}x{}", B::W, B::H);
 
-  pub fn pred_smooth_4x4(
-    output: &mut [u16], stride: usize, above: &[u16], left: &[u16]
-  ) {
-    unsafe {
-      highbd_smooth_predictor(
-        output.as_mut_ptr(),
-        stride as libc::ptrdiff_t,
-        4,
-        4,
-        above.as_ptr(),
-        left.as_ptr(),
-        8
-      );
-    }
-  }
+    pred_aom::<B>(highbd_dc_top_predictor, &mut o1, &edge, left);
+    B::pred_dc_top(&mut o2, STRIDE, above);
+    assert_eq!(o1, o2, "DC_TOP_PRED {}x{}", B::W, B::H);
 
-  pub fn pred_smooth_h_4x4(
-    output: &mut [u16], stride: usize, above: &[u16], left: &[u16]
-  ) {
-    unsafe {
-      highbd_smooth_h_predictor(
-        output.as_mut_ptr(),
-        stride as libc::ptrdiff_t,
-        4,
-        4,
-        above.as_ptr(),
-        left.as_ptr(),
-        8
-      );
-    }
-  }
+    pred_aom::<B>(highbd_h_predictor, &mut o1, &edge, left);
+    B::pred_h(&mut o2, STRIDE, left);
+    assert_eq!(o1, o2, "H_PRED {}x{}", B::W, B::H);
 
-  pub fn pred_smooth_v_4x4(
-    output: &mut [u16], stride: usize, above: &[u16], left: &[u16]
-  ) {
-    unsafe {
-      highbd_smooth_v_predictor(
-        output.as_mut_ptr(),
-        stride as libc::ptrdiff_t,
-        4,
-        4,
-        above.as_ptr(),
-        left.as_ptr(),
-        8
-      );
-    }
-  }
-
-  fn do_dc_pred(ra: &mut ChaChaRng) -> (Vec<u16>, Vec<u16>) {
-    let (above, left, mut o1, mut o2) = setup_pred(ra);
+    pred_aom::<B>(highbd_v_predictor, &mut o1, &edge, left);
+    B::pred_v(&mut o2, STRIDE, above);
+    assert_eq!(o1, o2, "V_PRED {}x{}", B::W, B::H);
 
-    pred_dc_4x4(&mut o1, 32, &above[..4], &left[..4]);
-    Block4x4::pred_dc(&mut o2, 32, &above[..4], &left[..4]);
-
-    (o1, o2)
-  }
+    pred_aom::<B>(highbd_paeth_predictor, &mut o1, &edge, left);
+    B::pred_paeth(&mut o2, STRIDE, above, left, above_left);
+    assert_eq!(o1, o2, "PAETH_PRED {}x{}", B::W, B::H);
 
-  fn do_h_pred(ra: &mut ChaChaRng) -> (Vec<u16>, Vec<u16>) {
-    let (above, left, mut o1, mut o2) = setup_pred(ra);
+    pred_aom::<B>(highbd_smooth_predictor, &mut o1, &edge, left);
+    B::pred_smooth(&mut o2, STRIDE, above, left);
+    assert_eq!(o1, o2, "SMOOTH_PRED {}x{}", B::W, B::H);
 
-    pred_h_4x4(&mut o1, 32, &above[..4], &left[..4]);
-    Block4x4::pred_h(&mut o2, 32, &left[..4]);
+    pred_aom::<B>(highbd_smooth_h_predictor, &mut o1, &edge, left);
+    B::pred_smooth_h(&mut o2, STRIDE, above, left);
+    assert_eq!(o1, o2, "SMOOTH_H_PRED {}x{}", B::W, B::H);
 
-    (o1, o2)
+    pred_aom::<B>(highbd_smooth_v_predictor, &mut o1, &edge, left);
+    B::pred_smooth_v(&mut o2, STRIDE, above, left);
+    assert_eq!(o1, o2, "SMOOTH_V_PRED {}x{}", B::W, B::H);
   }
 
-  fn do_v_pred(ra: &mut ChaChaRng) -> (Vec<u16>, Vec<u16>) {
-    let (above, left, mut o1, mut o2) = setup_pred(ra);
-
-    pred_v_4x4(&mut o1, 32, &above[..4], &left[..4]);
-    Block4x4::pred_v(&mut o2, 32, &above[..4]);
-
-    (o1, o2)
-  }
-
-  fn do_paeth_pred(ra: &mut ChaChaRng) -> (Vec<u16>, Vec<u16>) {
-    let (above, left, mut o1, mut o2) = setup_pred(ra);
-    let above_left = unsafe { *above.as_ptr().offset(-1) };
-
-    pred_paeth_4x4(&mut o1, 32, &above[..4], &left[..4]);
-    Block4x4::pred_paeth(&mut o2, 32, &above[..4], &left[..4], above_left);
-
-    (o1, o2)
-  }
-
-  fn do_smooth_pred(ra: &mut ChaChaRng) -> (Vec<u16>, Vec<u16>) {
-    let (above, left, mut o1, mut o2) = setup_pred(ra);
-
-    pred_smooth_4x4(&mut o1, 32, &above[..4], &left[..4]);
-    Block4x4::pred_smooth(&mut o2, 32, &above[..4], &left[..4]);
-
-    (o1, o2)
-  }
-
-  fn do_smooth_h_pred(ra: &mut ChaChaRng) -> (Vec<u16>, Vec<u16>) {
-    let (above, left, mut o1, mut o2) = setup_pred(ra);
-
-    pred_smooth_h_4x4(&mut o1, 32, &above[..4], &left[..4]);
-    Block4x4::pred_smooth_h(&mut o2, 32, &above[..4], &left[..4]);
-
-    (o1, o2)
+  fn check_pred_iter<B: Intra>(ra: &mut ChaChaRng) {
+    for _ in 0..MAX_PIXELS / (B::W * B::H) + 1 {
+      check_pred::<B>(ra);
+    }
   }
 
-  fn do_smooth_v_pred(ra: &mut ChaChaRng) -> (Vec<u16>, Vec<u16>) {
-    let (above, left, mut o1, mut o2) = setup_pred(ra);
-
-    pred_smooth_v_4x4(&mut o1, 32, &above[..4], &left[..4]);
-    Block4x4::pred_smooth_v(&mut o2, 32, &above[..4], &left[..4]);
-
-    (o1, o2)
+  #[test]
+  fn pred_matches() {
+    let mut ra = ChaChaRng::from_seed([0; 32]);
+    check_pred_iter::<Block4x4>(&mut ra);
+    check_pred_iter::<Block8x8>(&mut ra);
+    check_pred_iter::<Block16x16>(&mut ra);
+    check_pred_iter::<Block32x32>(&mut ra);
+    check_pred_iter::<Block64x64>(&mut ra);
+    check_pred_iter::<Block4x8>(&mut ra);
+    check_pred_iter::<Block8x4>(&mut ra);
+    check_pred_iter::<Block8x16>(&mut ra);
+    check_pred_iter::<Block16x8>(&mut ra);
+    check_pred_iter::<Block16x32>(&mut ra);
+    check_pred_iter::<Block32x16>(&mut ra);
+    check_pred_iter::<Block32x64>(&mut ra);
+    check_pred_iter::<Block64x32>(&mut ra);
+    check_pred_iter::<Block4x16>(&mut ra);
+    check_pred_iter::<Block16x4>(&mut ra);
+    check_pred_iter::<Block8x32>(&mut ra);
+    check_pred_iter::<Block32x8>(&mut ra);
+    check_pred_iter::<Block16x64>(&mut ra);
+    check_pred_iter::<Block64x16>(&mut ra);
   }
 
   fn assert_same(o2: Vec<u16>) {
-    for l in o2.chunks(32).take(4) {
+    for l in o2.chunks(STRIDE).take(4) {
       for v in l[..4].windows(2) {
         assert_eq!(v[0], v[1]);
       }
     }
   }
 
-  #[test]
-  fn pred_matches() {
-    let mut ra = ChaChaRng::from_seed([0; 32]);
-    for _ in 0..MAX_ITER {
-      let (o1, o2) = do_dc_pred(&mut ra);
-      assert_eq!(o1, o2);
-
-      let (o1, o2) = do_h_pred(&mut ra);
-      assert_eq!(o1, o2);
-
-      let (o1, o2) = do_v_pred(&mut ra);
-      assert_eq!(o1, o2);
-
-      let (o1, o2) = do_paeth_pred(&mut ra);
-      assert_eq!(o1, o2);
-
-      let (o1, o2) = do_smooth_pred(&mut ra);
-      assert_eq!(o1, o2);
-
-      let (o1, o2) = do_smooth_h_pred(&mut ra);
-      assert_eq!(o1, o2);
-
-      let (o1, o2) = do_smooth_v_pred(&mut ra);
-      assert_eq!(o1, o2);
-    }
-  }
-
   #[test]
   fn pred_same() {
     let mut ra = ChaChaRng::from_seed([0; 32]);
     for _ in 0..MAX_ITER {
-      let (_, o2) = do_dc_pred(&mut ra);
+      let (edge, left, _, mut o2) = setup_pred(&mut ra);
+
+      Block4x4::pred_dc(&mut o2, STRIDE, &edge[1..5], &left[..4]);
 
       assert_same(o2)
     }
@@ -678,6 +1064,22 @@ pub mod test {
       }
     }
 
+    Block4x4::pred_dc_left(&mut o, 32, &left[..4]);
+
+    for l in o.chunks(32).take(4) {
+      for v in l[..4].iter() {
+        assert_eq!(*v, max12bit);
+      }
+    }
+
+    Block4x4::pred_dc_top(&mut o, 32, &above[..4]);
+
+    for l in o.chunks(32).take(4) {
+      for v in l[..4].iter() {
+        assert_eq!(*v, max12bit);
+      }
+    }
+
     Block4x4::pred_h(&mut o, 32, &left[..4]);
 
     for l in o.chunks(32).take(4) {
@@ -694,7 +1096,7 @@ pub mod test {
       }
     }
 
-    let above_left = unsafe { *above.as_ptr().offset(-1) };
+    let above_left = max12bit;
 
     Block4x4::pred_paeth(&mut o, 32, &above[..4], &left[..4], above_left);
 
@@ -728,4 +1130,105 @@ pub mod test {
       }
     }
   }
+
+  #[test]
+  fn pred_directional_d45() {
+    let mut above = [0u16; 2 * MAX_TX_SIZE + DR_EDGE_OFFSET];
+    for (i, v) in above.iter_mut().enumerate() {
+      *v = (i * 8) as u16;
+    }
+    let left = [0u16; 2 * MAX_TX_SIZE + DR_EDGE_OFFSET];
+
+    let mut o = vec![0u16; 32 * 32];
+
+    Block4x4::pred_directional(&mut o, 32, &above, &left, 45, false, false);
+
+    // Each sample is projected exactly onto the above-right neighbours
+    for (r, l) in o.chunks(32).take(4).enumerate() {
+      for (c, v) in l[..4].iter().enumerate() {
+        assert_eq!(*v, above[DR_EDGE_OFFSET + r + c + 1]);
+      }
+    }
+  }
+
+  #[test]
+  fn pred_directional_max() {
+    let max12bit = 4096 - 1;
+    let mut above = [max12bit; 2 * MAX_TX_SIZE + DR_EDGE_OFFSET];
+    let mut left = [max12bit; 2 * MAX_TX_SIZE + DR_EDGE_OFFSET];
+
+    let mut o = vec![0u16; 32 * 32];
+
+    for &nominal in [45, 67, 90, 113, 135, 157, 180, 203].iter() {
+      for delta in -3..4 {
+        let angle = (nominal + delta * 3) as usize;
+
+        Block4x4::pred_directional(
+          &mut o, 32, &above, &left, angle, false, false
+        );
+
+        for l in o.chunks(32).take(4) {
+          for v in l[..4].iter() {
+            assert_eq!(*v, max12bit);
+          }
+        }
+      }
+    }
+
+    filter_intra_edge(&mut above, 9, 3);
+    upsample_intra_edge(&mut left, 4, 12);
+
+    for v in above.iter().chain(left.iter()) {
+      assert_eq!(*v, max12bit);
+    }
+  }
+
+  #[test]
+  fn pred_cfl() {
+    let mut o = vec![128u16; 32 * 32];
+    let mut ac = [0i16; 4 * 4];
+    for (i, v) in ac.iter_mut().enumerate() {
+      *v = if i % 2 == 0 { 8 * 16 } else { -8 * 16 };
+    }
+
+    // A scaling of 3/8 moves the prediction by 6 in both directions
+    Block4x4::pred_cfl(&mut o, 32, &ac, 3, 8);
+
+    for l in o.chunks(32).take(4) {
+      assert_eq!(&l[..4], &[134, 122, 134, 122]);
+    }
+
+    // The prediction is clipped to the range of the bit depth
+    let mut o = vec![128u16; 32 * 32];
+    for (i, v) in ac.iter_mut().enumerate() {
+      *v = if i % 2 == 0 { 8 * 255 } else { -8 * 255 };
+    }
+
+    Block4x4::pred_cfl(&mut o, 32, &ac, -16, 8);
+
+    for l in o.chunks(32).take(4) {
+      assert_eq!(&l[..4], &[0, 255, 0, 255]);
+    }
+  }
+
+  #[test]
+  fn pred_filter_max() {
+    let max12bit = 4096 - 1;
+    let above = [max12bit; 32];
+    let left = [max12bit; 32];
+
+    let mut o = vec![0u16; 32 * 32];
+
+    for &mode in RAV1E_FILTER_INTRA_MODES {
+      Block8x8::pred_filter(
+        &mut o, 32, &above[..8], &left[..8], max12bit, mode, 12
+      );
+
+      for l in o.chunks(32).take(8) {
+        for v in l[..8].iter() {
+          assert_eq!(*v, max12bit);
+        }
+      }
+    }
+  }
 }
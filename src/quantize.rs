@@ -17,11 +17,8 @@ extern {
 }
 
 fn get_tx_scale(tx_size: TxSize) -> u8 {
-  match tx_size {
-    TxSize::TX_64X64 => 4,
-    TxSize::TX_32X32 => 2,
-    _ => 1
-  }
+  let pels = tx_size.area();
+  1 << ((pels > 256) as u8 + (pels > 1024) as u8)
 }
 
 pub fn dc_q(qindex: usize) -> i16 {
@@ -11,24 +11,43 @@
 #![allow(non_camel_case_types)]
 #![cfg_attr(feature = "cargo-clippy", allow(cast_lossless))]
 
+use compound::*;
 use context::*;
+use dist::*;
 use ec::OD_BITRES;
-use ec::Writer;
+use ec::{Writer, WriterCounter, WriterRecorder};
 use encode_block;
+use filter_intra_allowed;
+use inter_mv;
+use intrabc::*;
+use compound_mvs;
+use luma_ac;
+use masked_compound_allowed;
+use max_motion_mode;
+use mc::*;
+use palette::*;
+use palette_allowed;
 use partition::*;
 use plane::*;
-use predict::{RAV1E_INTRA_MODES, RAV1E_INTRA_MODES_MINIMAL, RAV1E_INTER_MODES};
+use predict::{
+  RAV1E_ANGLE_DELTAS, RAV1E_FILTER_INTRA_MODES, RAV1E_INTRA_MODES,
+  RAV1E_INTERINTRA_MODES, RAV1E_INTRA_MODES_MINIMAL, RAV1E_INTER_MODES
+};
 use quantize::dc_q;
+use skip_mode_allowed;
 use std;
+use std::cmp;
 use std::f64;
+use std::rc::Rc;
 use std::vec::Vec;
+use tx_size_coded;
+use util::*;
 use write_tx_blocks;
 use write_tx_tree;
 use BlockSize;
 use FrameInvariants;
 use FrameState;
 use FrameType;
-use Tune;
 use Sequence;
 
 #[derive(Clone)]
@@ -44,64 +63,119 @@ pub struct RDOPartitionOutput {
   pub bo: BlockOffset,
   pub pred_mode_luma: PredictionMode,
   pub pred_mode_chroma: PredictionMode,
-  pub skip: bool
+  pub angle_delta: AngleDelta,
+  pub cfl_params: CFLParams,
+  pub filter_intra_mode: Option<FilterIntraMode>,
+  pub palette_params: PaletteParams,
+  pub intrabc: Option<MotionVector>,
+  pub motion_mode: MotionMode,
+  pub interintra: Option<InterIntraParams>,
+  pub compound_mask: Option<CompoundMask>,
+  pub tx_tree: TxTree,
+  pub skip: bool,
+  // State left by coding the block with these modes, while it is still the
+  // one the block would be coded from
+  pub coded: Option<Rc<CodedBlock>>
 }
 
-#[allow(unused)]
-fn cdef_dist_wxh_8x8(src1: &PlaneSlice, src2: &PlaneSlice) -> u64 {
-  //TODO: Handle high bit-depth here by setting coeff_shift
-  let coeff_shift = 0;
-  let mut sum_s: i32 = 0;
-  let mut sum_d: i32 = 0;
-  let mut sum_s2: i64 = 0;
-  let mut sum_d2: i64 = 0;
-  let mut sum_sd: i64 = 0;
-  for j in 0..8 {
-    for i in 0..8 {
-      let s = src1.p(i, j) as i32;
-      let d = src2.p(i, j) as i32;
-      sum_s += s;
-      sum_d += d;
-      sum_s2 += (s * s) as i64;
-      sum_d2 += (d * d) as i64;
-      sum_sd += (s * d) as i64;
+/// What coding a block leaves behind: the symbols written, along with the
+/// contexts, block information and reconstructed pixels. Restoring it saves
+/// coding the block again, as long as only the partition CDFs changed since.
+pub struct CodedBlock {
+  pub symbols: WriterRecorder,
+  bo: BlockOffset,
+  bsize: BlockSize,
+  cw: ContextWriterCheckpoint,
+  blocks: Vec<Block>,
+  rec: Vec<Vec<u16>>
+}
+
+// Area of plane `p` the block is reconstructed into. Chroma blocks cover at
+// least 8x8 luma pixels, and are coded with the last block inside them.
+fn reconstructed_area(
+  fs: &FrameState, p: usize, bo: &BlockOffset, bsize: BlockSize
+) -> (PlaneOffset, usize, usize) {
+  let PlaneConfig { xdec, ydec, .. } = fs.rec.planes[p].cfg;
+  let x = bo.x & !((1 << xdec) - 1);
+  let y = bo.y & !((1 << ydec) - 1);
+  let w = cmp::max(bsize.width_mi(), 1 << xdec);
+  let h = cmp::max(bsize.height_mi(), 1 << ydec);
+  (
+    PlaneOffset { x: x << MI_SIZE_LOG2 >> xdec, y: y << MI_SIZE_LOG2 >> ydec },
+    w << MI_SIZE_LOG2 >> xdec,
+    h << MI_SIZE_LOG2 >> ydec
+  )
+}
+
+impl CodedBlock {
+  fn new(
+    cw: &mut ContextWriter, bo: &BlockOffset, bsize: BlockSize
+  ) -> CodedBlock {
+    CodedBlock {
+      symbols: WriterRecorder::new(),
+      bo: bo.clone(),
+      bsize,
+      cw: cw.checkpoint(),
+      blocks: Vec::new(),
+      rec: vec![Vec::new(); 3]
     }
   }
-  let svar = (sum_s2 - ((sum_s as i64 * sum_s as i64 + 32) >> 6)) as f64;
-  let dvar = (sum_d2 - ((sum_d as i64 * sum_d as i64 + 32) >> 6)) as f64;
-  let sse = (sum_d2 + sum_s2 - 2 * sum_sd) as f64;
-  //The two constants were tuned for CDEF, but can probably be better tuned for use in general RDO
-  let ssim_boost = 0.5_f64 * (svar + dvar + (400 << 2 * coeff_shift) as f64)
-    / f64::sqrt((20000 << 4 * coeff_shift) as f64 + svar * dvar);
-  (sse * ssim_boost + 0.5_f64) as u64
-}
 
-#[allow(unused)]
-fn cdef_dist_wxh(
-  src1: &PlaneSlice, src2: &PlaneSlice, w: usize, h: usize
-) -> u64 {
-  let mut sum: u64 = 0;
-  for j in 0..h / 8 {
-    for i in 0..w / 8 {
-      sum += cdef_dist_wxh_8x8(
-        &src1.subslice(i * 8, j * 8),
-        &src2.subslice(i * 8, j * 8)
-      )
+  // Saves the state left by coding the block, overwriting the previous one
+  // without allocating again
+  fn save(
+    &mut self, fs: &FrameState, cw: &ContextWriter, symbols: &WriterRecorder
+  ) {
+    self.symbols.clone_from(symbols);
+    cw.checkpoint_into(&mut self.cw);
+    cw.bc.blocks_at(&self.bo, self.bsize, &mut self.blocks);
+    for p in 0..3 {
+      let (po, w, h) = reconstructed_area(fs, p, &self.bo, self.bsize);
+      let plane = &fs.rec.planes[p];
+      let stride = plane.cfg.stride;
+      let pixels = &mut self.rec[p];
+      pixels.clear();
+      for y in po.y..po.y + h {
+        pixels.extend_from_slice(&plane.data[y * stride + po.x..][..w]);
+      }
     }
   }
-  sum
-}
 
-// Sum of Squared Error for a wxh block
-fn sse_wxh(src1: &PlaneSlice, src2: &PlaneSlice, w: usize, h: usize) -> u64 {
-  let mut sse: u64 = 0;
-  for j in 0..h {
-    for i in 0..w {
-      let dist = (src1.p(i, j) as i16 - src2.p(i, j) as i16) as i64;
-      sse += (dist * dist) as u64;
+  /// Restore the state left by coding the block, whose symbols are then to
+  /// be replayed
+  pub fn restore(&self, fs: &mut FrameState, cw: &mut ContextWriter) {
+    cw.rollback_block(&self.cw);
+    cw.bc.set_blocks(&self.bo, self.bsize, &self.blocks);
+    for p in 0..3 {
+      let (po, w, h) = reconstructed_area(fs, p, &self.bo, self.bsize);
+      let plane = &mut fs.rec.planes[p];
+      let stride = plane.cfg.stride;
+      for (y, row) in (po.y..po.y + h).zip(self.rec[p].chunks(w)) {
+        plane.data[y * stride + po.x..][..w].copy_from_slice(row);
+      }
     }
   }
-  sse
+}
+
+// Dimensions of the chroma area coded along with a luma block, which are zero
+// for sub-8x8 blocks whose chroma is coded with a later block
+fn chroma_dimensions(
+  bo: &BlockOffset, bsize: BlockSize, xdec: usize, ydec: usize
+) -> (usize, usize) {
+  if has_chroma(bo, bsize, xdec, ydec) {
+    let plane_bsize = get_plane_block_size(bsize, xdec, ydec);
+    (plane_bsize.width(), plane_bsize.height())
+  } else {
+    (0, 0)
+  }
+}
+
+// Writer estimating the rate of the RDO candidates, adapting the CDFs as the
+// frame does
+fn rate_estimator(fi: &FrameInvariants) -> WriterCounter {
+  let mut wr = WriterCounter::new();
+  wr.set_update_cdfs(!fi.disable_cdf_update);
+  wr
 }
 
 // Compute the rate-distortion cost for an encode
@@ -117,27 +191,20 @@ fn compute_rd_cost(
 
   // Lambda formula from doc/theoretical_results.lyx in the daala repo
   // Use Q0 quantizer since lambda will be applied to Q0 pixel domain
-  let lambda = q0 * q0 * std::f64::consts::LN_2 / 6.0;
+  // Scaled for the distortion metric of the tuning
+  let lambda = q0 * q0 * std::f64::consts::LN_2 / 6.0
+    * lambda_scale(fi.config.tune);
 
   // Compute distortion
   let po = bo.plane_offset(&fs.input.planes[0].cfg);
-  let mut distortion = if fi.config.tune == Tune::Psnr {
-    sse_wxh(
-      &fs.input.planes[0].slice(&po),
-      &fs.rec.planes[0].slice(&po),
-      w_y,
-      h_y
-    )
-  } else if fi.config.tune == Tune::Psychovisual {
-    cdef_dist_wxh(
-      &fs.input.planes[0].slice(&po),
-      &fs.rec.planes[0].slice(&po),
-      w_y,
-      h_y
-    )
-  } else {
-    unimplemented!();
-  };
+  let mut distortion = distortion_wxh(
+    fi.config.tune,
+    0,
+    &fs.input.planes[0].slice(&po),
+    &fs.rec.planes[0].slice(&po),
+    w_y,
+    h_y
+  );
 
   // Add chroma distortion only when it is available
   if w_uv > 0 && h_uv > 0 {
@@ -148,7 +215,9 @@ fn compute_rd_cost(
         y: sb_offset.y + partition_start_y
       };
 
-      distortion += sse_wxh(
+      distortion += distortion_wxh(
+        fi.config.tune,
+        p,
         &fs.input.planes[p].slice(&po),
         &fs.rec.planes[p].slice(&po),
         w_uv,
@@ -163,16 +232,17 @@ fn compute_rd_cost(
   (distortion as f64) + lambda * rate
 }
 
-// RDO-based mode decision
-pub fn rdo_mode_decision(
+// Code a block with the given prediction modes and return the RD cost
+fn rdo_encode_block(
   seq: &Sequence, fi: &FrameInvariants, fs: &mut FrameState,
-  cw: &mut ContextWriter, wr: &mut Writer,
-  bsize: BlockSize, bo: &BlockOffset, cdef_index: u8
-) -> RDOOutput {
-  let mut best_mode_luma = PredictionMode::DC_PRED;
-  let mut best_mode_chroma = PredictionMode::DC_PRED;
-  let mut best_skip = false;
-  let mut best_rd = std::f64::MAX;
+  cw: &mut ContextWriter, wr: &mut Writer, luma_mode: PredictionMode,
+  chroma_mode: PredictionMode, angle_delta: AngleDelta, cfl: CFLParams,
+  filter_intra: Option<FilterIntraMode>, palette: PaletteParams,
+  intrabc: Option<MotionVector>, motion_mode: MotionMode,
+  interintra: Option<InterIntraParams>, compound_mask: Option<CompoundMask>,
+  tx_tree: &TxTree, bsize: BlockSize, bo: &BlockOffset, skip: bool,
+  cdef_index: u8
+) -> f64 {
   let tell = wr.tell_frac();
 
   // Get block luma and chroma dimensions
@@ -181,26 +251,299 @@ pub fn rdo_mode_decision(
 
   let PlaneConfig { xdec, ydec, .. } = fs.input.planes[1].cfg;
 
-  let mut w_uv = w >> xdec;
-  let mut h_uv = h >> ydec;
+  let (w_uv, h_uv) = chroma_dimensions(bo, bsize, xdec, ydec);
 
-  let is_chroma_block = has_chroma(bo, bsize, xdec, ydec);
+  let partition_start_x = (bo.x & LOCAL_BLOCK_MASK) >> xdec << MI_SIZE_LOG2;
+  let partition_start_y = (bo.y & LOCAL_BLOCK_MASK) >> ydec << MI_SIZE_LOG2;
+
+  encode_block(
+    seq, fi, fs, cw, wr, luma_mode, chroma_mode, angle_delta, cfl,
+    filter_intra, palette, intrabc, motion_mode, interintra, compound_mask,
+    tx_tree, bsize, bo, skip, cdef_index
+  );
+
+  let cost = wr.tell_frac() - tell;
+  compute_rd_cost(
+    fi,
+    fs,
+    w,
+    h,
+    w_uv,
+    h_uv,
+    partition_start_x,
+    partition_start_y,
+    bo,
+    cost
+  )
+}
+
+// Pixels of the luma (plane 0) or chroma (plane 1) of the block at `bo` inside
+// the frame, for the palette search. Chroma pixels are U and V pairs.
+fn palette_samples(
+  fs: &FrameState, bo: &BlockOffset, bsize: BlockSize, plane: usize
+) -> Vec<u16> {
+  let cfg = &fs.input.planes[plane].cfg;
+  let (po, w, h) = color_map_area(bo, bsize, cfg);
+  let w = cmp::min(w, cfg.width - po.x);
+  let h = cmp::min(h, cfg.height - po.y);
+  let planes: &[usize] = if plane == 0 { &[0] } else { &[1, 2] };
+
+  let mut samples = Vec::with_capacity(w * h * planes.len());
+  for y in 0..h {
+    for x in 0..w {
+      for &p in planes {
+        samples.push(fs.input.planes[p].p(po.x + x, po.y + y));
+      }
+    }
+  }
+  samples
+}
 
-  if (w_uv == 0 || h_uv == 0) && is_chroma_block {
-    w_uv = 4;
-    h_uv = 4;
+// The intra modes of `modes` whose predictions of the luma of the block at
+// `bo`, or of its chroma, are the `n` closest to the input by SATD, closest
+// first, after the other modes. The blocks are predicted in their largest
+// transform size from the reconstruction around them, without edge filter,
+// and the predictions are left in the reconstruction.
+fn screen_intra_modes(
+  fs: &mut FrameState, bo: &BlockOffset, bsize: BlockSize, chroma: bool,
+  modes: &[PredictionMode], n: usize
+) -> Vec<PredictionMode> {
+  assert!(n > 0);
+  let PlaneConfig { xdec, ydec, .. } = fs.input.planes[1].cfg;
+  let (planes, po, tx_size, (w, h)) = if chroma {
+    let partition_start_x = (bo.x & LOCAL_BLOCK_MASK) >> xdec << MI_SIZE_LOG2;
+    let partition_start_y = (bo.y & LOCAL_BLOCK_MASK) >> ydec << MI_SIZE_LOG2;
+    let sb_offset = bo.sb_offset().plane_offset(&fs.input.planes[1].cfg);
+    let po = PlaneOffset {
+      x: sb_offset.x + partition_start_x,
+      y: sb_offset.y + partition_start_y
+    };
+    let tx_size = bsize.largest_uv_tx_size(xdec, ydec);
+    (1..3, po, tx_size, chroma_dimensions(bo, bsize, xdec, ydec))
+  } else {
+    let po = bo.plane_offset(&fs.input.planes[0].cfg);
+    (0..1, po, bsize.largest_tx_size(), (bsize.width(), bsize.height()))
+  };
+
+  let mut candidates: Vec<(u64, PredictionMode)> = Vec::new();
+  for &mode in modes.iter().filter(|mode| mode.is_intra()) {
+    let mut satd = 0;
+    for p in planes.clone() {
+      for y in 0..h / tx_size.height() {
+        for x in 0..w / tx_size.width() {
+          let tx_po = PlaneOffset {
+            x: po.x + x * tx_size.width(),
+            y: po.y + y * tx_size.height()
+          };
+          mode.predict_intra(
+            &mut fs.rec.planes[p].mut_slice(&tx_po), tx_size, 0,
+            IntraEdgeFilter::Disabled, false, false, &[], 0, None
+          );
+        }
+      }
+      satd += satd_wxh(
+        &fs.input.planes[p].slice(&po),
+        &fs.rec.planes[p].slice(&po),
+        w,
+        h
+      );
+    }
+    candidates.push((satd, mode));
   }
+  candidates.sort_by_key(|&(satd, _)| satd);
+
+  modes
+    .iter()
+    .cloned()
+    .filter(|mode| !mode.is_intra())
+    .chain(candidates.into_iter().take(n).map(|(_, mode)| mode))
+    .collect()
+}
+
+// Chroma-from-luma scaling factors minimizing the chroma distortion of a
+// block whose luma is reconstructed, if any of them is non-zero
+fn rdo_cfl_alpha(
+  fs: &mut FrameState, bo: &BlockOffset, bsize: BlockSize
+) -> Option<CFLParams> {
+  let PlaneConfig { xdec, ydec, .. } = fs.input.planes[1].cfg;
+  let uv_tx_size = bsize.largest_uv_tx_size(xdec, ydec);
 
   let partition_start_x = (bo.x & LOCAL_BLOCK_MASK) >> xdec << MI_SIZE_LOG2;
   let partition_start_y = (bo.y & LOCAL_BLOCK_MASK) >> ydec << MI_SIZE_LOG2;
+  let sb_offset = bo.sb_offset().plane_offset(&fs.input.planes[1].cfg);
+  let po = PlaneOffset {
+    x: sb_offset.x + partition_start_x,
+    y: sb_offset.y + partition_start_y
+  };
+
+  let mut ac: AlignedArray<[i16; 32 * 32]> = UninitializedAlignedArray();
+  luma_ac(&mut ac.array, fs, &po, uv_tx_size, xdec, ydec);
+
+  let mut alpha = [0i16; 2];
+
+  for p in 1..3 {
+    let mut best_sse = std::u64::MAX;
+
+    // Zero first, so that it is kept unless a scaling is strictly better
+    let alphas = std::iter::once(0)
+      .chain(-CFL_ALPHA_MAX..0)
+      .chain(1..CFL_ALPHA_MAX + 1);
+    for a in alphas {
+      let rec = &mut fs.rec.planes[p];
+      PredictionMode::UV_CFL_PRED.predict_intra(
+        &mut rec.mut_slice(&po), uv_tx_size, 0, IntraEdgeFilter::Disabled,
+        false, false, &ac.array, a, None
+      );
+
+      let sse = sse_wxh(
+        &fs.input.planes[p].slice(&po),
+        &fs.rec.planes[p].slice(&po),
+        uv_tx_size.width(),
+        uv_tx_size.height()
+      );
+
+      if sse < best_sse {
+        best_sse = sse;
+        alpha[p - 1] = a;
+      }
+    }
+  }
+
+  if alpha == [0, 0] {
+    None
+  } else {
+    Some(CFLParams { alpha })
+  }
+}
+
+// Wedge of the inter-intra `mode` whose blend of the luma inter and intra
+// predictions of the block at `bo` is closest to the input. The predictions
+// are left in the reconstruction.
+fn rdo_interintra_wedge(
+  fi: &FrameInvariants, fs: &mut FrameState, luma_mode: PredictionMode,
+  mode: InterIntraMode, bsize: BlockSize, bo: &BlockOffset, mv: MotionVector
+) -> usize {
+  let (w, h) = (bsize.width(), bsize.height());
+  let input = &fs.input.planes[0];
+  let rec = &mut fs.rec.planes[0];
+  let po = bo.plane_offset(&input.cfg);
+  let stride = rec.cfg.stride;
+  let block_pixels = |plane: &Plane| {
+    let mut pixels = Vec::with_capacity(w * h);
+    for y in 0..h {
+      let row = (po.y + y) * stride + po.x;
+      pixels.extend_from_slice(&plane.data[row..row + w]);
+    }
+    pixels
+  };
+
+  luma_mode.predict_inter(fi, 0, &po, rec, w, h, mv, None);
+  let inter = block_pixels(rec);
+  mode.intra_mode().predict_intra(
+    &mut rec.mut_slice(&po), bsize.largest_tx_size(), 0,
+    IntraEdgeFilter::Disabled, false, false, &[], 0, None
+  );
+  let intra = block_pixels(rec);
+
+  let w_in = cmp::min(w, input.cfg.width - po.x);
+  let h_in = cmp::min(h, input.cfg.height - po.y);
+  (0..MAX_WEDGE_TYPES)
+    .min_by_key(|&index| {
+      let mask = wedge_mask(bsize, index, false);
+      let mut sse = 0u64;
+      for y in 0..h_in {
+        for x in 0..w_in {
+          let i = y * w + x;
+          let m = mask[i] as i32;
+          let blend =
+            (m * intra[i] as i32 + (64 - m) * inter[i] as i32 + 32) >> 6;
+          let d = input.p(po.x + x, po.y + y) as i32 - blend;
+          sse += (d * d) as u64;
+        }
+      }
+      sse
+    }).unwrap()
+}
+
+// Wedge and sign of the compound block at `bo` whose blend of the luma
+// predictions from the skip mode references, with the motion vectors `mvs`,
+// is closest to the input. There is none without both references.
+fn rdo_compound_wedge(
+  fi: &FrameInvariants, fs: &FrameState, bsize: BlockSize, bo: &BlockOffset,
+  mvs: [MotionVector; 2]
+) -> Option<CompoundMask> {
+  let (w, h) = (bsize.width(), bsize.height());
+  let input = &fs.input.planes[0];
+  let po = bo.plane_offset(&input.cfg);
+  let reference = |i: usize| {
+    let idx = fi.ref_frames[fi.skip_mode_frames[i] - LAST_FRAME];
+    fi.rec_buffer.frames[idx].as_ref().map(|frame| &frame.planes[0])
+  };
+  let refs = [reference(0)?, reference(1)?];
+  let preds = compound_predictions(&po, w, h, refs, mvs, fi.width, fi.height);
+
+  let w_in = cmp::min(w, input.cfg.width - po.x);
+  let h_in = cmp::min(h, input.cfg.height - po.y);
+  let shift = 6 + COMPOUND_POST_ROUND;
+  let (index, sign) = (0..MAX_WEDGE_TYPES)
+    .flat_map(|index| [false, true].iter().map(move |&sign| (index, sign)))
+    .min_by_key(|&(index, sign)| {
+      let mask = wedge_mask(bsize, index, sign);
+      let mut sse = 0u64;
+      for y in 0..h_in {
+        for x in 0..w_in {
+          let i = y * w + x;
+          let m = mask[i] as i32;
+          let blend = (m * preds[0][i] + (64 - m) * preds[1][i]
+            + (1 << shift >> 1))
+            >> shift;
+          let d = input.p(po.x + x, po.y + y) as i32 - blend;
+          sse += (d * d) as u64;
+        }
+      }
+      sse
+    }).unwrap();
+  Some(CompoundMask::Wedge { index, sign })
+}
+
+// RDO-based mode decision
+pub fn rdo_mode_decision(
+  seq: &Sequence, fi: &FrameInvariants, fs: &mut FrameState,
+  cw: &mut ContextWriter,
+  bsize: BlockSize, bo: &BlockOffset, cdef_index: u8
+) -> RDOOutput {
+  let mut recorder = WriterRecorder::new();
+  recorder.set_update_cdfs(!fi.disable_cdf_update);
+  let wr = &mut recorder;
+  let mut best_mode_luma = PredictionMode::DC_PRED;
+  let mut best_mode_chroma = PredictionMode::DC_PRED;
+  let mut best_angle_delta = AngleDelta::default();
+  let mut best_cfl = CFLParams::default();
+  let mut best_filter_intra = None;
+  let mut best_palette = PaletteParams::default();
+  let mut best_intrabc = None;
+  let mut best_motion_mode = MotionMode::SIMPLE_TRANSLATION;
+  let mut best_interintra = None;
+  let mut best_compound_mask = None;
+  let mut best_skip = false;
+  let mut best_rd = std::f64::MAX;
+  let mut best_coded = CodedBlock::new(cw, bo, bsize);
+
+  let PlaneConfig { xdec, ydec, .. } = fs.input.planes[1].cfg;
+
+  let is_chroma_block = has_chroma(bo, bsize, xdec, ydec);
 
   let skip = false;
 
+  // The modes are all tried with the largest transforms
+  let max_tx_tree = TxTree::uniform(bo, bsize, bsize.largest_tx_size());
+
   let cw_checkpoint = cw.checkpoint();
   let w_checkpoint = wr.checkpoint();
 
   // Exclude complex prediction modes at higher speed levels
-  let mode_set = if fi.config.speed <= 3 {
+  let complex_modes = fi.complex_prediction_modes();
+  let mode_set = if complex_modes {
     (if fi.frame_type == FrameType::INTER { RAV1E_INTER_MODES }
       else { RAV1E_INTRA_MODES })
   } else {
@@ -208,59 +551,448 @@ pub fn rdo_mode_decision(
     else { RAV1E_INTRA_MODES_MINIMAL })
   };
 
-  for &luma_mode in mode_set {
+  let gm_mv = global_mv(
+    &fi.gm_params[LAST_FRAME],
+    fi.globalmv_transformation_type[LAST_FRAME],
+    bo,
+    bsize,
+    fi.allow_high_precision_mv,
+    fi.force_integer_mv != 0
+  );
+  let mv_stack = if fi.frame_type == FrameType::INTER {
+    cw.bc.find_mv_stack(bo, bsize, LAST_FRAME, gm_mv).0
+  } else {
+    Vec::new()
+  };
+
+  // Only the intra modes predicting the block best are fully coded, at higher
+  // speed levels. The chroma modes are screened independently of the luma.
+  let search_chroma_modes = is_chroma_block
+    && complex_modes
+    && mode_set.iter().any(|mode| mode.is_intra());
+  let (mode_set, chroma_mode_set) =
+    match fi.config.speed_settings.rdo_mode_candidates {
+      Some(n) => (
+        screen_intra_modes(fs, bo, bsize, false, mode_set, n),
+        if search_chroma_modes {
+          screen_intra_modes(fs, bo, bsize, true, RAV1E_INTRA_MODES, n)
+        } else {
+          Vec::new()
+        }
+      ),
+      None => (mode_set.to_vec(), RAV1E_INTRA_MODES.to_vec())
+    };
+
+  for &luma_mode in &mode_set {
     assert!(fi.frame_type == FrameType::INTER || luma_mode.is_intra());
 
-    if is_chroma_block && fi.config.speed <= 3 && luma_mode.is_intra() {
-      // Find the best chroma prediction mode for the current luma prediction mode
-      for &chroma_mode in RAV1E_INTRA_MODES {
-        encode_block(seq, fi, fs, cw, wr, luma_mode, chroma_mode, bsize, bo, skip, cdef_index);
-
-        let cost = wr.tell_frac() - tell;
-        let rd = compute_rd_cost(
-          fi,
-          fs,
-          w,
-          h,
-          w_uv,
-          h_uv,
-          partition_start_x,
-          partition_start_y,
-          bo,
-          cost
+    // Without enough candidates, NEARMV repeats the motion vector of NEARESTMV
+    if luma_mode == PredictionMode::NEARMV
+      && inter_mv(luma_mode, &mv_stack, gm_mv)
+        == inter_mv(PredictionMode::NEARESTMV, &mv_stack, gm_mv)
+    {
+      continue;
+    }
+
+    // Find the best chroma prediction mode for the current luma prediction mode
+    let search_chroma = search_chroma_modes && luma_mode.is_intra();
+    let same_mode = [luma_mode];
+    let chroma_modes: &[PredictionMode] =
+      if search_chroma { &chroma_mode_set } else { &same_mode };
+
+    for &chroma_mode in chroma_modes {
+      let angle_delta = AngleDelta::default();
+      let cfl = CFLParams::default();
+      let rd = rdo_encode_block(
+        seq, fi, fs, cw, wr, luma_mode, chroma_mode, angle_delta, cfl, None,
+        PaletteParams::default(), None, MotionMode::SIMPLE_TRANSLATION, None,
+        None, &max_tx_tree, bsize, bo, skip, cdef_index
+      );
+
+      if rd < best_rd {
+        best_coded.save(fs, cw, wr);
+        best_rd = rd;
+        best_mode_luma = luma_mode;
+        best_mode_chroma = chroma_mode;
+        best_angle_delta = angle_delta;
+        best_cfl = cfl;
+        best_skip = skip;
+      }
+
+      cw.rollback(&cw_checkpoint);
+      wr.rollback(&w_checkpoint);
+    }
+
+    // The luma of the block is now reconstructed with the current luma mode,
+    // from which the chroma-from-luma scaling factors are derived
+    if search_chroma && bsize.cfl_allowed() {
+      if let Some(cfl) = rdo_cfl_alpha(fs, bo, bsize) {
+        let chroma_mode = PredictionMode::UV_CFL_PRED;
+        let angle_delta = AngleDelta::default();
+        let rd = rdo_encode_block(
+          seq, fi, fs, cw, wr, luma_mode, chroma_mode, angle_delta, cfl, None,
+          PaletteParams::default(), None, MotionMode::SIMPLE_TRANSLATION,
+          None, None, &max_tx_tree, bsize, bo, skip, cdef_index
         );
 
         if rd < best_rd {
+          best_coded.save(fs, cw, wr);
           best_rd = rd;
           best_mode_luma = luma_mode;
           best_mode_chroma = chroma_mode;
+          best_angle_delta = angle_delta;
+          best_cfl = cfl;
           best_skip = skip;
         }
 
         cw.rollback(&cw_checkpoint);
         wr.rollback(&w_checkpoint);
       }
-    } else {
-      encode_block(seq, fi, fs, cw, wr, luma_mode, luma_mode, bsize, bo, skip, cdef_index);
+    }
+  }
+
+  let mv = if best_mode_luma.is_intra() {
+    gm_mv
+  } else {
+    inter_mv(best_mode_luma, &mv_stack, gm_mv)
+  };
+
+  // Try overlapping and warping the prediction of the best inter mode with the
+  // motion of the neighbours, when that motion differs from the block's
+  if complex_modes && !best_mode_luma.is_intra() {
+    let max_mode =
+      max_motion_mode(fi, &cw.bc, best_mode_luma, bsize, bo, mv, false);
+
+    for &motion_mode in &[MotionMode::OBMC_CAUSAL, MotionMode::WARPED_CAUSAL] {
+      if motion_mode > max_mode {
+        continue;
+      }
+      let changes_prediction = if motion_mode == MotionMode::OBMC_CAUSAL {
+        cw.bc.overlap_candidates(bo, bsize).iter().any(|c| c.mv != mv)
+      } else {
+        let samples = cw.bc.find_warp_samples(bo, bsize, LAST_FRAME, mv);
+        local_warp_params(&samples, bo, bsize, mv)
+          .map_or(false, |params| params[2..] != GM_IDENTITY_PARAMS[2..])
+      };
+      if !changes_prediction {
+        continue;
+      }
+
+      let rd = rdo_encode_block(
+        seq, fi, fs, cw, wr, best_mode_luma, best_mode_chroma,
+        best_angle_delta, best_cfl, None, PaletteParams::default(), None,
+        motion_mode, None, None, &max_tx_tree, bsize, bo, best_skip, cdef_index
+      );
+
+      if rd < best_rd {
+        best_coded.save(fs, cw, wr);
+        best_rd = rd;
+        best_motion_mode = motion_mode;
+      }
+
+      cw.rollback(&cw_checkpoint);
+      wr.rollback(&w_checkpoint);
+    }
+  }
+
+  // Try blending intra predictions into the best translational inter
+  // prediction, over the whole block then on one side of the wedge that best
+  // separates them
+  if complex_modes && !best_mode_luma.is_intra() &&
+    best_motion_mode == MotionMode::SIMPLE_TRANSLATION &&
+    seq.enable_interintra_compound && interintra_allowed(bsize)
+  {
+    let mut best_ii_mode = InterIntraMode::II_DC_PRED;
+    let mut best_ii_rd = std::f64::MAX;
+
+    for &mode in RAV1E_INTERINTRA_MODES {
+      let interintra = Some(InterIntraParams { mode, wedge_index: None });
+      let rd = rdo_encode_block(
+        seq, fi, fs, cw, wr, best_mode_luma, best_mode_chroma,
+        best_angle_delta, best_cfl, None, PaletteParams::default(), None,
+        best_motion_mode, interintra, None, &max_tx_tree, bsize, bo, best_skip,
+        cdef_index
+      );
+
+      if rd < best_ii_rd {
+        best_ii_rd = rd;
+        best_ii_mode = mode;
+      }
+      if rd < best_rd {
+        best_coded.save(fs, cw, wr);
+        best_rd = rd;
+        best_interintra = interintra;
+      }
+
+      cw.rollback(&cw_checkpoint);
+      wr.rollback(&w_checkpoint);
+    }
+
+    let wedge_index =
+      rdo_interintra_wedge(fi, fs, best_mode_luma, best_ii_mode, bsize, bo, mv);
+    let interintra = Some(InterIntraParams {
+      mode: best_ii_mode,
+      wedge_index: Some(wedge_index)
+    });
+    let rd = rdo_encode_block(
+      seq, fi, fs, cw, wr, best_mode_luma, best_mode_chroma, best_angle_delta,
+      best_cfl, None, PaletteParams::default(), None, best_motion_mode,
+      interintra, None, &max_tx_tree, bsize, bo, best_skip, cdef_index
+    );
+
+    if rd < best_rd {
+      best_coded.save(fs, cw, wr);
+      best_rd = rd;
+      best_interintra = interintra;
+    }
 
-      let cost = wr.tell_frac() - tell;
-      let rd = compute_rd_cost(
+    cw.rollback(&cw_checkpoint);
+    wr.rollback(&w_checkpoint);
+  }
+
+  // Try the filter intra modes in place of DC_PRED, along with the best
+  // chroma prediction
+  if complex_modes && best_mode_luma.is_intra() &&
+    filter_intra_allowed(seq, PredictionMode::DC_PRED, bsize)
+  {
+    for &mode in RAV1E_FILTER_INTRA_MODES {
+      let luma_mode = PredictionMode::DC_PRED;
+      let angle_delta = AngleDelta { y: 0, uv: best_angle_delta.uv };
+      let rd = rdo_encode_block(
+        seq, fi, fs, cw, wr, luma_mode, best_mode_chroma, angle_delta,
+        best_cfl, Some(mode), PaletteParams::default(), None,
+        MotionMode::SIMPLE_TRANSLATION, None, None, &max_tx_tree, bsize, bo,
+        skip, cdef_index
+      );
+
+      if rd < best_rd {
+        best_coded.save(fs, cw, wr);
+        best_rd = rd;
+        best_mode_luma = luma_mode;
+        best_angle_delta = angle_delta;
+        best_filter_intra = Some(mode);
+      }
+
+      cw.rollback(&cw_checkpoint);
+      wr.rollback(&w_checkpoint);
+    }
+  }
+
+  // Refine the angles of the best directional modes, which are only
+  // signaled for blocks of at least 8x8
+  if complex_modes && bsize >= BlockSize::BLOCK_8X8 {
+    let refine_luma = best_mode_luma.is_directional();
+    let refine_chroma = is_chroma_block && best_mode_chroma.is_directional();
+
+    for &(refine, is_luma) in &[(refine_luma, true), (refine_chroma, false)] {
+      if !refine {
+        continue;
+      }
+
+      let base_angle_delta = best_angle_delta;
+
+      for &delta in RAV1E_ANGLE_DELTAS {
+        let angle_delta = if is_luma {
+          AngleDelta { y: delta, uv: base_angle_delta.uv }
+        } else {
+          AngleDelta { y: base_angle_delta.y, uv: delta }
+        };
+        let rd = rdo_encode_block(
+          seq, fi, fs, cw, wr, best_mode_luma, best_mode_chroma, angle_delta,
+          best_cfl, best_filter_intra, PaletteParams::default(), None,
+          MotionMode::SIMPLE_TRANSLATION, None, None, &max_tx_tree, bsize,
+          bo, skip, cdef_index
+        );
+
+        if rd < best_rd {
+          best_coded.save(fs, cw, wr);
+          best_rd = rd;
+          best_angle_delta = angle_delta;
+        }
+
+        cw.rollback(&cw_checkpoint);
+        wr.rollback(&w_checkpoint);
+      }
+    }
+  }
+
+  // Try palettes of the colors of the block, in place of DC_PRED for the luma
+  // then for the chroma
+  if complex_modes && best_mode_luma.is_intra() &&
+    palette_allowed(fi, bsize)
+  {
+    for colors in palette_candidates(&palette_samples(fs, bo, bsize, 0), 1) {
+      let mut palette = PaletteParams::default();
+      palette.size[0] = colors.len();
+      palette.colors[0][..colors.len()].copy_from_slice(&colors);
+
+      let luma_mode = PredictionMode::DC_PRED;
+      let angle_delta = AngleDelta { y: 0, uv: best_angle_delta.uv };
+      let rd = rdo_encode_block(
+        seq, fi, fs, cw, wr, luma_mode, best_mode_chroma, angle_delta,
+        best_cfl, None, palette, None, MotionMode::SIMPLE_TRANSLATION, None,
+        None, &max_tx_tree, bsize, bo, skip, cdef_index
+      );
+
+      if rd < best_rd {
+        best_coded.save(fs, cw, wr);
+        best_rd = rd;
+        best_mode_luma = luma_mode;
+        best_angle_delta = angle_delta;
+        best_filter_intra = None;
+        best_palette = palette;
+      }
+
+      cw.rollback(&cw_checkpoint);
+      wr.rollback(&w_checkpoint);
+    }
+
+    if is_chroma_block {
+      let luma_palette = best_palette;
+
+      for colors in palette_candidates(&palette_samples(fs, bo, bsize, 1), 2) {
+        let mut palette = luma_palette;
+        palette.size[1] = colors.len() / 2;
+        for (i, uv) in colors.chunks(2).enumerate() {
+          palette.colors[1][i] = uv[0];
+          palette.colors[2][i] = uv[1];
+        }
+
+        let chroma_mode = PredictionMode::DC_PRED;
+        let angle_delta = AngleDelta { y: best_angle_delta.y, uv: 0 };
+        let cfl = CFLParams::default();
+        let rd = rdo_encode_block(
+          seq, fi, fs, cw, wr, best_mode_luma, chroma_mode, angle_delta, cfl,
+          best_filter_intra, palette, None, MotionMode::SIMPLE_TRANSLATION,
+          None, None, &max_tx_tree, bsize, bo, skip, cdef_index
+        );
+
+        if rd < best_rd {
+          best_coded.save(fs, cw, wr);
+          best_rd = rd;
+          best_mode_chroma = chroma_mode;
+          best_angle_delta = angle_delta;
+          best_cfl = cfl;
+          best_palette = palette;
+        }
+
+        cw.rollback(&cw_checkpoint);
+        wr.rollback(&w_checkpoint);
+      }
+    }
+  }
+
+  // Try copying an identical block from the already coded part of the frame
+  if fi.allow_intrabc {
+    let ref_dv = cw.bc.ref_dv(bo, bsize);
+    let dv = fs.block_hashes.as_ref().and_then(|hashes| {
+      search_dv(
         fi,
-        fs,
-        w,
-        h,
-        w_uv,
-        h_uv,
-        partition_start_x,
-        partition_start_y,
+        seq.sb_size(),
+        hashes,
+        &fs.input.planes[0],
         bo,
-        cost
+        bsize,
+        ref_dv,
+        xdec,
+        ydec
+      )
+    });
+
+    if let Some(dv) = dv {
+      let luma_mode = PredictionMode::DC_PRED;
+      let chroma_mode = PredictionMode::DC_PRED;
+      let angle_delta = AngleDelta::default();
+      let cfl = CFLParams::default();
+      let rd = rdo_encode_block(
+        seq, fi, fs, cw, wr, luma_mode, chroma_mode, angle_delta, cfl, None,
+        PaletteParams::default(), Some(dv), MotionMode::SIMPLE_TRANSLATION,
+        None, None, &max_tx_tree, bsize, bo, skip, cdef_index
       );
 
       if rd < best_rd {
+        best_coded.save(fs, cw, wr);
+        best_rd = rd;
+        best_mode_luma = luma_mode;
+        best_mode_chroma = chroma_mode;
+        best_angle_delta = angle_delta;
+        best_cfl = cfl;
+        best_filter_intra = None;
+        best_palette = PaletteParams::default();
+        best_intrabc = Some(dv);
+      }
+
+      cw.rollback(&cw_checkpoint);
+      wr.rollback(&w_checkpoint);
+    }
+  }
+
+  // Try skip mode, whose averaged prediction from the nearest references on
+  // each side suits static content, coding no residual nor motion
+  if skip_mode_allowed(fi, bsize) {
+    let luma_mode = PredictionMode::NEAREST_NEARESTMV;
+    let angle_delta = AngleDelta::default();
+    let cfl = CFLParams::default();
+    let rd = rdo_encode_block(
+      seq, fi, fs, cw, wr, luma_mode, luma_mode, angle_delta, cfl, None,
+      PaletteParams::default(), None, MotionMode::SIMPLE_TRANSLATION, None,
+      None, &max_tx_tree, bsize, bo, true, cdef_index
+    );
+
+    if rd < best_rd {
+      best_coded.save(fs, cw, wr);
+      best_rd = rd;
+      best_mode_luma = luma_mode;
+      best_mode_chroma = luma_mode;
+      best_angle_delta = angle_delta;
+      best_cfl = cfl;
+      best_filter_intra = None;
+      best_palette = PaletteParams::default();
+      best_motion_mode = MotionMode::SIMPLE_TRANSLATION;
+      best_interintra = None;
+      best_compound_mask = None;
+      best_skip = true;
+    }
+
+    cw.rollback(&cw_checkpoint);
+    wr.rollback(&w_checkpoint);
+  }
+
+  // Try blending the predictions from the skip mode references with the
+  // masks weighting them by their difference, then with the wedge that best
+  // separates them, coding the residual
+  if complex_modes && masked_compound_allowed(seq, fi, bsize) {
+    let luma_mode = PredictionMode::NEAREST_NEARESTMV;
+    let angle_delta = AngleDelta::default();
+    let cfl = CFLParams::default();
+    let mut masks = vec![
+      CompoundMask::DiffWeighted { inverse: false },
+      CompoundMask::DiffWeighted { inverse: true },
+    ];
+    if wedge_bits(bsize) > 0 {
+      let (mvs, _) = compound_mvs(fi, &cw.bc, bo, bsize);
+      masks.extend(rdo_compound_wedge(fi, fs, bsize, bo, mvs));
+    }
+
+    for &mask in &masks {
+      let rd = rdo_encode_block(
+        seq, fi, fs, cw, wr, luma_mode, luma_mode, angle_delta, cfl, None,
+        PaletteParams::default(), None, MotionMode::SIMPLE_TRANSLATION, None,
+        Some(mask), &max_tx_tree, bsize, bo, skip, cdef_index
+      );
+
+      if rd < best_rd {
+        best_coded.save(fs, cw, wr);
         best_rd = rd;
         best_mode_luma = luma_mode;
         best_mode_chroma = luma_mode;
+        best_angle_delta = angle_delta;
+        best_cfl = cfl;
+        best_filter_intra = None;
+        best_palette = PaletteParams::default();
+        best_motion_mode = MotionMode::SIMPLE_TRANSLATION;
+        best_interintra = None;
+        best_compound_mask = Some(mask);
         best_skip = skip;
       }
 
@@ -269,6 +1001,53 @@ pub fn rdo_mode_decision(
     }
   }
 
+  // Then split the transforms of the best modes, when the block can signal
+  // it: all alike for intra blocks, node by node for inter blocks
+  let mut best_tx_tree = max_tx_tree.clone();
+  let best_is_inter = !best_mode_luma.is_intra() || best_intrabc.is_some();
+  if tx_size_coded(fi, bsize, best_is_inter, best_skip) {
+    let (cols, rows) = (cw.bc.cols, cw.bc.rows);
+    let max_tx_rd = best_rd;
+    let mut code_with = |tx_tree: &TxTree| {
+      let rd = rdo_encode_block(
+        seq, fi, fs, cw, wr, best_mode_luma, best_mode_chroma,
+        best_angle_delta, best_cfl, best_filter_intra, best_palette,
+        best_intrabc, best_motion_mode, best_interintra, best_compound_mask,
+        tx_tree, bsize, bo, best_skip, cdef_index
+      );
+
+      if rd < best_rd {
+        best_coded.save(fs, cw, wr);
+        best_rd = rd;
+      }
+
+      cw.rollback(&cw_checkpoint);
+      wr.rollback(&w_checkpoint);
+      rd
+    };
+
+    if best_is_inter {
+      let mut rd = max_tx_rd;
+      for (max_tx_bo, max_tx_size) in max_tx_tree.leaves {
+        if max_tx_bo.x < cols && max_tx_bo.y < rows {
+          rd = rdo_tx_tree(
+            &mut code_with, &mut best_tx_tree, &max_tx_bo, max_tx_size, 0, rd
+          );
+        }
+      }
+    } else {
+      let mut tx_rd = max_tx_rd;
+      for depth in 1..bsize.max_tx_depth() + 1 {
+        let tx_tree = TxTree::uniform(bo, bsize, bsize.tx_size_at_depth(depth));
+        let rd = code_with(&tx_tree);
+        if rd < tx_rd {
+          tx_rd = rd;
+          best_tx_tree = tx_tree;
+        }
+      }
+    }
+  }
+
   assert!(best_rd >= 0_f64);
 
   RDOOutput {
@@ -278,19 +1057,69 @@ pub fn rdo_mode_decision(
       bo: bo.clone(),
       pred_mode_luma: best_mode_luma,
       pred_mode_chroma: best_mode_chroma,
+      angle_delta: best_angle_delta,
+      cfl_params: best_cfl,
+      filter_intra_mode: best_filter_intra,
+      palette_params: best_palette,
+      intrabc: best_intrabc,
+      motion_mode: best_motion_mode,
+      interintra: best_interintra,
+      compound_mask: best_compound_mask,
+      tx_tree: best_tx_tree,
       rd_cost: best_rd,
-      skip: best_skip
+      skip: best_skip,
+      coded: Some(Rc::new(best_coded))
     }]
   }
 }
 
+// Decide whether to split the transform of size `tx_size` at `bo`, a leaf at
+// `depth` of the transform partitioning tree `tx_tree` of an inter block
+// coded at the cost `rd`. Its children are decided the same way in turn, and
+// the node is split if the block then costs less than with the node whole.
+// `code_with` codes the block with a tree and returns its cost. Returns the
+// cost of the block with the decided tree.
+fn rdo_tx_tree<F: FnMut(&TxTree) -> f64>(
+  code_with: &mut F, tx_tree: &mut TxTree, bo: &BlockOffset, tx_size: TxSize,
+  depth: usize, rd: f64
+) -> f64 {
+  if depth == MAX_VARTX_DEPTH || tx_size == TxSize::TX_4X4 {
+    return rd;
+  }
+
+  tx_tree.split(bo);
+  let mut split_rd = code_with(tx_tree);
+  let sub_tx_size = tx_size.split();
+  for by in 0..tx_size.height_mi() / sub_tx_size.height_mi() {
+    for bx in 0..tx_size.width_mi() / sub_tx_size.width_mi() {
+      let sub_bo = BlockOffset {
+        x: bo.x + bx * sub_tx_size.width_mi(),
+        y: bo.y + by * sub_tx_size.height_mi()
+      };
+      split_rd = rdo_tx_tree(
+        code_with, tx_tree, &sub_bo, sub_tx_size, depth + 1, split_rd
+      );
+    }
+  }
+
+  if split_rd < rd {
+    split_rd
+  } else {
+    tx_tree.merge(bo, tx_size);
+    rd
+  }
+}
+
 // RDO-based intra frame transform type decision
 pub fn rdo_tx_type_decision(
-  fi: &FrameInvariants, fs: &mut FrameState,
-  cw: &mut ContextWriter, wr: &mut Writer,
-  mode: PredictionMode, bsize: BlockSize, bo: &BlockOffset, tx_size: TxSize,
-  tx_set: TxSet
+  seq: &Sequence, fi: &FrameInvariants, fs: &mut FrameState,
+  cw: &mut ContextWriter,
+  mode: PredictionMode, angle_delta: AngleDelta,
+  filter_intra: Option<FilterIntraMode>, palette: PaletteParams,
+  intrabc: Option<MotionVector>, bsize: BlockSize, bo: &BlockOffset,
+  tx_tree: &TxTree, tx_set: TxSet
 ) -> TxType {
+  let wr: &mut Writer = &mut rate_estimator(fi);
   let mut best_type = TxType::DCT_DCT;
   let mut best_rd = std::f64::MAX;
   let tell = wr.tell_frac();
@@ -301,17 +1130,11 @@ pub fn rdo_tx_type_decision(
 
   let PlaneConfig { xdec, ydec, .. } = fs.input.planes[1].cfg;
 
-  let mut w_uv = w >> xdec;
-  let mut h_uv = h >> ydec;
-
-  if (w_uv == 0 || h_uv == 0) && has_chroma(bo, bsize, xdec, ydec) {
-    w_uv = 4;
-    h_uv = 4;
-  }
+  let (w_uv, h_uv) = chroma_dimensions(bo, bsize, xdec, ydec);
 
   let partition_start_x = (bo.x & LOCAL_BLOCK_MASK) >> xdec << MI_SIZE_LOG2;
   let partition_start_y = (bo.y & LOCAL_BLOCK_MASK) >> ydec << MI_SIZE_LOG2;
-  let is_inter = mode >= PredictionMode::NEARESTMV;
+  let is_inter = mode >= PredictionMode::NEARESTMV || intrabc.is_some();
 
   let cw_checkpoint = cw.checkpoint();
   let w_checkpoint = wr.checkpoint();
@@ -324,11 +1147,13 @@ pub fn rdo_tx_type_decision(
 
     if is_inter {
       write_tx_tree(
-        fi, fs, cw, wr, mode, mode, bo, bsize, tx_size, tx_type, false,
+        fi, fs, cw, wr, mode, mode, intrabc, bo, bsize, tx_tree, tx_type,
+        false,
       );
     }  else {
       write_tx_blocks(
-        fi, fs, cw, wr, mode, mode, bo, bsize, tx_size, tx_type, false,
+        seq, fi, fs, cw, wr, mode, mode, angle_delta, CFLParams::default(),
+        filter_intra, palette, bo, bsize, tx_tree.max_size(), tx_type, false,
       );
     }
 
@@ -363,7 +1188,7 @@ pub fn rdo_tx_type_decision(
 // RDO-based single level partitioning decision
 pub fn rdo_partition_decision(
   seq: &Sequence, fi: &FrameInvariants, fs: &mut FrameState,
-  cw: &mut ContextWriter, wr: &mut Writer,
+  cw: &mut ContextWriter,
   bsize: BlockSize, bo: &BlockOffset, cached_block: &RDOOutput, cdef_index: u8
 ) -> RDOOutput {
   let max_rd = std::f64::MAX;
@@ -373,67 +1198,73 @@ pub fn rdo_partition_decision(
   let mut best_pred_modes = cached_block.part_modes.clone();
 
   let cw_checkpoint = cw.checkpoint();
-  let w_checkpoint = wr.checkpoint();
 
-  for &partition in RAV1E_PARTITION_TYPES {
+  // Exclude complex partition shapes at higher speed levels
+  let partition_types = if fi.config.speed_settings.ext_partition_types {
+    RAV1E_PARTITION_TYPES
+  } else {
+    RAV1E_PARTITION_TYPES_MINIMAL
+  };
+
+  // Blocks coded well enough whole are not partitioned
+  let prune = fi.config.speed_settings.prune_partitions;
+  let mut pruned = prune
+    && cached_block.part_type == PartitionType::PARTITION_NONE
+    && prune_coded_split(fi, fs, cw, bsize, bo, &cached_block.part_modes[0]);
+  cw.rollback(&cw_checkpoint);
+
+  for &partition in partition_types {
     // Do not re-encode results we already have
     if partition == cached_block.part_type && cached_block.rd_cost < max_rd {
       continue;
     }
 
+    if pruned {
+      break;
+    }
+
+    if !partition_allowed(bsize, partition) {
+      continue;
+    }
+
     let mut rd: f64;
     let mut child_modes = std::vec::Vec::new();
 
     match partition {
       PartitionType::PARTITION_NONE => {
-        if bsize > BlockSize::BLOCK_32X32 {
-          continue;
-        }
-
         let mode_decision = cached_block
           .part_modes
           .get(0)
-          .unwrap_or(&rdo_mode_decision(seq, fi, fs, cw, wr, bsize, bo, cdef_index).part_modes[0])
-          .clone();
-        child_modes.push(mode_decision);
-      }
-      PartitionType::PARTITION_SPLIT => {
-        let subsize = get_subsize(bsize, partition);
-
-        if subsize == BlockSize::BLOCK_INVALID {
-          continue;
-        }
-
-        let bs = bsize.width_mi();
-        let hbs = bs >> 1; // Half the block size in blocks
-
-        let offset = BlockOffset { x: bo.x, y: bo.y };
-        let mode_decision = rdo_mode_decision(seq, fi, fs, cw, wr, subsize, &offset, cdef_index)
-          .part_modes[0]
-          .clone();
-        child_modes.push(mode_decision);
-
-        let offset = BlockOffset { x: bo.x + hbs as usize, y: bo.y };
-        let mode_decision = rdo_mode_decision(seq, fi, fs, cw, wr, subsize, &offset, cdef_index)
-          .part_modes[0]
-          .clone();
-        child_modes.push(mode_decision);
-
-        let offset = BlockOffset { x: bo.x, y: bo.y + hbs as usize };
-        let mode_decision = rdo_mode_decision(seq, fi, fs, cw, wr, subsize, &offset, cdef_index)
-          .part_modes[0]
-          .clone();
-        child_modes.push(mode_decision);
-
-        let offset =
-          BlockOffset { x: bo.x + hbs as usize, y: bo.y + hbs as usize };
-        let mode_decision = rdo_mode_decision(seq, fi, fs, cw, wr, subsize, &offset, cdef_index)
-          .part_modes[0]
-          .clone();
+          .cloned()
+          .unwrap_or_else(|| {
+            rdo_mode_decision(seq, fi, fs, cw, bsize, bo, cdef_index)
+              .part_modes
+              .swap_remove(0)
+          });
+        pruned =
+          prune && prune_coded_split(fi, fs, cw, bsize, bo, &mode_decision);
         child_modes.push(mode_decision);
       }
       _ => {
-        assert!(false);
+        // Each block is decided as coded after the ones preceding it, whose
+        // state is restored
+        for (subsize, offset) in get_partition_blocks(bsize, partition, bo) {
+          let mode_decision =
+            rdo_mode_decision(seq, fi, fs, cw, subsize, &offset, cdef_index)
+              .part_modes
+              .swap_remove(0);
+          if let Some(ref coded) = mode_decision.coded {
+            coded.restore(fs, cw);
+          }
+          child_modes.push(mode_decision);
+        }
+        // Split blocks are decided again when partitioned further, after
+        // which the state saved for the blocks following them is stale
+        if partition == PartitionType::PARTITION_SPLIT {
+          for mode_decision in child_modes.iter_mut().skip(1) {
+            mode_decision.coded = None;
+          }
+        }
       }
     }
 
@@ -446,7 +1277,6 @@ pub fn rdo_partition_decision(
     }
 
     cw.rollback(&cw_checkpoint);
-    wr.rollback(&w_checkpoint);
   }
 
   assert!(best_rd >= 0_f64);
@@ -457,3 +1287,87 @@ pub fn rdo_partition_decision(
     part_modes: best_pred_modes
   }
 }
+
+// Whether splitting the block at `bo` is pruned once coded whole with
+// `none_decision`, whose state is restored. The decisions without the state
+// left by coding them, which went stale, are never pruned.
+fn prune_coded_split(
+  fi: &FrameInvariants, fs: &mut FrameState, cw: &mut ContextWriter,
+  bsize: BlockSize, bo: &BlockOffset, none_decision: &RDOPartitionOutput
+) -> bool {
+  match none_decision.coded {
+    Some(ref coded) => {
+      coded.restore(fs, cw);
+      prune_split(fi, fs, bsize, bo, none_decision)
+    }
+    None => false
+  }
+}
+
+// Mean and variance of the luma source over `w`x`h` pixels at `po`
+fn source_variance(
+  fs: &FrameState, po: &PlaneOffset, w: usize, h: usize
+) -> (f64, f64) {
+  let src = fs.input.planes[0].slice(po);
+  let mut sum: u64 = 0;
+  let mut sum2: u64 = 0;
+  for j in 0..h {
+    for i in 0..w {
+      let s = src.p(i, j) as u64;
+      sum += s;
+      sum2 += s * s;
+    }
+  }
+  let area = (w * h) as f64;
+  let mean = sum as f64 / area;
+  (mean, sum2 as f64 / area - mean * mean)
+}
+
+// Whether splitting the block at `bo` is unlikely to pay off once it has been
+// coded whole with `none_decision`, whose reconstruction is in `fs.rec`:
+// - when it used skip mode or was reconstructed within the quantization
+//   noise, measured as SSE whatever the distortion metric RDO uses
+// - when the source block is predicted homogeneous: its quadrants have the
+//   same mean within the noise and variances within a factor of 2 of each
+//   other, so coding them apart would predict them no better
+pub fn prune_split(
+  fi: &FrameInvariants, fs: &FrameState, bsize: BlockSize, bo: &BlockOffset,
+  none_decision: &RDOPartitionOutput
+) -> bool {
+  let q0 = dc_q(fi.config.quantizer) as f64 / 8.0;
+  // Variance of the error of uniform quantization
+  let noise = q0 * q0 / 12.0;
+  let (w, h) = (bsize.width(), bsize.height());
+  let po = bo.plane_offset(&fs.input.planes[0].cfg);
+
+  if none_decision.skip {
+    return true;
+  }
+  let sse = sse_wxh(
+    &fs.input.planes[0].slice(&po),
+    &fs.rec.planes[0].slice(&po),
+    w,
+    h
+  );
+  if (sse as f64) < noise * (w * h) as f64 {
+    return true;
+  }
+
+  let offsets = [(0, 0), (w / 2, 0), (0, h / 2), (w / 2, h / 2)];
+  let quadrants: Vec<(f64, f64)> = offsets
+    .iter()
+    .map(|&(x, y)| {
+      let quadrant_po = PlaneOffset { x: po.x + x, y: po.y + y };
+      source_variance(fs, &quadrant_po, w / 2, h / 2)
+    })
+    .collect();
+  let mean = quadrants.iter().map(|&(m, _)| m).sum::<f64>() / 4.0;
+  // Part of the variance of the block between the quadrant means
+  let variance_between =
+    quadrants.iter().map(|&(m, _)| (m - mean) * (m - mean)).sum::<f64>() / 4.0;
+  let min_variance =
+    quadrants.iter().map(|&(_, v)| v).fold(f64::MAX, f64::min);
+  let max_variance = quadrants.iter().map(|&(_, v)| v).fold(0.0, f64::max);
+
+  variance_between < noise && max_variance <= 2.0 * min_variance + noise
+}
@@ -7,270 +7,1062 @@
 // Media Patent License 1.0 was not distributed with this source code in the
 // PATENTS file, you can obtain it at www.aomedia.org/license/patent.
 
-extern crate libc;
+#![cfg_attr(feature = "cargo-clippy", allow(cast_lossless))]
 
 use partition::TxSize;
 use partition::TxType;
 
-// In libaom, functions that have more than one specialization use function
-// pointers, so we need to declare them as static fields and call them
-// indirectly. Otherwise, we call SSE or C variants directly. To fully
-// understand what's going on here you should first understand the Perl code
-// in libaom that generates these function bindings.
+use std::cmp;
 
-extern {
-  fn av1_fwd_txfm2d_4x4_c(
-    input: *const i16, output: *mut i32, stride: libc::c_int,
-    tx_type: libc::c_int, bd: libc::c_int
-  );
-  fn av1_fwd_txfm2d_8x8_c(
-    input: *const i16, output: *mut i32, stride: libc::c_int,
-    tx_type: libc::c_int, bd: libc::c_int
-  );
-  fn av1_fwd_txfm2d_16x16_c(
-    input: *const i16, output: *mut i32, stride: libc::c_int,
-    tx_type: libc::c_int, bd: libc::c_int
-  );
-  fn av1_fwd_txfm2d_32x32_c(
-    input: *const i16, output: *mut i32, stride: libc::c_int,
-    tx_type: libc::c_int, bd: libc::c_int
-  );
+// The transforms below are bit-exact ports of the libaom C implementations
+// (av1_fwd_txfm2d_*_c and av1_inv_txfm2d_add_*_c), so that reconstruction
+// matches the decoder.
+
+const COS_BIT_MIN: usize = 10;
+
+const NEW_SQRT2_BITS: usize = 12;
+// 2^12 * sqrt(2)
+const NEW_SQRT2: i32 = 5793;
+// 2^12 / sqrt(2)
+const NEW_INV_SQRT2: i32 = 2896;
+
+// cospi[i] = round(cos(i * PI / 128) * 2^cos_bit), for cos_bit in 10..14
+const COSPI: [[i32; 64]; 4] = [
+  [
+    1024, 1024, 1023, 1021, 1019, 1016, 1013, 1009,
+    1004, 999, 993, 987, 980, 972, 964, 955,
+    946, 936, 926, 915, 903, 891, 878, 865,
+    851, 837, 822, 807, 792, 775, 759, 742,
+    724, 706, 688, 669, 650, 630, 610, 590,
+    569, 548, 526, 505, 483, 460, 438, 415,
+    392, 369, 345, 321, 297, 273, 249, 224,
+    200, 175, 150, 125, 100, 75, 50, 25,
+  ],
+  [
+    2048, 2047, 2046, 2042, 2038, 2033, 2026, 2018,
+    2009, 1998, 1987, 1974, 1960, 1945, 1928, 1911,
+    1892, 1872, 1851, 1829, 1806, 1782, 1757, 1730,
+    1703, 1674, 1645, 1615, 1583, 1551, 1517, 1483,
+    1448, 1412, 1375, 1338, 1299, 1260, 1220, 1179,
+    1138, 1096, 1053, 1009, 965, 921, 876, 830,
+    784, 737, 690, 642, 595, 546, 498, 449,
+    400, 350, 301, 251, 201, 151, 100, 50,
+  ],
+  [
+    4096, 4095, 4091, 4085, 4076, 4065, 4052, 4036,
+    4017, 3996, 3973, 3948, 3920, 3889, 3857, 3822,
+    3784, 3745, 3703, 3659, 3612, 3564, 3513, 3461,
+    3406, 3349, 3290, 3229, 3166, 3102, 3035, 2967,
+    2896, 2824, 2751, 2675, 2598, 2520, 2440, 2359,
+    2276, 2191, 2106, 2019, 1931, 1842, 1751, 1660,
+    1567, 1474, 1380, 1285, 1189, 1092, 995, 897,
+    799, 700, 601, 501, 401, 301, 201, 101,
+  ],
+  [
+    8192, 8190, 8182, 8170, 8153, 8130, 8103, 8071,
+    8035, 7993, 7946, 7895, 7839, 7779, 7713, 7643,
+    7568, 7489, 7405, 7317, 7225, 7128, 7027, 6921,
+    6811, 6698, 6580, 6458, 6333, 6203, 6070, 5933,
+    5793, 5649, 5501, 5351, 5197, 5040, 4880, 4717,
+    4551, 4383, 4212, 4038, 3862, 3683, 3503, 3320,
+    3135, 2948, 2760, 2570, 2378, 2185, 1990, 1795,
+    1598, 1401, 1202, 1003, 803, 603, 402, 201,
+  ],
+];
+
+// sinpi[i] = round(2^cos_bit * 2 * sqrt(2) * sin(i * PI / 9) / 3), adjusted
+// so that sinpi[1] + sinpi[2] == sinpi[4]
+const SINPI: [[i32; 5]; 4] = [
+  [0, 330, 621, 836, 951],
+  [0, 660, 1241, 1672, 1901],
+  [0, 1321, 2482, 3344, 3803],
+  [0, 2642, 4964, 6689, 7606],
+];
+
+fn cospi_arr(cos_bit: usize) -> &'static [i32; 64] {
+  &COSPI[cos_bit - COS_BIT_MIN]
 }
 
-extern "C" {
-  static av1_inv_txfm2d_add_4x4: extern fn(
-    input: *const i32,
-    output: *mut u16,
-    stride: libc::c_int,
-    tx_type: libc::c_int,
-    bd: libc::c_int
-  );
-  fn av1_inv_txfm2d_add_4x4_c(
-    input: *const i32,
-    output: *mut u16,
-    stride: libc::c_int,
-    tx_type: libc::c_int,
-    bd: libc::c_int
-  );
-  static av1_inv_txfm2d_add_8x8: extern fn(
-    input: *const i32,
-    output: *mut u16,
-    stride: libc::c_int,
-    tx_type: libc::c_int,
-    bd: libc::c_int
-  ) -> ();
-  fn av1_inv_txfm2d_add_8x8_c(
-    input: *const i32,
-    output: *mut u16,
-    stride: libc::c_int,
-    tx_type: libc::c_int,
-    bd: libc::c_int
-  ) -> ();
-  static av1_inv_txfm2d_add_16x16: extern fn(
-    input: *const i32,
-    output: *mut u16,
-    stride: libc::c_int,
-    tx_type: libc::c_int,
-    bd: libc::c_int
-  );
-  fn av1_inv_txfm2d_add_16x16_c(
-    input: *const i32, output: *mut u16, stride: libc::c_int,
-    tx_type: libc::c_int, bd: libc::c_int
-  );
-  static av1_inv_txfm2d_add_32x32: extern fn(
-    input: *const i32,
-    output: *mut u16,
-    stride: libc::c_int,
-    tx_type: libc::c_int,
-    bd: libc::c_int
-  );
-  fn av1_inv_txfm2d_add_32x32_c(
-    input: *const i32,
-    output: *mut u16,
-    stride: libc::c_int,
-    tx_type: libc::c_int,
-    bd: libc::c_int
-  );
+fn sinpi_arr(cos_bit: usize) -> &'static [i32; 5] {
+  &SINPI[cos_bit - COS_BIT_MIN]
 }
 
-pub fn forward_transform(
-  input: &[i16], output: &mut [i32], stride: usize, tx_size: TxSize,
-  tx_type: TxType
-) {
-  match tx_size {
-    TxSize::TX_4X4 => fht4x4(input, output, stride, tx_type),
-    TxSize::TX_8X8 => fht8x8(input, output, stride, tx_type),
-    TxSize::TX_16X16 => fht16x16(input, output, stride, tx_type),
-    TxSize::TX_32X32 => fht32x32(input, output, stride, tx_type),
-    _ => panic!("unimplemented tx size")
+fn round_shift(value: i64, bit: usize) -> i32 {
+  if bit == 0 {
+    value as i32
+  } else {
+    ((value + (1 << (bit - 1))) >> bit) as i32
   }
 }
 
-pub fn inverse_transform_add(
-  input: &[i32], output: &mut [u16], stride: usize, tx_size: TxSize,
-  tx_type: TxType
-) {
-  match tx_size {
-    TxSize::TX_4X4 => iht4x4_add(input, output, stride, tx_type),
-    TxSize::TX_8X8 => iht8x8_add(input, output, stride, tx_type),
-    TxSize::TX_16X16 => iht16x16_add(input, output, stride, tx_type),
-    TxSize::TX_32X32 => iht32x32_add(input, output, stride, tx_type),
-    _ => panic!("unimplemented tx size")
-  }
-}
-
-fn fht4x4(input: &[i16], output: &mut [i32], stride: usize, tx_type: TxType) {
-  unsafe {
-    av1_fwd_txfm2d_4x4_c(
-      input.as_ptr(),
-      output.as_mut_ptr(),
-      stride as libc::c_int,
-      tx_type as libc::c_int,
-      8
-    );
+fn round_shift_array(arr: &mut [i32], bit: i8) {
+  if bit > 0 {
+    for v in arr.iter_mut() {
+      *v = round_shift(*v as i64, bit as usize);
+    }
+  } else if bit < 0 {
+    for v in arr.iter_mut() {
+      *v = cmp::min(
+        cmp::max((*v as i64) << -bit, i32::min_value() as i64),
+        i32::max_value() as i64
+      ) as i32;
+    }
+  }
+}
+
+fn half_btf(w0: i32, in0: i32, w1: i32, in1: i32, bit: usize) -> i32 {
+  round_shift(w0 as i64 * in0 as i64 + w1 as i64 * in1 as i64, bit)
+}
+
+fn clamp_value(value: i32, bit: usize) -> i32 {
+  let max_value = (1i64 << (bit - 1)) - 1;
+  let min_value = -(1i64 << (bit - 1));
+  cmp::min(cmp::max(value as i64, min_value), max_value) as i32
+}
+
+fn clamp_buf(buf: &mut [i32], bit: usize) {
+  for v in buf.iter_mut() {
+    *v = clamp_value(*v, bit);
+  }
+}
+
+fn brev(num_bits: usize, x: usize) -> usize {
+  let mut reversed = 0;
+  for i in 0..num_bits {
+    reversed |= ((x >> i) & 1) << (num_bits - 1 - i);
+  }
+  reversed
+}
+
+fn log2(n: usize) -> usize {
+  n.trailing_zeros() as usize
+}
+
+// The DCT is computed recursively: the even outputs are a DCT of half the
+// size and the odd outputs go through a chain of butterflies and rotations.
+// Outputs are left in bit-reversed order. The inverse runs the same stages
+// backwards, clamping the results of each butterfly to the stage range.
+
+// Butterflies on the two quarters of an odd part group
+fn dct_odd_butterfly<F: Fn(i32) -> i32>(x: &mut [i32], f: &F) {
+  let n = x.len();
+  let h = n / 2;
+  for i in 0..h / 2 {
+    let (lo, hi) = (x[i], x[h - 1 - i]);
+    x[i] = f(lo + hi);
+    x[h - 1 - i] = f(lo - hi);
+  }
+  for i in 0..h / 2 {
+    let (lo, hi) = (x[h + i], x[n - 1 - i]);
+    x[h + i] = f(hi - lo);
+    x[n - 1 - i] = f(hi + lo);
   }
 }
 
-fn iht4x4_add(
-  input: &[i32], output: &mut [u16], stride: usize, tx_type: TxType
+// Rotations of the odd part between the pairs of the sub-blocks of size `s`.
+// Each split keeps the outer quarters together, halving the angle for them
+// and offsetting it by PI / 4 for the inner half. The rotation matrices are
+// symmetric, so the same code serves the forward and inverse transforms.
+fn dct_odd_rotation(
+  x: &mut [i32], pos: &[usize], angle: usize, s: usize, cospi: &[i32; 64],
+  bit: usize
 ) {
-  // SIMD code may assert for transform types beyond TxType::IDTX.
-  if tx_type < TxType::IDTX {
-    unsafe {
-      av1_inv_txfm2d_add_4x4(
-        input.as_ptr(),
-        output.as_mut_ptr(),
-        stride as libc::c_int,
-        tx_type as libc::c_int,
-        8
-      );
+  let n = pos.len();
+  if n > s {
+    let q = n / 4;
+    let mut outer = [0usize; 32];
+    outer[..q].copy_from_slice(&pos[..q]);
+    outer[q..2 * q].copy_from_slice(&pos[3 * q..]);
+    dct_odd_rotation(x, &outer[..2 * q], angle / 2, s, cospi, bit);
+    dct_odd_rotation(x, &pos[q..3 * q], 32 + angle / 2, s, cospi, bit);
+    return;
+  }
+
+  let (cx, cy) = (cospi[angle], cospi[64 - angle]);
+  for i in n / 8..n / 4 {
+    let (lo, hi) = (x[pos[i]], x[pos[n - 1 - i]]);
+    x[pos[i]] = half_btf(-cx, lo, cy, hi, bit);
+    x[pos[n - 1 - i]] = half_btf(cx, hi, cy, lo, bit);
+  }
+  for i in n / 4..3 * n / 8 {
+    let (lo, hi) = (x[pos[i]], x[pos[n - 1 - i]]);
+    x[pos[i]] = half_btf(-cy, lo, -cx, hi, bit);
+    x[pos[n - 1 - i]] = half_btf(cy, hi, -cx, lo, bit);
+  }
+}
+
+fn dct_odd_rotation_stage(x: &mut [i32], s: usize, cospi: &[i32; 64], bit: usize) {
+  let mut pos = [0usize; 32];
+  for (i, p) in pos.iter_mut().enumerate() {
+    *p = i;
+  }
+  let n = x.len();
+  dct_odd_rotation(x, &pos[..n], 16, s, cospi, bit);
+}
+
+// Angle of the last rotation of the odd part, giving output 2 * brev(i) + 1
+fn dct_odd_output_angle(n: usize, i: usize) -> usize {
+  32 / n + (128 / n) * brev(log2(n / 2), i)
+}
+
+fn fdct_odd(x: &mut [i32], cospi: &[i32; 64], bit: usize) {
+  let n = x.len();
+  if n >= 4 {
+    for i in n / 4..n / 2 {
+      let (lo, hi) = (x[i], x[n - 1 - i]);
+      x[i] = half_btf(-cospi[32], lo, cospi[32], hi, bit);
+      x[n - 1 - i] = half_btf(cospi[32], hi, cospi[32], lo, bit);
     }
-  } else {
-    unsafe {
-      av1_inv_txfm2d_add_4x4_c(
-        input.as_ptr(),
-        output.as_mut_ptr(),
-        stride as libc::c_int,
-        tx_type as libc::c_int,
-        8
-      );
+  }
+
+  let mut s = n;
+  while s >= 4 {
+    for group in x.chunks_mut(s) {
+      dct_odd_butterfly(group, &|v| v);
+    }
+    if s >= 8 {
+      dct_odd_rotation_stage(x, s, cospi, bit);
     }
+    s /= 2;
+  }
+
+  for i in 0..n / 2 {
+    let angle = dct_odd_output_angle(n, i);
+    let (cx, cy) = (cospi[64 - angle], cospi[angle]);
+    let (lo, hi) = (x[i], x[n - 1 - i]);
+    x[i] = half_btf(cx, lo, cy, hi, bit);
+    x[n - 1 - i] = half_btf(cx, hi, -cy, lo, bit);
   }
 }
 
-fn fht8x8(input: &[i16], output: &mut [i32], stride: usize, tx_type: TxType) {
-  unsafe {
-    av1_fwd_txfm2d_8x8_c(
-      input.as_ptr(),
-      output.as_mut_ptr(),
-      stride as libc::c_int,
-      tx_type as libc::c_int,
-      8
-    );
+fn fdct_stages(x: &mut [i32], cospi: &[i32; 64], bit: usize) {
+  let n = x.len();
+  if n == 2 {
+    let (a, b) = (x[0], x[1]);
+    x[0] = half_btf(cospi[32], a, cospi[32], b, bit);
+    x[1] = half_btf(-cospi[32], b, cospi[32], a, bit);
+    return;
+  }
+
+  for i in 0..n / 2 {
+    let (lo, hi) = (x[i], x[n - 1 - i]);
+    x[i] = lo + hi;
+    x[n - 1 - i] = lo - hi;
   }
+
+  let (even, odd) = x.split_at_mut(n / 2);
+  fdct_stages(even, cospi, bit);
+  fdct_odd(odd, cospi, bit);
 }
 
-fn iht8x8_add(
-  input: &[i32], output: &mut [u16], stride: usize, tx_type: TxType
-) {
-  // SIMD code may assert for transform types beyond TxType::IDTX.
-  if tx_type < TxType::IDTX {
-    unsafe {
-      av1_inv_txfm2d_add_8x8(
-        input.as_ptr(),
-        output.as_mut_ptr(),
-        stride as libc::c_int,
-        tx_type as libc::c_int,
-        8
-      );
+fn fdct(input: &[i32], output: &mut [i32], cos_bit: usize) {
+  let n = input.len();
+  let mut buf = [0i32; 64];
+  buf[..n].copy_from_slice(input);
+  fdct_stages(&mut buf[..n], cospi_arr(cos_bit), cos_bit);
+  for (k, out) in output[..n].iter_mut().enumerate() {
+    *out = buf[brev(log2(n), k)];
+  }
+}
+
+fn idct_odd(x: &mut [i32], cospi: &[i32; 64], bit: usize, range: usize) {
+  let n = x.len();
+  let clamp = |v| clamp_value(v, range);
+
+  for i in 0..n / 2 {
+    let angle = dct_odd_output_angle(n, i);
+    let (cx, cy) = (cospi[64 - angle], cospi[angle]);
+    let (lo, hi) = (x[i], x[n - 1 - i]);
+    x[i] = half_btf(cx, lo, -cy, hi, bit);
+    x[n - 1 - i] = half_btf(cy, lo, cx, hi, bit);
+  }
+
+  let mut s = 4;
+  while s <= n {
+    if s >= 8 {
+      dct_odd_rotation_stage(x, s, cospi, bit);
     }
-  } else {
-    unsafe {
-      av1_inv_txfm2d_add_8x8_c(
-        input.as_ptr(),
-        output.as_mut_ptr(),
-        stride as libc::c_int,
-        tx_type as libc::c_int,
-        8
-      );
+    for group in x.chunks_mut(s) {
+      dct_odd_butterfly(group, &clamp);
+    }
+    s *= 2;
+  }
+
+  if n >= 4 {
+    for i in n / 4..n / 2 {
+      let (lo, hi) = (x[i], x[n - 1 - i]);
+      x[i] = half_btf(-cospi[32], lo, cospi[32], hi, bit);
+      x[n - 1 - i] = half_btf(cospi[32], lo, cospi[32], hi, bit);
+    }
+  }
+}
+
+fn idct_stages(x: &mut [i32], cospi: &[i32; 64], bit: usize, range: usize) {
+  let n = x.len();
+  if n == 2 {
+    let (a, b) = (x[0], x[1]);
+    x[0] = half_btf(cospi[32], a, cospi[32], b, bit);
+    x[1] = half_btf(cospi[32], a, -cospi[32], b, bit);
+    return;
+  }
+
+  {
+    let (even, odd) = x.split_at_mut(n / 2);
+    idct_stages(even, cospi, bit, range);
+    idct_odd(odd, cospi, bit, range);
+  }
+
+  for i in 0..n / 2 {
+    let (lo, hi) = (x[i], x[n - 1 - i]);
+    x[i] = clamp_value(lo + hi, range);
+    x[n - 1 - i] = clamp_value(lo - hi, range);
+  }
+}
+
+fn idct(input: &[i32], output: &mut [i32], cos_bit: usize, range: usize) {
+  let n = input.len();
+  for (k, &coeff) in input.iter().enumerate() {
+    output[brev(log2(n), k)] = coeff;
+  }
+  idct_stages(&mut output[..n], cospi_arr(cos_bit), cos_bit, range);
+}
+
+fn fadst4(input: &[i32], output: &mut [i32], cos_bit: usize) {
+  let sinpi = sinpi_arr(cos_bit);
+
+  let (x0, x1, x2, x3) = (input[0], input[1], input[2], input[3]);
+
+  if (x0 | x1 | x2 | x3) == 0 {
+    for out in output[..4].iter_mut() {
+      *out = 0;
+    }
+    return;
+  }
+
+  let s0 = sinpi[1] * x0;
+  let s1 = sinpi[4] * x0;
+  let s2 = sinpi[2] * x1;
+  let s3 = sinpi[1] * x1;
+  let s4 = sinpi[3] * x2;
+  let s5 = sinpi[4] * x3;
+  let s6 = sinpi[2] * x3;
+  let s7 = x0 + x1 - x3;
+
+  let x0 = s0 + s2 + s5;
+  let x1 = sinpi[3] * s7;
+  let x2 = s1 - s3 + s6;
+  let x3 = s4;
+
+  let s0 = x0 + x3;
+  let s1 = x1;
+  let s2 = x2 - x3;
+  let s3 = x2 - x0 + x3;
+
+  // 1-D transform scaling factor is sqrt(2)
+  output[0] = round_shift(s0 as i64, cos_bit);
+  output[1] = round_shift(s1 as i64, cos_bit);
+  output[2] = round_shift(s2 as i64, cos_bit);
+  output[3] = round_shift(s3 as i64, cos_bit);
+}
+
+fn iadst4(input: &[i32], output: &mut [i32], cos_bit: usize) {
+  let sinpi = sinpi_arr(cos_bit);
+
+  let (x0, x1, x2, x3) = (input[0], input[1], input[2], input[3]);
+
+  if (x0 | x1 | x2 | x3) == 0 {
+    for out in output[..4].iter_mut() {
+      *out = 0;
+    }
+    return;
+  }
+
+  let s0 = sinpi[1] * x0;
+  let s1 = sinpi[2] * x0;
+  let s2 = sinpi[3] * x1;
+  let s3 = sinpi[4] * x2;
+  let s4 = sinpi[1] * x2;
+  let s5 = sinpi[2] * x3;
+  let s6 = sinpi[4] * x3;
+  let s7 = (x0 - x2) + x3;
+
+  let s0 = s0 + s3 + s5;
+  let s1 = s1 - s4 - s6;
+  let s3 = s2;
+  let s2 = sinpi[3] * s7;
+
+  output[0] = round_shift((s0 + s3) as i64, cos_bit);
+  output[1] = round_shift((s1 + s3) as i64, cos_bit);
+  output[2] = round_shift(s2 as i64, cos_bit);
+  output[3] = round_shift((s0 + s1 - s3) as i64, cos_bit);
+}
+
+// The 8- and 16-point ADSTs alternate rotations and butterflies between
+// sub-blocks of growing size `s`, then rotate the output pairs.
+
+// Input order of the inverse ADST outputs (and forward ADST inputs); odd
+// positions are negated
+const ADST8_PERM: [usize; 8] = [0, 4, 6, 2, 3, 7, 5, 1];
+const ADST16_PERM: [usize; 16] =
+  [0, 8, 12, 4, 6, 14, 10, 2, 3, 11, 15, 7, 5, 13, 9, 1];
+
+fn adst_perm(n: usize) -> &'static [usize] {
+  match n {
+    8 => &ADST8_PERM,
+    16 => &ADST16_PERM,
+    _ => unreachable!()
+  }
+}
+
+fn adst_rotation(x: &mut [i32], s: usize, cospi: &[i32; 64], bit: usize) {
+  let q = cmp::max(s / 4, 1);
+  for group in x.chunks_mut(2 * s) {
+    for j in 0..s / 2 {
+      let angle = 64 / s + 32 * (j % q);
+      let (ca, cb) = (cospi[angle], cospi[64 - angle]);
+      let (a, b) = (group[s + 2 * j], group[s + 2 * j + 1]);
+      if j < q {
+        group[s + 2 * j] = half_btf(ca, a, cb, b, bit);
+        group[s + 2 * j + 1] = half_btf(cb, a, -ca, b, bit);
+      } else {
+        group[s + 2 * j] = half_btf(-cb, a, ca, b, bit);
+        group[s + 2 * j + 1] = half_btf(ca, a, cb, b, bit);
+      }
+    }
+  }
+}
+
+fn adst_butterfly<F: Fn(i32) -> i32>(x: &mut [i32], s: usize, f: &F) {
+  for group in x.chunks_mut(2 * s) {
+    for i in 0..s {
+      let (a, b) = (group[i], group[s + i]);
+      group[i] = f(a + b);
+      group[s + i] = f(a - b);
     }
   }
 }
 
-fn fht16x16(
-  input: &[i16], output: &mut [i32], stride: usize, tx_type: TxType
+fn adst_output_rotation(x: &mut [i32], cospi: &[i32; 64], bit: usize) {
+  let n = x.len();
+  for k in 0..n / 2 {
+    let angle = 32 / n + (128 / n) * k;
+    let (ca, cb) = (cospi[angle], cospi[64 - angle]);
+    let (a, b) = (x[2 * k], x[2 * k + 1]);
+    x[2 * k] = half_btf(ca, a, cb, b, bit);
+    x[2 * k + 1] = half_btf(cb, a, -ca, b, bit);
+  }
+}
+
+fn fadst(input: &[i32], output: &mut [i32], cos_bit: usize) {
+  let n = input.len();
+  let cospi = cospi_arr(cos_bit);
+  let perm = adst_perm(n);
+
+  let mut buf = [0i32; 16];
+  let x = &mut buf[..n];
+  for (k, &v) in input.iter().enumerate() {
+    x[perm[k]] = if k & 1 == 1 { -v } else { v };
+  }
+
+  let mut s = 2;
+  while s < n {
+    adst_rotation(x, s, cospi, cos_bit);
+    adst_butterfly(x, s, &|v| v);
+    s *= 2;
+  }
+  adst_output_rotation(x, cospi, cos_bit);
+
+  for j in 0..n / 2 {
+    output[2 * j] = x[2 * j + 1];
+    output[2 * j + 1] = x[n - 2 - 2 * j];
+  }
+}
+
+fn iadst(input: &[i32], output: &mut [i32], cos_bit: usize, range: usize) {
+  let n = input.len();
+  let cospi = cospi_arr(cos_bit);
+  let perm = adst_perm(n);
+  let clamp = |v| clamp_value(v, range);
+
+  let mut buf = [0i32; 16];
+  let x = &mut buf[..n];
+  for j in 0..n / 2 {
+    x[2 * j + 1] = input[2 * j];
+    x[n - 2 - 2 * j] = input[2 * j + 1];
+  }
+
+  adst_output_rotation(x, cospi, cos_bit);
+  let mut s = n / 2;
+  while s >= 2 {
+    adst_butterfly(x, s, &clamp);
+    adst_rotation(x, s, cospi, cos_bit);
+    s /= 2;
+  }
+
+  for (k, out) in output[..n].iter_mut().enumerate() {
+    let v = x[perm[k]];
+    *out = if k & 1 == 1 { -v } else { v };
+  }
+}
+
+fn fidentity(input: &[i32], output: &mut [i32]) {
+  let n = input.len();
+  for (out, &v) in output[..n].iter_mut().zip(input) {
+    *out = match n {
+      4 => round_shift(NEW_SQRT2 as i64 * v as i64, NEW_SQRT2_BITS),
+      8 => v * 2,
+      16 => round_shift(NEW_SQRT2 as i64 * 2 * v as i64, NEW_SQRT2_BITS),
+      32 => v * 4,
+      _ => unreachable!()
+    };
+  }
+}
+
+fn iidentity(input: &[i32], output: &mut [i32]) {
+  // The identity transforms are their own inverses up to scaling
+  fidentity(input, output);
+}
+
+#[derive(Copy, Clone, PartialEq)]
+enum TxType1D {
+  DCT,
+  ADST,
+  FLIPADST,
+  IDTX
+}
+
+// Vertical and horizontal 1-D transforms
+fn get_1d_tx_types(tx_type: TxType) -> (TxType1D, TxType1D) {
+  match tx_type {
+    TxType::DCT_DCT => (TxType1D::DCT, TxType1D::DCT),
+    TxType::ADST_DCT => (TxType1D::ADST, TxType1D::DCT),
+    TxType::DCT_ADST => (TxType1D::DCT, TxType1D::ADST),
+    TxType::ADST_ADST => (TxType1D::ADST, TxType1D::ADST),
+    TxType::FLIPADST_DCT => (TxType1D::FLIPADST, TxType1D::DCT),
+    TxType::DCT_FLIPADST => (TxType1D::DCT, TxType1D::FLIPADST),
+    TxType::FLIPADST_FLIPADST => (TxType1D::FLIPADST, TxType1D::FLIPADST),
+    TxType::ADST_FLIPADST => (TxType1D::ADST, TxType1D::FLIPADST),
+    TxType::FLIPADST_ADST => (TxType1D::FLIPADST, TxType1D::ADST),
+    TxType::IDTX => (TxType1D::IDTX, TxType1D::IDTX),
+    TxType::V_DCT => (TxType1D::DCT, TxType1D::IDTX),
+    TxType::H_DCT => (TxType1D::IDTX, TxType1D::DCT),
+    TxType::V_ADST => (TxType1D::ADST, TxType1D::IDTX),
+    TxType::H_ADST => (TxType1D::IDTX, TxType1D::ADST),
+    TxType::V_FLIPADST => (TxType1D::FLIPADST, TxType1D::IDTX),
+    TxType::H_FLIPADST => (TxType1D::IDTX, TxType1D::FLIPADST)
+  }
+}
+
+fn fwd_txfm1d(
+  tx_type_1d: TxType1D, input: &[i32], output: &mut [i32], cos_bit: usize
 ) {
-  unsafe {
-    av1_fwd_txfm2d_16x16_c(
-      input.as_ptr(),
-      output.as_mut_ptr(),
-      stride as libc::c_int,
-      tx_type as libc::c_int,
-      8
-    );
+  match tx_type_1d {
+    TxType1D::DCT => fdct(input, output, cos_bit),
+    TxType1D::ADST | TxType1D::FLIPADST =>
+      if input.len() == 4 {
+        fadst4(input, output, cos_bit)
+      } else {
+        fadst(input, output, cos_bit)
+      },
+    TxType1D::IDTX => fidentity(input, output)
   }
 }
 
-fn iht16x16_add(
-  input: &[i32], output: &mut [u16], stride: usize, tx_type: TxType
+fn inv_txfm1d(
+  tx_type_1d: TxType1D, input: &[i32], output: &mut [i32], cos_bit: usize,
+  range: usize
 ) {
-  unsafe {
-    if tx_type < TxType::IDTX {
-      // SSE C code asserts for transform types beyond TxType::IDTX.
-      av1_inv_txfm2d_add_16x16(
-        input.as_ptr(),
-        output.as_mut_ptr(),
-        stride as libc::c_int,
-        tx_type as libc::c_int,
-        8
-      );
-    } else {
-      av1_inv_txfm2d_add_16x16_c(
-        input.as_ptr(),
-        output.as_mut_ptr(),
-        stride as libc::c_int,
-        tx_type as libc::c_int,
-        8
-      );
+  match tx_type_1d {
+    TxType1D::DCT => idct(input, output, cos_bit, range),
+    TxType1D::ADST | TxType1D::FLIPADST =>
+      if input.len() == 4 {
+        iadst4(input, output, cos_bit)
+      } else {
+        iadst(input, output, cos_bit, range)
+      },
+    TxType1D::IDTX => iidentity(input, output)
+  }
+}
+
+// Shifts before the column transform, after it and after the row transform
+const FWD_SHIFT: [[i8; 3]; TxSize::TX_SIZES_ALL] = [
+  [2, 0, 0],
+  [2, -1, 0],
+  [2, -2, 0],
+  [2, -4, 0],
+  [0, -2, -2],
+  [2, -1, 0],
+  [2, -1, 0],
+  [2, -2, 0],
+  [2, -2, 0],
+  [2, -4, 0],
+  [2, -4, 0],
+  [0, -2, -2],
+  [2, -4, -2],
+  [2, -1, 0],
+  [2, -1, 0],
+  [2, -2, 0],
+  [2, -2, 0],
+  [0, -2, 0],
+  [2, -4, 0]
+];
+
+// Shifts after the row transform and after the column transform
+const INV_SHIFT: [[i8; 2]; TxSize::TX_SIZES_ALL] = [
+  [0, -4],
+  [-1, -4],
+  [-2, -4],
+  [-2, -4],
+  [-2, -4],
+  [0, -4],
+  [0, -4],
+  [-1, -4],
+  [-1, -4],
+  [-1, -4],
+  [-1, -4],
+  [-1, -4],
+  [-1, -4],
+  [-1, -4],
+  [-1, -4],
+  [-2, -4],
+  [-2, -4],
+  [-2, -4],
+  [-2, -4]
+];
+
+// Indexed by [log2(width) - 2][log2(height) - 2]
+const FWD_COS_BIT_COL: [[usize; 5]; 5] = [
+  [13, 13, 13, 0, 0],
+  [13, 13, 13, 12, 0],
+  [13, 13, 13, 12, 13],
+  [0, 13, 13, 12, 13],
+  [0, 0, 13, 12, 13]
+];
+
+const FWD_COS_BIT_ROW: [[usize; 5]; 5] = [
+  [13, 13, 12, 0, 0],
+  [13, 13, 13, 12, 0],
+  [13, 13, 12, 13, 12],
+  [0, 12, 13, 12, 11],
+  [0, 0, 12, 11, 10]
+];
+
+const INV_COS_BIT: usize = 12;
+
+fn is_rect_2to1(tx_size: TxSize) -> bool {
+  let (w, h) = (tx_size.width_log2(), tx_size.height_log2());
+  w == h + 1 || h == w + 1
+}
+
+fn fwd_txfm2d(
+  input: &[i16], output: &mut [i32], stride: usize, tx_size: TxSize,
+  tx_type: TxType
+) {
+  let txfm_size_col = tx_size.width();
+  let txfm_size_row = tx_size.height();
+  let shift = FWD_SHIFT[tx_size as usize];
+  let txw_idx = tx_size.width_log2() - TxSize::smallest_width_log2();
+  let txh_idx = tx_size.height_log2() - TxSize::smallest_width_log2();
+  let cos_bit_col = FWD_COS_BIT_COL[txw_idx][txh_idx];
+  let cos_bit_row = FWD_COS_BIT_ROW[txw_idx][txh_idx];
+  let (col_type, row_type) = get_1d_tx_types(tx_type);
+  let ud_flip = col_type == TxType1D::FLIPADST;
+  let lr_flip = row_type == TxType1D::FLIPADST;
+
+  let mut buf = [0i32; 64 * 64];
+  let mut temp_in = [0i32; 64];
+  let mut temp_out = [0i32; 64];
+
+  // Columns
+  for c in 0..txfm_size_col {
+    for r in 0..txfm_size_row {
+      let r_in = if ud_flip { txfm_size_row - r - 1 } else { r };
+      temp_in[r] = input[r_in * stride + c] as i32;
+    }
+    round_shift_array(&mut temp_in[..txfm_size_row], -shift[0]);
+    fwd_txfm1d(
+      col_type,
+      &temp_in[..txfm_size_row],
+      &mut temp_out[..txfm_size_row],
+      cos_bit_col
+    );
+    round_shift_array(&mut temp_out[..txfm_size_row], -shift[1]);
+    let c_out = if lr_flip { txfm_size_col - c - 1 } else { c };
+    for r in 0..txfm_size_row {
+      buf[r * txfm_size_col + c_out] = temp_out[r];
+    }
+  }
+
+  // Rows
+  for r in 0..txfm_size_row {
+    let row_in = &buf[r * txfm_size_col..(r + 1) * txfm_size_col];
+    let row_out = &mut output[r * txfm_size_col..(r + 1) * txfm_size_col];
+    fwd_txfm1d(row_type, row_in, row_out, cos_bit_row);
+    round_shift_array(row_out, -shift[2]);
+    if is_rect_2to1(tx_size) {
+      for v in row_out.iter_mut() {
+        *v = round_shift(*v as i64 * NEW_SQRT2 as i64, NEW_SQRT2_BITS);
+      }
     }
   }
 }
 
-fn fht32x32(
-  input: &[i16], output: &mut [i32], stride: usize, tx_type: TxType
+fn inv_txfm2d_add(
+  input: &[i32], output: &mut [u16], stride: usize, tx_size: TxSize,
+  tx_type: TxType, bd: usize
 ) {
-  unsafe {
-    av1_fwd_txfm2d_32x32_c(
-      input.as_ptr(),
-      output.as_mut_ptr(),
-      stride as libc::c_int,
-      tx_type as libc::c_int,
-      8
+  let txfm_size_col = tx_size.width();
+  let txfm_size_row = tx_size.height();
+  let shift = INV_SHIFT[tx_size as usize];
+  let (col_type, row_type) = get_1d_tx_types(tx_type);
+  let ud_flip = col_type == TxType1D::FLIPADST;
+  let lr_flip = row_type == TxType1D::FLIPADST;
+  let range_row = bd + 8;
+  let range_col = cmp::max(bd + 6, 16);
+
+  // Only the top-left 32x32 coefficients of 64-point transforms are coded
+  let coded_w = cmp::min(txfm_size_col, 32);
+  let coded_h = cmp::min(txfm_size_row, 32);
+
+  let mut buf = [0i32; 64 * 64];
+  let mut temp_in = [0i32; 64];
+  let mut temp_out = [0i32; 64];
+
+  // Rows
+  for r in 0..txfm_size_row {
+    for c in 0..txfm_size_col {
+      temp_in[c] = if r < coded_h && c < coded_w {
+        input[r * coded_w + c]
+      } else {
+        0
+      };
+      if is_rect_2to1(tx_size) {
+        temp_in[c] =
+          round_shift(temp_in[c] as i64 * NEW_INV_SQRT2 as i64, NEW_SQRT2_BITS);
+      }
+    }
+    clamp_buf(&mut temp_in[..txfm_size_col], range_row);
+    let row_out = &mut buf[r * txfm_size_col..(r + 1) * txfm_size_col];
+    inv_txfm1d(
+      row_type,
+      &temp_in[..txfm_size_col],
+      row_out,
+      INV_COS_BIT,
+      range_row
     );
+    round_shift_array(row_out, -shift[0]);
+  }
+
+  // Columns
+  for c in 0..txfm_size_col {
+    let c_in = if lr_flip { txfm_size_col - c - 1 } else { c };
+    for r in 0..txfm_size_row {
+      temp_in[r] = buf[r * txfm_size_col + c_in];
+    }
+    clamp_buf(&mut temp_in[..txfm_size_row], range_col);
+    inv_txfm1d(
+      col_type,
+      &temp_in[..txfm_size_row],
+      &mut temp_out[..txfm_size_row],
+      INV_COS_BIT,
+      range_col
+    );
+    round_shift_array(&mut temp_out[..txfm_size_row], -shift[1]);
+    for r in 0..txfm_size_row {
+      let r_in = if ud_flip { txfm_size_row - r - 1 } else { r };
+      let v = output[r * stride + c] as i32 + temp_out[r_in];
+      output[r * stride + c] = cmp::min(cmp::max(v, 0), (1 << bd) - 1) as u16;
+    }
+  }
+}
+
+// Transforms with a 64-point dimension only keep the 32 lowest frequencies
+// along it. Their coefficients are packed at the start of the buffer with a
+// stride of at most 32, which is also the layout the inverse expects.
+pub fn forward_transform(
+  input: &[i16], output: &mut [i32], stride: usize, tx_size: TxSize,
+  tx_type: TxType
+) {
+  fwd_txfm2d(input, output, stride, tx_size, tx_type);
+
+  let txfm_size_col = tx_size.width();
+  if txfm_size_col == 64 {
+    for r in 1..cmp::min(tx_size.height(), 32) {
+      for c in 0..32 {
+        output[r * 32 + c] = output[r * 64 + c];
+      }
+    }
   }
 }
 
-fn iht32x32_add(
-  input: &[i32], output: &mut [u16], stride: usize, tx_type: TxType
+pub fn inverse_transform_add(
+  input: &[i32], output: &mut [u16], stride: usize, tx_size: TxSize,
+  tx_type: TxType
 ) {
-  unsafe {
-    if tx_type < TxType::IDTX {
-      // SIMDI code may assert for transform types beyond TxType::IDTX.
-      av1_inv_txfm2d_add_32x32(
-        input.as_ptr(),
-        output.as_mut_ptr(),
-        stride as libc::c_int,
+  inv_txfm2d_add(input, output, stride, tx_size, tx_type, 8);
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+  use partition::TxSize::*;
+  use partition::TxType::*;
+  use partition::TX_TYPES;
+  use libc;
+  use rand::{ChaChaRng, Rng, SeedableRng};
+  use std::f64::consts::PI;
+
+  type CFwdTxfm = unsafe extern "C" fn(
+    input: *const i16,
+    output: *mut i32,
+    stride: libc::c_int,
+    tx_type: libc::c_int,
+    bd: libc::c_int
+  );
+
+  type CInvTxfmAdd = unsafe extern "C" fn(
+    input: *const i32,
+    output: *mut u16,
+    stride: libc::c_int,
+    tx_type: libc::c_int,
+    bd: libc::c_int
+  );
+
+  macro_rules! aom_txfm {
+    ($($tx_size:ident => $fwd:ident, $inv:ident;)*) => {
+      extern {
+        $(
+          fn $fwd(
+            input: *const i16, output: *mut i32, stride: libc::c_int,
+            tx_type: libc::c_int, bd: libc::c_int
+          );
+          fn $inv(
+            input: *const i32, output: *mut u16, stride: libc::c_int,
+            tx_type: libc::c_int, bd: libc::c_int
+          );
+        )*
+      }
+
+      fn aom_txfm(tx_size: TxSize) -> (CFwdTxfm, CInvTxfmAdd) {
+        match tx_size {
+          $($tx_size => ($fwd as CFwdTxfm, $inv as CInvTxfmAdd),)*
+        }
+      }
+    };
+  }
+
+  aom_txfm! {
+    TX_4X4 => av1_fwd_txfm2d_4x4_c, av1_inv_txfm2d_add_4x4_c;
+    TX_8X8 => av1_fwd_txfm2d_8x8_c, av1_inv_txfm2d_add_8x8_c;
+    TX_16X16 => av1_fwd_txfm2d_16x16_c, av1_inv_txfm2d_add_16x16_c;
+    TX_32X32 => av1_fwd_txfm2d_32x32_c, av1_inv_txfm2d_add_32x32_c;
+    TX_64X64 => av1_fwd_txfm2d_64x64_c, av1_inv_txfm2d_add_64x64_c;
+    TX_4X8 => av1_fwd_txfm2d_4x8_c, av1_inv_txfm2d_add_4x8_c;
+    TX_8X4 => av1_fwd_txfm2d_8x4_c, av1_inv_txfm2d_add_8x4_c;
+    TX_8X16 => av1_fwd_txfm2d_8x16_c, av1_inv_txfm2d_add_8x16_c;
+    TX_16X8 => av1_fwd_txfm2d_16x8_c, av1_inv_txfm2d_add_16x8_c;
+    TX_16X32 => av1_fwd_txfm2d_16x32_c, av1_inv_txfm2d_add_16x32_c;
+    TX_32X16 => av1_fwd_txfm2d_32x16_c, av1_inv_txfm2d_add_32x16_c;
+    TX_32X64 => av1_fwd_txfm2d_32x64_c, av1_inv_txfm2d_add_32x64_c;
+    TX_64X32 => av1_fwd_txfm2d_64x32_c, av1_inv_txfm2d_add_64x32_c;
+    TX_4X16 => av1_fwd_txfm2d_4x16_c, av1_inv_txfm2d_add_4x16_c;
+    TX_16X4 => av1_fwd_txfm2d_16x4_c, av1_inv_txfm2d_add_16x4_c;
+    TX_8X32 => av1_fwd_txfm2d_8x32_c, av1_inv_txfm2d_add_8x32_c;
+    TX_32X8 => av1_fwd_txfm2d_32x8_c, av1_inv_txfm2d_add_32x8_c;
+    TX_16X64 => av1_fwd_txfm2d_16x64_c, av1_inv_txfm2d_add_16x64_c;
+    TX_64X16 => av1_fwd_txfm2d_64x16_c, av1_inv_txfm2d_add_64x16_c;
+  }
+
+  const ALL_TX_SIZES: [TxSize; TxSize::TX_SIZES_ALL] = [
+    TX_4X4, TX_8X8, TX_16X16, TX_32X32, TX_64X64, TX_4X8, TX_8X4, TX_8X16,
+    TX_16X8, TX_16X32, TX_32X16, TX_32X64, TX_64X32, TX_4X16, TX_16X4,
+    TX_8X32, TX_32X8, TX_16X64, TX_64X16
+  ];
+
+  const ALL_TX_TYPES: [TxType; TX_TYPES] = [
+    DCT_DCT, ADST_DCT, DCT_ADST, ADST_ADST, FLIPADST_DCT, DCT_FLIPADST,
+    FLIPADST_FLIPADST, ADST_FLIPADST, FLIPADST_ADST, IDTX, V_DCT, H_DCT,
+    V_ADST, H_ADST, V_FLIPADST, H_FLIPADST
+  ];
+
+  // The types libaom implements for a size: there is no 32-point ADST and
+  // 64-point transforms are DCT only
+  fn valid_tx_types(tx_size: TxSize) -> &'static [TxType] {
+    match cmp::max(tx_size.width(), tx_size.height()) {
+      64 => &ALL_TX_TYPES[..1],
+      32 => &[DCT_DCT, IDTX],
+      _ => &ALL_TX_TYPES
+    }
+  }
+
+  const TXFM_ITER: usize = 16;
+
+  fn check_txfm(ra: &mut ChaChaRng, tx_size: TxSize, tx_type: TxType) {
+    let (fwd_aom, inv_aom) = aom_txfm(tx_size);
+    let (w, h) = (tx_size.width(), tx_size.height());
+    let coded_area = cmp::min(w, 32) * cmp::min(h, 32);
+
+    let residual: Vec<i16> =
+      (0..w * h).map(|_| ra.gen_range(-255, 256)).collect();
+    let mut c1 = [0i32; 64 * 64];
+    let mut c2 = [0i32; 64 * 64];
+    unsafe {
+      fwd_aom(
+        residual.as_ptr(),
+        c1.as_mut_ptr(),
+        w as libc::c_int,
         tx_type as libc::c_int,
         8
       );
-    } else {
-      av1_inv_txfm2d_add_32x32_c(
-        input.as_ptr(),
-        output.as_mut_ptr(),
-        stride as libc::c_int,
+    }
+    forward_transform(&residual, &mut c2, w, tx_size, tx_type);
+    assert_eq!(
+      &c1[..coded_area],
+      &c2[..coded_area],
+      "forward {:?} {:?}",
+      tx_size,
+      tx_type
+    );
+
+    // Perturb the coefficients as quantization would, so the inverse also
+    // sees inputs that do not come from a forward transform
+    for c in c1[..coded_area].iter_mut() {
+      *c += ra.gen_range(-16, 17);
+    }
+    let pred: Vec<u16> = (0..w * h).map(|_| ra.gen::<u8>() as u16).collect();
+    let mut o1 = pred.clone();
+    let mut o2 = pred;
+    unsafe {
+      inv_aom(
+        c1.as_ptr(),
+        o1.as_mut_ptr(),
+        w as libc::c_int,
         tx_type as libc::c_int,
         8
       );
     }
+    inverse_transform_add(&c1[..coded_area], &mut o2, w, tx_size, tx_type);
+    assert_eq!(o1, o2, "inverse {:?} {:?}", tx_size, tx_type);
+  }
+
+  #[test]
+  fn txfm_matches() {
+    let mut ra = ChaChaRng::from_seed([0; 32]);
+    for &tx_size in ALL_TX_SIZES.iter() {
+      for &tx_type in valid_tx_types(tx_size) {
+        for _ in 0..TXFM_ITER {
+          check_txfm(&mut ra, tx_size, tx_type);
+        }
+      }
+    }
+  }
+
+  #[test]
+  fn sinpi_sum() {
+    for sinpi in SINPI.iter() {
+      assert_eq!(sinpi[1] + sinpi[2], sinpi[4]);
+    }
+  }
+
+  // The integer DCT approximates sqrt(n / 2) times the orthonormal DCT-II
+  #[test]
+  fn fdct_matches_float_dct() {
+    let mut ra = ChaChaRng::from_seed([0; 32]);
+    for &n in [4, 8, 16, 32, 64].iter() {
+      let input: Vec<i32> = (0..n).map(|_| ra.gen_range(-2048, 2048)).collect();
+      let mut output = [0i32; 64];
+      fdct(&input, &mut output, 13);
+      for k in 0..n {
+        let scale = if k == 0 { (1.0 / 2.0f64).sqrt() } else { 1.0 };
+        let expected: f64 = input
+          .iter()
+          .enumerate()
+          .map(|(i, &x)| {
+            x as f64 * (PI * (2 * i + 1) as f64 * k as f64 / (2 * n) as f64).cos()
+          })
+          .sum::<f64>()
+          * scale;
+        assert!(
+          (output[k] as f64 - expected).abs() < n as f64,
+          "n = {} k = {}: {} != {}",
+          n,
+          k,
+          output[k],
+          expected
+        );
+      }
+    }
+  }
+
+  fn roundtrip(tx_size: TxSize, tx_type: TxType) {
+    let mut ra = ChaChaRng::from_seed([0; 32]);
+    let (w, h) = (tx_size.width(), tx_size.height());
+    let pred: Vec<u16> = (0..w * h).map(|_| ra.gen_range(64, 192)).collect();
+    // 64-point transforms drop the upper half of the spectrum, so only
+    // flat residuals survive them
+    let src: Vec<u16> = if w == 64 || h == 64 {
+      let offset = ra.gen_range(-64, 64);
+      pred.iter().map(|&p| (p as i32 + offset) as u16).collect()
+    } else {
+      (0..w * h).map(|_| ra.gen_range(0, 256)).collect()
+    };
+    let residual: Vec<i16> =
+      src.iter().zip(&pred).map(|(&s, &p)| s as i16 - p as i16).collect();
+
+    let mut coeffs = [0i32; 64 * 64];
+    forward_transform(&residual, &mut coeffs, w, tx_size, tx_type);
+
+    let coded_area = cmp::min(w, 32) * cmp::min(h, 32);
+    let mut rec = pred.clone();
+    inverse_transform_add(&coeffs[..coded_area], &mut rec, w, tx_size, tx_type);
+
+    for (&s, &r) in src.iter().zip(&rec) {
+      assert!(
+        (s as i32 - r as i32).abs() <= 2,
+        "{:?} {:?}: {} != {}",
+        tx_size,
+        tx_type,
+        s,
+        r
+      );
+    }
+  }
+
+  #[test]
+  fn roundtrip_square() {
+    for &tx_size in [TX_4X4, TX_8X8, TX_16X16].iter() {
+      for &tx_type in [
+        DCT_DCT, ADST_DCT, DCT_ADST, ADST_ADST, FLIPADST_DCT, DCT_FLIPADST,
+        FLIPADST_FLIPADST, ADST_FLIPADST, FLIPADST_ADST, IDTX, V_DCT, H_DCT,
+        V_ADST, H_ADST, V_FLIPADST, H_FLIPADST
+      ].iter()
+      {
+        roundtrip(tx_size, tx_type);
+      }
+    }
+    roundtrip(TX_32X32, DCT_DCT);
+    roundtrip(TX_32X32, IDTX);
+  }
+
+  #[test]
+  fn roundtrip_rectangular() {
+    for &tx_size in [
+      TX_4X8, TX_8X4, TX_8X16, TX_16X8, TX_16X32, TX_32X16, TX_4X16, TX_16X4,
+      TX_8X32, TX_32X8
+    ].iter()
+    {
+      roundtrip(tx_size, DCT_DCT);
+    }
+    roundtrip(TX_4X8, ADST_FLIPADST);
+    roundtrip(TX_16X4, FLIPADST_ADST);
+  }
+
+  #[test]
+  fn roundtrip_64() {
+    for &tx_size in
+      [TX_64X64, TX_32X64, TX_64X32, TX_16X64, TX_64X16].iter()
+    {
+      roundtrip(tx_size, DCT_DCT);
+    }
   }
 }